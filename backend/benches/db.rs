@@ -0,0 +1,91 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use redis::Client;
+
+use efficio_server::{db, storage::Connection, types::*};
+
+// needs a running Redis, this database is flushed before each run
+const BENCH_DB_ADDR: &str = "redis://127.0.0.1/15";
+const USERNAME: &str = "bench_user";
+const PASSWORD: &str = "correct horse battery staple";
+
+fn connect() -> Connection {
+    let mut c = Client::open(BENCH_DB_ADDR)
+        .and_then(|client| client.get_connection())
+        .expect("benches need a Redis server on 127.0.0.1");
+    redis::cmd("FLUSHDB")
+        .query::<()>(&mut c)
+        .expect("error on flush");
+    Connection::Redis(c)
+}
+
+fn create_user(c: &mut Connection) -> ConnectionToken {
+    let user = User {
+        username: USERNAME.to_owned(),
+        email: "bench@efficio.app".into(),
+        password: PASSWORD.into(),
+        invite: None,
+        challenge: None,
+    };
+    db::users::save_user(c, &user).unwrap()
+}
+
+// a store with `aisles` aisles of `products` products each
+fn fill_store(c: &mut Connection, auth: &Auth, aisles: usize, products: usize) -> StoreId {
+    let store_id = db::stores::save_store(c, &auth, "bench store").unwrap();
+    for i in 0..aisles {
+        let aisle = db::aisles::save_aisle(c, &auth, &store_id, &format!("aisle {}", i)).unwrap();
+        let aisle_id = AisleId(aisle.aisle_id);
+        for j in 0..products {
+            db::products::save_product(c, &auth, &format!("product {}", j), &aisle_id).unwrap();
+        }
+    }
+    store_id
+}
+
+fn store_assembly(criterion: &mut Criterion) {
+    let mut c = connect();
+    let token = create_user(&mut c);
+    let auth = Auth(&token.session_token);
+    let mut group = criterion.benchmark_group("list_store");
+    for &(aisles, products) in &[(1, 10), (10, 10), (20, 30)] {
+        let store_id = fill_store(&mut c, &auth, aisles, products);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", aisles, products)),
+            &store_id,
+            |b, store_id| b.iter(|| db::stores::list_store(&mut c, &auth, &store_id).unwrap()),
+        );
+    }
+    group.finish();
+
+    let store_id = fill_store(&mut c, &auth, 10, 10);
+    let version = db::journal::current_version(&mut c, &store_id).unwrap();
+    let aisle = db::aisles::save_aisle(&mut c, &auth, &store_id, "new aisle").unwrap();
+    db::products::save_product(&mut c, &auth, "new product", &AisleId(aisle.aisle_id)).unwrap();
+    criterion.bench_function("list_store_since", |b| {
+        b.iter(|| db::stores::list_store_since(&mut c, &auth, &store_id, version).unwrap())
+    });
+    criterion.bench_function("get_all_stores", |b| {
+        b.iter(|| db::stores::get_all_stores(&mut c, &auth).unwrap())
+    });
+}
+
+fn auth(criterion: &mut Criterion) {
+    let mut c = connect();
+    let token = create_user(&mut c);
+    let auth = Auth(&token.session_token);
+    criterion.bench_function("validate_session", |b| {
+        b.iter(|| db::sessions::validate_session(&mut c, &auth).unwrap())
+    });
+    criterion.bench_function("login", |b| {
+        b.iter(|| {
+            let auth_info = AuthInfo {
+                username: USERNAME.to_owned(),
+                password: PASSWORD.into(),
+            };
+            db::users::login(&mut c, &auth_info).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, store_assembly, auth);
+criterion_main!(benches);
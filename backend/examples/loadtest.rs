@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use argh::FromArgs;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+
+use efficio_server::endpoints::HEADER_AUTH;
+
+#[derive(FromArgs)]
+/// Replay shopping traffic against a running Efficio server
+struct Opt {
+    /// api root, default http://127.0.0.1:3030/api
+    #[argh(option, default = "String::from(\"http://127.0.0.1:3030/api\")")]
+    url: String,
+    /// number of simulated users, default 10
+    #[argh(option, default = "10")]
+    users: usize,
+    /// stores per user, default 3
+    #[argh(option, default = "3")]
+    stores: usize,
+    /// aisles per store, default 5
+    #[argh(option, default = "5")]
+    aisles: usize,
+    /// products per aisle, default 10
+    #[argh(option, default = "10")]
+    products: usize,
+    /// shopping rounds replayed by each user, default 20
+    #[argh(option, default = "20")]
+    rounds: usize,
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: HashMap<&'static str, Vec<Duration>>,
+    errors: HashMap<&'static str, usize>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        for (name, mut latencies) in other.latencies {
+            self.latencies
+                .entry(name)
+                .or_default()
+                .append(&mut latencies);
+        }
+        for (name, errors) in other.errors {
+            *self.errors.entry(name).or_default() += errors;
+        }
+    }
+
+    fn report(mut self) {
+        println!(
+            "{:<16}{:>8}{:>8}{:>10}{:>10}{:>10}{:>10}",
+            "request", "count", "errors", "mean ms", "p50 ms", "p95 ms", "max ms"
+        );
+        let mut names: Vec<_> = self.latencies.keys().copied().collect();
+        names.sort();
+        for name in names {
+            let latencies = self.latencies.get_mut(name).unwrap();
+            latencies.sort();
+            let ms = |d: Duration| d.as_secs_f64() * 1000f64;
+            let percentile = |p: usize| ms(latencies[(latencies.len() - 1) * p / 100]);
+            let total: Duration = latencies.iter().sum();
+            println!(
+                "{:<16}{:>8}{:>8}{:>10.2}{:>10.2}{:>10.2}{:>10.2}",
+                name,
+                latencies.len(),
+                self.errors.get(name).unwrap_or(&0),
+                ms(total) / latencies.len() as f64,
+                percentile(50),
+                percentile(95),
+                ms(latencies[latencies.len() - 1]),
+            );
+        }
+    }
+}
+
+struct Session {
+    client: Client,
+    url: String,
+    auth: String,
+    stats: Stats,
+}
+
+impl Session {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, &format!("{}/{}", self.url, path))
+            .header(HEADER_AUTH, self.auth.as_str())
+    }
+
+    // send the request and time it, the body is parsed as JSON when there is one
+    async fn send(&mut self, name: &'static str, request: RequestBuilder) -> Option<Value> {
+        let start = Instant::now();
+        let res = match request.send().await {
+            Ok(res) if res.status() == StatusCode::OK => res.bytes().await.ok(),
+            _ => None,
+        };
+        self.stats
+            .latencies
+            .entry(name)
+            .or_default()
+            .push(start.elapsed());
+        if res.is_none() {
+            *self.stats.errors.entry(name).or_default() += 1;
+        }
+        res.map(|body| serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+}
+
+async fn simulate_user(opt: &Opt, run: u64, user: usize) -> Stats {
+    let mut session = Session {
+        client: Client::new(),
+        url: opt.url.clone(),
+        auth: String::new(),
+        stats: Stats::default(),
+    };
+    let username = format!("loadtest{}x{}", run, user);
+    let body = json!({
+        "username": username,
+        "email": format!("{}@loadtest.efficio.app", username),
+        "password": "correct horse battery staple",
+    });
+    let request = session.request(Method::POST, "user").json(&body);
+    let token = match session.send("create_user", request).await {
+        Some(token) => token,
+        None => return session.stats,
+    };
+    session.auth = token["session_token"]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned();
+    let user_id = token["user_id"].as_str().unwrap_or_default().to_owned();
+
+    // build the user's stores
+    let mut stores = vec![];
+    let mut products = vec![];
+    let mut aisles = vec![];
+    for s in 0..opt.stores {
+        let request = session
+            .request(Method::POST, "store")
+            .json(&json!({ "name": format!("store {}", s) }));
+        let store_id = match session.send("create_store", request).await {
+            Some(store) => store["store_id"].as_str().unwrap_or_default().to_owned(),
+            None => continue,
+        };
+        for a in 0..opt.aisles {
+            let request = session
+                .request(Method::POST, &format!("store/{}/aisle", store_id))
+                .json(&json!({ "name": format!("aisle {}", a) }));
+            let aisle_id = match session.send("create_aisle", request).await {
+                Some(aisle) => aisle["aisle_id"].as_str().unwrap_or_default().to_owned(),
+                None => continue,
+            };
+            for p in 0..opt.products {
+                let request = session
+                    .request(Method::POST, &format!("aisle/{}/product", aisle_id))
+                    .json(&json!({ "name": format!("product {}", p) }));
+                if let Some(product) = session.send("create_product", request).await {
+                    products.push(
+                        product["product_id"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned(),
+                    );
+                }
+            }
+            aisles.push(aisle_id);
+        }
+        stores.push(store_id);
+    }
+
+    // shopping: open the list, a store, tick products and sometimes rename an aisle
+    for round in 0..opt.rounds {
+        let request = session.request(Method::GET, "store");
+        session.send("list_stores", request).await;
+        if !stores.is_empty() {
+            let path = format!("store/{}", stores[round % stores.len()]);
+            let request = session.request(Method::GET, &path);
+            session.send("list_store", request).await;
+        }
+        for product_id in products.iter().skip(round).step_by(opt.rounds.max(1)) {
+            let request = session
+                .request(Method::PUT, &format!("product/{}", product_id))
+                .json(&json!({ "is_done": round % 2 == 0 }));
+            session.send("edit_product", request).await;
+        }
+        if round % 5 == 0 && !aisles.is_empty() {
+            let request = session
+                .request(
+                    Method::PUT,
+                    &format!("aisle/{}", aisles[round % aisles.len()]),
+                )
+                .json(&json!({ "name": format!("aisle renamed {}", round) }));
+            session.send("rename_aisle", request).await;
+        }
+    }
+
+    let request = session.request(Method::DELETE, &format!("user/{}", user_id));
+    session.send("delete_user", request).await;
+    session.stats
+}
+
+#[tokio::main]
+async fn main() {
+    let opt: &'static Opt = Box::leak(Box::new(argh::from_env()));
+    // usernames must not collide with a previous run
+    let run = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let start = Instant::now();
+    let handles: Vec<_> = (0..opt.users)
+        .map(|user| tokio::spawn(simulate_user(opt, run, user)))
+        .collect();
+    let mut stats = Stats::default();
+    for handle in handles {
+        stats.merge(handle.await.expect("simulated user panicked"));
+    }
+    println!(
+        "{} users replayed in {:.2}s",
+        opt.users,
+        start.elapsed().as_secs_f64()
+    );
+    stats.report();
+}
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use efficio_server::{projection, types::*};
+
+// `fields` parameter of GET /store/<id> applied to a small store
+fuzz_target!(|data: &[u8]| {
+    let fields = match std::str::from_utf8(data) {
+        Ok(fields) => fields,
+        Err(_) => return,
+    };
+    let product = Product::new(
+        "p1".to_owned(),
+        "Apple".to_owned(),
+        1,
+        false,
+        Unit::Unit,
+        1f32,
+    );
+    let aisle = Aisle::new("a1".to_owned(), "Fruits".to_owned(), 1f32, vec![product]);
+    let store = Store::new("s1".to_owned(), "MyStore".to_owned(), vec![aisle]);
+    let _ = projection::project(&store, fields);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use efficio_server::types::*;
+
+// bodies of the POST/PUT endpoints, dropping User and AuthInfo runs their wiping Drop
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<User>(data);
+    let _ = serde_json::from_slice::<AuthInfo>(data);
+    let _ = serde_json::from_slice::<NameData>(data);
+    if let Ok(edit) = serde_json::from_slice::<EditProduct>(data) {
+        edit.has_at_least_a_field();
+    }
+    if let Ok(edit) = serde_json::from_slice::<EditWeight>(data) {
+        edit.has_at_least_a_field();
+    }
+});
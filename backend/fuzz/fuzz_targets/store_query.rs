@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use efficio_server::types::StoreQuery;
+
+// query string of GET /store/<id>, decoded like warp::query does
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_urlencoded::from_bytes::<StoreQuery>(data);
+});
@@ -0,0 +1,641 @@
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+
+pub use efficio_types as types;
+use efficio_types::*;
+
+#[derive(Debug)]
+pub enum Error {
+    // the server could not be reached or sent something unreadable
+    Http(reqwest::Error),
+    // the server refused the request, with its reason
+    Api { status: StatusCode, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Api { status, message } => write!(f, "{}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Client of the Efficio API. Creating a user or logging in keeps the session
+// token for the following requests, and the tokens the server rotates it to.
+// Clones share the session. The admin endpoints are left out, they only
+// answer local clients.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    // like `https://efficio.app`, without the `/api`
+    base_url: String,
+    token: Arc<RwLock<Option<String>>>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // Use a session opened elsewhere
+    pub fn with_token(self, token: &str) -> Self {
+        self.set_token(Some(token.to_owned()));
+        self
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.token.read().unwrap().clone()
+    }
+
+    fn set_token(&self, token: Option<String>) {
+        *self.token.write().unwrap() = token;
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, &format!("{}/api/{}", self.base_url, path));
+        match self.token() {
+            Some(token) => request.header(HEADER_AUTH, token),
+            None => request,
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response> {
+        let res = request.send().await?;
+        if let Some(token) = res
+            .headers()
+            .get(HEADER_NEW_AUTH)
+            .and_then(|v| v.to_str().ok())
+        {
+            self.set_token(Some(token.to_owned()));
+        }
+        let status = res.status();
+        if status.is_success() {
+            Ok(res)
+        } else {
+            Err(Error::Api {
+                status,
+                message: res.text().await.unwrap_or_default(),
+            })
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    async fn empty(&self, request: RequestBuilder) -> Result<()> {
+        self.send(request).await.map(|_| ())
+    }
+
+    // Whether the server is in maintenance
+    pub async fn health(&self) -> Result<Health> {
+        self.json(self.request(Method::GET, "health")).await
+    }
+
+    // What to solve before signing up, answered in `User::challenge`
+    pub async fn challenge(&self) -> Result<ChallengeInfo> {
+        self.json(self.request(Method::GET, "challenge")).await
+    }
+
+    // Whether signing up with that username would work
+    pub async fn check_username(&self, username: &str) -> Result<UsernameCheck> {
+        let query = UsernameQuery {
+            username: username.to_owned(),
+        };
+        self.json(self.request(Method::GET, "user/check").query(&query))
+            .await
+    }
+
+    // Sign up, the client is then logged in as the new user
+    pub async fn create_user(&mut self, user: &User) -> Result<ConnectionToken> {
+        let token: ConnectionToken = self
+            .json(self.request(Method::POST, "user").json(user))
+            .await?;
+        self.set_token(Some(token.session_token.to_string()));
+        Ok(token)
+    }
+
+    pub async fn login(&mut self, auth_info: &AuthInfo) -> Result<ConnectionToken> {
+        let token: ConnectionToken = self
+            .json(self.request(Method::POST, "login").json(auth_info))
+            .await?;
+        self.set_token(Some(token.session_token.to_string()));
+        Ok(token)
+    }
+
+    // A code to log in on another device with `claim_pairing`
+    pub async fn pair(&self) -> Result<PairingCode> {
+        self.json(self.request(Method::POST, "user/pair")).await
+    }
+
+    // Log in with a code from a device of the user
+    pub async fn claim_pairing(&mut self, code: &str) -> Result<ConnectionToken> {
+        let claim = PairingClaim {
+            code: code.to_owned(),
+        };
+        let token: ConnectionToken = self
+            .json(self.request(Method::POST, "user/pair/claim").json(&claim))
+            .await?;
+        self.set_token(Some(token.session_token.to_string()));
+        Ok(token)
+    }
+
+    pub async fn logout(&mut self, user_id: &str) -> Result<()> {
+        let path = format!("logout/{}", user_id);
+        self.empty(self.request(Method::POST, &path)).await?;
+        self.set_token(None);
+        Ok(())
+    }
+
+    // Delete the user and everything they own
+    pub async fn delete_user(&mut self, user_id: &str) -> Result<()> {
+        let path = format!("user/{}", user_id);
+        self.empty(self.request(Method::DELETE, &path)).await?;
+        self.set_token(None);
+        Ok(())
+    }
+
+    // What `delete_user` would remove, removing nothing
+    pub async fn user_removal(&self, user_id: &str) -> Result<UserRemoval> {
+        let path = format!("user/{}", user_id);
+        let query = DeleteQuery {
+            dry_run: Some(true),
+        };
+        self.json(self.request(Method::DELETE, &path).query(&query))
+            .await
+    }
+
+    pub async fn stores(&self) -> Result<StoreLightList> {
+        self.json(self.request(Method::GET, "store")).await
+    }
+
+    pub async fn create_store(&self, name: &str) -> Result<StoreId> {
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.json(self.request(Method::POST, "store").json(&data))
+            .await
+    }
+
+    pub async fn create_store_with(&self, data: &NewStore) -> Result<StoreId> {
+        self.json(self.request(Method::POST, "store").json(data))
+            .await
+    }
+
+    pub async fn default_aisles(&self) -> Result<DefaultAisles> {
+        self.json(self.request(Method::GET, "user/default_aisles"))
+            .await
+    }
+
+    // None to go back to the server's
+    pub async fn set_default_aisles(&self, aisles: Option<Vec<String>>) -> Result<()> {
+        let data = DefaultAisles { aisles };
+        self.empty(self.request(Method::PUT, "user/default_aisles").json(&data))
+            .await
+    }
+
+    pub async fn aliases(&self) -> Result<ProductAliases> {
+        self.json(self.request(Method::GET, "user/aliases")).await
+    }
+
+    // Replace all the user's aliases
+    pub async fn set_aliases(&self, aliases: &ProductAliases) -> Result<()> {
+        self.empty(self.request(Method::PUT, "user/aliases").json(aliases))
+            .await
+    }
+
+    pub async fn anywhere(&self) -> Result<AnywhereProducts> {
+        self.json(self.request(Method::GET, "user/anywhere")).await
+    }
+
+    // Checked in one store, so gone from all
+    pub async fn check_anywhere(&self, product_id: &str) -> Result<()> {
+        let path = format!("user/anywhere/{}", product_id);
+        self.empty(self.request(Method::DELETE, &path)).await
+    }
+
+    pub async fn quotas(&self) -> Result<UserQuotas> {
+        self.json(self.request(Method::GET, "user/quota")).await
+    }
+
+    pub async fn staples(&self) -> Result<Staples> {
+        self.json(self.request(Method::GET, "user/staples")).await
+    }
+
+    // Replace all the user's staples
+    pub async fn set_staples(&self, staples: &Staples) -> Result<()> {
+        self.empty(self.request(Method::PUT, "user/staples").json(staples))
+            .await
+    }
+
+    // Add a staple, or move the one of that name to another aisle
+    pub async fn add_staple(&self, staple: &Staple) -> Result<()> {
+        self.empty(self.request(Method::POST, "user/staples").json(staple))
+            .await
+    }
+
+    pub async fn delete_staple(&self, name: &str) -> Result<()> {
+        let query = StapleQuery {
+            name: name.to_owned(),
+        };
+        self.empty(self.request(Method::DELETE, "user/staples").query(&query))
+            .await
+    }
+
+    pub async fn templates(&self) -> Result<AisleTemplates> {
+        self.json(self.request(Method::GET, "templates")).await
+    }
+
+    // Save the aisle layout of a store, replacing the template of that name
+    pub async fn save_template(&self, name: &str, store_id: &str) -> Result<AisleTemplate> {
+        let data = StoreTemplate::new(name.to_owned(), store_id.to_owned());
+        self.json(self.request(Method::POST, "templates").json(&data))
+            .await
+    }
+
+    pub async fn delete_template(&self, name: &str) -> Result<()> {
+        let query = TemplateQuery {
+            name: name.to_owned(),
+        };
+        self.empty(self.request(Method::DELETE, "templates").query(&query))
+            .await
+    }
+
+    // Add the template's aisles the store lacks
+    pub async fn apply_template(&self, name: &str, store_id: &str) -> Result<AddedAisles> {
+        let data = StoreTemplate::new(name.to_owned(), store_id.to_owned());
+        self.json(self.request(Method::POST, "templates/apply").json(&data))
+            .await
+    }
+
+    // A new store copied from someone's template code, named after theirs
+    // unless `name` is given
+    pub async fn redeem_template(&self, code: &str, name: Option<&str>) -> Result<StoreId> {
+        let data = RedeemTemplate::new(code.to_owned(), name.map(str::to_owned));
+        self.json(self.request(Method::POST, "templates/redeem").json(&data))
+            .await
+    }
+
+    pub async fn store(&self, store_id: &str) -> Result<Store> {
+        let path = format!("store/{}", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    // What changed since `version`, or the whole store if that is too old
+    pub async fn store_since(&self, store_id: &str, version: u64) -> Result<VersionedStore> {
+        let path = format!("store/{}", store_id);
+        let query = StoreQuery {
+            fields: None,
+            since_version: Some(version),
+        };
+        self.json(self.request(Method::GET, &path).query(&query))
+            .await
+    }
+
+    // Printable HTML page of the store
+    pub async fn print_store(&self, store_id: &str, include_done: bool) -> Result<String> {
+        let path = format!("store/{}/print", store_id);
+        let query = PrintQuery {
+            include_done: Some(include_done),
+        };
+        let res = self
+            .send(self.request(Method::GET, &path).query(&query))
+            .await?;
+        Ok(res.text().await?)
+    }
+
+    // The products left to buy as plain text
+    pub async fn text_store(&self, store_id: &str) -> Result<String> {
+        let path = format!("store/{}/text", store_id);
+        let res = self.send(self.request(Method::GET, &path)).await?;
+        Ok(res.text().await?)
+    }
+
+    pub async fn store_stats(&self, store_id: &str) -> Result<StoreStats> {
+        let path = format!("store/{}/stats", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn ui_state(&self, store_id: &str) -> Result<StoreUiState> {
+        let path = format!("store/{}/ui_state", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn set_ui_state(&self, store_id: &str, state: &StoreUiState) -> Result<()> {
+        let path = format!("store/{}/ui_state", store_id);
+        self.empty(self.request(Method::PUT, &path).json(state))
+            .await
+    }
+
+    pub async fn crdt_store(&self, store_id: &str) -> Result<CrdtStore> {
+        let path = format!("store/{}/crdt", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn merge_crdt_store(&self, store_id: &str, state: &CrdtStore) -> Result<CrdtStore> {
+        let path = format!("store/{}/crdt", store_id);
+        self.json(self.request(Method::POST, &path).json(state))
+            .await
+    }
+
+    // Of the products left to buy
+    pub async fn store_nutrition(&self, store_id: &str) -> Result<StoreNutrition> {
+        let path = format!("store/{}/nutrition", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    // Published templates whose supermarket or location has `query` in it
+    pub async fn community(&self, query: &str) -> Result<CommunityTemplates> {
+        let query = CommunityQuery {
+            q: Some(query.to_owned()),
+        };
+        self.json(self.request(Method::GET, "community").query(&query))
+            .await
+    }
+
+    // Publish one of the user's templates to the community directory
+    pub async fn publish_template(&self, data: &PublishTemplate) -> Result<CommunityTemplate> {
+        self.json(self.request(Method::POST, "community").json(data))
+            .await
+    }
+
+    pub async fn report_template(&self, template_id: &str) -> Result<()> {
+        let path = format!("community/{}/report", template_id);
+        self.empty(self.request(Method::POST, &path)).await
+    }
+
+    // Copy a published template to the user's own
+    pub async fn save_community_template(&self, template_id: &str) -> Result<AisleTemplate> {
+        let path = format!("community/{}/save", template_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    // For other users to copy the store with `redeem_template`
+    pub async fn template_code(&self, store_id: &str) -> Result<TemplateCode> {
+        let path = format!("store/{}/template_code", store_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    pub async fn create_snapshot(&self, store_id: &str, name: &str) -> Result<StoreSnapshot> {
+        let path = format!("store/{}/snapshot", store_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.json(self.request(Method::POST, &path).json(&data))
+            .await
+    }
+
+    // Newest first
+    pub async fn snapshots(&self, store_id: &str) -> Result<StoreSnapshots> {
+        let path = format!("store/{}/snapshots", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    // The store as it is once restored
+    pub async fn restore_snapshot(&self, store_id: &str, snapshot_id: &str) -> Result<Store> {
+        let path = format!("store/{}/snapshots/{}/restore", store_id, snapshot_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    // What changed from one snapshot to the other, `other_id` being "live" for
+    // the store as it is now
+    pub async fn snapshot_diff(
+        &self,
+        store_id: &str,
+        snapshot_id: &str,
+        other_id: &str,
+    ) -> Result<StoreDiff> {
+        let path = format!(
+            "store/{}/snapshots/{}/diff/{}",
+            store_id, snapshot_id, other_id
+        );
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    // Add the user's staples the store lacks
+    pub async fn add_staples(&self, store_id: &str) -> Result<AddedStaples> {
+        let path = format!("store/{}/add_staples", store_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    pub async fn store_insights(&self, store_id: &str) -> Result<StoreInsights> {
+        let path = format!("store/{}/insights", store_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn rename_store(&self, store_id: &str, name: &str) -> Result<()> {
+        let path = format!("store/{}", store_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.empty(self.request(Method::PUT, &path).json(&data))
+            .await
+    }
+
+    // What the products created in the store start with
+    pub async fn set_product_defaults(
+        &self,
+        store_id: &str,
+        defaults: &ProductDefaults,
+    ) -> Result<()> {
+        let path = format!("store/{}", store_id);
+        let data = EditStore {
+            name: None,
+            defaults: Some(defaults.clone()),
+        };
+        self.empty(self.request(Method::PUT, &path).json(&data))
+            .await
+    }
+
+    // Move the other store into this one, or only tell what would move
+    pub async fn merge_store(
+        &self,
+        store_id: &str,
+        other_id: &str,
+        dry_run: bool,
+    ) -> Result<StoreMerge> {
+        let path = format!("store/{}/merge_from/{}", store_id, other_id);
+        let query = MergeQuery {
+            dry_run: Some(dry_run),
+        };
+        self.json(self.request(Method::POST, &path).query(&query))
+            .await
+    }
+
+    pub async fn delete_store(&self, store_id: &str) -> Result<()> {
+        let path = format!("store/{}", store_id);
+        self.empty(self.request(Method::DELETE, &path)).await
+    }
+
+    // What `delete_store` would remove, removing nothing
+    pub async fn store_removal(&self, store_id: &str) -> Result<StoreRemoval> {
+        let path = format!("store/{}", store_id);
+        let query = DeleteQuery {
+            dry_run: Some(true),
+        };
+        self.json(self.request(Method::DELETE, &path).query(&query))
+            .await
+    }
+
+    pub async fn export_link(&self, store_id: &str) -> Result<ExportLink> {
+        let path = format!("store/{}/export_link", store_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    // Download through a link, which needs no session
+    pub async fn export(&self, link: &ExportLink) -> Result<Store> {
+        let url = format!("{}{}", self.base_url, link.url);
+        self.json(self.http.get(&url)).await
+    }
+
+    // `origin` is the web app's, what the link starts with
+    pub async fn share_link(&self, store_id: &str, origin: &str) -> Result<ShareLink> {
+        let path = format!("store/{}/share_link", store_id);
+        let data = ShareParams {
+            origin: origin.to_owned(),
+        };
+        self.json(self.request(Method::POST, &path).json(&data))
+            .await
+    }
+
+    pub async fn create_aisle(&self, store_id: &str, name: &str) -> Result<Aisle> {
+        let path = format!("store/{}/aisle", store_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.json(self.request(Method::POST, &path).json(&data))
+            .await
+    }
+
+    pub async fn rename_aisle(&self, aisle_id: &str, name: &str) -> Result<()> {
+        let path = format!("aisle/{}", aisle_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.empty(self.request(Method::PUT, &path).json(&data))
+            .await
+    }
+
+    // Add a product to the store, in the aisle the server guesses
+    pub async fn create_product_in_store(
+        &self,
+        store_id: &str,
+        name: &str,
+    ) -> Result<PlacedProduct> {
+        let path = format!("store/{}/product", store_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.json(self.request(Method::POST, &path).json(&data))
+            .await
+    }
+
+    // Looked up by the product's name
+    pub async fn product_nutrition(&self, product_id: &str) -> Result<ProductNutrition> {
+        let path = format!("product/{}/nutrition", product_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn product_history(&self, product_id: &str) -> Result<ProductHistory> {
+        let path = format!("product/{}/history", product_id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    // By barcode, or by name if None
+    pub async fn nutrition(&self, barcode: Option<&str>, name: Option<&str>) -> Result<Nutrition> {
+        let query = NutritionQuery {
+            barcode: barcode.map(str::to_owned),
+            name: name.map(str::to_owned),
+        };
+        self.json(self.request(Method::GET, "nutrition").query(&query))
+            .await
+    }
+
+    // Out of its store, into all of the user's until checked
+    pub async fn mark_anywhere(&self, product_id: &str) -> Result<Product> {
+        let path = format!("product/{}/anywhere", product_id);
+        self.json(self.request(Method::POST, &path)).await
+    }
+
+    pub async fn delete_aisle(&self, aisle_id: &str) -> Result<()> {
+        let path = format!("aisle/{}", aisle_id);
+        self.empty(self.request(Method::DELETE, &path)).await
+    }
+
+    pub async fn create_product(&self, aisle_id: &str, name: &str) -> Result<Product> {
+        let path = format!("aisle/{}/product", aisle_id);
+        let data = NameData {
+            name: name.to_owned(),
+        };
+        self.json(self.request(Method::POST, &path).json(&data))
+            .await
+    }
+
+    pub async fn edit_product(&self, product_id: &str, data: &EditProduct) -> Result<()> {
+        let path = format!("product/{}", product_id);
+        self.empty(self.request(Method::PUT, &path).json(data))
+            .await
+    }
+
+    pub async fn delete_product(&self, product_id: &str) -> Result<()> {
+        let path = format!("product/{}", product_id);
+        self.empty(self.request(Method::DELETE, &path)).await
+    }
+
+    // All of them or none
+    pub async fn set_products_done(&self, product_ids: &[&str], is_done: bool) -> Result<()> {
+        let ids = product_ids.iter().map(|id| id.to_string()).collect();
+        let data = ProductsDone::new(ids, is_done);
+        self.empty(self.request(Method::PUT, "products/done").json(&data))
+            .await
+    }
+
+    // All of them or none
+    pub async fn delete_products(&self, product_ids: &[&str]) -> Result<()> {
+        let data = ProductIds::new(product_ids.iter().map(|id| id.to_string()).collect());
+        self.empty(self.request(Method::DELETE, "products").json(&data))
+            .await
+    }
+
+    pub async fn change_sort_weight(&self, data: &EditWeight) -> Result<()> {
+        self.empty(self.request(Method::PUT, "sort_weight").json(data))
+            .await
+    }
+
+    pub async fn assistant_add(&self, data: &AssistantAdd) -> Result<AssistantReply> {
+        self.json(self.request(Method::POST, "assistant/add").json(data))
+            .await
+    }
+
+    pub async fn assistant_list(&self, data: &AssistantList) -> Result<AssistantReply> {
+        self.json(self.request(Method::POST, "assistant/list").json(data))
+            .await
+    }
+
+    // JSON Schema of the bodies, by route
+    pub async fn schema(&self) -> Result<serde_json::Value> {
+        self.json(self.request(Method::GET, "schema")).await
+    }
+}
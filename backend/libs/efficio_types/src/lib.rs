@@ -0,0 +1,1315 @@
+use std::{
+    cmp::Ordering, collections::BTreeMap, convert::Infallible, fmt, str::FromStr, string::ToString,
+};
+
+use derive_deref::Deref;
+use derive_new::new;
+#[cfg(feature = "schema")]
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use zeroize::Zeroize;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// Header carrying the session token
+pub const HEADER_AUTH: &str = "x-auth-token";
+// Header carrying the token replacing the session token, when rotated
+pub const HEADER_NEW_AUTH: &str = "x-new-auth-token";
+
+// Password, email, token or hash: hidden from Debug and wiped from memory on drop
+#[derive(Clone, Default, Deref, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(s)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        SecretString(s.to_owned())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretString(***)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AuthInfo {
+    // or the email
+    pub username: String,
+    pub password: SecretString,
+}
+
+#[derive(Debug, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ConnectionToken {
+    pub session_token: SecretString,
+    pub user_id: String,
+}
+
+// Body of a cookie mode login, the session token itself stays in an HttpOnly cookie
+#[derive(Debug, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct CookieSession {
+    pub user_id: String,
+    pub csrf_token: String,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct User {
+    pub username: String,
+    pub email: SecretString,
+    pub password: SecretString,
+    // needed when registration is by invitation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
+    // answer to GET /challenge, when the server asks for one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+}
+
+// Anti-bot test to pass before signing up, from GET /challenge
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ChallengeInfo {
+    None,
+    // the answer is the token the widget gives
+    Captcha { provider: String, site_key: String },
+    // the answer is `<seed>:<nonce>` whose BLAKE2b-256 hash starts with
+    // `difficulty` zero bits
+    Pow { seed: String, difficulty: u8 },
+}
+
+#[derive(Debug, Deref, PartialEq, Eq)]
+pub struct UserId(pub String);
+
+impl ToString for UserId {
+    fn to_string(&self) -> String {
+        self.0.to_owned()
+    }
+}
+
+impl FromStr for UserId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(UserId(s.to_owned()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, new, Deref, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreId {
+    pub store_id: String,
+}
+
+impl fmt::Display for StoreId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.store_id)
+    }
+}
+
+// Store, aisle and product ids are UUIDs, parsing them turns away the others
+impl FromStr for StoreId {
+    type Err = InvalidId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_uuid(s).map(|()| StoreId::new(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Deref, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AisleId(pub String);
+
+impl fmt::Display for AisleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for AisleId {
+    type Err = InvalidId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_uuid(s).map(|()| AisleId(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Deref, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProductId(pub String);
+
+impl fmt::Display for ProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ProductId {
+    type Err = InvalidId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_uuid(s).map(|()| ProductId(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidId;
+
+impl fmt::Display for InvalidId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Malformed id")
+    }
+}
+
+// hyphenated, as `Uuid::to_hyphenated_ref` writes them
+fn check_uuid(s: &str) -> Result<(), InvalidId> {
+    let well_formed = s.len() == 36
+        && s.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        });
+    if well_formed {
+        Ok(())
+    } else {
+        Err(InvalidId)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, new, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreLight {
+    pub name: String,
+    pub store_id: String,
+    // products in the store, and the ones not done yet
+    #[serde(default)]
+    pub items: u64,
+    #[serde(default)]
+    pub items_left: u64,
+    // UNIX timestamp in seconds of the last change, unknown for stores not
+    // changed since it is kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+}
+
+// GET /store/<id>/stats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreStats {
+    pub items: u64,
+    pub items_left: u64,
+    // share of the products done, from 0 to 100, 0 for an empty store
+    pub completed: f32,
+    // changes made to the store since it was created
+    pub changes: u64,
+    pub aisles: Vec<AisleStats>,
+    // most added first, over the weeks in `weeks`
+    pub most_added: Vec<AddedProduct>,
+    // this week first, weeks with nothing added included
+    pub weeks: Vec<AddedInWeek>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AisleStats {
+    pub aisle_id: String,
+    pub name: String,
+    pub items: u64,
+    pub items_left: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AddedProduct {
+    // lowercased, products of the same name or aliases of it count as one
+    pub name: String,
+    pub times: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AddedInWeek {
+    // UNIX timestamp in seconds
+    pub week_start: u64,
+    pub products: Vec<AddedProduct>,
+}
+
+// GET /store/<id>/insights
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreInsights {
+    // added more than once over the weeks of the stats, most first
+    pub readded: Vec<AddedProduct>,
+    // bought the most first
+    pub habits: Vec<PurchaseHabit>,
+}
+
+// How often a product is bought, that is marked done
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct PurchaseHabit {
+    // lowercased and resolved, like `AddedProduct`
+    pub name: String,
+    pub times: u64,
+    // UNIX timestamp in seconds
+    pub last_bought: u64,
+    // unknown until bought twice
+    pub average_days: Option<f32>,
+    // when the average says it will be needed again
+    pub due_at: Option<u64>,
+    // bought on a trip, unknown for the trips from before it was counted
+    pub average_quantity: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct NameData {
+    pub name: String,
+}
+
+// Body of POST /store
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct NewStore {
+    pub name: String,
+    // start empty rather than with the default aisles
+    #[serde(default)]
+    pub no_default_aisles: bool,
+}
+
+// The aisles new stores of a user start with, see `PUT /user/default_aisles`.
+// None for the server's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct DefaultAisles {
+    pub aisles: Option<Vec<String>>,
+}
+
+// POST /store/<id>/merge_from/<other id>, what was moved into the store or
+// would be with `?dry_run=true`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreMerge {
+    // aisles of the other store with no namesake, moved as they are
+    pub moved_aisles: Vec<String>,
+    // aisles of the store that got the products of their namesakes
+    pub merged_aisles: Vec<MergedAisle>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct MergedAisle {
+    pub name: String,
+    pub moved_products: Vec<String>,
+    // added to the quantity of a product of the same name, or an alias of
+    // it, and unit
+    pub combined_products: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct MergeQuery {
+    pub dry_run: Option<bool>,
+}
+
+// Query of DELETE /store/<id> and /user/<id>, `dry_run` to only get what
+// would be removed
+#[derive(Default, Serialize, Deserialize)]
+pub struct DeleteQuery {
+    pub dry_run: Option<bool>,
+}
+
+// What DELETE /store/<id>?dry_run=true would remove
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreRemoval {
+    pub store_id: String,
+    pub name: String,
+    pub aisles: u64,
+    pub products: u64,
+}
+
+// What DELETE /user/<id>?dry_run=true would remove, the stores by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct UserRemoval {
+    pub username: String,
+    pub stores: Vec<StoreRemoval>,
+}
+
+// How POST /store/<id>/product picked the aisle of the product
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AisleGuess {
+    // the aisle of the name the user last added the product to
+    History,
+    // none of the store's aisles had that name, or the product is new to the
+    // user: in the store's unsorted aisle
+    Unsorted,
+    // the same, but the unsorted aisle was made for it
+    NewUnsorted,
+}
+
+// Reply of POST /store/<id>/product, the aisle is a guess the client may
+// correct
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct PlacedProduct {
+    pub aisle_id: String,
+    pub aisle_name: String,
+    pub guess: AisleGuess,
+    pub product: Product,
+}
+
+// GET and PUT /user/aliases, alias to the name of the product it stands for
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ProductAliases {
+    pub aliases: BTreeMap<String, String>,
+}
+
+// Nutrition facts of a product per 100 g or 100 ml, the ones the source
+// knows
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Nutrition {
+    // what the source calls the product
+    pub name: Option<String>,
+    pub energy_kcal: Option<f32>,
+    pub fat_g: Option<f32>,
+    pub carbohydrates_g: Option<f32>,
+    pub sugars_g: Option<f32>,
+    pub proteins_g: Option<f32>,
+    pub salt_g: Option<f32>,
+}
+
+impl Nutrition {
+    pub fn has_facts(&self) -> bool {
+        self.energy_kcal.is_some()
+            || self.fat_g.is_some()
+            || self.carbohydrates_g.is_some()
+            || self.sugars_g.is_some()
+            || self.proteins_g.is_some()
+            || self.salt_g.is_some()
+    }
+}
+
+// An edit of a product
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ProductEdit {
+    // UNIX timestamp in seconds
+    pub at: u64,
+    // among `name`, `quantity`, `unit` and `is_done`, the fields whose last
+    // edit it was, the earlier edits of a field aren't kept
+    pub fields: Vec<String>,
+}
+
+// GET /product/<id>/history, from the journal of its store
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ProductHistory {
+    pub product_id: String,
+    // newest first, as far back as the journal goes
+    pub edits: Vec<ProductEdit>,
+    // None if never bought
+    pub purchases: Option<PurchaseHabit>,
+}
+
+// GET /product/<id>/nutrition, looked up by the product's name
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ProductNutrition {
+    pub product_id: String,
+    pub name: String,
+    // null if the source knows nothing of it
+    pub nutrition: Option<Nutrition>,
+}
+
+// GET /store/<id>/nutrition, of the products left to buy
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreNutrition {
+    pub products: Vec<ProductNutrition>,
+    // each fact averaged over the products it is known of
+    pub average: Nutrition,
+    // products the source knows nothing of
+    pub unknown: u32,
+}
+
+// GET /nutrition?barcode=<code> or ?name=<name>
+#[derive(Serialize, Deserialize)]
+pub struct NutritionQuery {
+    pub barcode: Option<String>,
+    pub name: Option<String>,
+}
+
+// GET /user/anywhere, the products the user needs from any store
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AnywhereProducts {
+    pub products: Vec<Product>,
+}
+
+// A product the user's household always needs, added by
+// POST /store/<id>/add_staples to the store's aisle of that name
+#[derive(Debug, Clone, PartialEq, Eq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Staple {
+    pub name: String,
+    pub aisle: String,
+}
+
+// GET and PUT /user/staples, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Staples {
+    pub staples: Vec<Staple>,
+}
+
+// DELETE /user/staples?name=<name>
+#[derive(Serialize, Deserialize)]
+pub struct StapleQuery {
+    pub name: String,
+}
+
+// What POST /store/<id>/add_staples added: the aisles it had to make and the
+// staples missing from the store
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AddedStaples {
+    pub aisles: Vec<Aisle>,
+    pub products: Vec<ProductChange>,
+}
+
+// A store's aisle names in order, saved to lay out other stores the same way
+#[derive(Debug, Clone, PartialEq, Eq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AisleTemplate {
+    pub name: String,
+    pub aisles: Vec<String>,
+}
+
+// GET /templates, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AisleTemplates {
+    pub templates: Vec<AisleTemplate>,
+}
+
+// POST /templates to save the layout of a store, and POST /templates/apply
+// to lay a store out after a template
+#[derive(Debug, Clone, PartialEq, Eq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct StoreTemplate {
+    pub name: String,
+    pub store_id: String,
+}
+
+// DELETE /templates?name=<name>
+#[derive(Serialize, Deserialize)]
+pub struct TemplateQuery {
+    pub name: String,
+}
+
+// What POST /templates/apply added, the aisles the store was missing
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AddedAisles {
+    pub aisles: Vec<Aisle>,
+}
+
+// A point in time copy of a store, from POST /store/<id>/snapshot
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreSnapshot {
+    pub snapshot_id: String,
+    pub name: String,
+    // UNIX timestamp in seconds
+    pub created_at: u64,
+    pub aisles: u64,
+    pub products: u64,
+}
+
+// GET /store/<id>/snapshots, the newest first
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreSnapshots {
+    pub snapshots: Vec<StoreSnapshot>,
+}
+
+// An aisle as it was in one of the states of a store compared
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct DiffedAisle {
+    pub name: String,
+    pub sort_weight: f32,
+}
+
+// Renamed or moved
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ChangedAisle {
+    pub before: DiffedAisle,
+    pub after: DiffedAisle,
+}
+
+// A product as it was in one of the states of a store compared, with the name
+// of its aisle
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct DiffedProduct {
+    pub aisle: String,
+    pub name: String,
+    pub quantity: u32,
+    pub unit: Unit,
+    pub is_done: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ChangedProduct {
+    pub before: DiffedProduct,
+    pub after: DiffedProduct,
+}
+
+// GET /store/<id>/snapshots/<a>/diff/<b>, what became of the store of
+// snapshot `a` in `b`
+#[derive(Debug, Default, PartialEq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreDiff {
+    pub added_aisles: Vec<DiffedAisle>,
+    pub removed_aisles: Vec<DiffedAisle>,
+    pub changed_aisles: Vec<ChangedAisle>,
+    pub added_products: Vec<DiffedProduct>,
+    pub removed_products: Vec<DiffedProduct>,
+    pub changed_products: Vec<ChangedProduct>,
+}
+
+impl StoreDiff {
+    // by name, the products by aisle then name
+    pub fn sort(&mut self) {
+        let aisle = |a: &DiffedAisle| a.name.to_lowercase();
+        let product = |p: &DiffedProduct| (p.aisle.to_lowercase(), p.name.to_lowercase());
+        self.added_aisles.sort_by_key(aisle);
+        self.removed_aisles.sort_by_key(aisle);
+        self.changed_aisles.sort_by_key(|c| aisle(&c.after));
+        self.added_products.sort_by_key(product);
+        self.removed_products.sort_by_key(product);
+        self.changed_products.sort_by_key(|c| product(&c.after));
+    }
+}
+
+// From POST /store/<id>/template_code, for other users to copy the store
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct TemplateCode {
+    pub code: String,
+    pub expires_in_s: u64,
+}
+
+// POST /templates/redeem, the copy is named after the store unless `name` is
+// given
+#[derive(Debug, Clone, PartialEq, Eq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct RedeemTemplate {
+    pub code: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+// A template of the community directory, published by a user for anyone to
+// lay their stores out with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct CommunityTemplate {
+    pub template_id: String,
+    pub name: String,
+    pub supermarket: String,
+    pub location: String,
+    pub aisles: Vec<String>,
+    // UNIX timestamp in seconds
+    pub published_at: u64,
+}
+
+// GET /community?q=<text>, by supermarket then location
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct CommunityTemplates {
+    pub templates: Vec<CommunityTemplate>,
+}
+
+// GET /community?q=<text>, matching the supermarket or the location
+#[derive(Serialize, Deserialize)]
+pub struct CommunityQuery {
+    #[serde(default)]
+    pub q: Option<String>,
+}
+
+// POST /community, `name` is of one of the user's templates
+#[derive(Debug, Clone, PartialEq, Eq, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct PublishTemplate {
+    pub name: String,
+    pub supermarket: String,
+    #[serde(default)]
+    pub location: String,
+}
+
+// GET and PUT /store/<id>/ui_state, how the user left the list on their
+// last device
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreUiState {
+    pub collapsed_aisles: Vec<String>,
+    pub hide_checked: bool,
+}
+
+// A field with when it was last edited, in milliseconds since the epoch: the
+// latest edit wins, the greater value on a tie
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Stamped<T> {
+    pub value: T,
+    pub at: u64,
+}
+
+// A product of the set, its id is made by the one client adding it. The aisle
+// is only read when it is added
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct CrdtProduct {
+    pub product_id: String,
+    pub aisle_id: String,
+    pub name: Stamped<String>,
+    pub quantity: Stamped<u32>,
+    pub unit: Stamped<Unit>,
+    pub is_done: Stamped<bool>,
+}
+
+// GET and POST /store/<id>/crdt, the products of the store as a set where
+// a product is in once added and until removed, `removed` keeping the ids of
+// the ones removed. Merged in any order, the same states give the same store
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct CrdtStore {
+    pub products: Vec<CrdtProduct>,
+    pub removed: Vec<String>,
+}
+
+// 422 reply to a JSON body that doesn't deserialize
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct InvalidBody {
+    // path to the field like `products[0].name`, empty for the whole body
+    pub field: String,
+    // the type or the values it takes, when known
+    pub expected: Option<String>,
+    // what the field broke: `type`, `value`, `length`, `required`,
+    // `unknown`, `duplicate` or `syntax` when the body isn't JSON at all
+    pub constraint: String,
+    pub msg: String,
+}
+
+// Body of POST /admin/invites, one use valid for a week by default
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InviteData {
+    pub uses: Option<u32>,
+    pub ttl_s: Option<u64>,
+}
+
+// Body of PUT /admin/maintenance, and the reply of GET. While enabled, the API
+// answers 503 but for the admin routes and GET /health.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Maintenance {
+    pub enabled: bool,
+    // for the users, a generic notice if empty
+    pub message: String,
+    // when clients should come back, a minute if 0
+    pub retry_after_s: u64,
+}
+
+// Body of PUT /admin/read_only
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadOnlySwitch {
+    pub enabled: bool,
+}
+
+// Reply of GET /health
+#[derive(Debug, Serialize, Deserialize, new, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Health {
+    pub maintenance: bool,
+    // only reads are served, the database's schema is out of range
+    pub read_only: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoreQuery {
+    pub fields: Option<String>,
+    pub since_version: Option<u64>,
+}
+
+// Signature of a link made by POST /store/<id>/export_link or share_link
+#[derive(Serialize, Deserialize)]
+pub struct ExportQuery {
+    pub expires: u64,
+    pub sig: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PrintQuery {
+    pub include_done: Option<bool>,
+}
+
+// Short-lived URL to download a store export, for clients like browser
+// download managers that can't send the session token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ExportLink {
+    pub url: String,
+    // UNIX timestamp in seconds
+    pub expires_at: u64,
+}
+
+// Body of POST /store/<id>/share_link, the web app's origin, like
+// `https://efficio.app`, which the links start with
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ShareParams {
+    pub origin: String,
+}
+
+// The unchecked products as a message for people without an account, ending
+// with a read-only link to the list as it is when they open it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ShareLink {
+    pub text: String,
+    pub url: String,
+    // the text in a new SMS or WhatsApp message
+    pub sms: String,
+    pub whatsapp: String,
+    // UNIX timestamp in seconds
+    pub expires_at: u64,
+}
+
+// How much of a per-user quota is used, `max` is None for no limit.
+// `warning` is set once `used` passes the share of `max` the server warns at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct QuotaUsage {
+    pub quota: String,
+    pub used: u64,
+    pub max: Option<u64>,
+    pub warning: bool,
+}
+
+// GET /user/quota
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct UserQuotas {
+    pub quotas: Vec<QuotaUsage>,
+}
+
+// Code for another device to log in with, from POST /user/pair
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct PairingCode {
+    pub code: String,
+    pub expires_in_s: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct PairingClaim {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UsernameQuery {
+    pub username: String,
+}
+
+// Whether a username would be accepted by POST /user, for live validation
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct UsernameCheck {
+    pub valid: bool,
+    pub available: bool,
+    // why it is not valid
+    pub problems: Vec<String>,
+}
+
+// Account linking request of a voice assistant, OAuth implicit grant
+#[derive(Serialize, Deserialize)]
+pub struct LinkQuery {
+    pub response_type: String,
+    pub redirect_uri: String,
+    pub state: Option<String>,
+}
+
+// What the user said after "add", in the store given or their first one
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AssistantAdd {
+    pub text: String,
+    pub store_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AssistantList {
+    pub store_id: Option<String>,
+}
+
+// Sentence for the assistant to say
+#[derive(Debug, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AssistantReply {
+    pub speech: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, new, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreLightList {
+    pub stores: Vec<StoreLight>,
+}
+
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Store {
+    pub store_id: String,
+    pub name: String,
+    pub aisles: Vec<Aisle>,
+    // the user's products needed from any store, in none of the aisles
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anywhere: Vec<Product>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "ProductDefaults::is_default")]
+    pub defaults: ProductDefaults,
+}
+
+// What the products created in a store start with, set by PUT /store/<id>
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ProductDefaults {
+    pub quantity: u32,
+    pub unit: Unit,
+}
+
+impl Default for ProductDefaults {
+    fn default() -> Self {
+        ProductDefaults {
+            quantity: 1,
+            unit: Unit::Unit,
+        }
+    }
+}
+
+impl ProductDefaults {
+    pub fn is_default(&self) -> bool {
+        *self == ProductDefaults::default()
+    }
+}
+
+// Body of PUT /store/<id>, what is missing stays as it is
+#[derive(Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct EditStore {
+    pub name: Option<String>,
+    pub defaults: Option<ProductDefaults>,
+}
+
+impl PartialEq for Store {
+    fn eq(&self, other: &Store) -> bool {
+        #[cfg(not(feature = "testing"))]
+        {
+            self.store_id == other.store_id
+                && self.name == other.name
+                && self.aisles.eq(&other.aisles)
+                && self.defaults == other.defaults
+        }
+        #[cfg(feature = "testing")]
+        {
+            self.name == other.name
+                && self.aisles.eq(&other.aisles)
+                && self.defaults == other.defaults
+        }
+    }
+}
+
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Aisle {
+    pub aisle_id: String,
+    pub name: String,
+    pub sort_weight: f32,
+    pub products: Vec<Product>,
+}
+
+impl PartialEq for Aisle {
+    fn eq(&self, other: &Aisle) -> bool {
+        #[cfg(not(feature = "testing"))]
+        {
+            self.aisle_id == other.aisle_id && self.name == other.name
+        }
+        #[cfg(feature = "testing")]
+        {
+            self.name == other.name
+        }
+    }
+}
+
+impl Eq for Aisle {}
+
+impl PartialOrd for Aisle {
+    fn partial_cmp(&self, other: &Aisle) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Aisle {
+    fn cmp(&self, other: &Aisle) -> Ordering {
+        if (self.sort_weight - other.sort_weight).abs() < std::f32::EPSILON {
+            self.name.cmp(&other.name)
+        } else if self.sort_weight < other.sort_weight {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Aisle {
+    pub fn id(&self) -> AisleId {
+        AisleId(self.aisle_id.to_owned())
+    }
+}
+
+#[derive(Deserialize_repr, Serialize_repr, Debug, Clone, PartialEq)]
+#[repr(u32)]
+#[serde(deny_unknown_fields)]
+pub enum Unit {
+    Unit = 0,
+    Gram = 1,
+    Ml = 2,
+}
+
+// serialized as its number, which the derive can't tell
+#[cfg(feature = "schema")]
+impl JsonSchema for Unit {
+    fn schema_name() -> String {
+        "Unit".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some("0: unit, 1: gram, 2: millilitre".to_owned()),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::Integer.into()),
+            enum_values: Some(vec![0.into(), 1.into(), 2.into()]),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl From<Unit> for u32 {
+    fn from(o: Unit) -> u32 {
+        match o {
+            Unit::Unit => 0,
+            Unit::Gram => 1,
+            Unit::Ml => 2,
+        }
+    }
+}
+
+impl From<u32> for Unit {
+    fn from(o: u32) -> Self {
+        if o == 1 {
+            Unit::Gram
+        } else if o == 2 {
+            Unit::Ml
+        } else {
+            Unit::Unit
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, new)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Product {
+    pub product_id: String,
+    pub name: String,
+    pub quantity: u32,
+    pub is_done: bool,
+    pub unit: Unit,
+    pub sort_weight: f32,
+}
+
+impl PartialEq for Product {
+    fn eq(&self, other: &Product) -> bool {
+        #[cfg(not(feature = "testing"))]
+        {
+            self.product_id == other.product_id && self.name == other.name
+        }
+        #[cfg(feature = "testing")]
+        {
+            self.name == other.name
+        }
+    }
+}
+
+impl Eq for Product {}
+
+impl PartialOrd for Product {
+    fn partial_cmp(&self, other: &Product) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Product {
+    fn cmp(&self, other: &Product) -> Ordering {
+        if (self.sort_weight - other.sort_weight).abs() < std::f32::EPSILON {
+            self.name.cmp(&other.name)
+        } else if self.sort_weight < other.sort_weight {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, new, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AisleChange {
+    pub aisle_id: String,
+    pub name: String,
+    pub sort_weight: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, new, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ProductChange {
+    pub aisle_id: String,
+    #[serde(flatten)]
+    pub product: Product,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct StoreDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defaults: Option<ProductDefaults>,
+    pub aisles: Vec<AisleChange>,
+    pub products: Vec<ProductChange>,
+    pub deleted_aisles: Vec<String>,
+    pub deleted_products: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum StoreChanges {
+    Snapshot(Store),
+    Delta(StoreDelta),
+}
+
+#[derive(Debug, Serialize, Deserialize, new, PartialEq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct VersionedStore {
+    pub version: u64,
+    #[serde(flatten)]
+    pub changes: StoreChanges,
+}
+
+#[cfg(feature = "testing")]
+impl Product {
+    pub fn id(&self) -> ProductId {
+        ProductId(self.product_id.to_owned())
+    }
+}
+
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ProductItemWeight {
+    pub id: String,
+    pub sort_weight: f32,
+}
+
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct AisleItemWeight {
+    pub id: String,
+    pub sort_weight: f32,
+}
+
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct EditWeight {
+    pub aisles: Option<Vec<AisleItemWeight>>,
+    pub products: Option<Vec<ProductItemWeight>>,
+}
+
+impl EditWeight {
+    pub fn has_at_least_a_field(&self) -> bool {
+        match (&self.aisles, &self.products) {
+            (None, None) => false,
+            (Some(aisles), None) => !aisles.is_empty(),
+            (None, Some(products)) => !products.is_empty(),
+            (Some(aisles), Some(products)) => !aisles.is_empty() || !products.is_empty(),
+        }
+    }
+}
+
+// Body of DELETE /products
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ProductIds {
+    pub product_ids: Vec<String>,
+}
+
+// Body of PUT /products/done
+#[derive(Debug, new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ProductsDone {
+    pub product_ids: Vec<String>,
+    pub is_done: bool,
+}
+
+#[derive(new, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct EditProduct {
+    pub name: Option<String>,
+    pub quantity: Option<u32>,
+    pub unit: Option<Unit>,
+    pub is_done: Option<bool>,
+    // part of the quantity just bought, the rest stays to buy; all of it
+    // checks the product off
+    #[new(default)]
+    #[serde(default)]
+    pub bought: Option<u32>,
+    // when the edit was made, in milliseconds since the epoch: a field
+    // edited later keeps its value whatever order the edits arrive in. Now
+    // if missing
+    #[new(default)]
+    #[serde(default)]
+    pub edited_at: Option<u64>,
+}
+
+impl EditProduct {
+    pub fn has_at_least_a_field(&self) -> bool {
+        self.name.is_some()
+            || self.quantity.is_some()
+            || self.unit.is_some()
+            || self.is_done.is_some()
+            || self.bought.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_product_has_as_least_a_field() {
+        let e = EditProduct::new(None, None, None, None);
+        assert_eq!(false, e.has_at_least_a_field());
+        let e = EditProduct::new(Some("Toto".to_owned()), None, None, None);
+        assert_eq!(true, e.has_at_least_a_field());
+        let e = EditProduct::new(None, Some(1), None, None);
+        assert_eq!(true, e.has_at_least_a_field());
+        let e = EditProduct::new(None, None, Some(Unit::Unit), None);
+        assert_eq!(true, e.has_at_least_a_field());
+        let e = EditProduct::new(None, None, None, Some(true));
+        assert_eq!(true, e.has_at_least_a_field());
+        let mut e = EditProduct::new(None, None, None, None);
+        e.bought = Some(2);
+        assert_eq!(true, e.has_at_least_a_field());
+    }
+
+    #[test]
+    fn id_test() {
+        let uuid = "8c5ab7ea-2b8f-4d2c-9d0e-5f2d6c1b7a90";
+        assert_eq!(Ok(StoreId::new(uuid.to_owned())), uuid.parse());
+        assert_eq!(Ok(AisleId(uuid.to_owned())), uuid.parse());
+        assert_eq!(uuid, uuid.parse::<ProductId>().unwrap().to_string());
+        assert_eq!(Err(InvalidId), "MyStore".parse::<StoreId>());
+        assert_eq!(Err(InvalidId), uuid.replace('-', "_").parse::<AisleId>());
+        assert_eq!(Err(InvalidId), uuid[1..].parse::<ProductId>());
+    }
+
+    #[test]
+    fn test_edit_weight_has_as_least_a_field() {
+        let e = EditWeight::new(None, None);
+        assert_eq!(false, e.has_at_least_a_field());
+        let e = EditWeight::new(Some(vec![]), None);
+        assert_eq!(false, e.has_at_least_a_field());
+        let e = EditWeight::new(None, Some(vec![]));
+        assert_eq!(false, e.has_at_least_a_field());
+        let e = EditWeight::new(Some(vec![AisleItemWeight::new("1".to_owned(), 1.0)]), None);
+        assert_eq!(true, e.has_at_least_a_field());
+        let e = EditWeight::new(
+            None,
+            Some(vec![ProductItemWeight::new("1".to_owned(), 1.0)]),
+        );
+        assert_eq!(true, e.has_at_least_a_field());
+    }
+}
@@ -0,0 +1,45 @@
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::*;
+
+// Reads a body and writes it back the way the server does, the error is the
+// reason it was refused
+fn normalize<T: DeserializeOwned + Serialize>(json: &str) -> Result<String, JsValue> {
+    serde_json::from_str::<T>(json)
+        .and_then(|body| serde_json::to_string(&body))
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+macro_rules! normalizers {
+    ($($name:ident: $body:ty),* $(,)?) => {
+        $(
+            #[wasm_bindgen]
+            pub fn $name(json: &str) -> Result<String, JsValue> {
+                normalize::<$body>(json)
+            }
+        )*
+    };
+}
+
+// JavaScript frontends check their bodies with the same serde code as the
+// server, one function per type
+normalizers! {
+    normalize_user: User,
+    normalize_auth_info: AuthInfo,
+    normalize_connection_token: ConnectionToken,
+    normalize_cookie_session: CookieSession,
+    normalize_name_data: NameData,
+    normalize_store_id: StoreId,
+    normalize_store_light_list: StoreLightList,
+    normalize_store: Store,
+    normalize_versioned_store: VersionedStore,
+    normalize_aisle: Aisle,
+    normalize_product: Product,
+    normalize_edit_product: EditProduct,
+    normalize_edit_weight: EditWeight,
+    normalize_export_link: ExportLink,
+    normalize_assistant_add: AssistantAdd,
+    normalize_assistant_list: AssistantList,
+    normalize_assistant_reply: AssistantReply,
+}
@@ -1,23 +1,202 @@
 use std::clone::Clone;
 use std::collections::HashMap;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use derive_new::new;
 use lazy_static::lazy_static;
 use redis::{self, from_redis_value, FromRedisValue, RedisResult, ToRedisArgs, Value};
 
+use crate::streams::ConsumerGroup;
+
 lazy_static! {
-    static ref POOL: Mutex<HashMap<i64, Storages>> = Mutex::new(HashMap::new());
+    pub(crate) static ref POOL: Mutex<HashMap<i64, Storages>> = Mutex::new(HashMap::new());
 }
 
 #[derive(new, Debug)]
-struct Storages {
+pub(crate) struct Storages {
     #[new(default)]
     pub k: HashMap<String, Value>,
     #[new(default)]
     pub h: HashMap<String, HashMap<String, Value>>,
     #[new(default)]
     pub s: HashMap<String, Vec<Value>>,
+    #[new(default)]
+    pub l: HashMap<String, Vec<Value>>,
+    #[new(default)]
+    pub x: HashMap<String, Vec<StreamEntry>>,
+    // consumer groups of each stream
+    #[new(default)]
+    pub g: HashMap<String, HashMap<String, ConsumerGroup>>,
+    // expiration deadlines, in ms of `clock`
+    #[new(default)]
+    pub e: HashMap<String, u64>,
+    // fake time in ms, only moves with `FakeConnection::advance_time`
+    #[new(default)]
+    pub clock: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StreamEntry {
+    pub id: (u64, u64),
+    pub fields: Vec<Value>,
+}
+
+impl StreamEntry {
+    pub(crate) fn to_value(&self) -> Value {
+        Value::Bulk(vec![
+            Value::Data(format!("{}-{}", self.id.0, self.id.1).into_bytes()),
+            Value::Bulk(self.fields.clone()),
+        ])
+    }
+}
+
+impl Storages {
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.k.contains_key(key)
+            || self.h.contains_key(key)
+            || self.s.contains_key(key)
+            || self.l.contains_key(key)
+            || self.x.contains_key(key)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> bool {
+        self.e.remove(key);
+        self.g.remove(key);
+        self.k.remove(key).is_some()
+            | self.h.remove(key).is_some()
+            | self.s.remove(key).is_some()
+            | self.l.remove(key).is_some()
+            | self.x.remove(key).is_some()
+    }
+
+    // the clock never goes back, it may have been advanced past `now` by a test
+    pub(crate) fn set_clock(&mut self, now: u64) {
+        if now > self.clock {
+            self.clock = now;
+            self.purge_expired();
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        let clock = self.clock;
+        let expired: Vec<String> = self
+            .e
+            .iter()
+            .filter(|(_, deadline)| **deadline <= clock)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    // -1 for a key without expiration, -2 for a missing one
+    pub(crate) fn pttl(&self, key: &str) -> i64 {
+        match self.e.get(key) {
+            Some(deadline) => (deadline - self.clock) as i64,
+            None if self.contains(key) => -1,
+            None => -2,
+        }
+    }
+
+    pub(crate) fn ttl(&self, key: &str) -> i64 {
+        let ttl = self.pttl(key);
+        // Redis rounds the remaining time to the closest second
+        if ttl < 0 {
+            ttl
+        } else {
+            (ttl + 500) / 1000
+        }
+    }
+
+    pub(crate) fn expire(&mut self, key: &str, ms: u64) -> Value {
+        if self.contains(key) {
+            self.e.insert(key.to_owned(), self.clock + ms);
+            Value::Int(1)
+        } else {
+            Value::Int(0)
+        }
+    }
+
+    pub(crate) fn xadd(&mut self, key: &str, id: &str, fields: Vec<Value>) -> RedisResult<Value> {
+        let clock = self.clock;
+        let stream = self.x.entry(key.to_owned()).or_insert_with(Vec::new);
+        let last = stream.last().map(|e| e.id);
+        let id = match (id, last) {
+            ("*", Some(last)) if last.0 >= clock => (last.0, last.1 + 1),
+            // 0-0 is never a valid id
+            ("*", _) => (clock, (clock == 0) as u64),
+            _ => parse_stream_id(id, 0)?,
+        };
+        if last.map_or(id == (0, 0), |last| id <= last) {
+            return Err((
+                redis::ErrorKind::ResponseError,
+                "The ID specified in XADD is equal or smaller than the target stream top item",
+            )
+                .into());
+        }
+        stream.push(StreamEntry { id, fields });
+        Ok(Value::Data(format!("{}-{}", id.0, id.1).into_bytes()))
+    }
+}
+
+// `-` and `+` are the smallest and greatest ids, a missing sequence is `default_seq`
+pub(crate) fn parse_stream_id(id: &str, default_seq: u64) -> RedisResult<(u64, u64)> {
+    let invalid = || -> redis::RedisError {
+        (
+            redis::ErrorKind::ResponseError,
+            "Invalid stream ID specified as stream command argument",
+        )
+            .into()
+    };
+    match id {
+        "-" => Ok((0, 0)),
+        "+" => Ok((u64::max_value(), u64::max_value())),
+        _ => {
+            let mut parts = id.splitn(2, '-');
+            let ms = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+            let seq = match parts.next() {
+                Some(seq) => seq.parse().map_err(|_| invalid())?,
+                None => default_seq,
+            };
+            Ok((ms, seq))
+        }
+    }
+}
+
+fn args_to_values<T: ToRedisArgs>(items: T) -> Vec<Value> {
+    items.to_redis_args().into_iter().map(Value::Data).collect()
+}
+
+// like redis, INCR works on the integers SET stored as strings
+fn incr_value(value: &mut Value, delta: i64) {
+    let current = match value {
+        Value::Int(i) => Some(*i),
+        Value::Data(d) => std::str::from_utf8(d).ok().and_then(|s| s.parse().ok()),
+        _ => None,
+    };
+    if let Some(current) = current {
+        *value = Value::Int(current + delta);
+    }
+}
+
+pub(crate) fn storages(pool: &mut HashMap<i64, Storages>, db: i64) -> &mut Storages {
+    let db = pool.entry(db).or_insert_with(Storages::new);
+    db.purge_expired();
+    db
+}
+
+// redis semantic for LRANGE/LTRIM indexes, negative ones count from the end
+pub(crate) fn list_range(len: usize, start: isize, stop: isize) -> std::ops::Range<usize> {
+    let len = len as isize;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    if start > stop {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
 }
 
 #[derive(new)]
@@ -28,25 +207,14 @@ pub struct FakeConnection {
 impl FakeConnection {
     pub fn get<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         from_redis_value(&db.k.get(key).map_or_else(|| Value::Nil, Clone::clone))
     }
 
     pub fn del<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
-        from_redis_value(&db.k.remove(&key.to_owned()).map_or_else(
-            || {
-                db.h.remove(&key.to_owned()).map_or_else(
-                    || {
-                        db.s.remove(&key.to_owned())
-                            .map_or_else(|| Value::Int(0), |_| Value::Int(1))
-                    },
-                    |_| Value::Int(1),
-                )
-            },
-            |_| Value::Int(1),
-        ))
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.remove(key) as i64))
     }
 
     pub fn set<V: ToRedisArgs, RV: FromRedisValue>(
@@ -55,21 +223,49 @@ impl FakeConnection {
         value: V,
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         let v = value.to_redis_args();
+        // SET drops the previous value and its expiration
+        db.remove(key);
         db.k.insert(key.to_owned(), Value::Data(v[0].clone()));
         from_redis_value(&Value::Okay)
     }
 
+    pub fn set_nx<V: ToRedisArgs, RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        value: V,
+    ) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        if db.k.contains_key(key) {
+            from_redis_value(&Value::Int(0))
+        } else {
+            let v = value.to_redis_args();
+            db.k.insert(key.to_owned(), Value::Data(v[0].clone()));
+            from_redis_value(&Value::Int(1))
+        }
+    }
+
+    pub fn getset<V: ToRedisArgs, RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        value: V,
+    ) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        let v = value.to_redis_args();
+        from_redis_value(
+            &db.k
+                .insert(key.to_owned(), Value::Data(v[0].clone()))
+                .unwrap_or(Value::Nil),
+        )
+    }
+
     pub fn exists<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
-        // dbg!(&db);
-        from_redis_value(&Value::Int(
-            (db.k.contains_key(&key.to_owned())
-                || db.h.contains_key(&key.to_owned())
-                || db.s.contains_key(&key.to_owned())) as i64,
-        ))
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.contains(key) as i64))
     }
 
     pub fn incr<V: Into<i64> + Copy, RV: FromRedisValue>(
@@ -78,15 +274,11 @@ impl FakeConnection {
         delta: V,
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         from_redis_value(
             &db.k
                 .entry(key.to_owned())
-                .and_modify(|e| {
-                    if let Value::Int(ref mut e) = e {
-                        *e += delta.into()
-                    }
-                })
+                .and_modify(|e| incr_value(e, delta.into()))
                 .or_insert_with(|| Value::Int(delta.into())),
         )
     }
@@ -98,7 +290,7 @@ impl FakeConnection {
         value: V,
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         let mut is_new = false;
         let v = value.to_redis_args();
         db.h.entry(key.to_owned())
@@ -120,13 +312,31 @@ impl FakeConnection {
         from_redis_value(&Value::Int(is_new as i64))
     }
 
+    pub fn hset_nx<V: ToRedisArgs, RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        field: &str,
+        value: V,
+    ) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        let h = db.h.entry(key.to_owned()).or_insert_with(HashMap::new);
+        if h.contains_key(field) {
+            from_redis_value(&Value::Int(0))
+        } else {
+            let v = value.to_redis_args();
+            h.insert(field.to_owned(), Value::Data(v[0].clone()));
+            from_redis_value(&Value::Int(1))
+        }
+    }
+
     pub fn hset_multiple<V: ToRedisArgs, RV: FromRedisValue>(
         &mut self,
         key: &str,
         items: &[(&str, V)],
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         for item in items {
             let field = item.0;
             let value = &item.1;
@@ -150,7 +360,7 @@ impl FakeConnection {
 
     pub fn hget<RV: FromRedisValue>(&mut self, key: &str, field: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         from_redis_value(&db.h.get(key).map_or_else(
             || Value::Nil,
             |h| h.get(field).map_or_else(|| Value::Nil, Clone::clone),
@@ -159,7 +369,7 @@ impl FakeConnection {
 
     pub fn hdel<RV: FromRedisValue>(&mut self, key: &str, field: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         let mut need_delete_key = false;
         let r = db.h.get_mut(key).map_or_else(
             || from_redis_value(&Value::Int(0)),
@@ -180,7 +390,7 @@ impl FakeConnection {
 
     pub fn hexists<RV: FromRedisValue>(&mut self, key: &str, field: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         db.h.get(key).map_or_else(
             || from_redis_value(&Value::Int(false as i64)),
             |h| from_redis_value(&Value::Int(h.contains_key(field) as i64)),
@@ -212,7 +422,7 @@ impl FakeConnection {
         member: M,
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         let mut need_delete_key = false;
         let v = member.to_redis_args();
         let r = db.s.get_mut(key).map_or_else(
@@ -238,7 +448,7 @@ impl FakeConnection {
 
     pub fn smembers<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         from_redis_value(
             &db.s
                 .get(key)
@@ -246,13 +456,39 @@ impl FakeConnection {
         )
     }
 
+    pub fn scard<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.s.get(key).map_or(0, Vec::len) as i64))
+    }
+
+    pub fn llen<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.l.get(key).map_or(0, Vec::len) as i64))
+    }
+
+    pub fn lrange<RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&db.l.get(key).map_or_else(
+            || Value::Bulk(vec![]),
+            |l| Value::Bulk(l[list_range(l.len(), start, stop)].to_vec()),
+        ))
+    }
+
     pub fn sismember<M: ToRedisArgs, RV: FromRedisValue>(
         &mut self,
         key: &str,
         member: M,
     ) -> RedisResult<RV> {
         let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let db = storages(&mut pool, self.db);
         let v = member.to_redis_args();
         db.s.get(key).map_or_else(
             || from_redis_value(&Value::Int(false as i64)),
@@ -266,95 +502,388 @@ impl FakeConnection {
             },
         )
     }
+
+    pub fn expire<RV: FromRedisValue>(&mut self, key: &str, seconds: usize) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&db.expire(key, seconds as u64 * 1000))
+    }
+
+    pub fn pexpire<RV: FromRedisValue>(&mut self, key: &str, ms: usize) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&db.expire(key, ms as u64))
+    }
+
+    pub fn persist<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.e.remove(key).is_some() as i64))
+    }
+
+    // -2 if the key doesn't exist, -1 if it has no expiration
+    pub fn pttl<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.pttl(key)))
+    }
+
+    pub fn ttl<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.ttl(key)))
+    }
+
+    // time control for TTL tests: expire the keys of this connection's db as
+    // if `duration` had elapsed
+    pub fn advance_time(&mut self, duration: Duration) {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        db.clock += duration.as_millis() as u64;
+        db.purge_expired();
+    }
+
+    pub fn xadd<F: ToRedisArgs, V: ToRedisArgs, RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        id: &str,
+        items: &[(F, V)],
+    ) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&db.xadd(key, id, args_to_values(items))?)
+    }
+
+    pub fn xlen<RV: FromRedisValue>(&mut self, key: &str) -> RedisResult<RV> {
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        from_redis_value(&Value::Int(db.x.get(key).map_or(0, Vec::len) as i64))
+    }
+
+    pub fn xrange<RV: FromRedisValue>(
+        &mut self,
+        key: &str,
+        start: &str,
+        end: &str,
+    ) -> RedisResult<RV> {
+        let start = parse_stream_id(start, 0)?;
+        let end = parse_stream_id(end, u64::max_value())?;
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        let entries = db.x.get(key).map_or_else(Vec::new, |stream| {
+            stream
+                .iter()
+                .filter(|e| start <= e.id && e.id <= end)
+                .map(StreamEntry::to_value)
+                .collect()
+        });
+        from_redis_value(&Value::Bulk(entries))
+    }
 }
+type Command = Box<dyn FnOnce(&mut Storages) -> RedisResult<Value> + Send>;
 
+// Commands are queued and applied together on `query`, like MULTI/EXEC
 #[derive(new)]
 pub struct FakePipeline {
     db: i64,
+    #[new(default)]
+    commands: Vec<(Command, bool)>,
 }
 
 impl FakePipeline {
-    pub fn del(&mut self, key: &str) -> &mut Self {
-        let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
-        db.k.remove(&key.to_owned());
-        db.h.remove(&key.to_owned());
-        db.s.remove(&key.to_owned());
+    fn add<F>(&mut self, command: F) -> &mut Self
+    where
+        F: FnOnce(&mut Storages) -> RedisResult<Value> + Send + 'static,
+    {
+        self.commands.push((Box::new(command), false));
         self
     }
 
+    pub fn del(&mut self, key: &str) -> &mut Self {
+        let key = key.to_owned();
+        self.add(move |db| Ok(Value::Int(db.remove(&key) as i64)))
+    }
+
+    pub fn incr<V: Into<i64> + Copy>(&mut self, key: &str, delta: V) -> &mut Self {
+        let key = key.to_owned();
+        let delta = delta.into();
+        self.add(move |db| {
+            let value = db
+                .k
+                .entry(key)
+                .and_modify(|e| incr_value(e, delta))
+                .or_insert_with(|| Value::Int(delta));
+            Ok(value.clone())
+        })
+    }
+
+    pub fn set<V: ToRedisArgs>(&mut self, key: &str, value: V) -> &mut Self {
+        let key = key.to_owned();
+        let v = value.to_redis_args();
+        self.add(move |db| {
+            db.remove(&key);
+            db.k.insert(key, Value::Data(v[0].clone()));
+            Ok(Value::Okay)
+        })
+    }
+
+    pub fn rpush<V: ToRedisArgs>(&mut self, key: &str, value: V) -> &mut Self {
+        let key = key.to_owned();
+        let v = value.to_redis_args();
+        self.add(move |db| {
+            let l = db.l.entry(key).or_insert_with(Vec::new);
+            l.push(Value::Data(v[0].clone()));
+            Ok(Value::Int(l.len() as i64))
+        })
+    }
+
+    pub fn ltrim(&mut self, key: &str, start: isize, stop: isize) -> &mut Self {
+        let key = key.to_owned();
+        self.add(move |db| {
+            let mut need_delete_key = false;
+            if let Some(l) = db.l.get_mut(&key) {
+                let range = list_range(l.len(), start, stop);
+                *l = l[range].to_vec();
+                need_delete_key = l.is_empty();
+            }
+            if need_delete_key {
+                db.remove(&key);
+            }
+            Ok(Value::Okay)
+        })
+    }
+
     pub fn sadd<M: ToRedisArgs>(&mut self, key: &str, member: M) -> &mut Self {
-        let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let key = key.to_owned();
         let v = member.to_redis_args();
-        db.s.entry(key.to_owned())
-            .and_modify(|h| h.push(Value::Data(v[0].clone())))
-            .or_insert_with(|| vec![Value::Data(v[0].clone())]);
-        self
+        self.add(move |db| {
+            db.s.entry(key)
+                .and_modify(|h| h.push(Value::Data(v[0].clone())))
+                .or_insert_with(|| vec![Value::Data(v[0].clone())]);
+            Ok(Value::Int(1))
+        })
     }
 
     pub fn srem<M: ToRedisArgs>(&mut self, key: &str, member: M) -> &mut Self {
-        let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
-        let mut need_delete_key = false;
+        let key = key.to_owned();
         let v = member.to_redis_args();
-        db.s.get_mut(key).map(|s| {
-            s.iter()
-                .position(|i| *i == Value::Data(v[0].clone()))
-                .map(|i| {
-                    s.remove(i);
-                    need_delete_key = s.is_empty();
-                })
-        });
-        if need_delete_key {
-            db.s.remove(key);
-        }
-        self
+        self.add(move |db| {
+            let mut need_delete_key = false;
+            let removed = db.s.get_mut(&key).and_then(|s| {
+                s.iter()
+                    .position(|i| *i == Value::Data(v[0].clone()))
+                    .map(|i| {
+                        s.remove(i);
+                        need_delete_key = s.is_empty();
+                    })
+            });
+            if need_delete_key {
+                db.remove(&key);
+            }
+            Ok(Value::Int(removed.is_some() as i64))
+        })
     }
 
     pub fn hset<V: ToRedisArgs>(&mut self, key: &str, field: &str, value: V) -> &mut Self {
-        let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
+        let key = key.to_owned();
+        let field = field.to_owned();
         let v = value.to_redis_args();
-        db.h.entry(key.to_owned())
-            .and_modify(|h| {
-                h.entry(field.to_owned())
-                    .and_modify(|e| {
-                        *e = Value::Data(v[0].clone());
-                    })
-                    .or_insert_with(|| Value::Data(v[0].clone()));
-            })
-            .or_insert_with(|| {
-                let mut h = HashMap::new();
-                h.insert(field.to_owned(), Value::Data(v[0].clone()));
-                h
-            });
-        self
+        self.add(move |db| {
+            let is_new =
+                db.h.entry(key)
+                    .or_insert_with(HashMap::new)
+                    .insert(field, Value::Data(v[0].clone()))
+                    .is_none();
+            Ok(Value::Int(is_new as i64))
+        })
+    }
+
+    pub fn hincr<V: Into<i64> + Copy>(&mut self, key: &str, field: &str, delta: V) -> &mut Self {
+        let key = key.to_owned();
+        let field = field.to_owned();
+        let delta = delta.into();
+        self.add(move |db| {
+            let value = db
+                .h
+                .entry(key)
+                .or_insert_with(HashMap::new)
+                .entry(field)
+                .and_modify(|e| incr_value(e, delta))
+                .or_insert_with(|| Value::Int(delta));
+            Ok(value.clone())
+        })
     }
 
     pub fn hdel(&mut self, key: &str, field: &str) -> &mut Self {
-        let mut pool = POOL.lock().unwrap();
-        let db = pool.entry(self.db).or_insert_with(Storages::new);
-        let mut need_delete_key = false;
-        if let Some(h) = db.h.get_mut(key) {
-            h.remove(field);
-            need_delete_key = h.is_empty();
-        };
-        if need_delete_key {
-            db.h.remove(key);
-        }
-        self
+        let key = key.to_owned();
+        let field = field.to_owned();
+        self.add(move |db| {
+            let mut need_delete_key = false;
+            let removed = db.h.get_mut(&key).and_then(|h| {
+                let removed = h.remove(&field);
+                need_delete_key = h.is_empty();
+                removed
+            });
+            if need_delete_key {
+                db.remove(&key);
+            }
+            Ok(Value::Int(removed.is_some() as i64))
+        })
+    }
+
+    pub fn expire(&mut self, key: &str, seconds: usize) -> &mut Self {
+        let key = key.to_owned();
+        self.add(move |db| Ok(db.expire(&key, seconds as u64 * 1000)))
+    }
+
+    pub fn pexpire(&mut self, key: &str, ms: usize) -> &mut Self {
+        let key = key.to_owned();
+        self.add(move |db| Ok(db.expire(&key, ms as u64)))
+    }
+
+    pub fn xadd<F: ToRedisArgs, V: ToRedisArgs>(
+        &mut self,
+        key: &str,
+        id: &str,
+        items: &[(F, V)],
+    ) -> &mut Self {
+        let key = key.to_owned();
+        let id = id.to_owned();
+        let fields = args_to_values(items);
+        self.add(move |db| db.xadd(&key, &id, fields))
     }
 
     pub fn ignore(&mut self) -> &mut Self {
+        if let Some(command) = self.commands.last_mut() {
+            command.1 = true;
+        }
         self
     }
 
+    // run the queued commands in one go, replies of ignored ones are dropped
     pub fn query<T: FromRedisValue>(&mut self, _con: &FakeConnection) -> RedisResult<T> {
-        from_redis_value(&Value::Nil)
+        let mut pool = POOL.lock().unwrap();
+        let db = storages(&mut pool, self.db);
+        let mut replies = vec![];
+        for (command, ignored) in self.commands.drain(..) {
+            let reply = command(db)?;
+            if !ignored {
+                replies.push(reply);
+            }
+        }
+        from_redis_value(&Value::Bulk(replies))
     }
 
     pub fn atomic(&mut self) -> &mut Self {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction;
+
+    #[test]
+    fn expire_test() {
+        let mut c = FakeConnection::new(0);
+        let _: () = c.set("key", "value").unwrap();
+        assert_eq!(Ok(-1), c.ttl::<i64>("key"));
+        assert_eq!(Ok(true), c.expire("key", 10));
+        assert_eq!(Ok(10), c.ttl::<i64>("key"));
+        c.advance_time(Duration::from_secs(9));
+        assert_eq!(Ok(1000), c.pttl::<i64>("key"));
+        c.advance_time(Duration::from_secs(1));
+        assert_eq!(Ok(false), c.exists("key"));
+        assert_eq!(Ok(-2), c.ttl::<i64>("key"));
+        assert_eq!(Ok(false), c.expire("key", 10));
+    }
+
+    #[test]
+    fn persist_test() {
+        let mut c = FakeConnection::new(1);
+        let _: () = c.hset("hash", "field", 1).unwrap();
+        assert_eq!(Ok(true), c.pexpire("hash", 10));
+        assert_eq!(Ok(true), c.persist("hash"));
+        c.advance_time(Duration::from_secs(1));
+        assert_eq!(Ok(1), c.hget("hash", "field"));
+        // a new value drops the expiration
+        assert_eq!(Ok(true), c.expire("hash", 1));
+        let _: () = c.set("hash", 2).unwrap();
+        assert_eq!(Ok(-1), c.ttl::<i64>("hash"));
+    }
+
+    #[test]
+    fn transaction_test() {
+        let mut c = FakeConnection::new(2);
+        let res: (i32, i32) = transaction(&mut c, &["hash", "set"], |c, pipe| {
+            pipe.hset("hash", "field", 1)
+                .sadd("set", "member")
+                .ignore()
+                .hset("hash", "field", 2);
+            // nothing is applied before EXEC
+            assert_eq!(Ok(false), c.exists("hash"));
+            pipe.query(c)
+        })
+        .unwrap();
+        assert_eq!((1, 0), res);
+        assert_eq!(Ok(2), c.hget("hash", "field"));
+        assert_eq!(Ok(true), c.sismember("set", "member"));
+    }
+
+    #[test]
+    fn pipeline_expire_test() {
+        let mut c = FakeConnection::new(3);
+        let mut pipe = FakePipeline::new(c.db);
+        let _: () = pipe
+            .hset("session", "user", "id")
+            .ignore()
+            .expire("session", 60)
+            .ignore()
+            .query(&c)
+            .unwrap();
+        assert_eq!(Ok(60), c.ttl::<i64>("session"));
+        c.advance_time(Duration::from_secs(60));
+        assert_eq!(Ok(false), c.exists("session"));
+    }
+
+    #[test]
+    fn pipeline_hincr_test() {
+        let c = FakeConnection::new(5);
+        let mut pipe = FakePipeline::new(c.db);
+        let res: (i64, i64) = pipe
+            .hincr("counts", "items", 3)
+            .hset("counts", "left", "2")
+            .ignore()
+            .hincr("counts", "left", -1)
+            .query(&c)
+            .unwrap();
+        assert_eq!((3, 1), res);
+    }
+
+    #[test]
+    fn stream_test() {
+        let mut c = FakeConnection::new(4);
+        assert_eq!(Ok("0-1".to_owned()), c.xadd("stream", "*", &[("a", 1)]));
+        assert_eq!(Ok("0-2".to_owned()), c.xadd("stream", "*", &[("b", 2)]));
+        c.advance_time(Duration::from_millis(5));
+        assert_eq!(Ok("5-0".to_owned()), c.xadd("stream", "*", &[("c", 3)]));
+        assert!(c
+            .xadd::<_, _, String>("stream", "5-0", &[("d", 4)])
+            .is_err());
+        assert_eq!(Ok(3), c.xlen("stream"));
+
+        let entries: Vec<Value> = c.xrange("stream", "0-2", "+").unwrap();
+        let entries: Vec<(String, HashMap<String, i32>)> = entries
+            .iter()
+            .map(|e| from_redis_value(e).unwrap())
+            .collect();
+        let ids: Vec<_> = entries.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["0-2", "5-0"], ids);
+        assert_eq!(Some(&3), entries[1].1.get("c"));
+        let entries: Vec<Value> = c.xrange("stream", "-", "0").unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!(Ok(1), c.del("stream"));
+        assert_eq!(Ok(0), c.xlen("stream"));
+    }
+}
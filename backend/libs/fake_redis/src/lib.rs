@@ -2,9 +2,12 @@ use redis::{self, FromRedisValue, RedisResult};
 
 mod fake_client;
 mod fake_connection;
+mod memory;
+mod streams;
 
 pub use fake_client::*;
 pub use fake_connection::*;
+pub use memory::*;
 
 pub fn transaction<
     T: FromRedisValue,
@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use redis::{self, ConnectionLike, ErrorKind, RedisError, RedisResult, Value};
+
+use crate::{
+    fake_connection::{list_range, storages, FakeConnection, Storages, POOL},
+    streams,
+};
+
+// An in-process Redis speaking the wire protocol, for running the server
+// without any external service. It shares the storage of `FakeConnection`:
+// one set of HashMaps per db number behind a lock, lost on exit.
+//
+// Only the commands the server uses are understood. A packed pipeline runs
+// under a single lock, so MULTI/EXEC blocks are atomic and WATCH never fails.
+pub struct MemoryConnection {
+    db: i64,
+    // commands queued between MULTI and EXEC
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+    // false when the time is driven by `FakeConnection::advance_time`
+    real_time: bool,
+}
+
+impl MemoryConnection {
+    pub fn new(db: i64) -> Self {
+        MemoryConnection {
+            db,
+            queued: None,
+            real_time: true,
+        }
+    }
+
+    fn run(&mut self, db: &mut Storages, args: Vec<Vec<u8>>) -> RedisResult<Value> {
+        let name = args
+            .first()
+            .map(|name| String::from_utf8_lossy(name).to_uppercase())
+            .unwrap_or_default();
+        match (name.as_str(), self.queued.as_mut()) {
+            ("EXEC", Some(_)) => {
+                let queued = self.queued.take().unwrap_or_default();
+                let replies = queued
+                    .into_iter()
+                    .map(|args| execute(db, &args))
+                    .collect::<RedisResult<Vec<_>>>()?;
+                Ok(Value::Bulk(replies))
+            }
+            ("DISCARD", Some(_)) => {
+                self.queued = None;
+                Ok(Value::Okay)
+            }
+            ("MULTI", Some(_)) => Err(error("MULTI calls can not be nested")),
+            (_, Some(queued)) => {
+                queued.push(args);
+                Ok(Value::Status("QUEUED".to_owned()))
+            }
+            ("MULTI", None) => {
+                self.queued = Some(vec![]);
+                Ok(Value::Okay)
+            }
+            ("EXEC", None) | ("DISCARD", None) => Err(error("EXEC without MULTI")),
+            ("SELECT", None) => {
+                self.db = int_arg(&args, 1)?;
+                Ok(Value::Okay)
+            }
+            _ => execute(db, &args),
+        }
+    }
+
+    fn run_packed(&mut self, cmd: &[u8]) -> RedisResult<Vec<Value>> {
+        let commands = parse_packed(cmd)?;
+        let mut pool = POOL.lock().unwrap();
+        let mut replies = Vec::with_capacity(commands.len());
+        for args in commands {
+            let db = storages(&mut pool, self.db);
+            // keys expire in real time, unlike with `FakeConnection`
+            if self.real_time {
+                db.set_clock(now_ms());
+            }
+            replies.push(self.run(db, args)?);
+        }
+        Ok(replies)
+    }
+}
+
+impl ConnectionLike for MemoryConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        self.run_packed(cmd)?
+            .pop()
+            .ok_or_else(|| error("Empty command"))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        Ok(self
+            .run_packed(cmd)?
+            .into_iter()
+            .skip(offset)
+            .take(count)
+            .collect())
+    }
+
+    fn get_db(&self) -> i64 {
+        self.db
+    }
+}
+
+// Lets the db layer send the commands `FakeConnection` has no method for, like
+// the stream consumer groups ones
+impl ConnectionLike for FakeConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        fake_time(self.db).req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        fake_time(self.db).req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.db
+    }
+}
+
+fn fake_time(db: i64) -> MemoryConnection {
+    MemoryConnection {
+        real_time: false,
+        ..MemoryConnection::new(db)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(crate) fn error(msg: &'static str) -> RedisError {
+    (ErrorKind::ResponseError, msg).into()
+}
+
+// An error reply as a server would send it, codes like BUSYGROUP then come
+// out as extension errors
+pub(crate) fn server_error(line: &str) -> RedisError {
+    match redis::parse_redis_value(format!("-{}\r\n", line).as_bytes()) {
+        Err(e) => e,
+        Ok(_) => error("unparsable error reply"),
+    }
+}
+
+fn wrong_type() -> RedisError {
+    error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+// `*<argc>\r\n` followed by `$<len>\r\n<bytes>\r\n` for each argument, as many
+// times as there are commands in the pipeline
+fn parse_packed(mut cmd: &[u8]) -> RedisResult<Vec<Vec<Vec<u8>>>> {
+    fn line<'a>(cmd: &mut &'a [u8], prefix: u8) -> RedisResult<usize> {
+        let end = cmd
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .filter(|_| cmd.first() == Some(&prefix))
+            .ok_or_else(|| error("Invalid packed command"))?;
+        let n = std::str::from_utf8(&cmd[1..end])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| error("Invalid packed command"))?;
+        *cmd = &cmd[end + 2..];
+        Ok(n)
+    }
+
+    let mut commands = vec![];
+    while !cmd.is_empty() {
+        let argc = line(&mut cmd, b'*')?;
+        let mut args = Vec::with_capacity(argc);
+        for _ in 0..argc {
+            let len = line(&mut cmd, b'$')?;
+            if cmd.len() < len + 2 {
+                return Err(error("Invalid packed command"));
+            }
+            args.push(cmd[..len].to_vec());
+            cmd = &cmd[len + 2..];
+        }
+        commands.push(args);
+    }
+    Ok(commands)
+}
+
+pub(crate) fn str_arg(args: &[Vec<u8>], i: usize) -> RedisResult<String> {
+    args.get(i)
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .ok_or_else(|| error("wrong number of arguments"))
+}
+
+pub(crate) fn int_arg<T: std::str::FromStr>(args: &[Vec<u8>], i: usize) -> RedisResult<T> {
+    str_arg(args, i)?
+        .parse()
+        .map_err(|_| error("value is not an integer or out of range"))
+}
+
+pub(crate) fn data(arg: &[u8]) -> Value {
+    Value::Data(arg.to_vec())
+}
+
+fn execute(db: &mut Storages, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let key = || str_arg(args, 1);
+    let rest = || args.iter().skip(2);
+    match name.as_str() {
+        "PING" => Ok(Value::Status("PONG".to_owned())),
+        "WATCH" | "UNWATCH" => Ok(Value::Okay),
+        "FLUSHDB" => {
+            *db = Storages::new();
+            Ok(Value::Okay)
+        }
+        "EXISTS" => Ok(Value::Int(
+            args[1..]
+                .iter()
+                .filter(|k| db.contains(&String::from_utf8_lossy(k)))
+                .count() as i64,
+        )),
+        "DEL" => Ok(Value::Int(
+            args[1..]
+                .iter()
+                .filter(|k| db.remove(&String::from_utf8_lossy(k)))
+                .count() as i64,
+        )),
+        "EXPIRE" => Ok(db.expire(&key()?, int_arg::<u64>(args, 2)? * 1000)),
+        "PEXPIRE" => Ok(db.expire(&key()?, int_arg(args, 2)?)),
+        "TTL" => Ok(Value::Int(db.ttl(&key()?))),
+        "PTTL" => Ok(Value::Int(db.pttl(&key()?))),
+        "GET" => match db.k.get(&key()?) {
+            Some(v) => Ok(v.clone()),
+            None if db.contains(&key()?) => Err(wrong_type()),
+            None => Ok(Value::Nil),
+        },
+        "SET" => {
+            let key = key()?;
+            db.remove(&key);
+            db.k.insert(key, data(&args[2]));
+            Ok(Value::Okay)
+        }
+        "SETNX" => {
+            let key = key()?;
+            if db.contains(&key) {
+                Ok(Value::Int(0))
+            } else {
+                db.k.insert(key, data(&args[2]));
+                Ok(Value::Int(1))
+            }
+        }
+        "GETSET" => {
+            let key = key()?;
+            let old = db.k.get(&key).cloned().unwrap_or(Value::Nil);
+            db.remove(&key);
+            db.k.insert(key, data(&args[2]));
+            Ok(old)
+        }
+        "INCR" | "INCRBY" => {
+            let key = key()?;
+            let delta: i64 = if name == "INCR" { 1 } else { int_arg(args, 2)? };
+            let current = match db.k.get(&key) {
+                None => 0,
+                Some(Value::Int(i)) => *i,
+                Some(Value::Data(d)) => String::from_utf8_lossy(d)
+                    .parse()
+                    .map_err(|_| error("value is not an integer or out of range"))?,
+                Some(_) => return Err(wrong_type()),
+            };
+            let value = current + delta;
+            db.k.insert(key, Value::Data(value.to_string().into_bytes()));
+            Ok(Value::Int(value))
+        }
+        "HINCRBY" => {
+            let field = str_arg(args, 2)?;
+            let delta: i64 = int_arg(args, 3)?;
+            let h = db.h.entry(key()?).or_insert_with(HashMap::new);
+            let current: i64 = match h.get(&field) {
+                None => 0,
+                Some(Value::Data(d)) => String::from_utf8_lossy(d)
+                    .parse()
+                    .map_err(|_| error("hash value is not an integer"))?,
+                Some(_) => return Err(wrong_type()),
+            };
+            let value = current + delta;
+            h.insert(field, Value::Data(value.to_string().into_bytes()));
+            Ok(Value::Int(value))
+        }
+        "HGET" => Ok(db
+            .h
+            .get(&key()?)
+            .and_then(|h| h.get(&str_arg(args, 2).ok()?).cloned())
+            .unwrap_or(Value::Nil)),
+        "HEXISTS" => Ok(Value::Int(db.h.get(&key()?).map_or(false, |h| {
+            h.contains_key(&String::from_utf8_lossy(&args[2]).into_owned())
+        }) as i64)),
+        "HGETALL" => Ok(Value::Bulk(
+            db.h.get(&key()?)
+                .map(|h| {
+                    h.iter()
+                        .flat_map(|(f, v)| vec![Value::Data(f.clone().into_bytes()), v.clone()])
+                        .collect()
+                })
+                .unwrap_or_default(),
+        )),
+        "HSET" | "HMSET" => {
+            if args.len() < 4 || args.len() % 2 != 0 {
+                return Err(error("wrong number of arguments"));
+            }
+            let h = db.h.entry(key()?).or_insert_with(HashMap::new);
+            let added = args[2..]
+                .chunks(2)
+                .filter(|fv| {
+                    h.insert(String::from_utf8_lossy(&fv[0]).into_owned(), data(&fv[1]))
+                        .is_none()
+                })
+                .count();
+            Ok(if name == "HMSET" {
+                Value::Okay
+            } else {
+                Value::Int(added as i64)
+            })
+        }
+        "HSETNX" => {
+            if args.len() != 4 {
+                return Err(error("wrong number of arguments"));
+            }
+            let h = db.h.entry(key()?).or_insert_with(HashMap::new);
+            let field = str_arg(args, 2)?;
+            if h.contains_key(&field) {
+                Ok(Value::Int(0))
+            } else {
+                h.insert(field, data(&args[3]));
+                Ok(Value::Int(1))
+            }
+        }
+        "HDEL" => {
+            let key = key()?;
+            let removed = match db.h.get_mut(&key) {
+                Some(h) => rest()
+                    .filter(|f| h.remove(&*String::from_utf8_lossy(f)).is_some())
+                    .count(),
+                None => 0,
+            };
+            if db.h.get(&key).map_or(false, HashMap::is_empty) {
+                db.remove(&key);
+            }
+            Ok(Value::Int(removed as i64))
+        }
+        "SADD" => {
+            let s = db.s.entry(key()?).or_insert_with(Vec::new);
+            let mut added = 0;
+            for member in rest() {
+                if !s.contains(&data(member)) {
+                    s.push(data(member));
+                    added += 1;
+                }
+            }
+            Ok(Value::Int(added))
+        }
+        "SREM" => {
+            let key = key()?;
+            let removed = match db.s.get_mut(&key) {
+                Some(s) => {
+                    let before = s.len();
+                    let members: Vec<Value> = rest().map(|m| data(m)).collect();
+                    s.retain(|v| !members.contains(v));
+                    before - s.len()
+                }
+                None => 0,
+            };
+            if db.s.get(&key).map_or(false, Vec::is_empty) {
+                db.remove(&key);
+            }
+            Ok(Value::Int(removed as i64))
+        }
+        "SISMEMBER" => Ok(Value::Int(
+            db.s.get(&key()?)
+                .map_or(false, |s| s.contains(&data(&args[2]))) as i64,
+        )),
+        "SCARD" => Ok(Value::Int(db.s.get(&key()?).map_or(0, Vec::len) as i64)),
+        "SMEMBERS" => Ok(Value::Bulk(db.s.get(&key()?).cloned().unwrap_or_default())),
+        "RPUSH" => {
+            let l = db.l.entry(key()?).or_insert_with(Vec::new);
+            l.extend(rest().map(|v| data(v)));
+            Ok(Value::Int(l.len() as i64))
+        }
+        "LLEN" => Ok(Value::Int(db.l.get(&key()?).map_or(0, Vec::len) as i64)),
+        "LRANGE" => {
+            let (start, stop) = (int_arg(args, 2)?, int_arg(args, 3)?);
+            Ok(Value::Bulk(db.l.get(&key()?).map_or_else(Vec::new, |l| {
+                l[list_range(l.len(), start, stop)].to_vec()
+            })))
+        }
+        "LTRIM" => {
+            let key = key()?;
+            let (start, stop) = (int_arg(args, 2)?, int_arg(args, 3)?);
+            if let Some(l) = db.l.get_mut(&key) {
+                *l = l[list_range(l.len(), start, stop)].to_vec();
+            }
+            if db.l.get(&key).map_or(false, Vec::is_empty) {
+                db.remove(&key);
+            }
+            Ok(Value::Okay)
+        }
+        "XADD" | "XLEN" | "XRANGE" | "XDEL" | "XGROUP" | "XREADGROUP" | "XACK" | "XPENDING"
+        | "XCLAIM" => streams::execute(db, &name, args),
+        _ => Err(RedisError::from((
+            ErrorKind::ResponseError,
+            "unknown command",
+            name,
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Commands;
+
+    #[test]
+    fn commands_test() {
+        let mut c = MemoryConnection::new(20);
+        let _: () = c.set("key", "value").unwrap();
+        assert_eq!(Ok("value".to_owned()), c.get("key"));
+        assert_eq!(Ok(true), c.exists("key"));
+        assert_eq!(Ok(false), c.set_nx("key", "other"));
+        assert_eq!(Ok("value".to_owned()), c.getset("key", "new"));
+        assert_eq!(Ok(1), c.del("key"));
+        assert_eq!(Ok(None::<String>), c.get("key"));
+
+        assert_eq!(Ok(5), c.incr("counter", 5));
+        assert_eq!(Ok(6), c.incr("counter", 1));
+        assert_eq!(Ok(-1), c.ttl::<_, i64>("counter"));
+        assert_eq!(Ok(true), c.expire("counter", 60));
+        assert_eq!(Ok(60), c.ttl::<_, i64>("counter"));
+        assert_eq!(Ok(-2), c.ttl::<_, i64>("nothing"));
+        assert_eq!(Ok(true), c.pexpire("counter", 1));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(Ok(false), c.exists("counter"));
+
+        let _: () = c.hset_multiple("hash", &[("a", "1"), ("b", "2")]).unwrap();
+        assert_eq!(Ok(true), c.hexists("hash", "a"));
+        assert_eq!(Ok("2".to_owned()), c.hget("hash", "b"));
+        assert_eq!(Ok(1), c.hdel("hash", "a"));
+        assert_eq!(Ok(false), c.hexists("hash", "a"));
+        assert_eq!(Ok(false), c.hset_nx("hash", "b", "3"));
+        assert_eq!(Ok(true), c.hset_nx("hash", "c", "3"));
+        assert_eq!(Ok("2".to_owned()), c.hget("hash", "b"));
+
+        assert_eq!(Ok(2), c.sadd("set", &["x", "y"]));
+        assert_eq!(Ok(0), c.sadd("set", "x"));
+        assert_eq!(Ok(true), c.sismember("set", "y"));
+        assert_eq!(Ok(1), c.srem("set", "y"));
+        assert_eq!(Ok(vec!["x".to_owned()]), c.smembers("set"));
+
+        assert_eq!(Ok(3), c.rpush("list", &[1, 2, 3]));
+        let _: () = c.ltrim("list", -2, -1).unwrap();
+        assert_eq!(Ok(vec![2, 3]), c.lrange("list", 0, -1));
+        assert_eq!(Ok(2), c.llen("list"));
+
+        assert_eq!(true, redis::cmd("NOPE").query::<()>(&mut c).is_err());
+        let _: () = redis::cmd("FLUSHDB").query(&mut c).unwrap();
+        assert_eq!(Ok(false), c.exists("hash"));
+    }
+
+    #[test]
+    fn transaction_test() {
+        let mut c = MemoryConnection::new(21);
+        let (len, added): (i64, i64) = redis::transaction(&mut c, &["list"], |c, pipe| {
+            pipe.rpush("list", "a")
+                .sadd("set", "b")
+                .hset("hash", "c", "d")
+                .ignore()
+                .query(c)
+        })
+        .unwrap();
+        assert_eq!((1, 1), (len, added));
+        assert_eq!(Ok("d".to_owned()), c.hget("hash", "c"));
+
+        // a plain pipeline gets all its replies too
+        let (a, b): (bool, bool) = redis::pipe()
+            .exists("list")
+            .exists("nothing")
+            .query(&mut c)
+            .unwrap();
+        assert_eq!((true, false), (a, b));
+    }
+
+    type Entry = (String, HashMap<String, String>);
+
+    fn entries(values: Vec<Value>) -> Vec<Entry> {
+        values
+            .iter()
+            .map(|e| redis::from_redis_value(e).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn consumer_group_test() {
+        // through `FakeConnection`, whose time only moves when told to
+        let mut c = FakeConnection::new(22);
+        let cmd = |args: &[&str]| {
+            let mut cmd = redis::cmd(args[0]);
+            for arg in &args[1..] {
+                cmd.arg(*arg);
+            }
+            cmd
+        };
+        let create = ["XGROUP", "CREATE", "jobs", "g", "$", "MKSTREAM"];
+        assert_eq!(Ok(()), cmd(&create).query(&mut c));
+        let busy = cmd(&create).query::<()>(&mut c).unwrap_err();
+        assert_eq!(Some("BUSYGROUP"), busy.extension_error_code());
+
+        let id: String = cmd(&["XADD", "jobs", "*", "kind", "mail"])
+            .query(&mut c)
+            .unwrap();
+        let read = [
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "w1",
+            "COUNT",
+            "10",
+            "STREAMS",
+            "jobs",
+            ">",
+        ];
+        let streams: Vec<(String, Vec<Value>)> = cmd(&read)
+            .query::<Vec<Value>>(&mut c)
+            .unwrap()
+            .iter()
+            .map(|s| redis::from_redis_value(s).unwrap())
+            .collect();
+        assert_eq!("jobs", streams[0].0);
+        let read_entries = entries(streams[0].1.clone());
+        assert_eq!(id, read_entries[0].0);
+        assert_eq!(Some(&"mail".to_owned()), read_entries[0].1.get("kind"));
+        assert_eq!(Ok(Value::Nil), cmd(&read).query(&mut c));
+
+        // w1 never acknowledged it, w2 takes it over once idle for long enough
+        c.advance_time(std::time::Duration::from_millis(100));
+        let pending = ["XPENDING", "jobs", "g", "-", "+", "10"];
+        let details: Vec<(String, String, u64, u64)> = cmd(&pending)
+            .query::<Vec<Value>>(&mut c)
+            .unwrap()
+            .iter()
+            .map(|p| redis::from_redis_value(p).unwrap())
+            .collect();
+        assert_eq!(vec![(id.clone(), "w1".to_owned(), 100, 1)], details);
+        let claimed = cmd(&["XCLAIM", "jobs", "g", "w2", "1000", &id]).query(&mut c);
+        assert_eq!(0, entries(claimed.unwrap()).len());
+        let claimed = cmd(&["XCLAIM", "jobs", "g", "w2", "100", &id]).query(&mut c);
+        assert_eq!(1, entries(claimed.unwrap()).len());
+        let details: Vec<Value> = cmd(&pending).query(&mut c).unwrap();
+        assert_eq!(
+            Ok((id.clone(), "w2".to_owned(), 0, 2)),
+            redis::from_redis_value::<(String, String, u64, u64)>(&details[0])
+        );
+
+        assert_eq!(Ok(1), cmd(&["XACK", "jobs", "g", &id]).query::<i64>(&mut c));
+        assert_eq!(Ok(1), cmd(&["XDEL", "jobs", &id]).query::<i64>(&mut c));
+        let summary: (u64, Option<String>, Option<String>, Value) =
+            cmd(&["XPENDING", "jobs", "g"]).query(&mut c).unwrap();
+        assert_eq!((0, None, None, Value::Nil), summary);
+        assert_eq!(
+            Ok(3),
+            cmd(&["HINCRBY", "metrics", "done", "3"]).query::<i64>(&mut c)
+        );
+    }
+}
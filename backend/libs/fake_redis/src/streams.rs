@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap};
+
+use redis::{RedisResult, Value};
+
+use crate::{
+    fake_connection::{parse_stream_id, Storages, StreamEntry},
+    memory::{data, error, int_arg, server_error, str_arg},
+};
+
+type StreamId = (u64, u64);
+
+// Delivered to `consumer` and not acknowledged yet
+#[derive(Debug, Clone)]
+struct PendingEntry {
+    consumer: String,
+    delivered_at: u64,
+    deliveries: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+}
+
+fn id_value(id: StreamId) -> Value {
+    Value::Data(format!("{}-{}", id.0, id.1).into_bytes())
+}
+
+fn no_group() -> redis::RedisError {
+    server_error("NOGROUP No such key or consumer group")
+}
+
+// Index of the `option` keyword among the arguments, case insensitive
+fn option(args: &[Vec<u8>], option: &str) -> Option<usize> {
+    args.iter()
+        .position(|a| String::from_utf8_lossy(a).eq_ignore_ascii_case(option))
+}
+
+fn count_option(args: &[Vec<u8>]) -> RedisResult<usize> {
+    match option(args, "COUNT") {
+        Some(i) => int_arg(args, i + 1),
+        None => Ok(usize::max_value()),
+    }
+}
+
+// Stream commands, consumer groups included. BLOCK is accepted but never
+// waits, there is nobody else to add entries meanwhile.
+pub(crate) fn execute(db: &mut Storages, name: &str, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let key = || str_arg(args, 1);
+    match name {
+        "XADD" => {
+            // XADD key [MAXLEN [~] count] id field value...
+            let (max_len, at) = if str_arg(args, 2)?.eq_ignore_ascii_case("MAXLEN") {
+                let at = if args.get(3).map_or(false, |a| a == b"~") { 4 } else { 3 };
+                (Some(int_arg::<usize>(args, at)?), at + 1)
+            } else {
+                (None, 2)
+            };
+            let fields = args.iter().skip(at + 1).map(|f| data(f)).collect();
+            let id = db.xadd(&key()?, &str_arg(args, at)?, fields)?;
+            if let (Some(max_len), Some(stream)) = (max_len, db.x.get_mut(&key()?)) {
+                let extra = stream.len().saturating_sub(max_len);
+                stream.drain(..extra);
+            }
+            Ok(id)
+        }
+        "XLEN" => Ok(Value::Int(db.x.get(&key()?).map_or(0, Vec::len) as i64)),
+        "XRANGE" => {
+            let start = parse_stream_id(&str_arg(args, 2)?, 0)?;
+            let end = parse_stream_id(&str_arg(args, 3)?, u64::max_value())?;
+            let count = count_option(args)?;
+            Ok(Value::Bulk(db.x.get(&key()?).map_or_else(
+                Vec::new,
+                |stream| {
+                    stream
+                        .iter()
+                        .filter(|e| start <= e.id && e.id <= end)
+                        .take(count)
+                        .map(StreamEntry::to_value)
+                        .collect()
+                },
+            )))
+        }
+        "XDEL" => {
+            let ids = args[2..]
+                .iter()
+                .map(|id| parse_stream_id(&String::from_utf8_lossy(id), 0))
+                .collect::<RedisResult<Vec<_>>>()?;
+            let stream = db.x.get_mut(&key()?);
+            Ok(Value::Int(stream.map_or(0, |stream| {
+                let before = stream.len();
+                stream.retain(|e| !ids.contains(&e.id));
+                (before - stream.len()) as i64
+            })))
+        }
+        "XGROUP" => xgroup(db, args),
+        "XREADGROUP" => xreadgroup(db, args),
+        "XACK" => {
+            let group =
+                db.g.get_mut(&key()?)
+                    .and_then(|groups| groups.get_mut(&*String::from_utf8_lossy(&args[2])))
+                    .ok_or_else(no_group)?;
+            let mut acked = 0;
+            for id in &args[3..] {
+                let id = parse_stream_id(&String::from_utf8_lossy(id), 0)?;
+                acked += group.pending.remove(&id).is_some() as i64;
+            }
+            Ok(Value::Int(acked))
+        }
+        "XPENDING" => xpending(db, args),
+        "XCLAIM" => xclaim(db, args),
+        _ => Err(error("unknown command")),
+    }
+}
+
+// XGROUP CREATE key group id [MKSTREAM] and XGROUP DESTROY key group
+fn xgroup(db: &mut Storages, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (key, group) = (str_arg(args, 2)?, str_arg(args, 3)?);
+    match str_arg(args, 1)?.to_uppercase().as_str() {
+        "CREATE" => {
+            if !db.x.contains_key(&key) {
+                if option(args, "MKSTREAM").is_none() {
+                    return Err(error("The XGROUP subcommand requires the key to exist"));
+                }
+                db.x.insert(key.clone(), vec![]);
+            }
+            let last_delivered = match str_arg(args, 4)?.as_str() {
+                "$" => db.x[&key].last().map_or((0, 0), |e| e.id),
+                id => parse_stream_id(id, 0)?,
+            };
+            let groups = db.g.entry(key).or_insert_with(HashMap::new);
+            if groups.contains_key(&group) {
+                return Err(server_error("BUSYGROUP Consumer Group name already exists"));
+            }
+            groups.insert(
+                group,
+                ConsumerGroup {
+                    last_delivered,
+                    ..ConsumerGroup::default()
+                },
+            );
+            Ok(Value::Okay)
+        }
+        "DESTROY" => Ok(Value::Int(
+            db.g.get_mut(&key)
+                .map_or(false, |groups| groups.remove(&group).is_some()) as i64,
+        )),
+        _ => Err(error("unknown XGROUP subcommand")),
+    }
+}
+
+// XREADGROUP GROUP group consumer [COUNT n] [BLOCK ms] [NOACK] STREAMS key id,
+// a single stream only. `>` reads new entries, any other id the consumer's
+// pending ones after it.
+fn xreadgroup(db: &mut Storages, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (group_name, consumer) = (str_arg(args, 2)?, str_arg(args, 3)?);
+    let streams = option(args, "STREAMS").ok_or_else(|| error("syntax error"))?;
+    let (key, id) = (str_arg(args, streams + 1)?, str_arg(args, streams + 2)?);
+    let count = count_option(args)?;
+    let no_ack = option(args, "NOACK").is_some();
+    let clock = db.clock;
+    let stream = db.x.get(&key).ok_or_else(no_group)?;
+    let group =
+        db.g.get_mut(&key)
+            .and_then(|groups| groups.get_mut(&group_name))
+            .ok_or_else(no_group)?;
+
+    let entries: Vec<Value> = if id == ">" {
+        let new: Vec<&StreamEntry> = stream
+            .iter()
+            .filter(|e| e.id > group.last_delivered)
+            .take(count)
+            .collect();
+        if new.is_empty() {
+            return Ok(Value::Nil);
+        }
+        for entry in &new {
+            group.last_delivered = entry.id;
+            if !no_ack {
+                group.pending.insert(
+                    entry.id,
+                    PendingEntry {
+                        consumer: consumer.clone(),
+                        delivered_at: clock,
+                        deliveries: 1,
+                    },
+                );
+            }
+        }
+        new.into_iter().map(StreamEntry::to_value).collect()
+    } else {
+        let after = parse_stream_id(&id, 0)?;
+        group
+            .pending
+            .iter()
+            .filter(|(id, p)| **id >= after && p.consumer == consumer)
+            .take(count)
+            .map(|(id, _)| {
+                stream.iter().find(|e| e.id == *id).map_or_else(
+                    || Value::Bulk(vec![id_value(*id), Value::Nil]),
+                    StreamEntry::to_value,
+                )
+            })
+            .collect()
+    };
+    Ok(Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(key.into_bytes()),
+        Value::Bulk(entries),
+    ])]))
+}
+
+// XPENDING key group: summary of the pending entries
+// XPENDING key group [IDLE min-idle] start end count [consumer]: their details
+fn xpending(db: &mut Storages, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let clock = db.clock;
+    let group =
+        db.g.get(&str_arg(args, 1)?)
+            .and_then(|groups| groups.get(&*String::from_utf8_lossy(&args[2])))
+            .ok_or_else(no_group)?;
+    if args.len() == 3 {
+        if group.pending.is_empty() {
+            return Ok(Value::Bulk(vec![
+                Value::Int(0),
+                Value::Nil,
+                Value::Nil,
+                Value::Nil,
+            ]));
+        }
+        let mut consumers: BTreeMap<&str, i64> = BTreeMap::new();
+        for p in group.pending.values() {
+            *consumers.entry(&p.consumer).or_insert(0) += 1;
+        }
+        return Ok(Value::Bulk(vec![
+            Value::Int(group.pending.len() as i64),
+            id_value(*group.pending.keys().next().unwrap()),
+            id_value(*group.pending.keys().last().unwrap()),
+            Value::Bulk(
+                consumers
+                    .into_iter()
+                    .map(|(consumer, count)| {
+                        Value::Bulk(vec![
+                            Value::Data(consumer.as_bytes().to_vec()),
+                            Value::Data(count.to_string().into_bytes()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]));
+    }
+
+    let (min_idle, first) = match option(args, "IDLE") {
+        Some(i) => (int_arg::<u64>(args, i + 1)?, i + 2),
+        None => (0, 3),
+    };
+    let start = parse_stream_id(&str_arg(args, first)?, 0)?;
+    let end = parse_stream_id(&str_arg(args, first + 1)?, u64::max_value())?;
+    let count: usize = int_arg(args, first + 2)?;
+    let consumer = str_arg(args, first + 3).ok();
+    Ok(Value::Bulk(
+        group
+            .pending
+            .iter()
+            .filter(|(id, p)| {
+                start <= **id
+                    && **id <= end
+                    && clock - p.delivered_at >= min_idle
+                    && consumer.as_ref().map_or(true, |c| *c == p.consumer)
+            })
+            .take(count)
+            .map(|(id, p)| {
+                Value::Bulk(vec![
+                    id_value(*id),
+                    Value::Data(p.consumer.as_bytes().to_vec()),
+                    Value::Int((clock - p.delivered_at) as i64),
+                    Value::Int(p.deliveries as i64),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+// XCLAIM key group consumer min-idle id [id ...]: take over entries idle for
+// long enough. Entries deleted from the stream are dropped from the pending
+// ones, like Redis 7 does.
+fn xclaim(db: &mut Storages, args: &[Vec<u8>]) -> RedisResult<Value> {
+    let key = str_arg(args, 1)?;
+    let consumer = str_arg(args, 3)?;
+    let min_idle: u64 = int_arg(args, 4)?;
+    let clock = db.clock;
+    let stream = db.x.get(&key).ok_or_else(no_group)?;
+    let group =
+        db.g.get_mut(&key)
+            .and_then(|groups| groups.get_mut(&*String::from_utf8_lossy(&args[2])))
+            .ok_or_else(no_group)?;
+    let mut claimed = vec![];
+    for id in &args[5..] {
+        let id = match parse_stream_id(&String::from_utf8_lossy(id), 0) {
+            Ok(id) => id,
+            // options like JUSTID or FORCE, not supported
+            Err(_) => break,
+        };
+        let entry = stream.iter().find(|e| e.id == id);
+        match (group.pending.get_mut(&id), entry) {
+            (Some(p), Some(entry)) if clock - p.delivered_at >= min_idle => {
+                p.consumer = consumer.clone();
+                p.delivered_at = clock;
+                p.deliveries += 1;
+                claimed.push(entry.to_value());
+            }
+            (Some(_), None) => {
+                group.pending.remove(&id);
+            }
+            _ => (),
+        }
+    }
+    Ok(Value::Bulk(claimed))
+}
@@ -0,0 +1,208 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+use hex_view::HexView;
+use log::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    cron::civil_from_days,
+    error::{self, ServerError},
+    links::hmac,
+};
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DAY_S: u64 = 24 * 3600;
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+// An S3-compatible bucket for what the server no longer keeps. Objects are
+// addressed path-style, `<endpoint>/<bucket>/<key>`, which every
+// S3-compatible server takes, and uploads are signed with AWS's signature
+// version 4.
+#[derive(Debug)]
+pub struct Archive {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    key_id: String,
+    secret: String,
+    http: reqwest::Client,
+}
+
+impl Archive {
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: Option<&str>,
+        key_id: &str,
+        secret: &str,
+    ) -> Self {
+        Archive {
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            bucket: bucket.to_owned(),
+            region: region.unwrap_or(DEFAULT_REGION).to_owned(),
+            key_id: key_id.to_owned(),
+            secret: secret.to_owned(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // Upload `items` as gzipped JSON lines, under a key made of `prefix`, the
+    // day and a random part. Returns the key.
+    pub async fn put_jsonl<T: Serialize>(
+        &self,
+        prefix: &str,
+        items: &[T],
+        now: u64,
+    ) -> error::Result<String> {
+        let (year, month, day) = civil_from_days(now / DAY_S);
+        let key = format!(
+            "{}/{}-{:02}-{:02}/{}-{}.jsonl.gz",
+            prefix,
+            year,
+            month,
+            day,
+            now,
+            Uuid::new_v4()
+        );
+        self.put(&key, jsonl_gz(items)?, now).await?;
+        Ok(key)
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, now: u64) -> error::Result<()> {
+        let path = format!("/{}/{}", self.bucket, key);
+        let host = self
+            .endpoint
+            .splitn(2, "://")
+            .last()
+            .unwrap_or_default()
+            .to_owned();
+        let payload_hash = hex(&Sha256::digest(&body));
+        let amz_date = amz_date(now);
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &amz_date[..8], self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret, &amz_date[..8], &self.region, "s3");
+        let signature = hex(&hmac::<Sha256>(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.key_id, scope, SIGNED_HEADERS, signature
+        );
+
+        let failed = |e: &dyn std::fmt::Display| {
+            warn!("Could not upload {} to the archive: {}", path, e);
+            ServerError::new(error::INTERNAL_ERROR, "Could not upload to the archive")
+        };
+        let res = self
+            .http
+            .put(&format!("{}{}", self.endpoint, path))
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header("authorization", authorization)
+            .header("content-type", "application/x-ndjson")
+            .header("content-encoding", "gzip")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| failed(&e))?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(failed(&res.status()))
+        }
+    }
+}
+
+// One JSON document per line, gzipped
+pub fn jsonl_gz<T: Serialize>(items: &[T]) -> error::Result<Vec<u8>> {
+    let failed = |e: &dyn std::fmt::Display| {
+        ServerError::new(
+            error::INTERNAL_ERROR,
+            &format!("Could not compress the archive: {}", e),
+        )
+    };
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    for item in items {
+        serde_json::to_writer(&mut gz, item).map_err(|e| failed(&e))?;
+        gz.write_all(b"\n").map_err(|e| failed(&e))?;
+    }
+    gz.finish().map_err(|e| failed(&e))
+}
+
+// `20201017T120000Z`
+fn amz_date(now: u64) -> String {
+    let (year, month, day) = civil_from_days(now / DAY_S);
+    let s = now % DAY_S;
+    format!(
+        "{}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        s / 3600,
+        s / 60 % 60,
+        s % 60
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    format!("{:x}", HexView::from(bytes))
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac::<Sha256>(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac::<Sha256>(&key, region.as_bytes());
+    let key = hmac::<Sha256>(&key, service.as_bytes());
+    hmac::<Sha256>(&key, b"aws4_request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use serde_json::{json, Value};
+    use std::io::Read;
+
+    #[test]
+    fn amz_date_test() {
+        assert_eq!("19700101T000000Z", amz_date(0));
+        assert_eq!("20200615T120503Z", amz_date(1_592_222_703));
+    }
+
+    #[test]
+    fn signing_key_test() {
+        // the example of AWS's documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d",
+            hex(&key)
+        );
+    }
+
+    #[test]
+    fn jsonl_gz_test() {
+        let items = vec![json!({ "id": "a1" }), json!({ "id": "a2" })];
+        let gz = jsonl_gz(&items).unwrap();
+        let mut jsonl = String::new();
+        GzDecoder::new(&gz[..]).read_to_string(&mut jsonl).unwrap();
+        let lines: Vec<Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(items, lines);
+    }
+}
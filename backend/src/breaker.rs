@@ -0,0 +1,114 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::*;
+
+// Consecutive database failures before requests are turned away
+const FAILURE_THRESHOLD: u32 = 5;
+
+#[derive(Debug)]
+struct State {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// Once Redis has timed out often enough in a row, fail requests straight away
+// for `cooldown` instead of letting them pile up on blocked connections. After
+// the cool-down requests go through again, and one more failure reopens it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(cooldown: Duration) -> Self {
+        CircuitBreaker {
+            cooldown,
+            state: Mutex::new(State {
+                failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn is_open_at(&self, now: Instant) -> bool {
+        match self.state.lock().unwrap().open_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.failures >= FAILURE_THRESHOLD {
+            if state.open_until.map_or(true, |until| until <= now) {
+                warn!(
+                    "Database failing, turning requests away for {:?}",
+                    self.cooldown
+                );
+            }
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_opens_after_threshold_test() {
+        let breaker = CircuitBreaker::new(Duration::from_secs(10));
+        let now = Instant::now();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure_at(now);
+        }
+        assert_eq!(false, breaker.is_open_at(now));
+        breaker.record_failure_at(now);
+        assert_eq!(true, breaker.is_open_at(now));
+        assert_eq!(true, breaker.is_open_at(now + Duration::from_secs(9)));
+
+        // half open after the cool-down, the next failure reopens it
+        let later = now + Duration::from_secs(10);
+        assert_eq!(false, breaker.is_open_at(later));
+        breaker.record_failure_at(later);
+        assert_eq!(true, breaker.is_open_at(later + Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn breaker_success_resets_test() {
+        let breaker = CircuitBreaker::new(Duration::from_secs(10));
+        let now = Instant::now();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure_at(now);
+        }
+        breaker.record_success();
+        breaker.record_failure_at(now);
+        assert_eq!(false, breaker.is_open_at(now));
+
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure_at(now);
+        }
+        assert_eq!(true, breaker.is_open_at(now));
+        breaker.record_success();
+        assert_eq!(false, breaker.is_open_at(now));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use blake2::Digest;
+use hex_view::HexView;
+use log::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{self, ServerError},
+    links::{constant_time_eq, hmac, now_s, Blake2b256},
+    types::ChallengeInfo,
+};
+
+// time to solve a proof of work before its seed expires
+pub const POW_TTL_S: u64 = 300;
+const HCAPTCHA_VERIFY_URL: &str = "https://hcaptcha.com/siteverify";
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+pub type VerifyFuture = Pin<Box<dyn Future<Output = error::Result<bool>> + Send>>;
+
+// An anti-bot test clients pass before signing up
+pub trait Challenge: fmt::Debug + Send + Sync {
+    // What a client needs to take the test, served at GET /challenge
+    fn issue(&self) -> ChallengeInfo;
+
+    // Whether the client's answer passes, `ip` is the client's address
+    fn verify(&self, answer: &str, ip: Option<IpAddr>) -> VerifyFuture;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeKind {
+    None,
+    Pow,
+    Hcaptcha,
+    Turnstile,
+}
+
+impl Default for ChallengeKind {
+    fn default() -> Self {
+        ChallengeKind::None
+    }
+}
+
+impl FromStr for ChallengeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ChallengeKind::None),
+            "pow" => Ok(ChallengeKind::Pow),
+            "hcaptcha" => Ok(ChallengeKind::Hcaptcha),
+            "turnstile" => Ok(ChallengeKind::Turnstile),
+            _ => Err(format!("{}: expected none, pow, hcaptcha or turnstile", s)),
+        }
+    }
+}
+
+// The challenge of the settings, None if sign ups take none
+pub fn build(
+    kind: ChallengeKind,
+    site_key: &str,
+    secret: &str,
+    difficulty: u8,
+) -> Option<Arc<dyn Challenge>> {
+    match kind {
+        ChallengeKind::None => None,
+        ChallengeKind::Pow => Some(Arc::new(ProofOfWork::new(secret.as_bytes(), difficulty))),
+        ChallengeKind::Hcaptcha => Some(Arc::new(Captcha::new(
+            "hcaptcha",
+            HCAPTCHA_VERIFY_URL,
+            site_key,
+            secret,
+        ))),
+        ChallengeKind::Turnstile => Some(Arc::new(Captcha::new(
+            "turnstile",
+            TURNSTILE_VERIFY_URL,
+            site_key,
+            secret,
+        ))),
+    }
+}
+
+// hCaptcha and Turnstile: the widget gives the client a token which the
+// provider's siteverify endpoint checks
+pub struct Captcha {
+    provider: &'static str,
+    verify_url: &'static str,
+    site_key: String,
+    secret: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct SiteVerify {
+    success: bool,
+}
+
+impl Captcha {
+    pub fn new(
+        provider: &'static str,
+        verify_url: &'static str,
+        site_key: &str,
+        secret: &str,
+    ) -> Self {
+        Captcha {
+            provider,
+            verify_url,
+            site_key: site_key.to_owned(),
+            secret: secret.to_owned(),
+            http: reqwest::Client::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Captcha {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Captcha")
+            .field("provider", &self.provider)
+            .field("site_key", &self.site_key)
+            .finish()
+    }
+}
+
+impl Challenge for Captcha {
+    fn issue(&self) -> ChallengeInfo {
+        ChallengeInfo::Captcha {
+            provider: self.provider.to_owned(),
+            site_key: self.site_key.clone(),
+        }
+    }
+
+    fn verify(&self, answer: &str, ip: Option<IpAddr>) -> VerifyFuture {
+        let mut form = vec![
+            ("secret", self.secret.clone()),
+            ("response", answer.to_owned()),
+        ];
+        if let Some(ip) = ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+        let request = self.http.post(self.verify_url).form(&form);
+        let provider = self.provider;
+        Box::pin(async move {
+            let failed = |e: reqwest::Error| {
+                warn!("Could not verify {} token: {}", provider, e);
+                ServerError::new(error::INTERNAL_ERROR, "Could not verify the challenge")
+            };
+            let res: SiteVerify = request
+                .send()
+                .await
+                .map_err(failed)?
+                .json()
+                .await
+                .map_err(failed)?;
+            Ok(res.success)
+        })
+    }
+}
+
+// Built-in challenge: find a nonce such that the hash of `<seed>:<nonce>`
+// starts with `difficulty` zero bits. Seeds are signed and expire, the
+// solved ones are remembered until then so each only passes once. Like
+// export links, they only work on the instance that issued them.
+#[derive(Debug)]
+pub struct ProofOfWork {
+    secret: Vec<u8>,
+    difficulty: u8,
+    // solved seeds and when they expire
+    used: Mutex<HashMap<String, u64>>,
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn pow_hash(answer: &str) -> u32 {
+    leading_zero_bits(&Blake2b256::digest(answer.as_bytes()))
+}
+
+// What clients do: the answer to a seed, `<seed>:<nonce>`
+pub fn solve_pow(seed: &str, difficulty: u8) -> String {
+    (0u64..)
+        .map(|nonce| format!("{}:{}", seed, nonce))
+        .find(|answer| pow_hash(answer) >= u32::from(difficulty))
+        .unwrap()
+}
+
+impl ProofOfWork {
+    // A random secret unless one is given
+    pub fn new(secret: &[u8], difficulty: u8) -> Self {
+        let secret = if secret.is_empty() {
+            let mut secret = vec![0u8; 32];
+            rand::thread_rng().fill(&mut secret[..]);
+            secret
+        } else {
+            secret.to_vec()
+        };
+        ProofOfWork {
+            secret,
+            difficulty,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sign(&self, msg: &str) -> String {
+        let mac = hmac::<Blake2b256>(&self.secret, format!("pow:{}", msg).as_bytes());
+        format!("{:x}", HexView::from(&mac[..]))
+    }
+
+    // `<expires_at>.<random>.<signature>`
+    fn seed(&self, now: u64) -> String {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill(&mut nonce[..]);
+        let msg = format!("{}.{:x}", now + POW_TTL_S, HexView::from(&nonce[..]));
+        let sig = self.sign(&msg);
+        format!("{}.{}", msg, sig)
+    }
+
+    fn check(&self, answer: &str, now: u64) -> bool {
+        let seed = match answer.rsplitn(2, ':').nth(1) {
+            Some(seed) => seed,
+            None => return false,
+        };
+        let (msg, sig) = match seed.rfind('.') {
+            Some(i) => (&seed[..i], &seed[i + 1..]),
+            None => return false,
+        };
+        let expires_at: u64 = match msg.split('.').next().and_then(|e| e.parse().ok()) {
+            Some(expires_at) => expires_at,
+            None => return false,
+        };
+        if now >= expires_at
+            || !constant_time_eq(&self.sign(msg), sig)
+            || pow_hash(answer) < u32::from(self.difficulty)
+        {
+            return false;
+        }
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expires_at| *expires_at > now);
+        used.insert(seed.to_owned(), expires_at).is_none()
+    }
+}
+
+impl Challenge for ProofOfWork {
+    fn issue(&self) -> ChallengeInfo {
+        ChallengeInfo::Pow {
+            seed: self.seed(now_s()),
+            difficulty: self.difficulty,
+        }
+    }
+
+    fn verify(&self, answer: &str, _ip: Option<IpAddr>) -> VerifyFuture {
+        let passed = self.check(answer, now_s());
+        Box::pin(async move { Ok(passed) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_zero_bits_test() {
+        assert_eq!(0, leading_zero_bits(&[0x80, 0]));
+        assert_eq!(3, leading_zero_bits(&[0x10, 0]));
+        assert_eq!(12, leading_zero_bits(&[0, 0x08, 0xff]));
+        assert_eq!(16, leading_zero_bits(&[0, 0]));
+    }
+
+    #[test]
+    fn proof_of_work_test() {
+        let pow = ProofOfWork::new(b"secret", 8);
+        let seed = pow.seed(1000);
+        let answer = solve_pow(&seed, 8);
+        assert_eq!(true, pow.check(&answer, 1000));
+        // each seed passes once
+        assert_eq!(false, pow.check(&answer, 1001));
+        let seed = pow.seed(1000);
+        let answer = solve_pow(&seed, 8);
+        assert_eq!(false, pow.check(&answer, 1000 + POW_TTL_S));
+        assert_eq!(true, pow.check(&answer, 1000));
+
+        // not enough work, or a seed it did not issue
+        let seed = pow.seed(1000);
+        let lazy = (0..)
+            .map(|nonce| format!("{}:{}", seed, nonce))
+            .find(|answer| pow_hash(answer) < 8)
+            .unwrap();
+        assert_eq!(false, pow.check(&lazy, 1000));
+        let forged = seed.replacen("1300", "9999", 1);
+        assert_eq!(false, pow.check(&solve_pow(&forged, 8), 1000));
+        let other = ProofOfWork::new(b"other", 8);
+        assert_eq!(false, other.check(&solve_pow(&seed, 8), 1000));
+        assert_eq!(false, pow.check("nonsense", 1000));
+        assert_eq!(true, pow.check(&solve_pow(&seed, 8), 1000));
+    }
+}
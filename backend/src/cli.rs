@@ -1,12 +1,195 @@
+use std::path::PathBuf;
+
 use argh::FromArgs;
 
+use crate::{
+    challenge::ChallengeKind, config::Registration, db::version::SchemaMismatch, ip_filter::Cidr,
+    nutrition::NutritionKind, storage::StorageKind,
+};
+
 #[derive(FromArgs)]
 /// Efficio's backend
 pub struct Opt {
+    /// JSON file with the log filters, IP lists, abuse limits, task schedule, assistant redirect URIs and admin token, reloaded on SIGHUP
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+    /// where to keep the data: redis (default) or memory, lost on exit
+    #[argh(option)]
+    pub storage: Option<StorageKind>,
     /// database host
     #[argh(option, short = 'h')]
     pub db_host: Option<String>,
     /// database port
     #[argh(option, short = 'p')]
     pub db_port: Option<u32>,
+    /// read replica host, store reads go there when set
+    #[argh(option)]
+    pub db_replica_host: Option<String>,
+    /// read replica port, the database port by default
+    #[argh(option)]
+    pub db_replica_port: Option<u32>,
+    /// time budget for a database command in milliseconds, 2000 by default
+    #[argh(option)]
+    pub db_timeout_ms: Option<u64>,
+    /// seconds to turn requests away once the database keeps failing, 10 by default
+    #[argh(option)]
+    pub breaker_cooldown_s: Option<u64>,
+    /// serve HTTP/1 only, HTTP/2 is accepted by default
+    #[argh(switch)]
+    pub http1_only: bool,
+    /// maximum concurrent streams per HTTP/2 connection, unlimited by default
+    #[argh(option)]
+    pub http2_max_streams: Option<u32>,
+    /// seconds between HTTP/2 keep-alive pings, none by default
+    #[argh(option)]
+    pub http2_keep_alive_s: Option<u64>,
+    /// seconds to wait for a keep-alive ping reply before closing, 20 by default
+    #[argh(option)]
+    pub http2_keep_alive_timeout_s: Option<u64>,
+    /// close HTTP/1 connections after each response
+    #[argh(switch)]
+    pub no_keep_alive: bool,
+    /// seconds between TCP keep-alive probes, none by default
+    #[argh(option)]
+    pub tcp_keepalive_s: Option<u64>,
+    /// maximum number of open connections, unlimited by default
+    #[argh(option)]
+    pub max_connections: Option<usize>,
+    /// stores a user can have, 100 by default, 0 for no limit
+    #[argh(option)]
+    pub max_stores: Option<u64>,
+    /// aisles in a store, 200 by default, 0 for no limit
+    #[argh(option)]
+    pub max_aisles: Option<u64>,
+    /// products in an aisle, 1000 by default, 0 for no limit
+    #[argh(option)]
+    pub max_products: Option<u64>,
+    /// bytes of names a user can store, 4 MiB by default, 0 for no limit
+    #[argh(option)]
+    pub max_user_bytes: Option<u64>,
+    /// share of the stores and bytes quotas in percent past which replies
+    /// warn, 80 by default, 0 for no warning
+    #[argh(option)]
+    pub quota_warning_percent: Option<u64>,
+    /// change journal entries a store keeps for syncing clients, 1000 by default
+    #[argh(option)]
+    pub journal_max_len: Option<u64>,
+    /// seconds journal entries are kept, 0 or unset to keep them until there are too many
+    #[argh(option)]
+    pub journal_max_age_s: Option<u64>,
+    /// requests per minute before a client is banned, 600 by default, 0 for no limit
+    #[argh(option)]
+    pub abuse_max_requests: Option<u64>,
+    /// share of failed requests in a minute before a client is banned, 0.5 by default, 0 to disable
+    #[argh(option)]
+    pub abuse_max_error_ratio: Option<f64>,
+    /// failed requests in a minute before the error share is looked at, 30 by default
+    #[argh(option)]
+    pub abuse_min_errors: Option<u64>,
+    /// seconds an abusive client stays banned, bans are off without it,
+    /// loopback addresses are never banned
+    #[argh(option)]
+    pub abuse_ban_s: Option<u64>,
+    /// file with the key of the email index, made once and kept in the db by
+    /// default, changing it loses the index
+    #[argh(option)]
+    pub email_secret_file: Option<PathBuf>,
+    /// file with the bearer token of the admin API and /metrics, both are
+    /// closed without one
+    #[argh(option)]
+    pub admin_token_file: Option<PathBuf>,
+    /// file with the key signing export links, random at each start by default
+    #[argh(option)]
+    pub link_secret_file: Option<PathBuf>,
+    /// file with a key signing session tokens, repeatable: the first signs
+    /// new sessions, the others still check theirs. A key kept in the
+    /// database by default
+    #[argh(option)]
+    pub token_secret_file: Vec<PathBuf>,
+    /// tasks running background jobs, 2 by default, 0 to run none
+    #[argh(option)]
+    pub job_workers: Option<usize>,
+    /// port of the gRPC API, not served if absent
+    #[argh(option)]
+    pub grpc_port: Option<u16>,
+    /// reverse proxy address or CIDR whose X-Forwarded-For is trusted, repeatable
+    #[argh(option)]
+    pub trusted_proxy: Vec<Cidr>,
+    /// only serve clients from this address or CIDR, repeatable
+    #[argh(option)]
+    pub allow_ip: Vec<Cidr>,
+    /// never serve clients from this address or CIDR, repeatable
+    #[argh(option)]
+    pub deny_ip: Vec<Cidr>,
+    /// redirect URI a voice assistant may link accounts with, repeatable
+    #[argh(option)]
+    pub assistant_redirect_uri: Vec<String>,
+    /// who may sign up: open (default), invite or closed
+    #[argh(option)]
+    pub registration: Option<Registration>,
+    /// only accept sign ups with emails of this domain, repeatable
+    #[argh(option)]
+    pub registration_email_domain: Vec<String>,
+    /// anti-bot challenge on sign up: none (default), pow, hcaptcha or turnstile
+    #[argh(option)]
+    pub challenge: Option<ChallengeKind>,
+    /// site key of the hCaptcha or Turnstile widget
+    #[argh(option)]
+    pub challenge_site_key: Option<String>,
+    /// file with the hCaptcha or Turnstile secret, or the key signing proofs
+    /// of work, random at each start by default
+    #[argh(option)]
+    pub challenge_secret_file: Option<PathBuf>,
+    /// leading zero bits of a proof of work, 20 by default
+    #[argh(option)]
+    pub pow_difficulty: Option<u8>,
+    /// give sessions a new token once they are this many seconds old, never
+    /// by default
+    #[argh(option)]
+    pub session_rotate_after_s: Option<u64>,
+    /// seconds a replaced session token keeps working, 60 by default
+    #[argh(option)]
+    pub session_rotation_grace_s: Option<u64>,
+    /// aisle new stores start with, for users with none of their own,
+    /// repeatable
+    #[argh(option)]
+    pub default_aisle: Vec<String>,
+    /// source of nutrition facts: none (default) or openfoodfacts
+    #[argh(option)]
+    pub nutrition: Option<NutritionKind>,
+    /// URL of the nutrition source, its public instance by default
+    #[argh(option)]
+    pub nutrition_url: Option<String>,
+    /// URL of an S3-compatible server to archive expired journal entries to,
+    /// along with --archive-bucket
+    #[argh(option)]
+    pub archive_url: Option<String>,
+    /// bucket of the archive
+    #[argh(option)]
+    pub archive_bucket: Option<String>,
+    /// region of the archive's bucket, us-east-1 by default
+    #[argh(option)]
+    pub archive_region: Option<String>,
+    /// access key id of the archive
+    #[argh(option)]
+    pub archive_key_id: Option<String>,
+    /// file with the secret access key of the archive
+    #[argh(option)]
+    pub archive_secret_file: Option<PathBuf>,
+    /// share anonymous daily counts at `GET /admin/telemetry`, off by default
+    #[argh(switch)]
+    pub telemetry: bool,
+    /// URL to post the anonymous daily counts to once a day, with --telemetry
+    #[argh(option)]
+    pub telemetry_url: Option<String>,
+    /// open the directory of templates users publish, under `/community`
+    #[argh(switch)]
+    pub community: bool,
+    /// start with writes answering 503, see `PUT /admin/read_only`
+    #[argh(switch)]
+    pub read_only: bool,
+    /// when the database's schema version is out of the supported range:
+    /// refuse (default) to start, or start read-only
+    #[argh(option)]
+    pub schema_mismatch: Option<SchemaMismatch>,
 }
@@ -0,0 +1,676 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use efficio_types::Maintenance;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    challenge::{self, Challenge, ChallengeKind},
+    cron::Cron,
+    db::abuse::AbuseLimits,
+    error::{self, ServerError},
+    ip_filter::{Cidr, IpPolicy},
+    links::constant_time_eq,
+    nutrition::{self, NutritionKind, NutritionSource},
+    redact,
+};
+
+// abuse limits when the command line doesn't set them, bans are off by default
+pub const DEFAULT_ABUSE_MAX_REQUESTS: u64 = 600;
+pub const DEFAULT_ABUSE_MAX_ERROR_RATIO: f64 = 0.5;
+pub const DEFAULT_ABUSE_MIN_ERRORS: u64 = 30;
+
+// Settings that can change without restarting the server
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Config {
+    // RUST_LOG style filters
+    pub log: String,
+    pub trusted_proxy: Vec<Cidr>,
+    pub allow_ip: Vec<Cidr>,
+    pub deny_ip: Vec<Cidr>,
+    // temporary bans of abusive clients, zeros turn them off
+    pub abuse_max_requests: u64,
+    pub abuse_max_error_ratio: f64,
+    pub abuse_min_errors: u64,
+    pub abuse_ban_s: u64,
+    // when each scheduled task runs, the ones missing don't
+    pub schedule: BTreeMap<String, Cron>,
+    // where voice assistants may be sent back with a session when linking
+    // an account
+    pub assistant_redirect_uri: Vec<String>,
+    // who may sign up, and with which email domains if any are listed
+    pub registration: Registration,
+    pub registration_email_domain: Vec<String>,
+    // anti-bot test on sign up, the secret is the captcha provider's or
+    // signs the proofs of work
+    pub challenge: ChallengeKind,
+    pub challenge_site_key: String,
+    #[serde(skip_serializing)]
+    pub challenge_secret: String,
+    // leading zero bits asked of proofs of work
+    pub pow_difficulty: u8,
+    // sessions older than this get a new token, 0 never rotates them
+    pub session_rotate_after_s: u64,
+    // how long a replaced token keeps working
+    pub session_rotation_grace_s: u64,
+    // aisles new stores start with, for users with none of their own
+    pub default_aisles: Vec<String>,
+    // where nutrition facts are looked up, the source's public instance if
+    // no URL is given
+    pub nutrition: NutritionKind,
+    pub nutrition_url: String,
+    // writes answer 503, for Redis failovers and backups
+    pub read_only: bool,
+    // anonymous daily counts are shown at GET /admin/telemetry, and posted to
+    // the URL if any, only when this is set
+    pub telemetry: bool,
+    pub telemetry_url: String,
+    // the directory of templates users publish, under /community
+    pub community: bool,
+    // bearer token of the admin API and /metrics, both are closed without one
+    #[serde(skip_serializing)]
+    pub admin_token: String,
+}
+
+// Who may create an account through `POST /user`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Registration {
+    Open,
+    // with a code minted by `POST /admin/invites`
+    Invite,
+    Closed,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration::Open
+    }
+}
+
+impl FromStr for Registration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Registration::Open),
+            "invite" => Ok(Registration::Invite),
+            "closed" => Ok(Registration::Closed),
+            _ => Err(format!("{}: expected open, invite or closed", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegistrationPolicy {
+    pub mode: Registration,
+    // lowercase, anything goes when empty
+    pub email_domains: Vec<String>,
+}
+
+impl RegistrationPolicy {
+    pub fn allows_email(&self, email: &str) -> bool {
+        if self.email_domains.is_empty() {
+            return true;
+        }
+        match email.rsplit_once('@') {
+            Some((_, domain)) => {
+                let domain = domain.to_lowercase();
+                self.email_domains.iter().any(|d| *d == domain)
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionRotation {
+    pub after_s: u64,
+    pub grace_s: u64,
+}
+
+impl SessionRotation {
+    pub fn is_enabled(&self) -> bool {
+        self.after_s > 0
+    }
+}
+
+// Content of the `--config` file, a missing field keeps the command line value
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    log: Option<String>,
+    trusted_proxy: Option<Vec<Cidr>>,
+    allow_ip: Option<Vec<Cidr>>,
+    deny_ip: Option<Vec<Cidr>>,
+    abuse_max_requests: Option<u64>,
+    abuse_max_error_ratio: Option<f64>,
+    abuse_min_errors: Option<u64>,
+    abuse_ban_s: Option<u64>,
+    schedule: Option<BTreeMap<String, Cron>>,
+    assistant_redirect_uri: Option<Vec<String>>,
+    registration: Option<Registration>,
+    registration_email_domain: Option<Vec<String>>,
+    challenge: Option<ChallengeKind>,
+    challenge_site_key: Option<String>,
+    challenge_secret: Option<String>,
+    pow_difficulty: Option<u8>,
+    session_rotate_after_s: Option<u64>,
+    session_rotation_grace_s: Option<u64>,
+    default_aisles: Option<Vec<String>>,
+    nutrition: Option<NutritionKind>,
+    nutrition_url: Option<String>,
+    read_only: Option<bool>,
+    telemetry: Option<bool>,
+    telemetry_url: Option<String>,
+    community: Option<bool>,
+    admin_token: Option<String>,
+}
+
+impl Config {
+    fn overlay(&self, file: ConfigFile) -> Config {
+        Config {
+            log: file.log.unwrap_or_else(|| self.log.clone()),
+            trusted_proxy: file
+                .trusted_proxy
+                .unwrap_or_else(|| self.trusted_proxy.clone()),
+            allow_ip: file.allow_ip.unwrap_or_else(|| self.allow_ip.clone()),
+            deny_ip: file.deny_ip.unwrap_or_else(|| self.deny_ip.clone()),
+            abuse_max_requests: file.abuse_max_requests.unwrap_or(self.abuse_max_requests),
+            abuse_max_error_ratio: file
+                .abuse_max_error_ratio
+                .unwrap_or(self.abuse_max_error_ratio),
+            abuse_min_errors: file.abuse_min_errors.unwrap_or(self.abuse_min_errors),
+            abuse_ban_s: file.abuse_ban_s.unwrap_or(self.abuse_ban_s),
+            schedule: file.schedule.unwrap_or_else(|| self.schedule.clone()),
+            assistant_redirect_uri: file
+                .assistant_redirect_uri
+                .unwrap_or_else(|| self.assistant_redirect_uri.clone()),
+            registration: file.registration.unwrap_or(self.registration),
+            registration_email_domain: file
+                .registration_email_domain
+                .unwrap_or_else(|| self.registration_email_domain.clone()),
+            challenge: file.challenge.unwrap_or(self.challenge),
+            challenge_site_key: file
+                .challenge_site_key
+                .unwrap_or_else(|| self.challenge_site_key.clone()),
+            challenge_secret: file
+                .challenge_secret
+                .unwrap_or_else(|| self.challenge_secret.clone()),
+            pow_difficulty: file.pow_difficulty.unwrap_or(self.pow_difficulty),
+            session_rotate_after_s: file
+                .session_rotate_after_s
+                .unwrap_or(self.session_rotate_after_s),
+            session_rotation_grace_s: file
+                .session_rotation_grace_s
+                .unwrap_or(self.session_rotation_grace_s),
+            default_aisles: file
+                .default_aisles
+                .unwrap_or_else(|| self.default_aisles.clone()),
+            nutrition: file.nutrition.unwrap_or(self.nutrition),
+            nutrition_url: file
+                .nutrition_url
+                .unwrap_or_else(|| self.nutrition_url.clone()),
+            read_only: file.read_only.unwrap_or(self.read_only),
+            telemetry: file.telemetry.unwrap_or(self.telemetry),
+            telemetry_url: file
+                .telemetry_url
+                .unwrap_or_else(|| self.telemetry_url.clone()),
+            community: file.community.unwrap_or(self.community),
+            admin_token: file.admin_token.unwrap_or_else(|| self.admin_token.clone()),
+        }
+    }
+
+    // Names of the settings that differ
+    pub fn changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = vec![];
+        if self.log != other.log {
+            changes.push("log");
+        }
+        if self.trusted_proxy != other.trusted_proxy {
+            changes.push("trusted_proxy");
+        }
+        if self.allow_ip != other.allow_ip {
+            changes.push("allow_ip");
+        }
+        if self.deny_ip != other.deny_ip {
+            changes.push("deny_ip");
+        }
+        if self.abuse_limits() != other.abuse_limits() {
+            changes.push("abuse");
+        }
+        if self.schedule != other.schedule {
+            changes.push("schedule");
+        }
+        if self.assistant_redirect_uri != other.assistant_redirect_uri {
+            changes.push("assistant_redirect_uri");
+        }
+        if self.registration_policy() != other.registration_policy() {
+            changes.push("registration");
+        }
+        if self.challenge != other.challenge
+            || self.challenge_site_key != other.challenge_site_key
+            || self.challenge_secret != other.challenge_secret
+            || self.pow_difficulty != other.pow_difficulty
+        {
+            changes.push("challenge");
+        }
+        if self.session_rotation() != other.session_rotation() {
+            changes.push("session_rotation");
+        }
+        if self.default_aisles != other.default_aisles {
+            changes.push("default_aisles");
+        }
+        if self.nutrition != other.nutrition || self.nutrition_url != other.nutrition_url {
+            changes.push("nutrition");
+        }
+        if self.read_only != other.read_only {
+            changes.push("read_only");
+        }
+        if self.telemetry != other.telemetry || self.telemetry_url != other.telemetry_url {
+            changes.push("telemetry");
+        }
+        if self.community != other.community {
+            changes.push("community");
+        }
+        if self.admin_token != other.admin_token {
+            changes.push("admin_token");
+        }
+        changes
+    }
+
+    pub fn abuse_limits(&self) -> AbuseLimits {
+        AbuseLimits {
+            max_requests: self.abuse_max_requests,
+            max_error_ratio: self.abuse_max_error_ratio,
+            min_errors: self.abuse_min_errors,
+            ban_s: self.abuse_ban_s,
+        }
+    }
+
+    pub fn registration_policy(&self) -> RegistrationPolicy {
+        RegistrationPolicy {
+            mode: self.registration,
+            email_domains: self
+                .registration_email_domain
+                .iter()
+                .map(|d| d.trim_start_matches('@').to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn session_rotation(&self) -> SessionRotation {
+        SessionRotation {
+            after_s: self.session_rotate_after_s,
+            grace_s: self.session_rotation_grace_s,
+        }
+    }
+
+    fn challenge(&self) -> Option<Arc<dyn Challenge>> {
+        challenge::build(
+            self.challenge,
+            &self.challenge_site_key,
+            &self.challenge_secret,
+            self.pow_difficulty,
+        )
+    }
+
+    fn nutrition(&self) -> Option<Arc<dyn NutritionSource>> {
+        nutrition::build(self.nutrition, &self.nutrition_url)
+    }
+
+    fn ip_policy(&self) -> IpPolicy {
+        IpPolicy::new(
+            self.trusted_proxy.clone(),
+            self.allow_ip.clone(),
+            self.deny_ip.clone(),
+        )
+    }
+}
+
+// Why writes are turned away, any of them is enough
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ReadOnly {
+    // set through PUT /admin/read_only
+    pub switch: bool,
+    // the `read_only` setting
+    pub config: bool,
+    // the database's schema was out of range at startup
+    pub schema_mismatch: bool,
+}
+
+impl ReadOnly {
+    pub fn is_enabled(&self) -> bool {
+        self.switch || self.config || self.schema_mismatch
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigReport {
+    pub config: Config,
+    // settings changed by the last reload
+    pub last_changes: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+struct State {
+    config: Config,
+    ip_policy: Arc<IpPolicy>,
+    challenge: Option<Arc<dyn Challenge>>,
+    nutrition: Option<Arc<dyn NutritionSource>>,
+    last_changes: Vec<&'static str>,
+}
+
+// The running configuration: the command line values, overridden by the
+// config file if any. `reload` reads the file again and applies what changed.
+#[derive(Debug)]
+pub struct LiveConfig {
+    base: Config,
+    path: Option<PathBuf>,
+    state: RwLock<State>,
+    // set through the admin API rather than the file, reloads leave it be
+    maintenance: RwLock<Maintenance>,
+    read_only: AtomicBool,
+    // set at startup when the database's schema is out of range
+    schema_mismatch: AtomicBool,
+}
+
+impl LiveConfig {
+    pub fn new(base: Config) -> Self {
+        LiveConfig {
+            path: None,
+            state: RwLock::new(State {
+                ip_policy: Arc::new(base.ip_policy()),
+                challenge: base.challenge(),
+                nutrition: base.nutrition(),
+                config: base.clone(),
+                last_changes: vec![],
+            }),
+            base,
+            maintenance: RwLock::new(Maintenance::default()),
+            read_only: AtomicBool::new(false),
+            schema_mismatch: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_file(base: Config, path: &Path) -> error::Result<Self> {
+        let mut live = LiveConfig::new(base);
+        live.path = Some(path.to_owned());
+        live.reload()?;
+        Ok(live)
+    }
+
+    pub fn ip_policy(&self) -> Arc<IpPolicy> {
+        self.state.read().unwrap().ip_policy.clone()
+    }
+
+    pub fn abuse_limits(&self) -> AbuseLimits {
+        self.state.read().unwrap().config.abuse_limits()
+    }
+
+    pub fn schedule(&self) -> BTreeMap<String, Cron> {
+        self.state.read().unwrap().config.schedule.clone()
+    }
+
+    pub fn assistant_redirect_uri(&self) -> Vec<String> {
+        self.state
+            .read()
+            .unwrap()
+            .config
+            .assistant_redirect_uri
+            .clone()
+    }
+
+    pub fn registration_policy(&self) -> RegistrationPolicy {
+        self.state.read().unwrap().config.registration_policy()
+    }
+
+    pub fn session_rotation(&self) -> SessionRotation {
+        self.state.read().unwrap().config.session_rotation()
+    }
+
+    pub fn default_aisles(&self) -> Vec<String> {
+        self.state.read().unwrap().config.default_aisles.clone()
+    }
+
+    // None when sign ups take no challenge
+    pub fn challenge(&self) -> Option<Arc<dyn Challenge>> {
+        self.state.read().unwrap().challenge.clone()
+    }
+
+    // None when nutrition lookups are off
+    pub fn nutrition(&self) -> Option<Arc<dyn NutritionSource>> {
+        self.state.read().unwrap().nutrition.clone()
+    }
+
+    // None unless telemetry is opted in, else the URL to post the counts to,
+    // empty for none
+    pub fn telemetry(&self) -> Option<String> {
+        let state = self.state.read().unwrap();
+        Some(state.config.telemetry_url.clone()).filter(|_| state.config.telemetry)
+    }
+
+    pub fn community(&self) -> bool {
+        self.state.read().unwrap().config.community
+    }
+
+    // Whether an Authorization header carries the admin token, never when
+    // none is set
+    pub fn is_admin(&self, authorization: Option<&str>) -> bool {
+        let state = self.state.read().unwrap();
+        let token = &state.config.admin_token;
+        !token.is_empty()
+            && authorization
+                .and_then(|a| a.strip_prefix("Bearer "))
+                .map_or(false, |given| constant_time_eq(token, given.trim()))
+    }
+
+    // None out of maintenance
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        Some(self.maintenance.read().unwrap().clone()).filter(|m| m.enabled)
+    }
+
+    pub fn set_maintenance(&self, maintenance: Maintenance) {
+        *self.maintenance.write().unwrap() = maintenance;
+    }
+
+    pub fn read_only(&self) -> ReadOnly {
+        ReadOnly {
+            switch: self.read_only.load(Ordering::Relaxed),
+            config: self.state.read().unwrap().config.read_only,
+            schema_mismatch: self.schema_mismatch.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only().is_enabled()
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    // for good, the database won't change layout under this instance
+    pub fn set_schema_mismatch(&self) {
+        self.schema_mismatch.store(true, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> ConfigReport {
+        let state = self.state.read().unwrap();
+        ConfigReport {
+            config: state.config.clone(),
+            last_changes: state.last_changes.clone(),
+        }
+    }
+
+    // An invalid file is reported and leaves the running configuration untouched
+    pub fn reload(&self) -> error::Result<Vec<&'static str>> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(vec![]),
+        };
+        let invalid = |e: String| {
+            ServerError::new(
+                error::INTERNAL_ERROR,
+                &format!("Invalid config {}: {}", path.display(), e),
+            )
+        };
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let file: ConfigFile =
+            serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+        let config = self.base.overlay(file);
+        Ok(self.apply(config))
+    }
+
+    fn apply(&self, config: Config) -> Vec<&'static str> {
+        let mut state = self.state.write().unwrap();
+        let changes = state.config.changes(&config);
+        if changes.contains(&"log") {
+            redact::set_log_filters(&config.log);
+        }
+        state.ip_policy = Arc::new(config.ip_policy());
+        // the proof of work keeps the seeds solved so far
+        if changes.contains(&"challenge") {
+            state.challenge = config.challenge();
+        }
+        if changes.contains(&"nutrition") {
+            state.nutrition = config.nutrition();
+        }
+        state.config = config;
+        if !changes.is_empty() {
+            info!("Configuration reloaded, changed: {}", changes.join(", "));
+        }
+        state.last_changes = changes.clone();
+        changes
+    }
+
+    // Reload whenever the process gets a SIGHUP
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self: Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Cannot listen to SIGHUP, config reload disabled: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ChallengeInfo;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("efficio-{}-{}.json", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn reload_test() {
+        let base = Config {
+            log: "info".to_owned(),
+            deny_ip: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Config::default()
+        };
+        let path = write_config("reload", r#"{ "allow_ip": ["127.0.0.1"] }"#);
+        let live = LiveConfig::with_file(base, &path).unwrap();
+        // the file overrides the allow list, the deny list is kept
+        assert_eq!(vec!["allow_ip"], live.report().last_changes);
+        assert_eq!(false, live.ip_policy().is_allowed(&ip("203.0.113.9")));
+        assert_eq!(true, live.ip_policy().is_allowed(&ip("127.0.0.1")));
+
+        fs::write(&path, r#"{ "allow_ip": [], "deny_ip": [] }"#).unwrap();
+        assert_eq!(Ok(vec!["allow_ip", "deny_ip"]), live.reload());
+        assert_eq!(true, live.ip_policy().is_allowed(&ip("203.0.113.9")));
+        assert_eq!(Ok(vec![]), live.reload());
+        fs::write(&path, r#"{ "abuse_max_requests": 100, "abuse_ban_s": 60 }"#).unwrap();
+        assert_eq!(Ok(vec!["deny_ip", "abuse"]), live.reload());
+        assert_eq!(true, live.abuse_limits().is_enabled());
+        fs::write(&path, r#"{ "schedule": { "janitor": "@daily" } }"#).unwrap();
+        assert_eq!(Ok(vec!["abuse", "schedule"]), live.reload());
+        assert_eq!(vec!["janitor"], live.schedule().keys().collect::<Vec<_>>());
+        fs::write(
+            &path,
+            r#"{ "registration": "invite", "registration_email_domain": ["@Efficio.app"] }"#,
+        )
+        .unwrap();
+        assert_eq!(Ok(vec!["schedule", "registration"]), live.reload());
+        let policy = live.registration_policy();
+        assert_eq!(Registration::Invite, policy.mode);
+        assert_eq!(true, policy.allows_email("bob@EFFICIO.app"));
+        assert_eq!(false, policy.allows_email("bob@efficio.app.evil.com"));
+        assert_eq!(false, policy.allows_email("bob"));
+        assert_eq!(true, live.challenge().is_none());
+        fs::write(
+            &path,
+            r#"{ "challenge": "pow", "pow_difficulty": 4, "challenge_secret": "s3cr3t" }"#,
+        )
+        .unwrap();
+        assert_eq!(Ok(vec!["registration", "challenge"]), live.reload());
+        assert_eq!(
+            true,
+            match live.challenge().unwrap().issue() {
+                ChallengeInfo::Pow { difficulty, .. } => difficulty == 4,
+                _ => false,
+            }
+        );
+        // the secret stays out of reports
+        let report = serde_json::to_string(&live.report()).unwrap();
+        assert_eq!(false, report.contains("s3cr3t"));
+        assert_eq!(false, live.is_admin(Some("Bearer ")));
+        fs::write(&path, r#"{ "admin_token": "t0ken" }"#).unwrap();
+        assert_eq!(Ok(vec!["challenge", "admin_token"]), live.reload());
+        assert_eq!(true, live.is_admin(Some("Bearer t0ken")));
+        assert_eq!(false, live.is_admin(Some("Bearer t0ke")));
+        assert_eq!(false, live.is_admin(Some("t0ken")));
+        assert_eq!(false, live.is_admin(None));
+        let report = serde_json::to_string(&live.report()).unwrap();
+        assert_eq!(false, report.contains("t0ken"));
+        fs::write(&path, r#"{ "default_aisles": ["Produce", "Dairy"] }"#).unwrap();
+        assert_eq!(Ok(vec!["admin_token", "default_aisles"]), live.reload());
+        assert_eq!(vec!["Produce", "Dairy"], live.default_aisles());
+        fs::write(&path, r#"{ "read_only": true }"#).unwrap();
+        assert_eq!(Ok(vec!["default_aisles", "read_only"]), live.reload());
+        assert_eq!(true, live.is_read_only());
+        // the admin switch can't lift the setting
+        live.set_read_only(false);
+        assert_eq!(true, live.is_read_only());
+
+        // a broken file keeps the running configuration
+        fs::write(&path, r#"{ "allow_ip": ["not an ip"] }"#).unwrap();
+        assert_eq!(true, live.reload().is_err());
+        assert_eq!(true, live.ip_policy().is_allowed(&ip("203.0.113.9")));
+        fs::write(&path, r#"{ "rate_limit": 5 }"#).unwrap();
+        assert_eq!(true, live.reload().is_err());
+        fs::write(&path, r#"{ "schedule": { "janitor": "daily" } }"#).unwrap();
+        assert_eq!(true, live.reload().is_err());
+        fs::write(&path, r#"{ "registration": "sometimes" }"#).unwrap();
+        assert_eq!(true, live.reload().is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+// Caps the number of open connections, long-lived ones included. Without a
+// maximum it only counts them.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    open: Arc<AtomicUsize>,
+}
+
+// Held for the lifetime of a connection, frees its place when dropped
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        ConnectionLimit {
+            max,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    // `None` when the limit is reached
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        let mut open = self.open.load(Ordering::Relaxed);
+        loop {
+            if self.max.map_or(false, |max| open >= max) {
+                return None;
+            }
+            match self.open.compare_exchange_weak(
+                open,
+                open + 1,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(ConnectionSlot(self.open.clone())),
+                Err(current) => open = current,
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_limit_test() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.acquire();
+        let second = limit.acquire();
+        assert_eq!(true, first.is_some() && second.is_some());
+        assert_eq!(true, limit.acquire().is_none());
+        assert_eq!(2, limit.open());
+
+        drop(first);
+        assert_eq!(1, limit.open());
+        assert_eq!(true, limit.acquire().is_some());
+
+        let unlimited = ConnectionLimit::new(None);
+        let slots: Vec<_> = (0..100).filter_map(|_| unlimited.acquire()).collect();
+        assert_eq!(100, slots.len());
+        assert_eq!(100, unlimited.open());
+    }
+}
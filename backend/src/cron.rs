@@ -0,0 +1,232 @@
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const DAY_S: u64 = 24 * 3600;
+// an expression that matches nothing in that many days never will
+const MAX_SEARCH_DAYS: u64 = 5 * 366;
+
+// Crontab schedule: `minute hour day-of-month month day-of-week`, in UTC.
+// Fields take `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and comma
+// separated lists of those. Sunday is 0 or 7. The `@hourly`, `@daily`,
+// `@weekly`, `@monthly` and `@yearly` shorthands are understood too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // like cron, when both days are restricted either one is enough
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// Bit mask of the values a field allows
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid field {}", field);
+    let mut mask = 0;
+    for part in field.split(',') {
+        let mut parts = part.splitn(2, '/');
+        let range = parts.next().unwrap_or_default();
+        let step = match parts.next() {
+            Some(step) => step
+                .parse::<u64>()
+                .ok()
+                .filter(|s| *s > 0)
+                .ok_or_else(invalid)?,
+            None => 1,
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let start = bounds
+                .next()
+                .unwrap_or_default()
+                .parse::<u64>()
+                .map_err(|_| invalid())?;
+            let end = match bounds.next() {
+                Some(end) => end.parse::<u64>().map_err(|_| invalid())?,
+                // `5/15` runs from 5 to the end
+                None if step > 1 => max,
+                None => start,
+            };
+            (start, end)
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn has(mask: u64, value: u64) -> bool {
+    mask & (1 << value) != 0
+}
+
+// Year, month and day of a number of days since 1970-01-01
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("{}: expected 5 fields", s));
+        }
+        let field = |i: usize, min, max| {
+            parse_field(fields[i], min, max).map_err(|e| format!("{}: {}", s, e))
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            expr: s.trim().to_owned(),
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+impl Serialize for Cron {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cron {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        let day_ok = has(self.days, day);
+        let weekday_ok = has(self.weekdays, weekday);
+        has(self.months, month)
+            && match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => weekday_ok,
+                (false, true) => day_ok,
+                (false, false) => day_ok || weekday_ok,
+            }
+    }
+
+    // First matching time strictly after `after`, both in seconds since the
+    // epoch. None when nothing ever matches, like the 30th of February.
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let mut t = (after / 60 + 1) * 60;
+        let give_up = t + MAX_SEARCH_DAYS * DAY_S;
+        while t < give_up {
+            let days = t / DAY_S;
+            if !self.matches_day(days) {
+                t = (days + 1) * DAY_S;
+            } else if !has(self.hours, t % DAY_S / 3600) {
+                t = (t / 3600 + 1) * 3600;
+            } else if !has(self.minutes, t % 3600 / 60) {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-06-15 was a Monday
+    const MONDAY_NOON: u64 = 1_592_222_400;
+
+    fn next(expr: &str, after: u64) -> Option<u64> {
+        expr.parse::<Cron>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!((2020, 6, 15), civil_from_days(MONDAY_NOON / DAY_S));
+        assert_eq!((2000, 2, 29), civil_from_days(11_016));
+        assert_eq!(true, "*/15 0-6,22 * * 1-5".parse::<Cron>().is_ok());
+        assert_eq!(true, "@daily".parse::<Cron>().is_ok());
+        assert_eq!(true, "* * * *".parse::<Cron>().is_err());
+        assert_eq!(true, "60 * * * *".parse::<Cron>().is_err());
+        assert_eq!(true, "* * 0 * *".parse::<Cron>().is_err());
+        assert_eq!(true, "*/0 * * * *".parse::<Cron>().is_err());
+        assert_eq!(true, "5-1 * * * *".parse::<Cron>().is_err());
+        let cron: Cron = serde_json::from_str(r#""0 4 * * *""#).unwrap();
+        assert_eq!(r#""0 4 * * *""#, serde_json::to_string(&cron).unwrap());
+    }
+
+    #[test]
+    fn next_after_test() {
+        assert_eq!(Some(MONDAY_NOON + 60), next("* * * * *", MONDAY_NOON));
+        assert_eq!(Some(MONDAY_NOON + 60), next("* * * * *", MONDAY_NOON + 59));
+        assert_eq!(
+            Some(MONDAY_NOON + 15 * 60),
+            next("*/15 * * * *", MONDAY_NOON)
+        );
+        assert_eq!(Some(MONDAY_NOON + 3600), next("@hourly", MONDAY_NOON));
+        // 04:30 the next day
+        assert_eq!(
+            Some(MONDAY_NOON + 16 * 3600 + 30 * 60),
+            next("30 4 * * *", MONDAY_NOON)
+        );
+        // next Sunday, 7 is Sunday too
+        let sunday = MONDAY_NOON + 6 * DAY_S - 12 * 3600;
+        assert_eq!(Some(sunday), next("@weekly", MONDAY_NOON));
+        assert_eq!(Some(sunday), next("0 0 * * 7", MONDAY_NOON));
+        // the 1st, or any Wednesday, whichever comes first
+        let wednesday = MONDAY_NOON + 2 * DAY_S - 12 * 3600;
+        assert_eq!(Some(wednesday), next("0 0 1 * 3", MONDAY_NOON));
+        assert_eq!(
+            Some(MONDAY_NOON + 16 * DAY_S - 12 * 3600),
+            next("0 0 1 7 *", MONDAY_NOON)
+        );
+        assert_eq!(None, next("0 0 30 2 *", MONDAY_NOON));
+    }
+}
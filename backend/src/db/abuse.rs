@@ -0,0 +1,329 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{transaction, Commands};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection};
+
+use serde::Serialize;
+
+use crate::error::Result;
+
+const BANS_LIST: &str = "bans";
+// requests and errors are counted over fixed windows of that many seconds
+const WINDOW_S: usize = 60;
+
+// When to ban a client, a zero disables the corresponding check
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AbuseLimits {
+    // requests per window
+    pub max_requests: u64,
+    // failed requests over all requests in a window
+    pub max_error_ratio: f64,
+    // failed requests in a window before the ratio is looked at
+    pub min_errors: u64,
+    pub ban_s: u64,
+}
+
+impl AbuseLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.ban_s > 0 && (self.max_requests > 0 || self.max_error_ratio > 0.0)
+    }
+
+    // requests are counted for the rate limit headers even without bans
+    pub fn is_counted(&self) -> bool {
+        self.max_requests > 0 || self.is_enabled()
+    }
+}
+
+// Where a subject stands against `max_requests` in the current window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    // seconds until the window ends
+    pub reset_s: u64,
+}
+
+// A client turned away until the ban expires, `subject` is `ip:<address>` or
+// `user:<id>`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ban {
+    pub subject: String,
+    pub reason: String,
+    pub expires_in_s: u64,
+}
+
+fn requests_key(subject: &str) -> String {
+    format!("abuse:requests:{}", subject)
+}
+
+fn errors_key(subject: &str) -> String {
+    format!("abuse:errors:{}", subject)
+}
+
+fn ban_key(subject: &str) -> String {
+    format!("ban:{}", subject)
+}
+
+pub fn get_ban(c: &mut Connection, subject: &str) -> Result<Option<Ban>> {
+    let key = ban_key(subject);
+    let reason: Option<String> = c.get(&key)?;
+    match reason {
+        Some(reason) => {
+            let ttl: i64 = c.ttl(&key)?;
+            Ok(Some(Ban {
+                subject: subject.to_owned(),
+                reason,
+                expires_in_s: ttl.max(0) as u64,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+// Count a request of `subject` and ban it if that crosses a limit, when bans
+// are on
+pub fn record_request(
+    c: &mut Connection,
+    subject: &str,
+    is_error: bool,
+    limits: &AbuseLimits,
+) -> Result<Option<Ban>> {
+    let requests: u64 = c.incr(&requests_key(subject), 1)?;
+    if requests == 1 {
+        // new window, errors of the previous one are forgotten
+        c.expire(&requests_key(subject), WINDOW_S)?;
+        c.del(&errors_key(subject))?;
+    }
+    let errors: u64 = if is_error {
+        let errors: u64 = c.incr(&errors_key(subject), 1)?;
+        if errors == 1 {
+            c.expire(&errors_key(subject), WINDOW_S)?;
+        }
+        errors
+    } else {
+        let errors: Option<u64> = c.get(&errors_key(subject))?;
+        errors.unwrap_or(0)
+    };
+
+    let reason = if limits.max_requests > 0 && requests > limits.max_requests {
+        Some(format!(
+            "More than {} requests in {} seconds",
+            limits.max_requests, WINDOW_S
+        ))
+    } else if limits.max_error_ratio > 0.0
+        && errors >= limits.min_errors.max(1)
+        && errors as f64 / requests as f64 > limits.max_error_ratio
+    {
+        Some(format!("{} failed requests out of {}", errors, requests))
+    } else {
+        None
+    };
+    match reason {
+        Some(reason) if limits.is_enabled() => ban(c, subject, &reason, limits.ban_s).map(Some),
+        _ => Ok(None),
+    }
+}
+
+pub fn rate_limit_status(
+    c: &mut Connection,
+    subject: &str,
+    max_requests: u64,
+) -> Result<RateLimit> {
+    let key = requests_key(subject);
+    let requests: Option<u64> = c.get(&key)?;
+    let ttl: i64 = c.ttl(&key)?;
+    Ok(RateLimit {
+        limit: max_requests,
+        remaining: max_requests.saturating_sub(requests.unwrap_or(0)),
+        reset_s: if ttl < 0 { WINDOW_S as u64 } else { ttl as u64 },
+    })
+}
+
+pub fn ban(c: &mut Connection, subject: &str, reason: &str, ban_s: u64) -> Result<Ban> {
+    let key = ban_key(subject);
+    transaction(c, &[&key, BANS_LIST], |c, pipe| {
+        pipe.set(&key, reason)
+            .ignore()
+            .expire(&key, ban_s as usize)
+            .ignore()
+            .sadd(BANS_LIST, subject)
+            .ignore()
+            .del(&requests_key(subject))
+            .ignore()
+            .del(&errors_key(subject))
+            .ignore()
+            .query(c)
+    })?;
+    log::warn!("Banned {} for {}s: {}", subject, ban_s, reason);
+    Ok(Ban {
+        subject: subject.to_owned(),
+        reason: reason.to_owned(),
+        expires_in_s: ban_s,
+    })
+}
+
+// Bans still running, the expired ones are dropped from the list on the way
+pub fn list_bans(c: &mut Connection) -> Result<Vec<Ban>> {
+    let subjects: Vec<String> = c.smembers(BANS_LIST)?;
+    let mut bans = vec![];
+    for subject in subjects {
+        match get_ban(c, &subject)? {
+            Some(ban) => bans.push(ban),
+            None => c.srem(BANS_LIST, &subject)?,
+        }
+    }
+    bans.sort_by(|a, b| a.subject.cmp(&b.subject));
+    Ok(bans)
+}
+
+fn limit_key(bucket: &str, subject: &str) -> String {
+    format!("limit:{}:{}", bucket, subject)
+}
+
+// Count a call of `subject` to an endpoint allowing `max` per window of
+// `window_s`, the seconds until the window ends if that is one too many
+pub fn rate_limit(
+    c: &mut Connection,
+    bucket: &str,
+    subject: &str,
+    max: u64,
+    window_s: u64,
+) -> Result<Option<u64>> {
+    let key = limit_key(bucket, subject);
+    let calls: u64 = c.incr(&key, 1)?;
+    if calls == 1 {
+        c.expire(&key, window_s as usize)?;
+    }
+    if calls > max {
+        let ttl: i64 = c.ttl(&key)?;
+        Ok(Some(ttl.max(1) as u64))
+    } else {
+        Ok(None)
+    }
+}
+
+// Whether there was a ban to lift
+pub fn revoke_ban(c: &mut Connection, subject: &str) -> Result<bool> {
+    let lifted: bool = c.del(&ban_key(subject))?;
+    c.srem(BANS_LIST, subject)?;
+    if lifted {
+        log::info!("Ban of {} revoked", subject);
+    }
+    Ok(lifted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+    use std::time::Duration;
+
+    const SUBJECT: &str = "ip:203.0.113.9";
+
+    #[test]
+    fn request_rate_ban_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let limits = AbuseLimits {
+            max_requests: 3,
+            ban_s: 600,
+            ..AbuseLimits::default()
+        };
+        for _ in 0..3 {
+            assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        }
+        // a new window starts from scratch
+        c.advance_time(Duration::from_secs(WINDOW_S as u64));
+        for _ in 0..3 {
+            assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        }
+        let ban = record_request(&mut c, SUBJECT, false, &limits)
+            .unwrap()
+            .unwrap();
+        assert_eq!(600, ban.expires_in_s);
+        assert_eq!(Ok(Some(ban.clone())), get_ban(&mut c, SUBJECT));
+        assert_eq!(Ok(vec![ban]), list_bans(&mut c));
+
+        c.advance_time(Duration::from_secs(600));
+        assert_eq!(Ok(None), get_ban(&mut c, SUBJECT));
+        assert_eq!(Ok(vec![]), list_bans(&mut c));
+        assert_eq!(Ok(false), c.sismember(BANS_LIST, SUBJECT));
+    }
+
+    #[test]
+    fn rate_limit_status_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let limits = AbuseLimits {
+            max_requests: 3,
+            ban_s: 600,
+            ..AbuseLimits::default()
+        };
+        let fresh = RateLimit {
+            limit: 3,
+            remaining: 3,
+            reset_s: WINDOW_S as u64,
+        };
+        assert_eq!(Ok(fresh), rate_limit_status(&mut c, SUBJECT, 3));
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        c.advance_time(Duration::from_secs(20));
+        assert_eq!(
+            Ok(RateLimit {
+                limit: 3,
+                remaining: 1,
+                reset_s: 40,
+            }),
+            rate_limit_status(&mut c, SUBJECT, 3)
+        );
+        c.advance_time(Duration::from_secs(40));
+        assert_eq!(Ok(fresh), rate_limit_status(&mut c, SUBJECT, 3));
+    }
+
+    #[test]
+    fn error_ratio_ban_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let limits = AbuseLimits {
+            max_error_ratio: 0.5,
+            min_errors: 3,
+            ban_s: 60,
+            ..AbuseLimits::default()
+        };
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, true, &limits));
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, true, &limits));
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, false, &limits));
+        // 3 errors out of 5 requests
+        let ban = record_request(&mut c, SUBJECT, true, &limits).unwrap();
+        assert_eq!(
+            Some("3 failed requests out of 5".to_owned()),
+            ban.map(|ban| ban.reason)
+        );
+
+        assert_eq!(Ok(true), revoke_ban(&mut c, SUBJECT));
+        assert_eq!(Ok(None), get_ban(&mut c, SUBJECT));
+        assert_eq!(Ok(false), revoke_ban(&mut c, SUBJECT));
+        // counting starts over after a ban
+        assert_eq!(Ok(None), record_request(&mut c, SUBJECT, true, &limits));
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        assert_eq!(Ok(None), rate_limit(&mut c, "check", SUBJECT, 2, 60));
+        assert_eq!(Ok(None), rate_limit(&mut c, "check", SUBJECT, 2, 60));
+        // buckets and subjects are counted apart
+        assert_eq!(Ok(None), rate_limit(&mut c, "other", SUBJECT, 2, 60));
+        assert_eq!(Ok(None), rate_limit(&mut c, "check", "ip:::1", 2, 60));
+        c.advance_time(Duration::from_secs(20));
+        assert_eq!(Ok(Some(40)), rate_limit(&mut c, "check", SUBJECT, 2, 60));
+        c.advance_time(Duration::from_secs(40));
+        assert_eq!(Ok(None), rate_limit(&mut c, "check", SUBJECT, 2, 60));
+    }
+}
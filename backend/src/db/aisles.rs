@@ -1,10 +1,19 @@
 #[cfg(not(test))]
-use redis::{self, transaction, Commands, Connection, Pipeline};
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands, Pipeline};
 
 #[cfg(test)]
 use fake_redis::{transaction, FakeConnection as Connection, FakePipeline as Pipeline};
 
-use crate::{db, error::Result, types::*};
+use crate::{
+    db::{
+        self,
+        journal::{Change, Entity},
+    },
+    error::Result,
+    types::*,
+};
 
 const AISLE_NAME: &str = "name";
 const AISLE_WEIGHT: &str = "sort_weight";
@@ -19,8 +28,45 @@ fn aisles_in_store_key(id: &StoreId) -> String {
     format!("aisles_in_store:{}", **id)
 }
 
+// Not found if there is no such aisle
 pub fn get_aisle_owner(c: &mut Connection, aisle_id: &AisleId) -> Result<UserId> {
-    Ok(UserId(c.hget(&aisle_key(&aisle_id), AISLE_OWNER)?))
+    let owner_id: Option<String> = c.hget(&aisle_key(&aisle_id), AISLE_OWNER)?;
+    owner_id.map(UserId).ok_or_else(db::not_found)
+}
+
+pub fn get_aisle_name(c: &mut Connection, aisle_id: &AisleId) -> Result<String> {
+    Ok(c.hget(&aisle_key(&aisle_id), AISLE_NAME)?)
+}
+
+pub fn get_aisle_store(c: &mut Connection, aisle_id: &AisleId) -> Result<StoreId> {
+    find_aisle_store(c, &aisle_id)?.ok_or_else(db::not_found)
+}
+
+// None if there is no such aisle
+pub fn find_aisle_store(c: &mut Connection, aisle_id: &AisleId) -> Result<Option<StoreId>> {
+    let store_id: Option<String> = c.hget(&aisle_key(&aisle_id), AISLE_STORE)?;
+    Ok(store_id.map(StoreId::new))
+}
+
+pub fn is_aisle_in_store(
+    c: &mut Connection,
+    aisle_id: &AisleId,
+    store_id: &StoreId,
+) -> Result<bool> {
+    Ok(c.sismember(&aisles_in_store_key(&store_id), &**aisle_id)?)
+}
+
+pub fn get_aisle_change(c: &mut Connection, aisle_id: &AisleId) -> Result<Option<AisleChange>> {
+    let aisle_key = aisle_key(&aisle_id);
+    if c.exists(&aisle_key)? {
+        Ok(Some(AisleChange::new(
+            aisle_id.to_string(),
+            c.hget(&aisle_key, AISLE_NAME)?,
+            c.hget(&aisle_key, AISLE_WEIGHT)?,
+        )))
+    } else {
+        Ok(None)
+    }
 }
 
 pub fn get_aisles_in_store(c: &mut Connection, store_id: &StoreId) -> Result<Vec<Aisle>> {
@@ -50,20 +96,44 @@ pub fn save_aisle(
     auth: &Auth,
     store_id: &StoreId,
     name: &str,
+) -> Result<Aisle> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        insert_aisle(c, &user_id, &store_id, name)
+    })
+}
+
+// to be called with the store lock held
+pub(crate) fn insert_aisle(
+    c: &mut Connection,
+    user_id: &UserId,
+    store_id: &StoreId,
+    name: &str,
 ) -> Result<Aisle> {
     let aisle_id = db::ids::get_next_aisle_id();
     let aisle_key = aisle_key(&aisle_id);
     let aisle_in_store_key = aisles_in_store_key(&store_id);
-    let user_id = db::sessions::get_user_id(c, &auth)?;
     let store_owner = db::stores::get_store_owner(c, &store_id)?;
-    db::verify_permission(&user_id, &store_owner)?;
+    db::assert_owner_or_member(&user_id, &store_owner)?;
+    let size = db::quotas::entity_size(name);
+    db::quotas::check_aisles(c, &aisle_in_store_key)?;
+    db::quotas::check_bytes(c, &user_id, size)?;
     let new_sort_weight = find_max_weight_in_store(c, &store_id)? + 1f32;
     transaction(c, &[&aisle_key, &aisle_in_store_key], |c, pipe| {
+        db::journal::record(
+            c,
+            pipe,
+            &store_id,
+            Entity::Aisle,
+            Change::Updated,
+            &aisle_id,
+        )?;
+        db::quotas::transaction_charge(c, pipe, &user_id, size)?;
         pipe.hset(&aisle_key, AISLE_NAME, name)
             .ignore()
             .hset(&aisle_key, AISLE_WEIGHT, new_sort_weight)
             .ignore()
-            .hset(&aisle_key, AISLE_OWNER, &*user_id)
+            .hset(&aisle_key, AISLE_OWNER, &**user_id)
             .ignore()
             .hset(&aisle_key, AISLE_STORE, &**store_id)
             .ignore()
@@ -86,38 +156,164 @@ pub fn edit_aisle(
     new_name: &str,
 ) -> Result<()> {
     let aisle_key = aisle_key(&aisle_id);
-    let aisle_owner = get_aisle_owner(c, &aisle_id)?;
-    db::verify_permission_auth(c, &auth, &aisle_owner)?;
-    Ok(c.hset(&aisle_key, AISLE_NAME, new_name)?)
+    let store_id = get_aisle_store(c, &aisle_id)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let aisle_owner = get_aisle_owner(c, &aisle_id)?;
+        db::assert_owner_or_member(&user_id, &aisle_owner)?;
+        let old_name: String = c.hget(&aisle_key, AISLE_NAME)?;
+        let growth = new_name.len() as i64 - old_name.len() as i64;
+        db::quotas::check_bytes(c, &aisle_owner, growth)?;
+        transaction(c, &[&aisle_key], |c, pipe| {
+            db::journal::record(
+                c,
+                pipe,
+                &store_id,
+                Entity::Aisle,
+                Change::Updated,
+                &aisle_id,
+            )?;
+            db::quotas::transaction_charge(c, pipe, &aisle_owner, growth)?;
+            pipe.hset(&aisle_key, AISLE_NAME, new_name).query(c)
+        })?;
+        Ok(())
+    })
 }
 
 pub fn delete_aisle(c: &mut Connection, auth: &Auth, aisle_id: &AisleId) -> Result<()> {
     let aisle_key = aisle_key(&aisle_id);
-    let aisle_owner = get_aisle_owner(c, &aisle_id)?;
-    db::verify_permission_auth(c, &auth, &aisle_owner)?;
-    let store_id = StoreId::new(c.hget(&aisle_key, AISLE_STORE)?);
+    let store_id = get_aisle_store(c, &aisle_id)?;
     let aisle_in_store_key = aisles_in_store_key(&store_id);
-    transaction(c, &[&aisle_key, &aisle_in_store_key], |c, mut pipe| {
-        db::products::transaction_purge_products_in_aisle(c, &mut pipe, &aisle_id)?;
-        pipe.srem(&aisle_in_store_key, &**aisle_id)
-            .ignore()
-            .del(&aisle_key)
-            .query(c)
-    })?;
-    Ok(())
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let aisle_owner = get_aisle_owner(c, &aisle_id)?;
+        db::assert_owner_or_member(&user_id, &aisle_owner)?;
+        transaction(c, &[&aisle_key, &aisle_in_store_key], |c, mut pipe| {
+            db::journal::record(
+                c,
+                pipe,
+                &store_id,
+                Entity::Aisle,
+                Change::Deleted,
+                &aisle_id,
+            )?;
+            let name: Option<String> = c.hget(&aisle_key, AISLE_NAME)?;
+            let freed = db::quotas::entity_size(&name.unwrap_or_default())
+                + db::products::transaction_purge_products_in_aisle(c, &mut pipe, &aisle_id)?;
+            db::quotas::transaction_charge(c, &mut pipe, &aisle_owner, -freed)?;
+            let (items, items_left) = db::products::count_items_in_aisle(c, &aisle_id)?;
+            db::stores::transaction_count_items(
+                c,
+                &mut pipe,
+                &store_id,
+                -(items as i64),
+                -(items_left as i64),
+            )?;
+            pipe.srem(&aisle_in_store_key, &**aisle_id)
+                .ignore()
+                .del(&aisle_key)
+                .query(c)
+        })?;
+        Ok(())
+    })
+}
+
+// the products in the store and the ones not done, by going through them
+pub fn count_items_in_store(c: &mut Connection, store_id: &StoreId) -> Result<(u64, u64)> {
+    let aisles: Vec<String> = c.smembers(&aisles_in_store_key(&store_id))?;
+    let mut counts = (0, 0);
+    for aisle_id in aisles {
+        let (items, items_left) = db::products::count_items_in_aisle(c, &AisleId(aisle_id))?;
+        counts = (counts.0 + items, counts.1 + items_left);
+    }
+    Ok(counts)
 }
 
+pub fn get_aisle_stats(c: &mut Connection, store_id: &StoreId) -> Result<Vec<AisleStats>> {
+    let aisles: Vec<String> = c.smembers(&aisles_in_store_key(&store_id))?;
+    aisles
+        .into_iter()
+        .map(|id| {
+            let aisle_id = AisleId(id.clone());
+            let (items, items_left) = db::products::count_items_in_aisle(c, &aisle_id)?;
+            let name: String = c.hget(&aisle_key(&aisle_id), AISLE_NAME)?;
+            Ok(AisleStats::new(id, name, items, items_left))
+        })
+        .collect()
+}
+
+// `db::quotas::check_more_aisles` for moving aisles into this store
+pub fn check_more_aisles(c: &mut Connection, store_id: &StoreId, adding: u64) -> Result<()> {
+    db::quotas::check_more_aisles(c, &aisles_in_store_key(&store_id), adding)
+}
+
+// to be used only in a transaction, doesn't execute the `pipe`
+pub fn transaction_move_aisle(
+    pipe: &mut Pipeline,
+    aisle_id: &AisleId,
+    from: &StoreId,
+    to: &StoreId,
+    sort_weight: f32,
+) {
+    let aisle_key = aisle_key(&aisle_id);
+    pipe.srem(&aisles_in_store_key(&from), &**aisle_id)
+        .ignore()
+        .sadd(&aisles_in_store_key(&to), &**aisle_id)
+        .ignore()
+        .hset(&aisle_key, AISLE_STORE, &**to)
+        .ignore()
+        .hset(&aisle_key, AISLE_WEIGHT, sort_weight)
+        .ignore();
+}
+
+// Delete an aisle whose products are all gone elsewhere, to be used only in
+// a transaction, doesn't execute the `pipe`
+pub fn transaction_drop_emptied_aisle(pipe: &mut Pipeline, aisle_id: &AisleId, store_id: &StoreId) {
+    pipe.srem(&aisles_in_store_key(&store_id), &**aisle_id)
+        .ignore()
+        .del(&aisle_key(&aisle_id))
+        .ignore()
+        .del(&db::products::products_in_aisle_key(&aisle_id))
+        .ignore();
+}
+
+// Write the aisle as it was, without its products, see `db::snapshots`. To
+// be used only in a transaction, doesn't execute the `pipe`
+pub(crate) fn transaction_restore_aisle(
+    pipe: &mut Pipeline,
+    owner_id: &UserId,
+    store_id: &StoreId,
+    aisle_id: &AisleId,
+    aisle: &Aisle,
+) {
+    let aisle_key = aisle_key(&aisle_id);
+    pipe.hset(&aisle_key, AISLE_NAME, &aisle.name)
+        .ignore()
+        .hset(&aisle_key, AISLE_WEIGHT, aisle.sort_weight)
+        .ignore()
+        .hset(&aisle_key, AISLE_OWNER, &**owner_id)
+        .ignore()
+        .hset(&aisle_key, AISLE_STORE, &**store_id)
+        .ignore()
+        .sadd(&aisles_in_store_key(&store_id), &**aisle_id)
+        .ignore();
+}
+
+// returns the bytes freed, see `db::quotas`
 pub fn transaction_purge_aisles_in_store(
     c: &mut Connection,
     mut pipe: &mut Pipeline,
     store_id: &StoreId,
-) -> Result<()> {
+) -> Result<i64> {
     let aisles_in_store_key = aisles_in_store_key(&store_id);
     let aisles: Option<Vec<String>> = c.smembers(&aisles_in_store_key)?;
+    let mut freed = 0;
     if let Some(aisles) = aisles {
         for aisle_id in aisles {
             let aisle_id = AisleId(aisle_id);
-            db::products::transaction_purge_products_in_aisle(c, &mut pipe, &aisle_id)?;
+            let name: Option<String> = c.hget(&aisle_key(&aisle_id), AISLE_NAME)?;
+            freed += db::quotas::entity_size(&name.unwrap_or_default())
+                + db::products::transaction_purge_products_in_aisle(c, &mut pipe, &aisle_id)?;
             pipe.del(&aisle_key(&aisle_id))
                 .ignore()
                 .del(&db::products::products_in_aisle_key(&aisle_id))
@@ -125,22 +321,15 @@ pub fn transaction_purge_aisles_in_store(
         }
         pipe.del(&aisles_in_store_key).ignore();
     }
-    Ok(())
+    Ok(freed)
 }
 
-pub fn edit_aisle_sort_weight(
-    c: &mut Connection,
-    pipe: &mut Pipeline,
-    auth: &Auth,
-    data: &AisleItemWeight,
-) -> Result<()> {
-    let aisle_id = AisleId(data.id.clone());
-    let aisle_owner = get_aisle_owner(c, &aisle_id)?;
-    db::verify_permission_auth(c, &auth, &aisle_owner)?;
-    let aisle_key = aisle_key(&aisle_id);
+// to be called by `db::stores::edit_sort_weights`, which checked that the
+// aisle is in the store and journals the change
+pub(crate) fn edit_aisle_sort_weight(pipe: &mut Pipeline, data: &AisleItemWeight) {
+    let aisle_key = aisle_key(&AisleId(data.id.clone()));
     pipe.hset(&aisle_key, AISLE_WEIGHT, data.sort_weight)
         .ignore();
-    Ok(())
 }
 
 #[cfg(test)]
@@ -339,8 +528,12 @@ pub mod tests {
         let aisle_in_store_key = aisles_in_store_key(&store_id);
         let mut pipe = Pipeline::new(c.db);
         pipe.atomic();
+        let sizes = [NAME, RENAMED, "product1", "product2", "product3"]
+            .iter()
+            .map(|name| db::quotas::entity_size(name))
+            .sum();
         assert_eq!(
-            Ok(()),
+            Ok(sizes),
             transaction_purge_aisles_in_store(&mut c, &mut pipe, &store_id)
         );
         assert_eq!(Ok(()), pipe.query(&mut c));
@@ -368,14 +561,9 @@ pub mod tests {
         let (_, aisle_id) = save_aisle_for_test(&mut c);
         let mut pipe = Pipeline::new(c.db);
         pipe.atomic();
-        assert_eq!(
-            Ok(()),
-            edit_aisle_sort_weight(
-                &mut c,
-                &mut pipe,
-                &AUTH,
-                &AisleItemWeight::new(aisle_id.to_string(), 2.0f32)
-            )
+        edit_aisle_sort_weight(
+            &mut pipe,
+            &AisleItemWeight::new(aisle_id.to_string(), 2.0f32),
         );
         assert_eq!(Ok(()), pipe.query(&mut c));
         assert_eq!(Ok(2.0f32), c.hget(&aisle_key(&aisle_id), AISLE_WEIGHT));
@@ -0,0 +1,103 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{error::Result, types::*};
+
+// Names of products a user takes for the same one: with "tomatoes" an alias
+// of "tomato", both count as "tomato" in the stats and insights and when
+// merging stores. Kept by name so renaming or deleting products breaks
+// nothing.
+fn aliases_key(user_id: &UserId) -> String {
+    format!("aliases:{}", **user_id)
+}
+
+pub fn normalized(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// Alias to canonical name, both normalized
+pub fn get_aliases(c: &mut Connection, user_id: &UserId) -> Result<HashMap<String, String>> {
+    Ok(redis::cmd("HGETALL").arg(aliases_key(&user_id)).query(c)?)
+}
+
+pub fn resolve(aliases: &HashMap<String, String>, name: &str) -> String {
+    let name = normalized(name);
+    aliases.get(&name).cloned().unwrap_or(name)
+}
+
+// The canonical name of a product, normalized
+pub fn canonical(c: &mut Connection, user_id: &UserId, name: &str) -> Result<String> {
+    let name = normalized(name);
+    let canonical: Option<String> = c.hget(&aliases_key(&user_id), &name)?;
+    Ok(canonical.unwrap_or(name))
+}
+
+// Replace the whole table, checked by the caller
+pub fn set_aliases(
+    c: &mut Connection,
+    user_id: &UserId,
+    aliases: &BTreeMap<String, String>,
+) -> Result<()> {
+    let aliases_key = aliases_key(&user_id);
+    transaction(c, &[&aliases_key], |c, pipe| {
+        pipe.del(&aliases_key).ignore();
+        for (alias, canonical) in aliases {
+            pipe.hset(&aliases_key, &normalized(alias), normalized(canonical))
+                .ignore();
+        }
+        pipe.query(c)
+    })?;
+    Ok(())
+}
+
+pub fn delete_aliases(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&aliases_key(&user_id))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn aliases_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId("1".to_owned());
+        let aliases: BTreeMap<String, String> = vec![
+            (" Tomatoes".to_owned(), "tomato".to_owned()),
+            ("tomatos".to_owned(), "Tomato ".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(Ok(()), set_aliases(&mut c, &user_id, &aliases));
+        assert_eq!(
+            Ok("tomato".to_owned()),
+            canonical(&mut c, &user_id, "TOMATOS")
+        );
+        assert_eq!(Ok("salt".to_owned()), canonical(&mut c, &user_id, "Salt"));
+        let table = get_aliases(&mut c, &user_id).unwrap();
+        assert_eq!(2, table.len());
+        assert_eq!("tomato", resolve(&table, "tomatoes "));
+
+        // replaced as a whole
+        let aliases = vec![("spuds".to_owned(), "potato".to_owned())]
+            .into_iter()
+            .collect();
+        assert_eq!(Ok(()), set_aliases(&mut c, &user_id, &aliases));
+        assert_eq!(
+            Ok("tomatos".to_owned()),
+            canonical(&mut c, &user_id, "tomatos")
+        );
+        assert_eq!(Ok(()), delete_aliases(&mut c, &user_id));
+        assert_eq!(Ok("spuds".to_owned()), canonical(&mut c, &user_id, "spuds"));
+    }
+}
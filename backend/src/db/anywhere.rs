@@ -0,0 +1,155 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection};
+
+use std::collections::HashMap;
+
+use crate::{
+    db,
+    error::{self, *},
+    types::*,
+};
+
+pub const MAX_ANYWHERE: usize = 100;
+
+// Products the user needs from whichever store they go to first, as JSON by
+// id. They leave their store when marked so, are in every store of the user
+// when read whole, and are gone from all once checked in one.
+fn anywhere_key(user_id: &UserId) -> String {
+    format!("anywhere:{}", **user_id)
+}
+
+// sorted by name
+pub fn get_anywhere(c: &mut Connection, user_id: &UserId) -> Result<Vec<Product>> {
+    let raw: HashMap<String, String> =
+        redis::cmd("HGETALL").arg(anywhere_key(&user_id)).query(c)?;
+    let mut products = raw
+        .values()
+        .map(|raw| {
+            serde_json::from_str(raw)
+                .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted anywhere products"))
+        })
+        .collect::<Result<Vec<Product>>>()?;
+    products.sort_by(|a: &Product, b| {
+        a.name
+            .to_lowercase()
+            .cmp(&b.name.to_lowercase())
+            .then_with(|| a.product_id.cmp(&b.product_id))
+    });
+    Ok(products)
+}
+
+// Take the product out of its store, to be needed anywhere
+pub fn mark_anywhere(c: &mut Connection, auth: &Auth, product_id: &ProductId) -> Result<Product> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let store_id = db::products::find_product_store(c, &product_id)?.ok_or_else(db::not_found)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let anywhere_key = anywhere_key(&user_id);
+        if get_anywhere(c, &user_id)?.len() >= MAX_ANYWHERE {
+            return Err(ServerError::quota_exceeded(
+                "anywhere",
+                MAX_ANYWHERE as u64,
+                "products needed anywhere",
+            ));
+        }
+        let mut product = match db::products::get_product_change(c, &product_id)? {
+            Some(change) => change.product,
+            None => return Err(db::not_found()),
+        };
+        product.is_done = false;
+        db::products::remove_product(c, &store_id, &user_id, &product_id)?;
+        let raw = serde_json::to_string(&product).expect("products always serialize");
+        let size = db::quotas::entity_size(&product.name);
+        transaction(c, &[&anywhere_key], |c, pipe| {
+            db::quotas::transaction_charge(c, pipe, &user_id, size)?;
+            pipe.hset(&anywhere_key, &product.product_id, &raw)
+                .ignore()
+                .query(c)
+        })?;
+        Ok(product)
+    })
+}
+
+// Checked in one store, so needed in none. Fine if it already was, from
+// another store or device.
+pub fn check_anywhere(c: &mut Connection, auth: &Auth, product_id: &ProductId) -> Result<()> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let anywhere_key = anywhere_key(&user_id);
+    transaction(c, &[&anywhere_key], |c, pipe| {
+        let raw: Option<String> = c.hget(&anywhere_key, &**product_id)?;
+        if let Some(product) = raw.and_then(|raw| serde_json::from_str::<Product>(&raw).ok()) {
+            let freed = db::quotas::entity_size(&product.name);
+            db::quotas::transaction_charge(c, pipe, &user_id, -freed)?;
+        }
+        pipe.hdel(&anywhere_key, &**product_id).ignore().query(c)
+    })?;
+    Ok(())
+}
+
+// What the products needed anywhere take of the bytes quota
+pub fn stored_bytes(c: &mut Connection, user_id: &UserId) -> Result<i64> {
+    Ok(get_anywhere(c, &user_id)?
+        .iter()
+        .map(|p| db::quotas::entity_size(&p.name))
+        .sum())
+}
+
+pub fn delete_anywhere(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&anywhere_key(&user_id))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{products::tests::save_product_for_test, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+    use warp::http::StatusCode;
+
+    #[test]
+    fn anywhere_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, product_id) = save_product_for_test(&mut c);
+        let user_id = db::sessions::get_user_id(&mut c, &AUTH).unwrap();
+        let other = Auth("other");
+        assert_eq!(
+            Ok(()),
+            db::sessions::store_session(&mut c, "other", &UserId("other".to_owned()))
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            mark_anywhere(&mut c, &other, &product_id)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+
+        let product = mark_anywhere(&mut c, &AUTH, &product_id).unwrap();
+        assert_eq!(product_id.to_string(), product.product_id);
+        assert_eq!(
+            Ok(0),
+            db::products::get_products_in_aisle(&mut c, &aisle_id).map(|p| p.len())
+        );
+        assert_eq!(
+            Ok(vec![product_id.to_string()]),
+            get_anywhere(&mut c, &user_id)
+                .map(|p| p.into_iter().map(|p| p.product_id).collect::<Vec<_>>())
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            mark_anywhere(&mut c, &AUTH, &product_id)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+
+        // another user can't check it for them
+        assert_eq!(Ok(()), check_anywhere(&mut c, &other, &product_id));
+        assert_eq!(Ok(1), get_anywhere(&mut c, &user_id).map(|p| p.len()));
+        assert_eq!(Ok(()), check_anywhere(&mut c, &AUTH, &product_id));
+        assert_eq!(Ok(()), check_anywhere(&mut c, &AUTH, &product_id));
+        assert_eq!(Ok(0), get_anywhere(&mut c, &user_id).map(|p| p.len()));
+    }
+}
@@ -0,0 +1,253 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, Commands};
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    db::aliases::normalized,
+    error::{self, Result, ServerError},
+    types::*,
+};
+
+// The directory is kept apart from the users' data, nothing in it tells who
+// published a template
+const TEMPLATES: &str = "community:templates";
+const HIDDEN: &str = "community:hidden";
+// reports of different users hiding a template until a moderator looks at it
+pub const REPORTS_TO_HIDE: u64 = 3;
+
+// For moderators, a template reported at least once
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportedTemplate {
+    pub template: CommunityTemplate,
+    pub reports: u64,
+    pub hidden: bool,
+}
+
+fn reports_key(template_id: &str) -> String {
+    format!("community:reports:{}", template_id)
+}
+
+fn all_templates(c: &mut Connection) -> Result<Vec<CommunityTemplate>> {
+    let raw: HashMap<String, String> = redis::cmd("HGETALL").arg(TEMPLATES).query(c)?;
+    raw.values()
+        .map(|raw| {
+            serde_json::from_str(raw).map_err(|_| {
+                ServerError::new(error::INTERNAL_ERROR, "Corrupted community templates")
+            })
+        })
+        .collect()
+}
+
+pub fn get_template(c: &mut Connection, template_id: &str) -> Result<Option<CommunityTemplate>> {
+    let hidden: bool = c.sismember(HIDDEN, template_id)?;
+    if hidden {
+        return Ok(None);
+    }
+    let raw: Option<String> = c.hget(TEMPLATES, template_id)?;
+    raw.map(|raw| {
+        serde_json::from_str(&raw)
+            .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted community templates"))
+    })
+    .transpose()
+}
+
+pub fn publish(
+    c: &mut Connection,
+    template: &AisleTemplate,
+    supermarket: &str,
+    location: &str,
+    now: u64,
+) -> Result<CommunityTemplate> {
+    let published = CommunityTemplate {
+        template_id: Uuid::new_v4().to_hyphenated().to_string(),
+        name: template.name.trim().to_owned(),
+        supermarket: supermarket.trim().to_owned(),
+        location: location.trim().to_owned(),
+        aisles: template.aisles.clone(),
+        published_at: now,
+    };
+    let raw = serde_json::to_string(&published).expect("templates always serialize");
+    c.hset(TEMPLATES, &published.template_id, raw)?;
+    log::info!(
+        "Community template {} published for {}",
+        published.template_id,
+        published.supermarket
+    );
+    Ok(published)
+}
+
+// The visible templates with `query` in their supermarket or location, at
+// most `max` of them
+pub fn search(c: &mut Connection, query: &str, max: usize) -> Result<Vec<CommunityTemplate>> {
+    let query = normalized(query);
+    let hidden: Vec<String> = c.smembers(HIDDEN)?;
+    let mut found: Vec<CommunityTemplate> = all_templates(c)?
+        .into_iter()
+        .filter(|t| !hidden.contains(&t.template_id))
+        .filter(|t| {
+            normalized(&t.supermarket).contains(&query) || normalized(&t.location).contains(&query)
+        })
+        .collect();
+    found.sort_by(|a, b| {
+        (
+            normalized(&a.supermarket),
+            normalized(&a.location),
+            b.published_at,
+        )
+            .cmp(&(
+                normalized(&b.supermarket),
+                normalized(&b.location),
+                a.published_at,
+            ))
+    });
+    found.truncate(max);
+    Ok(found)
+}
+
+// A user's report counts once, enough of them hide the template. Whether
+// the template was hidden by this one.
+pub fn report(c: &mut Connection, template_id: &str, user_id: &UserId) -> Result<bool> {
+    if get_template(c, template_id)?.is_none() {
+        return Err(crate::db::not_found());
+    }
+    c.sadd(&reports_key(template_id), user_id.to_string())?;
+    let reports: u64 = c.scard(&reports_key(template_id))?;
+    if reports < REPORTS_TO_HIDE {
+        return Ok(false);
+    }
+    c.sadd(HIDDEN, template_id)?;
+    log::warn!(
+        "Community template {} hidden after {} reports",
+        template_id,
+        reports
+    );
+    Ok(true)
+}
+
+// The moderation queue, hidden templates first then the most reported
+pub fn reported(c: &mut Connection) -> Result<Vec<ReportedTemplate>> {
+    let hidden: Vec<String> = c.smembers(HIDDEN)?;
+    let mut reported = vec![];
+    for template in all_templates(c)? {
+        let reports: u64 = c.scard(&reports_key(&template.template_id))?;
+        if reports > 0 || hidden.contains(&template.template_id) {
+            reported.push(ReportedTemplate {
+                hidden: hidden.contains(&template.template_id),
+                template,
+                reports,
+            });
+        }
+    }
+    reported.sort_by(|a, b| {
+        (b.hidden, b.reports, &a.template.template_id).cmp(&(
+            a.hidden,
+            a.reports,
+            &b.template.template_id,
+        ))
+    });
+    Ok(reported)
+}
+
+// Back in the directory with its reports forgotten, whether there was such
+// a template
+pub fn restore(c: &mut Connection, template_id: &str) -> Result<bool> {
+    let exists: bool = c.hexists(TEMPLATES, template_id)?;
+    if !exists {
+        return Ok(false);
+    }
+    c.srem(HIDDEN, template_id)?;
+    c.del(&reports_key(template_id))?;
+    log::info!("Community template {} restored", template_id);
+    Ok(true)
+}
+
+// Whether there was such a template
+pub fn remove(c: &mut Connection, template_id: &str) -> Result<bool> {
+    let removed: bool = c.hdel(TEMPLATES, template_id)?;
+    c.srem(HIDDEN, template_id)?;
+    c.del(&reports_key(template_id))?;
+    if removed {
+        log::info!("Community template {} removed", template_id);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    fn template(name: &str) -> AisleTemplate {
+        AisleTemplate::new(
+            name.to_owned(),
+            vec!["Produce".to_owned(), "Dairy".to_owned()],
+        )
+    }
+
+    #[test]
+    fn search_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let lyon = publish(&mut c, &template("Mine"), " Carrefour ", "Lyon", 10).unwrap();
+        let paris = publish(&mut c, &template("Other"), "carrefour", "Paris", 20).unwrap();
+        let old_paris = publish(&mut c, &template("Old"), "Carrefour", "Paris", 5).unwrap();
+        let aldi = publish(&mut c, &template("Aldi"), "Aldi", "", 30).unwrap();
+        assert_eq!("Carrefour", lyon.supermarket);
+        assert_eq!(
+            Ok(vec![lyon.clone(), paris.clone(), old_paris.clone()]),
+            search(&mut c, "CARREF", 10)
+        );
+        assert_eq!(Ok(vec![paris.clone()]), search(&mut c, "paris", 1));
+        assert_eq!(
+            Ok(vec![aldi.clone(), lyon.clone(), paris.clone(), old_paris]),
+            search(&mut c, "", 10)
+        );
+        assert_eq!(
+            Ok(Some(aldi.clone())),
+            get_template(&mut c, &aldi.template_id)
+        );
+        assert_eq!(Ok(None), get_template(&mut c, "nope"));
+    }
+
+    #[test]
+    fn moderation_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let published = publish(&mut c, &template("Mine"), "Aldi", "Berlin", 10).unwrap();
+        let id = published.template_id.as_str();
+        for user in &["1", "2", "2"] {
+            assert_eq!(Ok(false), report(&mut c, id, &UserId(user.to_string())));
+        }
+        assert_eq!(
+            Ok(vec![ReportedTemplate {
+                template: published.clone(),
+                reports: 2,
+                hidden: false,
+            }]),
+            reported(&mut c)
+        );
+        assert_eq!(Ok(true), report(&mut c, id, &UserId("3".to_owned())));
+        assert_eq!(Ok(vec![]), search(&mut c, "aldi", 10));
+        assert_eq!(Ok(None), get_template(&mut c, id));
+        assert_eq!(true, report(&mut c, id, &UserId("4".to_owned())).is_err());
+        assert_eq!(Ok(true), reported(&mut c).map(|r| r[0].hidden));
+
+        assert_eq!(Ok(true), restore(&mut c, id));
+        assert_eq!(Ok(vec![published]), search(&mut c, "aldi", 10));
+        assert_eq!(Ok(vec![]), reported(&mut c));
+        assert_eq!(Ok(true), remove(&mut c, id));
+        assert_eq!(Ok(false), remove(&mut c, id));
+        assert_eq!(Ok(false), restore(&mut c, id));
+        assert_eq!(Ok(vec![]), search(&mut c, "", 10));
+    }
+}
@@ -0,0 +1,159 @@
+// Stores for clients editing offline for long, as a state they can merge
+// without us: the products are a set where a product is in once added and
+// until removed, its id made by the one add and kept in the store's removed
+// products once removed, for as long as the journal goes back, and each field
+// keeps its latest edit. A client offline for longer may bring back what was
+// removed since. The store and its products stay the same for the other
+// endpoints.
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use crate::{db, error::Result, types::*};
+
+pub fn get_crdt_store(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<CrdtStore> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    crdt_store(c, &store_id)
+}
+
+fn crdt_store(c: &mut Connection, store_id: &StoreId) -> Result<CrdtStore> {
+    let mut products = vec![];
+    for aisle in db::aisles::get_aisles_in_store(c, &store_id)? {
+        for p in aisle.products {
+            products.push(db::products::get_crdt_product(c, &ProductId(p.product_id))?);
+        }
+    }
+    products.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    let removed = c.smembers(&db::products::removed_products_key(&store_id))?;
+    Ok(CrdtStore::new(products, removed))
+}
+
+// Merge the client's state into ours and return the result. The products it
+// has in aisles no longer in the store are dropped, as the aisle's removal
+// removed them.
+pub fn merge_crdt_store(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    theirs: &CrdtStore,
+) -> Result<CrdtStore> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        for id in &theirs.removed {
+            let product_id = ProductId(id.clone());
+            if db::products::find_product_store(c, &product_id)?.as_ref() == Some(store_id) {
+                db::products::remove_product(c, &store_id, &user_id, &product_id)?;
+            }
+        }
+        let removed_key = db::products::removed_products_key(&store_id);
+        for p in &theirs.products {
+            let product_id = ProductId(p.product_id.clone());
+            if c.sismember(&removed_key, &p.product_id)? {
+                continue;
+            }
+            match db::products::find_product_store(c, &product_id)? {
+                Some(s) if s == *store_id => {}
+                Some(_) => continue,
+                None => {
+                    let aisle_id = AisleId(p.aisle_id.clone());
+                    if !db::aisles::is_aisle_in_store(c, &aisle_id, &store_id)? {
+                        continue;
+                    }
+                    db::products::insert_product(
+                        c,
+                        &user_id,
+                        &store_id,
+                        &product_id,
+                        &p.name.value,
+                        &aisle_id,
+                    )?;
+                }
+            }
+            db::products::merge_product_fields(c, &store_id, &user_id, p)?;
+        }
+        crdt_store(c, &store_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{products::tests::*, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+    use warp::http::StatusCode;
+
+    const ADDED: &str = "00000000-0000-0000-0000-000000000001";
+
+    #[test]
+    fn merge_crdt_store_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, product_id) = save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        let base = get_crdt_store(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!(1, base.products.len());
+        assert_eq!(Stamped::new(1, 0), base.products[0].quantity);
+        let now = db::locks::now_ms();
+
+        // one renames the product and adds another, also in an aisle since
+        // removed
+        let mut first = base.clone();
+        first.products[0].name = Stamped::new(RENAME.to_owned(), now - 1000);
+        let mut added = first.products[0].clone();
+        added.product_id = ADDED.to_owned();
+        added.name = Stamped::new("added".to_owned(), now - 1000);
+        let mut lost = added.clone();
+        lost.product_id = "lost".to_owned();
+        lost.aisle_id = "lost".to_owned();
+        first.products.extend(vec![added, lost]);
+        // the other, from the same state, renamed it before and checked it
+        let mut second = base.clone();
+        second.products[0].name = Stamped::new("older".to_owned(), now - 2000);
+        second.products[0].is_done = Stamped::new(true, now - 2000);
+
+        assert!(merge_crdt_store(&mut c, &AUTH, &store_id, &first).is_ok());
+        let merged = merge_crdt_store(&mut c, &AUTH, &store_id, &second).unwrap();
+        assert_eq!(2, merged.products.len());
+        let product = merged
+            .products
+            .iter()
+            .find(|p| p.product_id == *product_id)
+            .unwrap();
+        assert_eq!(RENAME, product.name.value);
+        assert_eq!(Stamped::new(true, now - 2000), product.is_done);
+        assert_eq!(
+            Ok(merged.clone()),
+            merge_crdt_store(&mut c, &AUTH, &store_id, &merged)
+        );
+
+        // removed, a state from before can't bring it back
+        let mut removing = merged.clone();
+        removing.products.clear();
+        removing.removed.push(ADDED.to_owned());
+        assert!(merge_crdt_store(&mut c, &AUTH, &store_id, &removing).is_ok());
+        let after = merge_crdt_store(&mut c, &AUTH, &store_id, &merged).unwrap();
+        assert_eq!(1, after.products.len());
+        assert_eq!(vec![ADDED.to_owned()], after.removed);
+        assert_eq!(
+            Ok(1),
+            db::products::get_products_in_aisle(&mut c, &aisle_id).map(|p| p.len())
+        );
+
+        let other = Auth("other");
+        let other_id = UserId("other".to_owned());
+        assert_eq!(
+            Ok(()),
+            db::sessions::store_session(&mut c, "other", &other_id)
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            merge_crdt_store(&mut c, &other, &store_id, &merged).map_err(|e| e.status)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use std::collections::HashMap;
+
+use crate::error::Result;
+
+const DAY_S: u64 = 24 * 3600;
+// counts of every event since the first one
+const EVENTS_KEY: &str = "events";
+// a day's counts are kept that long, the metrics show today's and the
+// telemetry yesterday's
+const DAILY_EVENTS_TTL_S: u64 = 2 * DAY_S;
+
+// What users do, counted for GET /metrics apart from the HTTP traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    Registration,
+    Login,
+    StoreCreated,
+    ProductChecked,
+}
+
+pub const EVENTS: [Event; 4] = [
+    Event::Registration,
+    Event::Login,
+    Event::StoreCreated,
+    Event::ProductChecked,
+];
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Registration => "registration",
+            Event::Login => "login",
+            Event::StoreCreated => "store_created",
+            Event::ProductChecked => "product_checked",
+        }
+    }
+}
+
+fn daily_key(day: u64) -> String {
+    format!("events:{}", day)
+}
+
+pub fn count(c: &mut Connection, event: Event, now: u64) -> Result<()> {
+    let daily_key = daily_key(now / DAY_S);
+    redis::pipe()
+        .cmd("HINCRBY")
+        .arg(EVENTS_KEY)
+        .arg(event.name())
+        .arg(1)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&daily_key)
+        .arg(event.name())
+        .arg(1)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&daily_key)
+        .arg(DAILY_EVENTS_TTL_S)
+        .ignore()
+        .query(c)?;
+    Ok(())
+}
+
+// Every event with its count since the first one and since midnight UTC,
+// zeros included
+pub fn counts(c: &mut Connection, now: u64) -> Result<Vec<(Event, u64, u64)>> {
+    let mut total: HashMap<String, u64> = redis::cmd("HGETALL").arg(EVENTS_KEY).query(c)?;
+    Ok(day_counts(c, now / DAY_S)?
+        .into_iter()
+        .map(|(event, today)| (event, total.remove(event.name()).unwrap_or(0), today))
+        .collect())
+}
+
+// Every event with its count on `day`, in days since 1970-01-01, zeros
+// included. Yesterday is the oldest day still kept.
+pub fn day_counts(c: &mut Connection, day: u64) -> Result<Vec<(Event, u64)>> {
+    let mut counts: HashMap<String, u64> = redis::cmd("HGETALL").arg(daily_key(day)).query(c)?;
+    Ok(EVENTS
+        .iter()
+        .map(|&event| (event, counts.remove(event.name()).unwrap_or(0)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+    use std::time::Duration;
+
+    #[test]
+    fn counts_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let now = 100 * DAY_S + 3600;
+        assert_eq!(Ok(()), count(&mut c, Event::Login, now - DAY_S));
+        assert_eq!(Ok(()), count(&mut c, Event::Login, now));
+        assert_eq!(Ok(()), count(&mut c, Event::StoreCreated, now));
+        assert_eq!(
+            Ok(vec![
+                (Event::Registration, 0, 0),
+                (Event::Login, 2, 1),
+                (Event::StoreCreated, 1, 1),
+                (Event::ProductChecked, 0, 0),
+            ]),
+            counts(&mut c, now)
+        );
+
+        assert_eq!(
+            Ok(vec![
+                (Event::Registration, 0),
+                (Event::Login, 1),
+                (Event::StoreCreated, 0),
+                (Event::ProductChecked, 0),
+            ]),
+            day_counts(&mut c, now / DAY_S - 1)
+        );
+
+        // the days go, the totals stay
+        c.advance_time(Duration::from_secs(DAILY_EVENTS_TTL_S));
+        assert_eq!(Ok(2), c.hget(EVENTS_KEY, "login"));
+        assert_eq!(Ok(0), c.exists(&daily_key(now / DAY_S)));
+    }
+}
@@ -2,10 +2,12 @@ use hex_view::HexView;
 use rand::{self, Rng};
 use uuid::Uuid;
 
+#[cfg(not(test))]
+use crate::storage::Connection;
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
 #[cfg(not(test))]
-use redis::{self, Commands, Connection};
+use redis::{self, Commands};
 
 use crate::{
     error::{self, *},
@@ -0,0 +1,107 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use hex_view::HexView;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{error::Result, links::now_s};
+
+// Code letting someone sign up while registration is by invitation, for a
+// number of sign ups until it expires
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub uses: u32,
+    // UNIX timestamp in seconds
+    pub expires_at: u64,
+}
+
+fn invite_key(code: &str) -> String {
+    format!("invite:{}", code)
+}
+
+pub fn create_invite(c: &mut Connection, uses: u32, ttl_s: u64) -> Result<Invite> {
+    let mut code = [0u8; 16];
+    rand::thread_rng().fill(&mut code[..]);
+    let code = format!("{:x}", HexView::from(&code[..]));
+    let key = invite_key(&code);
+    c.set(&key, uses)?;
+    c.expire(&key, ttl_s as usize)?;
+    Ok(Invite {
+        code,
+        uses,
+        expires_at: now_s() + ttl_s,
+    })
+}
+
+// Take a use of the invite, false if it is unknown, expired or used up
+pub fn use_invite(c: &mut Connection, code: &str) -> Result<bool> {
+    let key = invite_key(code);
+    let left: i64 = c.incr(&key, -1)?;
+    if left >= 0 {
+        return Ok(true);
+    }
+    // the decrement made up an unknown code, without expiry
+    let ttl: i64 = c.ttl(&key)?;
+    if ttl < 0 {
+        c.del(&key)?;
+    }
+    Ok(false)
+}
+
+// Give back the use of a sign up that failed after taking it
+pub fn return_invite(c: &mut Connection, code: &str) -> Result<()> {
+    let key = invite_key(code);
+    if c.exists(&key)? {
+        c.incr(&key, 1)?;
+    }
+    Ok(())
+}
+
+// Whether there was an invite to revoke
+pub fn revoke_invite(c: &mut Connection, code: &str) -> Result<bool> {
+    Ok(c.del(&invite_key(code))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+    use std::time::Duration;
+
+    #[test]
+    fn use_invite_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let invite = create_invite(&mut c, 2, 3600).unwrap();
+        assert_eq!(32, invite.code.len());
+        assert_eq!(Ok(true), use_invite(&mut c, &invite.code));
+        assert_eq!(Ok(true), use_invite(&mut c, &invite.code));
+        assert_eq!(Ok(false), use_invite(&mut c, &invite.code));
+        assert_eq!(Ok(()), return_invite(&mut c, &invite.code));
+        assert_eq!(Ok(false), use_invite(&mut c, &invite.code));
+        assert_eq!(Ok(()), return_invite(&mut c, &invite.code));
+        assert_eq!(Ok(()), return_invite(&mut c, &invite.code));
+        assert_eq!(Ok(true), use_invite(&mut c, &invite.code));
+
+        // unknown codes leave nothing behind
+        assert_eq!(Ok(false), use_invite(&mut c, "nope"));
+        assert_eq!(Ok(false), c.exists(&invite_key("nope")));
+        assert_eq!(Ok(()), return_invite(&mut c, "nope"));
+        assert_eq!(Ok(false), c.exists(&invite_key("nope")));
+
+        let invite = create_invite(&mut c, 5, 60).unwrap();
+        c.advance_time(Duration::from_secs(60));
+        assert_eq!(Ok(false), use_invite(&mut c, &invite.code));
+        let invite = create_invite(&mut c, 5, 60).unwrap();
+        assert_eq!(Ok(true), revoke_invite(&mut c, &invite.code));
+        assert_eq!(Ok(false), use_invite(&mut c, &invite.code));
+    }
+}
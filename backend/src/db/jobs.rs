@@ -0,0 +1,458 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use std::collections::HashMap;
+
+use redis::{from_redis_value, Value};
+use serde::Serialize;
+
+use crate::error::Result;
+
+// Consumer group shared by every worker of a queue
+const GROUP: &str = "workers";
+// failed jobs kept for inspection, the oldest go first
+const DEAD_MAX_LEN: usize = 1000;
+
+// When to hand a job that was not acknowledged to another worker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // time a failed or lost job waits before its next attempt
+    pub min_idle_ms: u64,
+    // attempts before the job goes to the dead-letter queue
+    pub max_attempts: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            min_idle_ms: 30_000,
+            max_attempts: 5,
+        }
+    }
+}
+
+// A unit of deferred work, `kind` picks its handler and `payload` is up to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub payload: String,
+    // 1 on the first delivery
+    pub attempt: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadJob {
+    pub id: String,
+    pub kind: String,
+    pub payload: String,
+    pub attempts: u64,
+    pub error: String,
+}
+
+// Counters are kept since the queue was created, the rest is its current state
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueueStats {
+    pub queue: String,
+    pub waiting: u64,
+    pub running: u64,
+    pub dead: u64,
+    pub enqueued: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub dead_lettered: u64,
+}
+
+fn queue_key(queue: &str) -> String {
+    format!("jobs:{}", queue)
+}
+
+fn dead_key(queue: &str) -> String {
+    format!("jobs:{}:dead", queue)
+}
+
+// last error of the jobs that failed and will be retried
+fn errors_key(queue: &str) -> String {
+    format!("jobs:{}:errors", queue)
+}
+
+fn metrics_key(queue: &str) -> String {
+    format!("jobs:{}:metrics", queue)
+}
+
+fn field(fields: &mut HashMap<String, String>, name: &str) -> String {
+    fields.remove(name).unwrap_or_default()
+}
+
+// Stream entries as returned by XRANGE and XCLAIM, deleted ones come back
+// as nil from XCLAIM and are skipped
+fn entries(values: &[Value]) -> Result<Vec<(String, HashMap<String, String>)>> {
+    let mut entries = vec![];
+    for value in values {
+        if let Some(entry) = from_redis_value(value)? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn jobs(values: &[Value], attempt: u64) -> Result<Vec<Job>> {
+    Ok(entries(values)?
+        .into_iter()
+        .map(|(id, mut fields)| Job {
+            id,
+            kind: field(&mut fields, "kind"),
+            payload: field(&mut fields, "payload"),
+            attempt,
+        })
+        .collect())
+}
+
+// Workers call it before claiming jobs, the queue may not exist yet
+pub fn create_group(c: &mut Connection, queue: &str) -> Result<()> {
+    let res: redis::RedisResult<()> = redis::cmd("XGROUP")
+        .arg("CREATE")
+        .arg(queue_key(queue))
+        .arg(GROUP)
+        .arg("0")
+        .arg("MKSTREAM")
+        .query(c);
+    match res {
+        Err(ref e) if e.extension_error_code() == Some("BUSYGROUP") => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+pub fn enqueue(c: &mut Connection, queue: &str, kind: &str, payload: &str) -> Result<String> {
+    let (id,): (String,) = redis::pipe()
+        .atomic()
+        .cmd("XADD")
+        .arg(queue_key(queue))
+        .arg("*")
+        .arg("kind")
+        .arg(kind)
+        .arg("payload")
+        .arg(payload)
+        .cmd("HINCRBY")
+        .arg(metrics_key(queue))
+        .arg("enqueued")
+        .arg(1)
+        .ignore()
+        .query(c)?;
+    log::debug!("Job {} of kind {} queued on {}", id, kind, queue);
+    Ok(id)
+}
+
+// Up to `count` jobs nobody got yet, they stay pending until completed
+pub fn claim(c: &mut Connection, queue: &str, consumer: &str, count: usize) -> Result<Vec<Job>> {
+    let streams: Option<Vec<Value>> = redis::cmd("XREADGROUP")
+        .arg("GROUP")
+        .arg(GROUP)
+        .arg(consumer)
+        .arg("COUNT")
+        .arg(count)
+        .arg("STREAMS")
+        .arg(queue_key(queue))
+        .arg(">")
+        .query(c)?;
+    match streams.as_ref().and_then(|streams| streams.first()) {
+        Some(stream) => {
+            let (_, entries): (String, Vec<Value>) = from_redis_value(stream)?;
+            jobs(&entries, 1)
+        }
+        None => Ok(vec![]),
+    }
+}
+
+// Jobs that failed, or whose worker died, and waited long enough: up to
+// `count` of them are taken over by `consumer`. The ones out of attempts go
+// to the dead-letter queue instead.
+pub fn reclaim(
+    c: &mut Connection,
+    queue: &str,
+    consumer: &str,
+    policy: &RetryPolicy,
+    count: usize,
+) -> Result<Vec<Job>> {
+    let pending: Vec<Value> = redis::cmd("XPENDING")
+        .arg(queue_key(queue))
+        .arg(GROUP)
+        .arg("-")
+        .arg("+")
+        .arg(count)
+        .query(c)?;
+    let mut jobs = vec![];
+    for p in pending {
+        let (id, _, idle_ms, deliveries): (String, String, u64, u64) = from_redis_value(&p)?;
+        if idle_ms < policy.min_idle_ms {
+            continue;
+        }
+        if deliveries >= policy.max_attempts {
+            dead_letter(c, queue, &id, deliveries)?;
+            continue;
+        }
+        let claimed: Vec<Value> = redis::cmd("XCLAIM")
+            .arg(queue_key(queue))
+            .arg(GROUP)
+            .arg(consumer)
+            .arg(policy.min_idle_ms)
+            .arg(&id)
+            .query(c)?;
+        // XCLAIM counts the new delivery
+        jobs.extend(self::jobs(&claimed, deliveries + 1)?);
+    }
+    Ok(jobs)
+}
+
+pub fn complete(c: &mut Connection, queue: &str, job: &Job) -> Result<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("XACK")
+        .arg(queue_key(queue))
+        .arg(GROUP)
+        .arg(&job.id)
+        .ignore()
+        .cmd("XDEL")
+        .arg(queue_key(queue))
+        .arg(&job.id)
+        .ignore()
+        .cmd("HDEL")
+        .arg(errors_key(queue))
+        .arg(&job.id)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(metrics_key(queue))
+        .arg("completed")
+        .arg(1)
+        .ignore()
+        .query(c)?;
+    Ok(())
+}
+
+// The job stays pending, `reclaim` retries it later
+pub fn fail(c: &mut Connection, queue: &str, job: &Job, error: &str) -> Result<()> {
+    log::warn!(
+        "Job {} of kind {} failed, attempt {}: {}",
+        job.id,
+        job.kind,
+        job.attempt,
+        error
+    );
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(errors_key(queue))
+        .arg(&job.id)
+        .arg(error)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(metrics_key(queue))
+        .arg("failed")
+        .arg(1)
+        .ignore()
+        .query(c)?;
+    Ok(())
+}
+
+fn dead_letter(c: &mut Connection, queue: &str, id: &str, attempts: u64) -> Result<()> {
+    let found: Vec<Value> = redis::cmd("XRANGE")
+        .arg(queue_key(queue))
+        .arg(id)
+        .arg(id)
+        .query(c)?;
+    let error: Option<String> = redis::cmd("HGET").arg(errors_key(queue)).arg(id).query(c)?;
+    let error = error.unwrap_or_else(|| "Worker lost".to_owned());
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some((_, mut fields)) = entries(&found)?.pop() {
+        log::error!(
+            "Job {} of kind {} failed {} times, moved to the dead-letter queue: {}",
+            id,
+            fields.get("kind").map_or("", String::as_str),
+            attempts,
+            error
+        );
+        pipe.cmd("XADD")
+            .arg(dead_key(queue))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(DEAD_MAX_LEN)
+            .arg("*")
+            .arg("id")
+            .arg(id)
+            .arg("kind")
+            .arg(field(&mut fields, "kind"))
+            .arg("payload")
+            .arg(field(&mut fields, "payload"))
+            .arg("attempts")
+            .arg(attempts)
+            .arg("error")
+            .arg(&error)
+            .ignore()
+            .cmd("HINCRBY")
+            .arg(metrics_key(queue))
+            .arg("dead_lettered")
+            .arg(1)
+            .ignore();
+    }
+    pipe.cmd("XACK")
+        .arg(queue_key(queue))
+        .arg(GROUP)
+        .arg(id)
+        .ignore()
+        .cmd("XDEL")
+        .arg(queue_key(queue))
+        .arg(id)
+        .ignore()
+        .cmd("HDEL")
+        .arg(errors_key(queue))
+        .arg(id)
+        .ignore()
+        .query(c)?;
+    Ok(())
+}
+
+// The oldest `count` jobs of the dead-letter queue
+pub fn dead_jobs(c: &mut Connection, queue: &str, count: usize) -> Result<Vec<DeadJob>> {
+    let found: Vec<Value> = redis::cmd("XRANGE")
+        .arg(dead_key(queue))
+        .arg("-")
+        .arg("+")
+        .arg("COUNT")
+        .arg(count)
+        .query(c)?;
+    Ok(entries(&found)?
+        .into_iter()
+        .map(|(_, mut fields)| DeadJob {
+            id: field(&mut fields, "id"),
+            kind: field(&mut fields, "kind"),
+            payload: field(&mut fields, "payload"),
+            attempts: field(&mut fields, "attempts").parse().unwrap_or(0),
+            error: field(&mut fields, "error"),
+        })
+        .collect())
+}
+
+pub fn stats(c: &mut Connection, queue: &str) -> Result<QueueStats> {
+    create_group(c, queue)?;
+    let (length, dead, (running, _, _, _), mut metrics): (
+        u64,
+        u64,
+        (u64, Value, Value, Value),
+        HashMap<String, u64>,
+    ) = redis::pipe()
+        .cmd("XLEN")
+        .arg(queue_key(queue))
+        .cmd("XLEN")
+        .arg(dead_key(queue))
+        .cmd("XPENDING")
+        .arg(queue_key(queue))
+        .arg(GROUP)
+        .cmd("HGETALL")
+        .arg(metrics_key(queue))
+        .query(c)?;
+    let mut metric = |name: &str| metrics.remove(name).unwrap_or(0);
+    Ok(QueueStats {
+        queue: queue.to_owned(),
+        waiting: length.saturating_sub(running),
+        running,
+        dead,
+        enqueued: metric("enqueued"),
+        completed: metric("completed"),
+        failed: metric("failed"),
+        dead_lettered: metric("dead_lettered"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+    use std::time::Duration;
+
+    const QUEUE: &str = "test";
+
+    #[test]
+    fn enqueue_claim_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        assert_eq!(Ok(()), create_group(&mut c, QUEUE));
+        assert_eq!(Ok(()), create_group(&mut c, QUEUE));
+        let first = enqueue(&mut c, QUEUE, "mail", "{}").unwrap();
+        enqueue(&mut c, QUEUE, "webhook", "42").unwrap();
+
+        let jobs = claim(&mut c, QUEUE, "w1", 1).unwrap();
+        assert_eq!(
+            vec![Job {
+                id: first,
+                kind: "mail".to_owned(),
+                payload: "{}".to_owned(),
+                attempt: 1,
+            }],
+            jobs
+        );
+        let others = claim(&mut c, QUEUE, "w2", 10).unwrap();
+        assert_eq!(1, others.len());
+        assert_eq!(Ok(vec![]), claim(&mut c, QUEUE, "w2", 10));
+
+        assert_eq!(Ok(()), complete(&mut c, QUEUE, &jobs[0]));
+        let stats = stats(&mut c, QUEUE).unwrap();
+        assert_eq!(
+            QueueStats {
+                queue: QUEUE.to_owned(),
+                running: 1,
+                enqueued: 2,
+                completed: 1,
+                ..QueueStats::default()
+            },
+            stats
+        );
+    }
+
+    #[test]
+    fn retry_dead_letter_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let policy = RetryPolicy {
+            min_idle_ms: 1000,
+            max_attempts: 2,
+        };
+        create_group(&mut c, QUEUE).unwrap();
+        let id = enqueue(&mut c, QUEUE, "mail", "{}").unwrap();
+        let job = claim(&mut c, QUEUE, "w1", 1).unwrap().pop().unwrap();
+        assert_eq!(Ok(()), fail(&mut c, QUEUE, &job, "SMTP down"));
+
+        // retried once it waited long enough, by whoever comes
+        assert_eq!(Ok(vec![]), reclaim(&mut c, QUEUE, "w2", &policy, 10));
+        c.advance_time(Duration::from_millis(1000));
+        let retried = reclaim(&mut c, QUEUE, "w2", &policy, 10).unwrap();
+        assert_eq!(
+            vec![(id.clone(), 2)],
+            retried
+                .iter()
+                .map(|j| (j.id.clone(), j.attempt))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(()), fail(&mut c, QUEUE, &retried[0], "SMTP still down"));
+
+        c.advance_time(Duration::from_millis(1000));
+        assert_eq!(Ok(vec![]), reclaim(&mut c, QUEUE, "w1", &policy, 10));
+        assert_eq!(
+            Ok(vec![DeadJob {
+                id,
+                kind: "mail".to_owned(),
+                payload: "{}".to_owned(),
+                attempts: 2,
+                error: "SMTP still down".to_owned(),
+            }]),
+            dead_jobs(&mut c, QUEUE, 10)
+        );
+        let stats = stats(&mut c, QUEUE).unwrap();
+        assert_eq!((0, 0, 1), (stats.waiting, stats.running, stats.dead));
+        assert_eq!((2, 1), (stats.failed, stats.dead_lettered));
+    }
+}
@@ -0,0 +1,587 @@
+use std::fmt;
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{transaction, Commands, Pipeline};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection, FakePipeline as Pipeline};
+
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(not(test))]
+use std::sync::RwLock;
+
+#[cfg(not(test))]
+use lazy_static::lazy_static;
+
+use serde::Serialize;
+
+use crate::{db, error::Result, links::now_s, types::*};
+
+// counters of the journals of all the stores
+const JOURNAL_METRICS: &str = "journal_metrics";
+// expired entries waiting for `archive_journals`, as `<store id>|<entry>`
+const JOURNAL_ARCHIVE: &str = "journal_archive";
+
+// How much of its journal a store keeps. Clients older than what is left get
+// a full snapshot of the store instead of the changes, built from the store
+// itself, so trimming never leaves them without a base.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub max_len: u64,
+    // entries older than that go at the next `trim_journals`
+    pub max_age_s: Option<u64>,
+    // expired entries are queued for the archive rather than dropped
+    pub archive: bool,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_len: 1000,
+            max_age_s: None,
+            archive: false,
+        }
+    }
+}
+
+#[cfg(not(test))]
+lazy_static! {
+    static ref RETENTION: RwLock<Retention> = RwLock::new(Retention::default());
+}
+
+#[cfg(not(test))]
+pub fn set_retention(retention: Retention) {
+    *RETENTION.write().unwrap() = retention;
+}
+
+#[cfg(not(test))]
+fn retention() -> Retention {
+    *RETENTION.read().unwrap()
+}
+
+// tests run in parallel, each one gets its own retention
+#[cfg(test)]
+thread_local! {
+    static RETENTION: Cell<Retention> = Cell::new(Retention::default());
+}
+
+#[cfg(test)]
+pub fn set_retention(retention: Retention) {
+    RETENTION.with(|r| r.set(retention));
+}
+
+#[cfg(test)]
+fn retention() -> Retention {
+    RETENTION.with(Cell::get)
+}
+
+// The journals as they are, but `expired` counted since the first trim
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct JournalStats {
+    pub stores: u64,
+    pub entries: u64,
+    pub longest: u64,
+    pub expired: u64,
+}
+
+fn journal_key(id: &StoreId) -> String {
+    format!("journal:{}", **id)
+}
+
+fn store_version_key(id: &StoreId) -> String {
+    format!("store_version:{}", **id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Entity {
+    Store,
+    Aisle,
+    Product,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Updated,
+    Deleted,
+    // a bulk change of the store, clients behind it take a snapshot instead
+    // of hearing about every product it touched
+    Replaced,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Entry {
+    pub version: u64,
+    pub entity: Entity,
+    pub change: Change,
+    pub id: String,
+    // when the change was made, 0 for entries from before it was kept
+    pub at: u64,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entity = match self.entity {
+            Entity::Store => "store",
+            Entity::Aisle => "aisle",
+            Entity::Product => "product",
+        };
+        let change = match self.change {
+            Change::Updated => "updated",
+            Change::Deleted => "deleted",
+            Change::Replaced => "replaced",
+        };
+        write!(
+            f,
+            "{}|{}|{}|{}|{}",
+            self.version, entity, change, self.id, self.at
+        )
+    }
+}
+
+impl Entry {
+    fn parse(raw: &str) -> Option<Entry> {
+        let mut parts = raw.splitn(5, '|');
+        let version = parts.next()?.parse().ok()?;
+        let entity = match parts.next()? {
+            "store" => Entity::Store,
+            "aisle" => Entity::Aisle,
+            "product" => Entity::Product,
+            _ => return None,
+        };
+        let change = match parts.next()? {
+            "updated" => Change::Updated,
+            "deleted" => Change::Deleted,
+            "replaced" => Change::Replaced,
+            _ => return None,
+        };
+        Some(Entry {
+            version,
+            entity,
+            change,
+            id: parts.next()?.to_owned(),
+            at: match parts.next() {
+                Some(at) => at.parse().ok()?,
+                None => 0,
+            },
+        })
+    }
+}
+
+pub fn current_version(c: &mut Connection, store_id: &StoreId) -> Result<u64> {
+    let version: Option<u64> = c.get(&store_version_key(store_id))?;
+    Ok(version.unwrap_or(0))
+}
+
+// bump the store version and queue the matching journal entry in `pipe`
+// to be used in the transaction doing the change, once per store and
+// transaction: see `record_all` for more
+pub fn record(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    entity: Entity,
+    change: Change,
+    id: &str,
+) -> Result<()> {
+    record_all(c, pipe, store_id, &[(entity, change, id)])
+}
+
+// Queue the journal entries of several changes of the store and the version
+// after them. The version is watched: it only moves with the transaction, and
+// a concurrent change of the store makes the transaction start over rather
+// than take the same versions.
+pub fn record_all(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    changes: &[(Entity, Change, &str)],
+) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let version_key = store_version_key(store_id);
+    let journal_key = journal_key(store_id);
+    redis::cmd("WATCH")
+        .arg(&version_key)
+        .arg(&journal_key)
+        .query::<()>(c)?;
+    let version = current_version(c, store_id)?;
+    // the entries these push out of the journal take their removals along
+    let len: u64 = c.llen(&journal_key)?;
+    let overflow = (len + changes.len() as u64).saturating_sub(retention().max_len);
+    if overflow > 0 {
+        let raw: Vec<String> = c.lrange(&journal_key, 0, overflow as isize - 1)?;
+        let leaving: Vec<Entry> = raw.iter().filter_map(|e| Entry::parse(e)).collect();
+        db::products::transaction_forget_removed(c, pipe, store_id, &leaving)?;
+    }
+    let now = now_s();
+    for (i, (entity, change, id)) in changes.iter().enumerate() {
+        let entry = Entry {
+            version: version + 1 + i as u64,
+            entity: *entity,
+            change: *change,
+            id: (*id).to_owned(),
+            at: now,
+        };
+        pipe.rpush(&journal_key, entry.to_string()).ignore();
+    }
+    pipe.set(&version_key, version + changes.len() as u64)
+        .ignore()
+        .ltrim(&journal_key, -(retention().max_len as isize), -1)
+        .ignore();
+    db::stores::transaction_touch(pipe, store_id, now);
+    Ok(())
+}
+
+// entries newer than `since`, None if the journal doesn't go back that far
+// or the store was replaced since
+pub fn entries_since(
+    c: &mut Connection,
+    store_id: &StoreId,
+    since: u64,
+) -> Result<Option<Vec<Entry>>> {
+    if since >= current_version(c, store_id)? {
+        return Ok(Some(vec![]));
+    }
+    let raw: Vec<String> = c.lrange(&journal_key(store_id), 0, -1)?;
+    let entries: Vec<Entry> = raw.iter().filter_map(|e| Entry::parse(e)).collect();
+    match entries.first() {
+        Some(oldest) if oldest.version <= since + 1 => {
+            let entries: Vec<Entry> = entries.into_iter().filter(|e| e.version > since).collect();
+            if entries.iter().any(|e| e.change == Change::Replaced) {
+                Ok(None)
+            } else {
+                Ok(Some(entries))
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+// the entries about that aisle or product still in the journal, oldest first
+pub fn entries_about(c: &mut Connection, store_id: &StoreId, id: &str) -> Result<Vec<Entry>> {
+    let raw: Vec<String> = c.lrange(&journal_key(store_id), 0, -1)?;
+    Ok(raw
+        .iter()
+        .filter_map(|e| Entry::parse(e))
+        .filter(|e| e.id == id)
+        .collect())
+}
+
+// Drop the entries of the store older than the retention allows, returns
+// how many
+pub fn trim_journal(c: &mut Connection, store_id: &StoreId, now: u64) -> Result<u64> {
+    let max_age_s = match retention().max_age_s {
+        Some(max_age_s) => max_age_s,
+        None => return Ok(0),
+    };
+    let journal_key = journal_key(store_id);
+    let mut expired = 0;
+    transaction(c, &[&journal_key], |c, pipe| {
+        let raw: Vec<String> = c.lrange(&journal_key, 0, -1)?;
+        expired = raw
+            .iter()
+            .take_while(|e| Entry::parse(e).map_or(true, |e| e.at + max_age_s < now))
+            .count() as u64;
+        let leaving: Vec<Entry> = raw
+            .iter()
+            .take(expired as usize)
+            .filter_map(|e| Entry::parse(e))
+            .collect();
+        db::products::transaction_forget_removed(c, pipe, store_id, &leaving)?;
+        if retention().archive {
+            for entry in raw.iter().take(expired as usize) {
+                pipe.rpush(JOURNAL_ARCHIVE, format!("{}|{}", **store_id, entry))
+                    .ignore();
+            }
+        }
+        pipe.ltrim(&journal_key, expired as isize, -1)
+            .ignore()
+            .hincr(JOURNAL_METRICS, "expired", expired as i64)
+            .ignore();
+        pipe.query(c)
+    })?;
+    Ok(expired)
+}
+
+// Trim the journals of every store, returns how many entries went
+pub fn trim_journals(c: &mut Connection, now: u64) -> Result<u64> {
+    let mut expired = 0;
+    if retention().max_age_s.is_none() {
+        return Ok(expired);
+    }
+    for store_id in db::stores::get_all_store_ids(c)? {
+        expired += trim_journal(c, &store_id, now)?;
+    }
+    Ok(expired)
+}
+
+// An expired entry on its way to the archive
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ArchivedEntry {
+    pub store_id: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+// The oldest `max` entries waiting for the archive, and how many were read
+// counting the unreadable ones, to be dropped with `drop_archived` once
+// archived
+pub fn archived(c: &mut Connection, max: u64) -> Result<(Vec<ArchivedEntry>, u64)> {
+    let raw: Vec<String> = c.lrange(JOURNAL_ARCHIVE, 0, max as isize - 1)?;
+    let entries = raw
+        .iter()
+        .filter_map(|raw| {
+            let mut parts = raw.splitn(2, '|');
+            Some(ArchivedEntry {
+                store_id: parts.next()?.to_owned(),
+                entry: Entry::parse(parts.next()?)?,
+            })
+        })
+        .collect();
+    Ok((entries, raw.len() as u64))
+}
+
+pub fn drop_archived(c: &mut Connection, count: u64) -> Result<()> {
+    transaction(c, &[JOURNAL_ARCHIVE], |c, pipe| {
+        pipe.ltrim(JOURNAL_ARCHIVE, count as isize, -1)
+            .ignore()
+            .query(c)
+    })?;
+    Ok(())
+}
+
+pub fn stats(c: &mut Connection) -> Result<JournalStats> {
+    let mut stats = JournalStats::default();
+    for store_id in db::stores::get_all_store_ids(c)? {
+        let raw: Vec<String> = c.lrange(&journal_key(&store_id), 0, -1)?;
+        let len = raw.len() as u64;
+        stats.stores += 1;
+        stats.entries += len;
+        stats.longest = stats.longest.max(len);
+    }
+    let expired: Option<u64> = redis::cmd("HGET")
+        .arg(JOURNAL_METRICS)
+        .arg("expired")
+        .query(c)?;
+    stats.expired = expired.unwrap_or(0);
+    Ok(stats)
+}
+
+// to be used only in a transaction, doesn't execute the `pipe`
+pub fn transaction_purge_journal(pipe: &mut Pipeline, store_id: &StoreId) {
+    pipe.del(&journal_key(store_id))
+        .ignore()
+        .del(&store_version_key(store_id))
+        .ignore();
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    const STORE: &str = "journal_store";
+
+    fn record_for_test(c: &mut Connection, entity: Entity, change: Change, id: &str) -> u64 {
+        let store_id = StoreId::new(STORE.to_owned());
+        let mut pipe = Pipeline::new(c.db);
+        pipe.atomic();
+        assert_eq!(Ok(()), record(c, &mut pipe, &store_id, entity, change, id));
+        assert_eq!(Ok(()), pipe.query(c));
+        current_version(c, &store_id).unwrap()
+    }
+
+    #[test]
+    fn entry_format_test() {
+        let entry = Entry {
+            version: 3,
+            entity: Entity::Product,
+            change: Change::Deleted,
+            id: "pid".to_owned(),
+            at: 60,
+        };
+        assert_eq!("3|product|deleted|pid|60", entry.to_string());
+        assert_eq!(Some(entry), Entry::parse("3|product|deleted|pid|60"));
+        // from before the time was kept
+        assert_eq!(Some(0), Entry::parse("3|product|deleted|pid").map(|e| e.at));
+        assert_eq!(None, Entry::parse("3|product|deleted|pid|soon"));
+        assert_eq!(
+            Some(Change::Replaced),
+            Entry::parse("4|store|replaced|sid|60").map(|e| e.change)
+        );
+        assert_eq!(None, Entry::parse("3|shelf|deleted|pid"));
+        assert_eq!(None, Entry::parse("nope"));
+    }
+
+    #[test]
+    fn entries_since_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = StoreId::new(STORE.to_owned());
+        assert_eq!(Ok(0), current_version(&mut c, &store_id));
+        assert_eq!(
+            1,
+            record_for_test(&mut c, Entity::Aisle, Change::Updated, "a1")
+        );
+        assert_eq!(
+            2,
+            record_for_test(&mut c, Entity::Product, Change::Updated, "p1")
+        );
+        assert_eq!(
+            3,
+            record_for_test(&mut c, Entity::Product, Change::Deleted, "p1")
+        );
+        assert_eq!(Ok(3), current_version(&mut c, &store_id));
+
+        let entries = entries_since(&mut c, &store_id, 1).unwrap().unwrap();
+        assert_eq!(
+            vec![(2, Change::Updated), (3, Change::Deleted)],
+            entries
+                .iter()
+                .map(|e| (e.version, e.change))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(Some(vec![])), entries_since(&mut c, &store_id, 3));
+
+        // before a bulk change, a snapshot; after it, changes again
+        let store = STORE.to_owned();
+        assert_eq!(
+            4,
+            record_for_test(&mut c, Entity::Store, Change::Replaced, &store)
+        );
+        record_for_test(&mut c, Entity::Product, Change::Updated, "p2");
+        assert_eq!(Ok(None), entries_since(&mut c, &store_id, 3));
+        assert_eq!(
+            1,
+            entries_since(&mut c, &store_id, 4).unwrap().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn record_all_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = StoreId::new(STORE.to_owned());
+        let mut pipe = Pipeline::new(c.db);
+        pipe.atomic();
+        let changes = [
+            (Entity::Aisle, Change::Updated, "a1"),
+            (Entity::Product, Change::Deleted, "p1"),
+        ];
+        assert_eq!(Ok(()), record_all(&mut c, &mut pipe, &store_id, &changes));
+        // nothing moves before the transaction goes through
+        assert_eq!(Ok(0), current_version(&mut c, &store_id));
+        assert_eq!(Ok(()), pipe.query(&c));
+        assert_eq!(Ok(2), current_version(&mut c, &store_id));
+        let entries = entries_since(&mut c, &store_id, 0).unwrap().unwrap();
+        assert_eq!(
+            vec![(1, "a1"), (2, "p1")],
+            entries
+                .iter()
+                .map(|e| (e.version, e.id.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn entries_since_trimmed_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = StoreId::new(STORE.to_owned());
+        let max_len = retention().max_len;
+        for _ in 0..max_len + 2 {
+            record_for_test(&mut c, Entity::Aisle, Change::Updated, "a1");
+        }
+        assert_eq!(Ok(None), entries_since(&mut c, &store_id, 1));
+        assert_eq!(
+            max_len as usize,
+            entries_since(&mut c, &store_id, 2).unwrap().unwrap().len()
+        );
+
+        let mut pipe = Pipeline::new(c.db);
+        transaction_purge_journal(&mut pipe, &store_id);
+        assert_eq!(Ok(()), pipe.query(&c));
+        assert_eq!(Ok(false), c.exists(&journal_key(&store_id)));
+        assert_eq!(Ok(0), current_version(&mut c, &store_id));
+    }
+
+    #[test]
+    fn trim_journal_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        set_retention(Retention {
+            max_len: 3,
+            max_age_s: Some(60),
+            archive: false,
+        });
+        let mut pipe = Pipeline::new(c.db);
+        let changes: Vec<_> = ["a1", "a2", "a3", "a4"]
+            .iter()
+            .map(|id| (Entity::Aisle, Change::Updated, *id))
+            .collect();
+        assert_eq!(Ok(()), record_all(&mut c, &mut pipe, &store_id, &changes));
+        assert_eq!(Ok(()), pipe.query(&c));
+        // the last 3 of the 4 are kept
+        assert_eq!(Ok(None), entries_since(&mut c, &store_id, 0));
+        assert_eq!(
+            3,
+            entries_since(&mut c, &store_id, 1).unwrap().unwrap().len()
+        );
+        assert_eq!(Ok(0), trim_journal(&mut c, &store_id, now_s()));
+
+        // a minute later, all are too old
+        assert_eq!(Ok(3), trim_journals(&mut c, now_s() + 61));
+        assert_eq!(Ok(None), entries_since(&mut c, &store_id, 3));
+        assert_eq!(Ok(Some(vec![])), entries_since(&mut c, &store_id, 4));
+        assert_eq!(
+            Ok(JournalStats {
+                stores: 1,
+                entries: 0,
+                longest: 0,
+                expired: 3,
+            }),
+            stats(&mut c)
+        );
+    }
+
+    #[test]
+    fn archive_journal_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        set_retention(Retention {
+            max_age_s: Some(60),
+            archive: true,
+            ..Retention::default()
+        });
+        let mut pipe = Pipeline::new(c.db);
+        let changes: Vec<_> = ["a1", "a2", "a3"]
+            .iter()
+            .map(|id| (Entity::Aisle, Change::Updated, *id))
+            .collect();
+        assert_eq!(Ok(()), record_all(&mut c, &mut pipe, &store_id, &changes));
+        assert_eq!(Ok(()), pipe.query(&c));
+        assert_eq!(Ok((vec![], 0)), archived(&mut c, 10));
+
+        assert_eq!(Ok(3), trim_journal(&mut c, &store_id, now_s() + 61));
+        let (entries, read) = archived(&mut c, 2).unwrap();
+        assert_eq!(2, read);
+        assert_eq!(*store_id, entries[0].store_id);
+        assert_eq!("a1", entries[0].entry.id);
+        assert_eq!(2, entries[1].entry.version);
+        assert_eq!(Ok(()), drop_archived(&mut c, read));
+        let (entries, read) = archived(&mut c, 2).unwrap();
+        assert_eq!(1, read);
+        assert_eq!("a3", entries[0].entry.id);
+        assert_eq!(Ok(()), drop_archived(&mut c, read));
+        assert_eq!(Ok((vec![], 0)), archived(&mut c, 2));
+    }
+}
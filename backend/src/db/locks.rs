@@ -0,0 +1,389 @@
+use std::{
+    cell::Cell,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use crate::{db, error::*, types::*};
+
+// a crashed holder can't keep a store locked longer than this
+const LOCK_TTL_MS: u64 = 5_000;
+const ACQUIRE_TIMEOUT_MS: u64 = 2_000;
+const RETRY_DELAY_MS: u64 = 5;
+
+thread_local! {
+    // Whether this thread is a worker of the server's multi-threaded runtime,
+    // which can hand its other tasks over before waiting for a lock
+    static ON_WORKER: Cell<bool> = Cell::new(false);
+}
+
+fn store_lock_key(id: &StoreId) -> String {
+    format!("lock:store:{}", **id)
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// Flag the `workers` core threads of the runtime this runs on, see `acquire`.
+// Each flagging task holds its thread until all of them run, so no two share
+// one. The runtime's blocking pool threads, where waiting in place panics, are
+// left out.
+pub async fn mark_workers(workers: usize) {
+    let barrier = Arc::new(Barrier::new(workers));
+    let tasks: Vec<_> = (0..workers)
+        .map(|_| {
+            let barrier = barrier.clone();
+            tokio::spawn(async move {
+                ON_WORKER.with(|on_worker| on_worker.set(true));
+                barrier.wait();
+            })
+        })
+        .collect();
+    for task in tasks {
+        let _ = task.await;
+    }
+}
+
+// Run `f` while holding the lock of `store_id`, so the reads and writes of two
+// requests mutating the same store can't interleave. Only the store's owner
+// can take it, others can't keep it from them.
+pub fn with_store_lock<T, F>(
+    c: &mut Connection,
+    user_id: &UserId,
+    store_id: &StoreId,
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut Connection) -> Result<T>,
+{
+    db::assert_owner_or_member(user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    with_store_lock_unchecked(c, &store_id, f)
+}
+
+// `with_store_lock` for the server's own upkeep, with no user to check
+pub(crate) fn with_store_lock_unchecked<T, F>(
+    c: &mut Connection,
+    store_id: &StoreId,
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut Connection) -> Result<T>,
+{
+    let lock_key = store_lock_key(&store_id);
+    let deadline = acquire(c, &lock_key, ACQUIRE_TIMEOUT_MS)?;
+    let res = f(c);
+    // what `f` failed with comes first, an unreleased lock expires anyway
+    let released = release(c, &lock_key, deadline);
+    res.and_then(|v| released.map(|()| v))
+}
+
+// Both locks, always taken in the same order so that two requests locking the
+// same stores can't each wait for the other
+pub fn with_store_locks<T, F>(
+    c: &mut Connection,
+    user_id: &UserId,
+    a: &StoreId,
+    b: &StoreId,
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut Connection) -> Result<T>,
+{
+    let store_ids = [StoreId::new(a.to_string()), StoreId::new(b.to_string())];
+    with_all_store_locks(c, user_id, &store_ids, f)
+}
+
+// The locks of all the stores of the user, taken in the order of their keys
+pub fn with_all_store_locks<T, F>(
+    c: &mut Connection,
+    user_id: &UserId,
+    store_ids: &[StoreId],
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut Connection) -> Result<T>,
+{
+    for store_id in store_ids {
+        db::assert_owner_or_member(user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    }
+    let mut lock_keys: Vec<String> = store_ids.iter().map(store_lock_key).collect();
+    lock_keys.sort();
+    lock_keys.dedup();
+    let mut held = vec![];
+    let mut res = Ok(());
+    for lock_key in &lock_keys {
+        match acquire(c, lock_key, ACQUIRE_TIMEOUT_MS) {
+            Ok(deadline) => held.push((lock_key, deadline)),
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        }
+    }
+    let mut res = res.and_then(|()| f(c));
+    // every lock is let go even if one fails to be, the first error wins
+    for (lock_key, deadline) in held.into_iter().rev() {
+        let released = release(c, lock_key, deadline);
+        res = res.and_then(|v| released.map(|()| v));
+    }
+    res
+}
+
+// SETNX locking: the value is the deadline after which the lock is abandoned
+// and can be taken over with GETSET. Returns that deadline, None if the lock
+// is held.
+pub(crate) fn try_acquire(c: &mut Connection, lock_key: &str, ttl_ms: u64) -> Result<Option<u64>> {
+    let now = now_ms();
+    let deadline = now + ttl_ms;
+    if c.set_nx(lock_key, deadline)? {
+        return Ok(Some(deadline));
+    }
+    let held: Option<u64> = c.get(lock_key)?;
+    if held.map_or(true, |d| d < now) {
+        // another waiter may have taken it over first
+        let previous: Option<u64> = c.getset(lock_key, deadline)?;
+        if previous == held {
+            return Ok(Some(deadline));
+        }
+    }
+    Ok(None)
+}
+
+// Waiting in place would stall the other tasks of a runtime worker, so they
+// are handed over to another thread first. Off the server's runtime, as in
+// tests, the thread can simply sleep.
+fn acquire(c: &mut Connection, lock_key: &str, timeout_ms: u64) -> Result<u64> {
+    if let Some(deadline) = try_acquire(c, lock_key, LOCK_TTL_MS)? {
+        return Ok(deadline);
+    }
+    if ON_WORKER.with(Cell::get) {
+        tokio::task::block_in_place(|| wait_for(c, lock_key, timeout_ms))
+    } else {
+        wait_for(c, lock_key, timeout_ms)
+    }
+}
+
+fn wait_for(c: &mut Connection, lock_key: &str, timeout_ms: u64) -> Result<u64> {
+    let give_up = now_ms() + timeout_ms;
+    loop {
+        if now_ms() >= give_up {
+            return Err(ServerError::new(
+                STORE_BUSY,
+                "Store is being modified, try again",
+            ));
+        }
+        thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+        if let Some(deadline) = try_acquire(c, lock_key, LOCK_TTL_MS)? {
+            return Ok(deadline);
+        }
+    }
+}
+
+pub(crate) fn release(c: &mut Connection, lock_key: &str, deadline: u64) -> Result<()> {
+    // past its deadline the lock may belong to someone else now
+    let held: Option<u64> = c.get(lock_key)?;
+    if held == Some(deadline) {
+        c.del(lock_key)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::db::{self, ids::tests::*, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+    use warp::http::StatusCode;
+
+    #[test]
+    fn mark_workers_test() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(2)
+            .build()
+            .unwrap();
+        let marked = runtime.block_on(async {
+            mark_workers(2).await;
+            let mut marked = vec![];
+            for _ in 0..8 {
+                marked.push(
+                    tokio::spawn(async { ON_WORKER.with(Cell::get) })
+                        .await
+                        .unwrap(),
+                );
+            }
+            marked
+        });
+        assert_eq!(vec![true; 8], marked);
+        // not a worker itself
+        assert_eq!(false, ON_WORKER.with(Cell::get));
+    }
+
+    #[test]
+    fn lock_is_exclusive_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let lock_key = store_lock_key(&StoreId::new("s1".to_owned()));
+        let deadline = acquire(&mut c, &lock_key, 0).unwrap();
+        assert_eq!(
+            Err(STORE_BUSY),
+            acquire(&mut c, &lock_key, 0).map_err(|e| e.status)
+        );
+        assert_eq!(Ok(()), release(&mut c, &lock_key, deadline));
+        assert!(acquire(&mut c, &lock_key, 0).is_ok());
+    }
+
+    #[test]
+    fn all_store_locks_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let s1 = db::stores::tests::save_store_for_test(&mut c);
+        let s2 = db::stores::save_store(&mut c, &AUTH, "other").unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        let store = |id: &StoreId| StoreId::new(id.to_string());
+        let held = acquire(&mut c, &store_lock_key(&s2), 0).unwrap();
+        assert_eq!(
+            Err(STORE_BUSY),
+            with_all_store_locks(&mut c, &user_id, &[store(&s1), store(&s2)], |_| Ok(()))
+                .map_err(|e| e.status)
+        );
+        // the lock taken before giving up is let go
+        assert_eq!(Ok(false), c.exists(store_lock_key(&s1)));
+        release(&mut c, &store_lock_key(&s2), held).unwrap();
+        assert_eq!(
+            Ok(2),
+            with_all_store_locks(
+                &mut c,
+                &user_id,
+                &[store(&s2), store(&s1), store(&s2)],
+                |c| {
+                    let locked: bool = c.exists(store_lock_key(&s1))?;
+                    let also_locked: bool = c.exists(store_lock_key(&s2))?;
+                    Ok(locked as u8 + also_locked as u8)
+                }
+            )
+        );
+        assert_eq!(Ok(false), c.exists(store_lock_key(&s1)));
+    }
+
+    #[test]
+    fn only_owner_takes_lock_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        let intruder = UserId(HASH_2.to_owned());
+        let held = acquire(&mut c, &store_lock_key(&store_id), 0).unwrap();
+        // turned away before waiting for the lock, and without taking it
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            with_store_lock(&mut c, &intruder, &store_id, |_| Ok(())).map_err(|e| e.status)
+        );
+        release(&mut c, &store_lock_key(&store_id), held).unwrap();
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            with_store_lock(&mut c, &intruder, &store_id, |_| Ok(())).map_err(|e| e.status)
+        );
+        assert_eq!(Ok(false), c.exists(store_lock_key(&store_id)));
+    }
+
+    #[test]
+    fn error_of_f_is_returned_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        let user_id = UserId(HASH_1.to_owned());
+        assert_eq!(
+            Err(db::not_found()),
+            with_store_lock::<(), _>(&mut c, &user_id, &store_id, |_| Err(db::not_found()))
+        );
+        // and the lock is let go all the same
+        assert_eq!(Ok(false), c.exists(store_lock_key(&store_id)));
+    }
+
+    #[test]
+    fn abandoned_lock_is_taken_over_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let lock_key = store_lock_key(&StoreId::new("s1".to_owned()));
+        let _: () = c.set(&lock_key, now_ms() - 1).unwrap();
+        let deadline = acquire(&mut c, &lock_key, 0).unwrap();
+        assert_eq!(Ok(Some(deadline)), c.get(&lock_key));
+        // the previous holder finishing late must not release our lock
+        assert_eq!(Ok(()), release(&mut c, &lock_key, deadline - 1));
+        assert_eq!(Ok(Some(deadline)), c.get(&lock_key));
+    }
+
+    // run `f` on `n` threads at once, each with its own connection to `db_addr`
+    fn run_concurrently<T, F>(db_addr: &str, n: usize, f: F) -> Vec<T>
+    where
+        T: Send + 'static,
+        F: Fn(&mut Connection, usize) -> T + Send + Sync + 'static,
+    {
+        let f = std::sync::Arc::new(f);
+        let handles: Vec<_> = (0..n)
+            .map(|i| {
+                let client = Client::open(db_addr).unwrap();
+                let f = f.clone();
+                thread::spawn(move || f(&mut client.get_connection().unwrap(), i))
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn concurrent_save_aisle_test() {
+        let db_addr = get_db_addr();
+        let client = Client::open(db_addr.as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        let id = StoreId::new(store_id.to_string());
+        run_concurrently(&db_addr, 16, move |c, i| {
+            db::aisles::save_aisle(c, &AUTH, &id, &i.to_string()).unwrap()
+        });
+        // each aisle read the max weight after the previous one was written
+        let mut weights: Vec<_> = db::aisles::get_aisles_in_store(&mut c, &store_id)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.sort_weight as u32)
+            .collect();
+        weights.sort();
+        assert_eq!((1..=16).collect::<Vec<_>>(), weights);
+    }
+
+    #[test]
+    fn concurrent_delete_aisle_and_save_product_test() {
+        let db_addr = get_db_addr();
+        let client = Client::open(db_addr.as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::stores::tests::save_store_for_test(&mut c);
+        let aisle = db::aisles::save_aisle(&mut c, &AUTH, &store_id, "aisle").unwrap();
+        let aisle_id = aisle.id();
+        let products = run_concurrently(&db_addr, 16, move |c, i| {
+            if i == 8 {
+                db::aisles::delete_aisle(c, &AUTH, &aisle_id).unwrap();
+                None
+            } else {
+                db::products::save_product(c, &AUTH, "product", &aisle_id).ok()
+            }
+        });
+        // a product is either purged with its aisle or never saved, none is left behind
+        for product in products.into_iter().flatten() {
+            assert_eq!(
+                Ok(None),
+                db::products::get_product_change(&mut c, &product.id())
+            );
+        }
+    }
+}
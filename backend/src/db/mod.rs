@@ -1,36 +1,63 @@
 #[cfg(not(test))]
-use redis::{self, Connection};
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
 
+pub mod abuse;
 pub mod aisles;
+pub mod aliases;
+pub mod anywhere;
+pub mod community;
+pub mod crdt;
+pub mod events;
 pub mod ids;
+pub mod invites;
+pub mod jobs;
+pub mod journal;
+pub mod locks;
+pub mod nutrition;
+pub mod pairing;
+pub mod placement;
 pub mod products;
+pub mod quotas;
+pub mod schedule;
 pub mod sessions;
+pub mod snapshots;
+pub mod staples;
+pub mod stats;
 pub mod stores;
+pub mod templates;
 pub mod users;
+pub mod version;
+
+use warp::http::StatusCode;
 
 use crate::{error::*, types::*};
 
-pub(crate) fn verify_permission(wanted_user_id: &UserId, user_id: &UserId) -> Result<()> {
-    if wanted_user_id != user_id {
-        Err(ServerError::new(
-            PERMISSION_DENIED,
-            "User does not have permission to edit this resource",
-        ))
+// A store, aisle or product which doesn't exist, or isn't the user's: the
+// answer is the same so that ids can't be probed
+pub(crate) fn not_found() -> ServerError {
+    ServerError::new(StatusCode::NOT_FOUND, "Not found")
+}
+
+// Whether `user_id` may see and edit an object owned by `owner_id`. Stores
+// have no members besides their owner, so it is the owner only.
+pub(crate) fn assert_owner_or_member(user_id: &UserId, owner_id: &UserId) -> Result<()> {
+    if user_id != owner_id {
+        Err(not_found())
     } else {
         Ok(())
     }
 }
 
-pub(crate) fn verify_permission_auth(
+pub(crate) fn assert_owner_or_member_auth(
     c: &mut Connection,
     auth: &Auth,
-    user_id: &UserId,
+    owner_id: &UserId,
 ) -> Result<()> {
-    let wanted_user_id = sessions::get_user_id(c, &auth)?;
-    verify_permission(&wanted_user_id, &user_id)
+    let user_id = sessions::get_user_id(c, &auth)?;
+    assert_owner_or_member(&user_id, &owner_id)
 }
 
 #[cfg(test)]
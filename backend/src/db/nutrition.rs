@@ -0,0 +1,78 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use crate::{db, error::Result, nutrition::Lookup, types::*};
+
+// how long the source's answers are kept, shorter when it knew nothing as it
+// may learn of the product
+const KNOWN_TTL_S: usize = 30 * 24 * 3600;
+const UNKNOWN_TTL_S: usize = 24 * 3600;
+
+// JSON of the source's answer, `null` if it knew nothing
+fn nutrition_key(lookup: &Lookup) -> String {
+    format!("nutrition:{}", lookup.cache_key())
+}
+
+// None if not cached, Some(None) if the source knew nothing. An entry that
+// doesn't parse is looked up again.
+pub fn get_cached(c: &mut Connection, lookup: &Lookup) -> Result<Option<Option<Nutrition>>> {
+    let raw: Option<String> = c.get(&nutrition_key(&lookup))?;
+    Ok(raw.and_then(|raw| serde_json::from_str(&raw).ok()))
+}
+
+pub fn cache(c: &mut Connection, lookup: &Lookup, nutrition: &Option<Nutrition>) -> Result<()> {
+    let key = nutrition_key(&lookup);
+    let raw = serde_json::to_string(nutrition).expect("nutrition always serializes");
+    let ttl_s = if nutrition.is_some() {
+        KNOWN_TTL_S
+    } else {
+        UNKNOWN_TTL_S
+    };
+    c.set(&key, raw)?;
+    Ok(c.expire(&key, ttl_s)?)
+}
+
+// The name of a product of the user's
+pub fn get_product_name(c: &mut Connection, auth: &Auth, product_id: &ProductId) -> Result<String> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let store_id = db::products::find_product_store(c, &product_id)?.ok_or_else(db::not_found)?;
+    db::assert_owner_or_member(&user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    match db::products::get_product_change(c, &product_id)? {
+        Some(change) => Ok(change.product.name),
+        None => Err(db::not_found()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn cache_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let milk = Lookup::Name("Milk".to_owned());
+        let nutrition = Nutrition {
+            energy_kcal: Some(64.0),
+            ..Nutrition::default()
+        };
+        assert_eq!(Ok(None), get_cached(&mut c, &milk));
+        assert_eq!(Ok(()), cache(&mut c, &milk, &Some(nutrition.clone())));
+        assert_eq!(
+            Ok(Some(Some(nutrition))),
+            get_cached(&mut c, &Lookup::Name("milk ".to_owned()))
+        );
+
+        let unknown = Lookup::Barcode("0000".to_owned());
+        assert_eq!(Ok(()), cache(&mut c, &unknown, &None));
+        assert_eq!(Ok(Some(None)), get_cached(&mut c, &unknown));
+        assert_eq!(Ok(UNKNOWN_TTL_S as i64), c.ttl(&nutrition_key(&unknown)));
+    }
+}
@@ -0,0 +1,93 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use rand::Rng;
+
+use crate::{
+    db,
+    error::{self, Result, ServerError},
+    types::*,
+};
+
+pub const PAIRING_TTL_S: u64 = 120;
+const CODE_LEN: usize = 8;
+// no 0/O nor 1/I/L, the code may be typed on a remote control
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+fn pairing_key(code: &str) -> String {
+    format!("pairing:{}", code)
+}
+
+// Short enough to be typed, also the codes of shared templates
+pub(crate) fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0, CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// A code letting another device open a session of the user, once and for
+// PAIRING_TTL_S seconds
+pub fn mint_code(c: &mut Connection, auth: &Auth) -> Result<PairingCode> {
+    let user_id = db::sessions::get_user_id(c, auth)?;
+    let code = new_code();
+    let key = pairing_key(&code);
+    c.set(&key, user_id.to_string())?;
+    c.expire(&key, PAIRING_TTL_S as usize)?;
+    Ok(PairingCode {
+        code,
+        expires_in_s: PAIRING_TTL_S,
+    })
+}
+
+// The new device's own session, the code is then spent
+pub fn claim_code(c: &mut Connection, code: &str) -> Result<ConnectionToken> {
+    let key = pairing_key(&code.trim().to_uppercase());
+    let user_id: Option<String> = c.get(&key)?;
+    let invalid = || ServerError::new(error::UNAUTHORISED, "Invalid or expired pairing code");
+    let user_id = user_id.ok_or_else(invalid)?;
+    // only the first of two devices racing for the code gets it
+    let spent: bool = c.del(&key)?;
+    if !spent {
+        return Err(invalid());
+    }
+    db::users::open_session(c, &UserId(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{tests::*, users::tests::*};
+    use fake_redis::FakeCient as Client;
+    use std::time::Duration;
+
+    #[test]
+    fn pairing_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let token = store_user_for_test(&mut c);
+        let auth = Auth(&token.session_token);
+
+        let pairing = mint_code(&mut c, &auth).unwrap();
+        assert_eq!(CODE_LEN, pairing.code.len());
+        let paired = claim_code(&mut c, &pairing.code.to_lowercase()).unwrap();
+        assert_eq!(token.user_id, paired.user_id);
+        assert_ne!(token.session_token, paired.session_token);
+        assert_eq!(
+            Ok(()),
+            db::sessions::validate_session(&mut c, &Auth(&paired.session_token))
+        );
+        // spent
+        assert_eq!(true, claim_code(&mut c, &pairing.code).is_err());
+
+        let pairing = mint_code(&mut c, &auth).unwrap();
+        c.advance_time(Duration::from_secs(PAIRING_TTL_S));
+        assert_eq!(true, claim_code(&mut c, &pairing.code).is_err());
+        assert_eq!(true, mint_code(&mut c, &Auth("nope")).is_err());
+    }
+}
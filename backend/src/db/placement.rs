@@ -0,0 +1,114 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{Commands, Pipeline};
+
+#[cfg(test)]
+use fake_redis::{FakeConnection as Connection, FakePipeline as Pipeline};
+
+use crate::{
+    db::{self, aliases::normalized},
+    error::Result,
+    types::*,
+};
+
+// where the products of no aisle we know of go, made in the store on demand
+pub const UNSORTED_AISLE: &str = "Unsorted";
+
+// The name of the aisle the user last added each product to, by canonical
+// name, to put it in the aisle of that name when added with none. By name as
+// every store has its own aisles.
+fn history_key(user_id: &UserId) -> String {
+    format!("aisle_history:{}", **user_id)
+}
+
+// to be used in the transaction adding the product, the unsorted aisle tells
+// nothing of where it goes
+pub fn transaction_learn(pipe: &mut Pipeline, user_id: &UserId, canonical: &str, aisle: &str) {
+    if normalized(aisle) != normalized(UNSORTED_AISLE) {
+        pipe.hset(&history_key(&user_id), canonical, aisle).ignore();
+    }
+}
+
+pub fn delete_history(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&history_key(&user_id))?)
+}
+
+// Add the product to the aisle of the store the user put it in last, else to
+// the unsorted one
+pub fn save_product_in_store(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    name: &str,
+) -> Result<PlacedProduct> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let prod_id = db::ids::get_next_product_id();
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let canonical = db::aliases::canonical(c, &user_id, name)?;
+        let learnt: Option<String> = c.hget(&history_key(&user_id), &canonical)?;
+        let aisles = db::aisles::get_aisles_in_store(c, &store_id)?;
+        let find = |wanted: &str| {
+            aisles
+                .iter()
+                .find(|a| normalized(&a.name) == normalized(wanted))
+                .map(|a| AisleId(a.aisle_id.clone()))
+        };
+        let (aisle_id, guess) = match learnt.as_deref().and_then(find) {
+            Some(aisle_id) => (aisle_id, AisleGuess::History),
+            None => match find(UNSORTED_AISLE) {
+                Some(aisle_id) => (aisle_id, AisleGuess::Unsorted),
+                None => {
+                    let aisle = db::aisles::insert_aisle(c, &user_id, &store_id, UNSORTED_AISLE)?;
+                    (AisleId(aisle.aisle_id), AisleGuess::NewUnsorted)
+                }
+            },
+        };
+        let product =
+            db::products::insert_product(c, &user_id, &store_id, &prod_id, name, &aisle_id)?;
+        let aisle_name = db::aisles::get_aisle_name(c, &aisle_id)?;
+        Ok(PlacedProduct::new(
+            aisle_id.to_string(),
+            aisle_name,
+            guess,
+            product,
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{aisles::tests::save_aisle_for_test, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn save_product_in_store_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, aisle_id) = save_aisle_for_test(&mut c);
+        let placed = save_product_in_store(&mut c, &AUTH, &store_id, "Milk").unwrap();
+        assert_eq!(AisleGuess::NewUnsorted, placed.guess);
+        assert_eq!(UNSORTED_AISLE, placed.aisle_name);
+        let unsorted = placed.aisle_id;
+        let placed = save_product_in_store(&mut c, &AUTH, &store_id, "milk").unwrap();
+        assert_eq!(AisleGuess::Unsorted, placed.guess);
+        assert_eq!(unsorted, placed.aisle_id);
+
+        // the aisle it was last added to wins, in any store with one of that
+        // name
+        db::products::save_product(&mut c, &AUTH, "Bread", &aisle_id).unwrap();
+        let placed = save_product_in_store(&mut c, &AUTH, &store_id, " bread").unwrap();
+        assert_eq!(AisleGuess::History, placed.guess);
+        assert_eq!(aisle_id.to_string(), placed.aisle_id);
+        assert_eq!(" bread", placed.product.name);
+        let other_store = db::stores::save_store(&mut c, &AUTH, "Other").unwrap();
+        let placed = save_product_in_store(&mut c, &AUTH, &other_store, "Bread").unwrap();
+        assert_eq!(AisleGuess::NewUnsorted, placed.guess);
+        let aisle = db::aisles::get_aisle_name(&mut c, &aisle_id).unwrap();
+        let other_aisle = db::aisles::save_aisle(&mut c, &AUTH, &other_store, &aisle).unwrap();
+        let placed = save_product_in_store(&mut c, &AUTH, &other_store, "Bread").unwrap();
+        assert_eq!(AisleGuess::History, placed.guess);
+        assert_eq!(other_aisle.aisle_id, placed.aisle_id);
+    }
+}
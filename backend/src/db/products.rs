@@ -1,12 +1,22 @@
-use std::convert::From;
+use std::{collections::BTreeMap, convert::From};
 
 #[cfg(not(test))]
-use redis::{self, transaction, Commands, Connection, Pipeline};
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands, Pipeline};
 
 #[cfg(test)]
 use fake_redis::{transaction, FakeConnection as Connection, FakePipeline as Pipeline};
 
-use crate::{db, error::Result, types::*};
+use crate::{
+    db::{
+        self,
+        journal::{Change, Entity, Entry},
+    },
+    error::{Result, ServerError, STORE_BUSY},
+    links::now_s,
+    types::*,
+};
 
 const PROD_NAME: &str = "name";
 const PROD_SORT_WEIGHT: &str = "sort_weight";
@@ -15,6 +25,11 @@ const PROD_OWNER: &str = "product_owner";
 const PROD_QTY: &str = "quantity";
 const PROD_UNIT: &str = "unit";
 const PROD_AISLE: &str = "aisle";
+// when the fields were last edited, in ms, missing until they are
+const PROD_NAME_AT: &str = "name_at";
+const PROD_STATE_AT: &str = "is_done_at";
+const PROD_QTY_AT: &str = "quantity_at";
+const PROD_UNIT_AT: &str = "unit_at";
 
 pub fn product_key(id: &ProductId) -> String {
     format!("product:{}", **id)
@@ -24,28 +39,129 @@ pub fn products_in_aisle_key(id: &AisleId) -> String {
     format!("products_in_aisle:{}", **id)
 }
 
+// ids of the products removed from the store, so that clients who haven't
+// seen the removal can't add them back, see `db::crdt`. They are kept as long
+// as the journal has the removal.
+pub fn removed_products_key(id: &StoreId) -> String {
+    format!("removed_products:{}", **id)
+}
+
+// Not found if there is no such product
 fn get_product_owner(c: &mut Connection, id: &ProductId) -> Result<UserId> {
-    Ok(UserId(c.hget(&product_key(&id), PROD_OWNER)?))
+    let owner_id: Option<String> = c.hget(&product_key(&id), PROD_OWNER)?;
+    owner_id.map(UserId).ok_or_else(db::not_found)
+}
+
+fn get_product_store(c: &mut Connection, id: &ProductId) -> Result<StoreId> {
+    find_product_store(c, &id)?.ok_or_else(db::not_found)
+}
+
+// None if there is no such product
+pub fn find_product_store(c: &mut Connection, id: &ProductId) -> Result<Option<StoreId>> {
+    let aisle_id: Option<String> = c.hget(&product_key(&id), PROD_AISLE)?;
+    aisle_id
+        .map(|a| db::aisles::get_aisle_store(c, &AisleId(a)))
+        .transpose()
+}
+
+fn get_product(c: &mut Connection, id: &ProductId) -> Result<Product> {
+    let product_key = product_key(&id);
+    let unit: u32 = c.hget(&product_key, PROD_UNIT)?;
+    let state: i32 = c.hget(&product_key, PROD_STATE)?;
+    let state = state != 0;
+    Ok(Product::new(
+        id.to_string(),
+        c.hget(&product_key, PROD_NAME)?,
+        c.hget(&product_key, PROD_QTY)?,
+        state,
+        Unit::from(unit),
+        c.hget(&product_key, PROD_SORT_WEIGHT)?,
+    ))
+}
+
+pub fn get_crdt_product(c: &mut Connection, id: &ProductId) -> Result<CrdtProduct> {
+    let product_key = product_key(&id);
+    let product = get_product(c, &id)?;
+    Ok(CrdtProduct::new(
+        id.to_string(),
+        c.hget(&product_key, PROD_AISLE)?,
+        Stamped::new(product.name, edited_at(c, &product_key, PROD_NAME_AT)?),
+        Stamped::new(product.quantity, edited_at(c, &product_key, PROD_QTY_AT)?),
+        Stamped::new(product.unit, edited_at(c, &product_key, PROD_UNIT_AT)?),
+        Stamped::new(product.is_done, edited_at(c, &product_key, PROD_STATE_AT)?),
+    ))
+}
+
+// 0 if never edited
+fn edited_at(c: &mut Connection, product_key: &str, field_at: &str) -> Result<u64> {
+    let at: Option<u64> = c.hget(product_key, field_at)?;
+    Ok(at.unwrap_or(0))
+}
+
+pub fn get_product_change(c: &mut Connection, id: &ProductId) -> Result<Option<ProductChange>> {
+    let product_key = product_key(&id);
+    if c.exists(&product_key)? {
+        Ok(Some(ProductChange::new(
+            c.hget(&product_key, PROD_AISLE)?,
+            get_product(c, &id)?,
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
+// The edits of the product still in its store's journal, newest first, and
+// how it is bought. Only the last edit of each field is known, it labels the
+// entry it made.
+pub fn get_product_history(
+    c: &mut Connection,
+    auth: &Auth,
+    product_id: &ProductId,
+) -> Result<ProductHistory> {
+    let store_id = get_product_store(c, &product_id)?;
+    let product_owner = get_product_owner(c, &product_id)?;
+    db::assert_owner_or_member_auth(c, &auth, &product_owner)?;
+    let product_key = product_key(&product_id);
+    let mut last_edits = vec![];
+    for (field, field_at) in &[
+        ("name", PROD_NAME_AT),
+        ("quantity", PROD_QTY_AT),
+        ("unit", PROD_UNIT_AT),
+        ("is_done", PROD_STATE_AT),
+    ] {
+        let at: Option<u64> = c.hget(&product_key, *field_at)?;
+        if let Some(at) = at {
+            last_edits.push((*field, at / 1000));
+        }
+    }
+    let entries = db::journal::entries_about(c, &store_id, &product_id)?;
+    let mut edits = vec![];
+    for entry in entries.iter().rev() {
+        if entry.entity != Entity::Product || entry.change != Change::Updated || entry.at == 0 {
+            continue;
+        }
+        // the entry is made a moment after the edit
+        let (fields, others): (Vec<_>, Vec<_>) = last_edits
+            .into_iter()
+            .partition(|(_, at)| *at <= entry.at && entry.at <= at + 1);
+        last_edits = others;
+        let fields = fields.into_iter().map(|(f, _)| f.to_owned()).collect();
+        edits.push(ProductEdit::new(entry.at, fields));
+    }
+    let name: String = c.hget(&product_key, PROD_NAME)?;
+    let name = db::aliases::canonical(c, &product_owner, &name)?;
+    Ok(ProductHistory::new(
+        product_id.to_string(),
+        edits,
+        db::stats::get_purchase_habit(c, &store_id, &name)?,
+    ))
 }
 
 pub fn get_products_in_aisle(c: &mut Connection, aisle_id: &AisleId) -> Result<Vec<Product>> {
     let products: Vec<String> = c.smembers(&products_in_aisle_key(&aisle_id))?;
     products
         .into_iter()
-        .map(|p| {
-            let product_key = product_key(&ProductId(p.clone()));
-            let unit: u32 = c.hget(&product_key, PROD_UNIT)?;
-            let state: i32 = c.hget(&product_key, PROD_STATE)?;
-            let state = state != 0;
-            Ok(Product::new(
-                p,
-                c.hget(&product_key, PROD_NAME)?,
-                c.hget(&product_key, PROD_QTY)?,
-                state,
-                Unit::from(unit),
-                c.hget(&product_key, PROD_SORT_WEIGHT)?,
-            ))
-        })
+        .map(|p| get_product(c, &ProductId(p)))
         .collect()
 }
 
@@ -60,111 +176,617 @@ pub fn save_product(
     name: &str,
     aisle_id: &AisleId,
 ) -> Result<Product> {
-    let aisle_owner = db::aisles::get_aisle_owner(c, &aisle_id)?;
+    let store_id = db::aisles::get_aisle_store(c, &aisle_id)?;
     let user_id = db::sessions::get_user_id(c, &auth)?;
-    db::verify_permission(&user_id, &aisle_owner)?;
     let prod_id = db::ids::get_next_product_id();
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        insert_product(c, &user_id, &store_id, &prod_id, name, &aisle_id)
+    })
+}
+
+// to be called with the store lock held
+pub(crate) fn insert_product(
+    c: &mut Connection,
+    user_id: &UserId,
+    store_id: &StoreId,
+    prod_id: &ProductId,
+    name: &str,
+    aisle_id: &AisleId,
+) -> Result<Product> {
     let prod_key = product_key(&prod_id);
     let prod_in_aisle_key = products_in_aisle_key(&aisle_id);
+    // the aisle may have been deleted while we waited for the lock
+    let aisle_owner = db::aisles::get_aisle_owner(c, &aisle_id)?;
+    db::assert_owner_or_member(&user_id, &aisle_owner)?;
+    let size = db::quotas::entity_size(name);
+    db::quotas::check_products(c, &prod_in_aisle_key)?;
+    db::quotas::check_bytes(c, &user_id, size)?;
     let new_sort_weight = find_max_weight_in_aisle(c, &aisle_id)? + 1f32;
+    let canonical = db::aliases::canonical(c, &user_id, name)?;
+    let aisle_name = db::aisles::get_aisle_name(c, &aisle_id)?;
+    let defaults = db::stores::get_product_defaults(c, &store_id)?;
     transaction(c, &[&prod_key, &prod_in_aisle_key], |c, pipe| {
+        db::journal::record(
+            c,
+            pipe,
+            &store_id,
+            Entity::Product,
+            Change::Updated,
+            &prod_id,
+        )?;
+        db::quotas::transaction_charge(c, pipe, &user_id, size)?;
+        db::stores::transaction_count_items(c, pipe, &store_id, 1, 1)?;
+        db::stats::transaction_count_added(pipe, &store_id, &canonical, now_s());
+        db::placement::transaction_learn(pipe, &user_id, &canonical, &aisle_name);
         pipe.hset(&prod_key, PROD_NAME, name)
             .ignore()
-            .hset(&prod_key, PROD_QTY, 1)
+            .hset(&prod_key, PROD_QTY, defaults.quantity)
             .ignore()
             .hset(&prod_key, PROD_SORT_WEIGHT, new_sort_weight)
             .ignore()
             .hset(&prod_key, PROD_STATE, false as i32)
             .ignore()
-            .hset(&prod_key, PROD_OWNER, &*user_id)
+            .hset(&prod_key, PROD_OWNER, &**user_id)
             .ignore()
-            .hset(&prod_key, PROD_UNIT, u32::from(Unit::Unit))
+            .hset(&prod_key, PROD_UNIT, u32::from(defaults.unit.clone()))
             .ignore()
             .hset(&prod_key, PROD_AISLE, &**aisle_id)
             .ignore()
-            .sadd(&prod_in_aisle_key, &*prod_id)
+            .sadd(&prod_in_aisle_key, &**prod_id)
             .query(c)
     })?;
     Ok(Product::new(
         prod_id.to_string(),
         name.to_owned(),
-        1,
+        defaults.quantity,
         false,
-        Unit::Unit,
+        defaults.unit,
         new_sort_weight,
     ))
 }
 
+fn is_latest(
+    c: &mut Connection,
+    product_key: &str,
+    field_at: &str,
+    edited_at: u64,
+) -> Result<bool> {
+    let at: Option<u64> = c.hget(product_key, field_at)?;
+    Ok(at.map_or(true, |at| at <= edited_at))
+}
+
+// The part of the edit made after the last edit of each field: last writer
+// wins field by field, so servers and offline clients sending the same
+// edits in different orders end up with the same product. `bought` changes
+// both the quantity and the state, it needs to be the latest of both.
+fn latest_fields(
+    c: &mut Connection,
+    product_key: &str,
+    edit_data: &EditProduct,
+    edited_at: u64,
+) -> Result<EditProduct> {
+    let name = is_latest(c, product_key, PROD_NAME_AT, edited_at)?;
+    let quantity = is_latest(c, product_key, PROD_QTY_AT, edited_at)?;
+    let unit = is_latest(c, product_key, PROD_UNIT_AT, edited_at)?;
+    let is_done = is_latest(c, product_key, PROD_STATE_AT, edited_at)?;
+    let mut latest = EditProduct::new(
+        edit_data.name.clone().filter(|_| name),
+        edit_data.quantity.filter(|_| quantity),
+        edit_data.unit.clone().filter(|_| unit),
+        edit_data.is_done.filter(|_| is_done),
+    );
+    latest.bought = edit_data.bought.filter(|_| quantity && is_done);
+    latest.edited_at = Some(edited_at);
+    Ok(latest)
+}
+
 pub fn modify_product(
     c: &mut Connection,
     auth: &Auth,
     edit_data: &EditProduct,
     product_id: &ProductId,
 ) -> Result<()> {
-    let product_owner = get_product_owner(c, &product_id)?;
-    db::verify_permission_auth(c, &auth, &product_owner)?;
-    let product_key = product_key(&product_id);
-    if let Some(ref new_name) = edit_data.name {
-        c.hset(&product_key, PROD_NAME, new_name)?;
+    let store_id = get_product_store(c, &product_id)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let product_owner = get_product_owner(c, &product_id)?;
+        db::assert_owner_or_member(&user_id, &product_owner)?;
+        apply_edit(c, &store_id, &product_owner, &product_id, edit_data)
+    })
+}
+
+// Apply the fields of `theirs` newer than ours, see `db::crdt`. The fields
+// edited at the same time go in one edit. To be called with the store lock held
+pub(crate) fn merge_product_fields(
+    c: &mut Connection,
+    store_id: &StoreId,
+    product_owner: &UserId,
+    theirs: &CrdtProduct,
+) -> Result<()> {
+    let product_id = ProductId(theirs.product_id.clone());
+    let ours = get_crdt_product(c, &product_id)?;
+    let now = db::locks::now_ms();
+    let mut edits = BTreeMap::new();
+    if (theirs.name.at.min(now), &theirs.name.value) > (ours.name.at, &ours.name.value) {
+        edit_at(&mut edits, theirs.name.at.min(now)).name = Some(theirs.name.value.clone());
     }
-    if let Some(qty) = edit_data.quantity {
-        c.hset(&product_key, PROD_QTY, qty)?;
+    if (theirs.quantity.at.min(now), theirs.quantity.value)
+        > (ours.quantity.at, ours.quantity.value)
+    {
+        edit_at(&mut edits, theirs.quantity.at.min(now)).quantity = Some(theirs.quantity.value);
     }
-    if let Some(is_done) = edit_data.is_done {
-        c.hset(&product_key, PROD_STATE, is_done as i32)?;
+    if (
+        theirs.unit.at.min(now),
+        u32::from(theirs.unit.value.clone()),
+    ) > (ours.unit.at, u32::from(ours.unit.value))
+    {
+        edit_at(&mut edits, theirs.unit.at.min(now)).unit = Some(theirs.unit.value.clone());
     }
-    if let Some(unit) = &edit_data.unit {
-        c.hset(&product_key, PROD_UNIT, u32::from(unit.clone()))?;
+    if (theirs.is_done.at.min(now), theirs.is_done.value) > (ours.is_done.at, ours.is_done.value) {
+        edit_at(&mut edits, theirs.is_done.at.min(now)).is_done = Some(theirs.is_done.value);
+    }
+    for (at, mut edit) in edits {
+        edit.edited_at = Some(at);
+        apply_edit(c, &store_id, &product_owner, &product_id, &edit)?;
     }
     Ok(())
 }
 
+fn edit_at(edits: &mut BTreeMap<u64, EditProduct>, at: u64) -> &mut EditProduct {
+    edits
+        .entry(at)
+        .or_insert_with(|| EditProduct::new(None, None, None, None))
+}
+
+// to be called with the store lock held, once the permission is checked
+fn apply_edit(
+    c: &mut Connection,
+    store_id: &StoreId,
+    product_owner: &UserId,
+    product_id: &ProductId,
+    edit_data: &EditProduct,
+) -> Result<()> {
+    let product_key = product_key(&product_id);
+    // a time ahead of ours counts as now, or a client with its clock
+    // ahead would hold the fields it edits
+    let now = db::locks::now_ms();
+    let edited_at = edit_data.edited_at.map_or(now, |at| at.min(now));
+    let edit_data = &latest_fields(c, &product_key, edit_data, edited_at)?;
+    if !edit_data.has_at_least_a_field() {
+        return Ok(());
+    }
+    let growth = match edit_data.name {
+        Some(ref new_name) => {
+            let old_name: String = c.hget(&product_key, PROD_NAME)?;
+            new_name.len() as i64 - old_name.len() as i64
+        }
+        None => 0,
+    };
+    db::quotas::check_bytes(c, &product_owner, growth)?;
+    transaction(c, &[&product_key], |c, pipe| {
+        db::journal::record(
+            c,
+            pipe,
+            &store_id,
+            Entity::Product,
+            Change::Updated,
+            &product_id,
+        )?;
+        db::quotas::transaction_charge(c, pipe, &product_owner, growth)?;
+        // buying part of what's left lowers the quantity, buying the rest
+        // checks it off
+        let (is_done, quantity, mut purchased) = match edit_data.bought {
+            Some(bought) => {
+                let was_done: i32 = c.hget(&product_key, PROD_STATE)?;
+                let qty: u32 = c.hget(&product_key, PROD_QTY)?;
+                if was_done == 0 && bought < qty {
+                    (None, Some(qty - bought), true)
+                } else {
+                    (Some(true), None, false)
+                }
+            }
+            None => (edit_data.is_done, edit_data.quantity, false),
+        };
+        if let Some(is_done) = is_done {
+            let was_done: i32 = c.hget(&product_key, PROD_STATE)?;
+            let left = was_done as i64 - is_done as i64;
+            db::stores::transaction_count_items(c, pipe, &store_id, 0, left)?;
+            purchased |= left < 0;
+        }
+        if purchased {
+            let name = match edit_data.name {
+                Some(ref new_name) => new_name.clone(),
+                None => c.hget(&product_key, PROD_NAME)?,
+            };
+            let name = db::aliases::canonical(c, &product_owner, &name)?;
+            let bought = match (edit_data.bought, quantity) {
+                (Some(bought), _) | (None, Some(bought)) => bought,
+                (None, None) => c.hget(&product_key, PROD_QTY)?,
+            };
+            db::stats::transaction_count_purchase(c, pipe, &store_id, &name, bought, now_s())?;
+        }
+        if let Some(ref new_name) = edit_data.name {
+            pipe.hset(&product_key, PROD_NAME, new_name)
+                .ignore()
+                .hset(&product_key, PROD_NAME_AT, edited_at)
+                .ignore();
+        }
+        if let Some(qty) = quantity {
+            pipe.hset(&product_key, PROD_QTY, qty)
+                .ignore()
+                .hset(&product_key, PROD_QTY_AT, edited_at)
+                .ignore();
+        }
+        if let Some(is_done) = is_done {
+            pipe.hset(&product_key, PROD_STATE, is_done as i32)
+                .ignore()
+                .hset(&product_key, PROD_STATE_AT, edited_at)
+                .ignore();
+        }
+        if let Some(unit) = &edit_data.unit {
+            pipe.hset(&product_key, PROD_UNIT, u32::from(unit.clone()))
+                .ignore()
+                .hset(&product_key, PROD_UNIT_AT, edited_at)
+                .ignore();
+        }
+        pipe.query(c)
+    })?;
+    Ok(())
+}
+
 pub fn delete_product(c: &mut Connection, auth: &Auth, product_id: &ProductId) -> Result<()> {
-    let product_owner = get_product_owner(c, &product_id)?;
-    db::verify_permission_auth(c, &auth, &product_owner)?;
+    let store_id = get_product_store(c, &product_id)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let product_owner = get_product_owner(c, &product_id)?;
+        db::assert_owner_or_member(&user_id, &product_owner)?;
+        remove_product(c, &store_id, &product_owner, &product_id)
+    })
+}
+
+// to be called with the store lock held, once the permission is checked
+pub(crate) fn remove_product(
+    c: &mut Connection,
+    store_id: &StoreId,
+    product_owner: &UserId,
+    product_id: &ProductId,
+) -> Result<()> {
     let product_key = product_key(&product_id);
     let aisle_id = AisleId(c.hget(&product_key, PROD_AISLE)?);
     let prod_in_aisle_key = products_in_aisle_key(&aisle_id);
     transaction(c, &[&product_key, &prod_in_aisle_key], |c, pipe| {
-        pipe.srem(&prod_in_aisle_key, &**product_id)
-            .ignore()
-            .del(&product_key)
-            .query(c)
+        db::journal::record(
+            c,
+            pipe,
+            &store_id,
+            Entity::Product,
+            Change::Deleted,
+            &product_id,
+        )?;
+        transaction_remove_product(c, pipe, &store_id, &product_owner, &product_id, &aisle_id)?;
+        pipe.query(c)
     })?;
     Ok(())
 }
 
+// to be used only in a transaction journaling the removal, doesn't execute
+// the `pipe`
+fn transaction_remove_product(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    product_owner: &UserId,
+    product_id: &ProductId,
+    aisle_id: &AisleId,
+) -> Result<()> {
+    let product_key = product_key(&product_id);
+    let name: Option<String> = c.hget(&product_key, PROD_NAME)?;
+    let freed = db::quotas::entity_size(&name.unwrap_or_default());
+    db::quotas::transaction_charge(c, pipe, &product_owner, -freed)?;
+    let store_owner = db::stores::get_store_owner(c, &store_id)?;
+    db::quotas::transaction_charge(
+        c,
+        pipe,
+        &store_owner,
+        db::quotas::tombstone_size(&product_id),
+    )?;
+    let is_done: i32 = c.hget(&product_key, PROD_STATE)?;
+    let left = if is_done != 0 { 0 } else { -1 };
+    db::stores::transaction_count_items(c, pipe, &store_id, -1, left)?;
+    pipe.srem(&products_in_aisle_key(&aisle_id), &**product_id)
+        .ignore()
+        .sadd(&removed_products_key(&store_id), &**product_id)
+        .ignore()
+        .del(&product_key)
+        .ignore();
+    Ok(())
+}
+
+// Queue forgetting the removed products of the store whose removal is among
+// the `leaving` entries of its journal, and give back what they cost. To be
+// used only in the transaction trimming the journal, doesn't execute the `pipe`
+pub(crate) fn transaction_forget_removed(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    leaving: &[Entry],
+) -> Result<()> {
+    let removed_key = removed_products_key(&store_id);
+    redis::cmd("WATCH").arg(&removed_key).query::<()>(c)?;
+    let mut freed = 0;
+    for entry in leaving
+        .iter()
+        .filter(|e| e.entity == Entity::Product && e.change == Change::Deleted)
+    {
+        if c.sismember(&removed_key, &entry.id)? {
+            pipe.srem(&removed_key, &entry.id).ignore();
+            freed += db::quotas::tombstone_size(&entry.id);
+        }
+    }
+    if freed > 0 {
+        let store_owner = db::stores::get_store_owner(c, &store_id)?;
+        db::quotas::transaction_charge(c, pipe, &store_owner, -freed)?;
+    }
+    Ok(())
+}
+
+// What the removed products of the store take of its owner's bytes quota
+pub fn removed_bytes(c: &mut Connection, store_id: &StoreId) -> Result<i64> {
+    let removed: Vec<String> = c.smembers(&removed_products_key(&store_id))?;
+    Ok(removed
+        .iter()
+        .map(|id| db::quotas::tombstone_size(id))
+        .sum())
+}
+
+// Run `f` with the stores of the products locked, once checked that the user
+// may edit all of them. `f` gets each product once, with its store and owner
+fn with_products_locked<T, F>(
+    c: &mut Connection,
+    auth: &Auth,
+    product_ids: &[ProductId],
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut Connection, &[(ProductId, &StoreId, UserId)]) -> Result<T>,
+{
+    let mut product_ids: Vec<&str> = product_ids.iter().map(|id| id.as_str()).collect();
+    product_ids.sort();
+    product_ids.dedup();
+    let mut store_ids = vec![];
+    for product_id in &product_ids {
+        store_ids.push(get_product_store(c, &ProductId(product_id.to_string()))?);
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_all_store_locks(c, &user_id, &store_ids, |c| {
+        let mut locked = vec![];
+        for (product_id, store_id) in product_ids.iter().zip(&store_ids) {
+            let product_id = ProductId(product_id.to_string());
+            // moved to another store before the locks were taken
+            if get_product_store(c, &product_id)? != *store_id {
+                return Err(ServerError::new(
+                    STORE_BUSY,
+                    "Store is being modified, try again",
+                ));
+            }
+            let product_owner = get_product_owner(c, &product_id)?;
+            db::assert_owner_or_member(&user_id, &product_owner)?;
+            locked.push((product_id, store_id, product_owner));
+        }
+        f(c, &locked)
+    })
+}
+
+// All or none of the products, each journaled as deleted
+pub fn delete_products(c: &mut Connection, auth: &Auth, product_ids: &[ProductId]) -> Result<()> {
+    with_products_locked(c, auth, product_ids, |c, locked| {
+        let mut keys = vec![];
+        let mut aisle_ids = vec![];
+        for (product_id, _, _) in locked {
+            let aisle_id = AisleId(c.hget(&product_key(&product_id), PROD_AISLE)?);
+            keys.push(product_key(&product_id));
+            keys.push(products_in_aisle_key(&aisle_id));
+            aisle_ids.push(aisle_id);
+        }
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        transaction(c, &keys, |c, pipe| {
+            let mut changes = Changes::new();
+            for ((product_id, store_id, product_owner), aisle_id) in locked.iter().zip(&aisle_ids) {
+                transaction_remove_product(
+                    c,
+                    pipe,
+                    &store_id,
+                    &product_owner,
+                    &product_id,
+                    &aisle_id,
+                )?;
+                changes
+                    .entry(store_id.as_str())
+                    .or_insert((*store_id, vec![]))
+                    .1
+                    .push((Entity::Product, Change::Deleted, product_id.as_str()));
+            }
+            transaction_record_changes(c, pipe, &changes)?;
+            pipe.query(c)
+        })?;
+        Ok(())
+    })
+}
+
+// Check off or back on all or none of the products, each one changed
+// journaled as updated. A product whose state was edited later keeps it.
+// Returns the number of products checked off.
+pub fn set_products_done(
+    c: &mut Connection,
+    auth: &Auth,
+    product_ids: &[ProductId],
+    is_done: bool,
+) -> Result<usize> {
+    with_products_locked(c, auth, product_ids, |c, locked| {
+        let edited_at = db::locks::now_ms();
+        let keys: Vec<String> = locked.iter().map(|(id, _, _)| product_key(&id)).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let mut checked = 0;
+        transaction(c, &keys, |c, pipe| {
+            checked = 0;
+            let mut changes = Changes::new();
+            for (product_id, store_id, product_owner) in locked {
+                let product_key = product_key(&product_id);
+                if !is_latest(c, &product_key, PROD_STATE_AT, edited_at)? {
+                    continue;
+                }
+                let was_done: i32 = c.hget(&product_key, PROD_STATE)?;
+                let left = was_done as i64 - is_done as i64;
+                if left < 0 {
+                    checked += 1;
+                    let name: String = c.hget(&product_key, PROD_NAME)?;
+                    let name = db::aliases::canonical(c, &product_owner, &name)?;
+                    let quantity: u32 = c.hget(&product_key, PROD_QTY)?;
+                    db::stats::transaction_count_purchase(
+                        c,
+                        pipe,
+                        &store_id,
+                        &name,
+                        quantity,
+                        now_s(),
+                    )?;
+                }
+                db::stores::transaction_count_items(c, pipe, &store_id, 0, left)?;
+                changes
+                    .entry(store_id.as_str())
+                    .or_insert((*store_id, vec![]))
+                    .1
+                    .push((Entity::Product, Change::Updated, product_id.as_str()));
+                pipe.hset(&product_key, PROD_STATE, is_done as i32)
+                    .ignore()
+                    .hset(&product_key, PROD_STATE_AT, edited_at)
+                    .ignore();
+            }
+            transaction_record_changes(c, pipe, &changes)?;
+            pipe.query(c)
+        })?;
+        Ok(checked)
+    })
+}
+
+// The journal entries of a bulk edit, by store
+type Changes<'a> = BTreeMap<&'a str, (&'a StoreId, Vec<(Entity, Change, &'a str)>)>;
+
+// Each store journals its products edited, in a single `record_all` as it
+// can be called once per transaction
+fn transaction_record_changes(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    changes: &Changes,
+) -> Result<()> {
+    for (store_id, changes) in changes.values() {
+        db::journal::record_all(c, pipe, &store_id, changes)?;
+    }
+    Ok(())
+}
+
+// the products in the aisle and the ones not done
+pub fn count_items_in_aisle(c: &mut Connection, aisle_id: &AisleId) -> Result<(u64, u64)> {
+    let products = get_products_in_aisle(c, &aisle_id)?;
+    let left = products.iter().filter(|p| !p.is_done).count();
+    Ok((products.len() as u64, left as u64))
+}
+
+// to be used only in a transaction, doesn't execute the `pipe`
+pub fn transaction_move_product(
+    pipe: &mut Pipeline,
+    product_id: &ProductId,
+    from: &AisleId,
+    to: &AisleId,
+    sort_weight: f32,
+) {
+    let product_key = product_key(&product_id);
+    pipe.srem(&products_in_aisle_key(&from), &**product_id)
+        .ignore()
+        .sadd(&products_in_aisle_key(&to), &**product_id)
+        .ignore()
+        .hset(&product_key, PROD_AISLE, &**to)
+        .ignore()
+        .hset(&product_key, PROD_SORT_WEIGHT, sort_weight)
+        .ignore();
+}
+
+// Fold `from` into `into`: the quantities add up, and it is done only if they
+// all were. The aisles of `from` are left to the caller, who is dropping them.
+// To be used only in a transaction, doesn't execute the `pipe`
+pub fn transaction_combine_products(pipe: &mut Pipeline, into: &Product, from: &[&Product]) {
+    let into_key = product_key(&ProductId(into.product_id.clone()));
+    let quantity = from
+        .iter()
+        .fold(into.quantity, |qty, p| qty.saturating_add(p.quantity));
+    let is_done = into.is_done && from.iter().all(|p| p.is_done);
+    pipe.hset(&into_key, PROD_QTY, quantity)
+        .ignore()
+        .hset(&into_key, PROD_STATE, is_done as i32)
+        .ignore();
+    for p in from {
+        pipe.del(&product_key(&ProductId(p.product_id.clone())))
+            .ignore();
+    }
+}
+
+// Write the product as it was, see `db::snapshots`. To be used only in a
+// transaction, doesn't execute the `pipe`
+pub(crate) fn transaction_restore_product(
+    pipe: &mut Pipeline,
+    owner_id: &UserId,
+    aisle_id: &AisleId,
+    product_id: &ProductId,
+    product: &Product,
+) {
+    let prod_key = product_key(&product_id);
+    pipe.hset(&prod_key, PROD_NAME, &product.name)
+        .ignore()
+        .hset(&prod_key, PROD_QTY, product.quantity)
+        .ignore()
+        .hset(&prod_key, PROD_SORT_WEIGHT, product.sort_weight)
+        .ignore()
+        .hset(&prod_key, PROD_STATE, product.is_done as i32)
+        .ignore()
+        .hset(&prod_key, PROD_OWNER, &**owner_id)
+        .ignore()
+        .hset(&prod_key, PROD_UNIT, u32::from(product.unit.clone()))
+        .ignore()
+        .hset(&prod_key, PROD_AISLE, &**aisle_id)
+        .ignore()
+        .sadd(&products_in_aisle_key(&aisle_id), &**product_id)
+        .ignore();
+}
+
 // purge all products contained in aisle
 // to be used only in a transaction, doesn't execute the `pipe`
+// returns the bytes freed, see `db::quotas`
 pub fn transaction_purge_products_in_aisle(
     c: &mut Connection,
     pipe: &mut Pipeline,
     aisle_id: &AisleId,
-) -> Result<()> {
+) -> Result<i64> {
     let products_in_aisle_key = products_in_aisle_key(&aisle_id);
     let products: Option<Vec<String>> = c.smembers(&products_in_aisle_key)?;
+    let mut freed = 0;
     if let Some(products) = products {
-        products.into_iter().for_each(|p| {
-            pipe.del(&product_key(&ProductId(p))).ignore();
-        });
+        for p in products {
+            let product_key = product_key(&ProductId(p));
+            let name: Option<String> = c.hget(&product_key, PROD_NAME)?;
+            freed += db::quotas::entity_size(&name.unwrap_or_default());
+            pipe.del(&product_key).ignore();
+        }
         pipe.del(&products_in_aisle_key).ignore();
     }
-    Ok(())
+    Ok(freed)
 }
 
-pub fn edit_product_sort_weight(
-    c: &mut Connection,
-    pipe: &mut Pipeline,
-    auth: &Auth,
-    data: &ProductItemWeight,
-) -> Result<()> {
-    let product_id = ProductId(data.id.clone());
-    let product_owner = get_product_owner(c, &product_id)?;
-    db::verify_permission_auth(c, &auth, &product_owner)?;
-    let product_key = product_key(&product_id);
+// to be called by `db::stores::edit_sort_weights`, which checked that the
+// product is in the store and journals the change
+pub(crate) fn edit_product_sort_weight(pipe: &mut Pipeline, data: &ProductItemWeight) {
+    let product_key = product_key(&ProductId(data.id.clone()));
     pipe.hset(&product_key, PROD_SORT_WEIGHT, data.sort_weight)
         .ignore();
-    Ok(())
 }
 
 #[cfg(test)]
@@ -244,6 +866,67 @@ pub mod tests {
         assert_eq!(true, state != 0);
     }
 
+    #[test]
+    fn modify_product_latest_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (_, product_id) = save_product_for_test(&mut c);
+        let product_key = product_key(&product_id);
+        let now = db::locks::now_ms();
+        let mut later = EditProduct::new(Some(RENAME.to_owned()), Some(3), None, None);
+        later.edited_at = Some(now - 1000);
+        let mut earlier = EditProduct::new(Some(NAME.to_owned()), None, None, Some(true));
+        earlier.edited_at = Some(now - 2000);
+
+        // the earlier edit comes last, only its state is newer
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &later, &product_id));
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &earlier, &product_id));
+        let name: String = c.hget(&product_key, PROD_NAME).unwrap();
+        assert_eq!(RENAME, &name);
+        assert_eq!(Ok(3), c.hget(&product_key, PROD_QTY));
+        assert_eq!(Ok(1), c.hget(&product_key, PROD_STATE));
+
+        // from the future, it counts as now and an edit now still wins
+        let mut ahead = EditProduct::new(None, Some(4), None, None);
+        ahead.edited_at = Some(now + 3_600_000);
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &ahead, &product_id));
+        let edit = EditProduct::new(None, Some(5), None, None);
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &edit, &product_id));
+        assert_eq!(Ok(5), c.hget(&product_key, PROD_QTY));
+    }
+
+    #[test]
+    fn modify_product_bought_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (_, product_id) = save_product_for_test(&mut c);
+        let product_key = product_key(&product_id);
+        let quantity = EditProduct::new(None, Some(6), None, None);
+        assert_eq!(
+            Ok(()),
+            modify_product(&mut c, &AUTH, &quantity, &product_id)
+        );
+
+        // 2 of 6, the other 4 are still to buy
+        let mut bought = EditProduct::new(None, None, None, None);
+        bought.bought = Some(2);
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &bought, &product_id));
+        assert_eq!(Ok(4), c.hget(&product_key, PROD_QTY));
+        assert_eq!(Ok(0), c.hget(&product_key, PROD_STATE));
+        let store_id = get_product_store(&mut c, &product_id).unwrap();
+        let habits = db::stats::get_purchase_habits(&mut c, &store_id).unwrap();
+        assert_eq!(1, habits.len());
+        assert_eq!(1, habits[0].times);
+
+        // the rest, or more, checks it off and keeps the quantity
+        bought.bought = Some(5);
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &bought, &product_id));
+        assert_eq!(Ok(4), c.hget(&product_key, PROD_QTY));
+        assert_eq!(Ok(1), c.hget(&product_key, PROD_STATE));
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &bought, &product_id));
+        assert_eq!(Ok(1), c.hget(&product_key, PROD_STATE));
+    }
+
     #[test]
     fn get_products_in_aisle_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -269,6 +952,157 @@ pub mod tests {
         assert_eq!(Ok(false), c.exists(&product_key(&p)));
     }
 
+    #[test]
+    fn product_history_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (_, product_id) = save_product_for_test(&mut c);
+        let quantity = EditProduct::new(None, Some(3), None, None);
+        assert_eq!(
+            Ok(()),
+            modify_product(&mut c, &AUTH, &quantity, &product_id)
+        );
+        let history = get_product_history(&mut c, &AUTH, &product_id).unwrap();
+        assert_eq!(vec!["quantity"], history.edits[0].fields);
+        assert_eq!(None, history.purchases);
+
+        let done = EditProduct::new(None, None, None, Some(true));
+        assert_eq!(Ok(()), modify_product(&mut c, &AUTH, &done, &product_id));
+        let history = get_product_history(&mut c, &AUTH, &product_id).unwrap();
+        assert!(history.edits[0].fields.contains(&"is_done".to_owned()));
+        let purchases = history.purchases.unwrap();
+        assert_eq!(
+            (1, Some(3f32)),
+            (purchases.times, purchases.average_quantity)
+        );
+        assert!(get_product_history(&mut c, &AUTH, &ProductId("missing".to_owned())).is_err());
+    }
+
+    #[test]
+    fn delete_products_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+
+        let (aisle_id, p1) = save_product_for_test(&mut c);
+        let p2 = add_2nd_product(&mut c, &aisle_id);
+        let store_id = db::stores::save_store(&mut c, &AUTH, "Other").unwrap();
+        let aisle = db::aisles::save_aisle(&mut c, &AUTH, &store_id, "Dairy").unwrap();
+        let p3 = save_product(&mut c, &AUTH, "milk", &aisle.id())
+            .unwrap()
+            .id();
+        let missing = ProductId("missing".to_owned());
+
+        // none of them if one can't be
+        let ids = [ProductId(p1.to_string()), ProductId(missing.to_string())];
+        assert!(delete_products(&mut c, &AUTH, &ids).is_err());
+        assert_eq!(Ok(true), c.exists(&product_key(&p1)));
+
+        let ids = [
+            ProductId(p1.to_string()),
+            ProductId(p3.to_string()),
+            ProductId(p1.to_string()),
+        ];
+        let since = db::journal::current_version(&mut c, &store_id).unwrap();
+        assert_eq!(Ok(()), delete_products(&mut c, &AUTH, &ids));
+        assert_eq!(Ok(false), c.exists(&product_key(&p1)));
+        assert_eq!(Ok(false), c.exists(&product_key(&p3)));
+        assert_eq!(Ok(true), c.exists(&product_key(&p2)));
+        assert_eq!(
+            Ok(vec![p2.to_string()]),
+            c.smembers(&products_in_aisle_key(&aisle_id))
+        );
+        assert_eq!(
+            Ok(true),
+            c.sismember(&removed_products_key(&store_id), &*p3)
+        );
+        // each product is journaled in its store
+        let entries = db::journal::entries_since(&mut c, &store_id, since)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![(Entity::Product, Change::Deleted, p3.to_string())],
+            entries
+                .into_iter()
+                .map(|e| (e.entity, e.change, e.id))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn forget_removed_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        let (aisle_id, product_id) = save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        db::journal::set_retention(db::journal::Retention {
+            max_len: 2,
+            ..db::journal::Retention::default()
+        });
+        let used = db::quotas::get_usage(&mut c, &user_id).unwrap() - db::quotas::entity_size(NAME);
+
+        assert_eq!(Ok(()), delete_product(&mut c, &AUTH, &product_id));
+        let removed_key = removed_products_key(&store_id);
+        assert_eq!(Ok(true), c.sismember(&removed_key, &*product_id));
+        let tombstone = db::quotas::tombstone_size(&product_id);
+        assert_eq!(
+            Ok(used + tombstone),
+            db::quotas::get_usage(&mut c, &user_id)
+        );
+
+        // once the journal no longer has the removal, the id goes too
+        save_product(&mut c, &AUTH, "a", &aisle_id).unwrap();
+        assert_eq!(Ok(true), c.sismember(&removed_key, &*product_id));
+        save_product(&mut c, &AUTH, "b", &aisle_id).unwrap();
+        assert_eq!(Ok(false), c.sismember(&removed_key, &*product_id));
+        assert_eq!(
+            Ok(used + db::quotas::entity_size("a") + db::quotas::entity_size("b")),
+            db::quotas::get_usage(&mut c, &user_id)
+        );
+        db::journal::set_retention(db::journal::Retention::default());
+    }
+
+    #[test]
+    fn set_products_done_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+
+        let (aisle_id, p1) = save_product_for_test(&mut c);
+        let p2 = add_2nd_product(&mut c, &aisle_id);
+        let ids = [ProductId(p1.to_string()), ProductId(p2.to_string())];
+        let store_id = find_product_store(&mut c, &p1).unwrap().unwrap();
+        let since = db::journal::current_version(&mut c, &store_id).unwrap();
+        assert_eq!(Ok(2), set_products_done(&mut c, &AUTH, &ids, true));
+        let entries = db::journal::entries_since(&mut c, &store_id, since)
+            .unwrap()
+            .unwrap();
+        let mut updated: Vec<_> = entries
+            .into_iter()
+            .map(|e| (e.entity, e.change, e.id))
+            .collect();
+        updated.sort_by(|a, b| a.2.cmp(&b.2));
+        let mut expected = vec![
+            (Entity::Product, Change::Updated, p1.to_string()),
+            (Entity::Product, Change::Updated, p2.to_string()),
+        ];
+        expected.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(expected, updated);
+        assert_eq!(Ok(1), c.hget(&product_key(&p1), PROD_STATE));
+        assert_eq!(Ok(1), c.hget(&product_key(&p2), PROD_STATE));
+        // already done, nothing more is checked off
+        assert_eq!(Ok(0), set_products_done(&mut c, &AUTH, &ids[..1], true));
+
+        // edited later, the state stays
+        c.hset(&product_key(&p2), PROD_STATE_AT, u64::MAX).unwrap();
+        assert_eq!(Ok(0), set_products_done(&mut c, &AUTH, &ids, false));
+        assert_eq!(Ok(0), c.hget(&product_key(&p1), PROD_STATE));
+        assert_eq!(Ok(1), c.hget(&product_key(&p2), PROD_STATE));
+
+        let missing = [ProductId(p1.to_string()), ProductId("missing".to_owned())];
+        assert!(set_products_done(&mut c, &AUTH, &missing, true).is_err());
+        assert_eq!(Ok(0), c.hget(&product_key(&p1), PROD_STATE));
+    }
+
     #[test]
     fn transaction_purge_products_in_aisle_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -279,7 +1113,7 @@ pub mod tests {
         let mut pipe = Pipeline::new(c.db);
         pipe.atomic();
         assert_eq!(
-            Ok(()),
+            Ok(db::quotas::entity_size(NAME) + db::quotas::entity_size(RENAME)),
             transaction_purge_products_in_aisle(&mut c, &mut pipe, &aisle_id)
         );
         assert_eq!(Ok(()), pipe.query(&c));
@@ -295,14 +1129,9 @@ pub mod tests {
         let (_, product_id) = save_product_for_test(&mut c);
         let mut pipe = Pipeline::new(c.db);
         pipe.atomic();
-        assert_eq!(
-            Ok(()),
-            edit_product_sort_weight(
-                &mut c,
-                &mut pipe,
-                &AUTH,
-                &ProductItemWeight::new(product_id.to_string(), 2.0f32)
-            )
+        edit_product_sort_weight(
+            &mut pipe,
+            &ProductItemWeight::new(product_id.to_string(), 2.0f32),
         );
         assert_eq!(Ok(()), pipe.query(&c));
         assert_eq!(
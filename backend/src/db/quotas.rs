@@ -0,0 +1,391 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{Commands, Pipeline};
+
+#[cfg(test)]
+use fake_redis::{FakeConnection as Connection, FakePipeline as Pipeline};
+
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(not(test))]
+use std::sync::RwLock;
+
+#[cfg(not(test))]
+use lazy_static::lazy_static;
+
+use crate::{
+    db,
+    error::{Result, ServerError},
+    types::*,
+};
+
+// What a store, aisle or product costs on top of its name: ids, owner, weights
+const ENTITY_OVERHEAD: u64 = 64;
+
+// Limits on what one account can store, checked when something is created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quotas {
+    pub max_stores: Option<u64>,
+    // per store
+    pub max_aisles: Option<u64>,
+    // per aisle
+    pub max_products: Option<u64>,
+    // all the names of a user's stores, aisles and products, plus overhead,
+    // the gzipped snapshots of the stores and the ids of their removed
+    // products
+    pub max_bytes: Option<u64>,
+    // share of a per-user quota in percent past which clients are warned,
+    // 0 for no warning
+    pub warn_percent: u64,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas {
+            max_stores: Some(100),
+            max_aisles: Some(200),
+            max_products: Some(1000),
+            max_bytes: Some(4 * 1024 * 1024),
+            warn_percent: 80,
+        }
+    }
+}
+
+#[cfg(not(test))]
+lazy_static! {
+    static ref QUOTAS: RwLock<Quotas> = RwLock::new(Quotas::default());
+}
+
+#[cfg(not(test))]
+pub fn set_quotas(quotas: Quotas) {
+    *QUOTAS.write().unwrap() = quotas;
+}
+
+#[cfg(not(test))]
+fn quotas() -> Quotas {
+    *QUOTAS.read().unwrap()
+}
+
+// tests run in parallel, each one gets its own quotas
+#[cfg(test)]
+thread_local! {
+    static QUOTAS: Cell<Quotas> = Cell::new(Quotas::default());
+}
+
+#[cfg(test)]
+pub fn set_quotas(quotas: Quotas) {
+    QUOTAS.with(|q| q.set(quotas));
+}
+
+#[cfg(test)]
+fn quotas() -> Quotas {
+    QUOTAS.with(Cell::get)
+}
+
+fn usage_key(user_id: &UserId) -> String {
+    format!("usage:{}", **user_id)
+}
+
+// Bytes accounted for an entity with this name
+pub fn entity_size(name: &str) -> i64 {
+    (ENTITY_OVERHEAD + name.len() as u64) as i64
+}
+
+// Bytes accounted for the id of a removed product kept in its store
+pub fn tombstone_size(product_id: &str) -> i64 {
+    product_id.len() as i64
+}
+
+// Would `adding` more members fit in the set, as counted by `quota`
+fn check_count(
+    c: &mut Connection,
+    set_key: &str,
+    adding: u64,
+    max: Option<u64>,
+    quota: &str,
+    what: &str,
+) -> Result<()> {
+    match max {
+        Some(max) => {
+            let count: u64 = c.scard(set_key)?;
+            if count + adding > max {
+                Err(ServerError::quota_exceeded(quota, max, what))
+            } else {
+                Ok(())
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+// `stores_key` is the set of the user's stores
+pub fn check_stores(c: &mut Connection, stores_key: &str) -> Result<()> {
+    check_count(c, stores_key, 1, quotas().max_stores, "stores", "stores")
+}
+
+// `aisles_key` is the set of the store's aisles
+pub fn check_aisles(c: &mut Connection, aisles_key: &str) -> Result<()> {
+    check_more_aisles(c, aisles_key, 1)
+}
+
+pub fn check_more_aisles(c: &mut Connection, aisles_key: &str, adding: u64) -> Result<()> {
+    check_count(
+        c,
+        aisles_key,
+        adding,
+        quotas().max_aisles,
+        "aisles",
+        "aisles per store",
+    )
+}
+
+// `products_key` is the set of the aisle's products
+pub fn check_products(c: &mut Connection, products_key: &str) -> Result<()> {
+    check_more_products(c, products_key, 1)
+}
+
+pub fn check_more_products(c: &mut Connection, products_key: &str, adding: u64) -> Result<()> {
+    check_count(
+        c,
+        products_key,
+        adding,
+        quotas().max_products,
+        "products",
+        "products per aisle",
+    )
+}
+
+// Would `extra` more bytes fit in the user's quota
+pub fn check_bytes(c: &mut Connection, user_id: &UserId, extra: i64) -> Result<()> {
+    match quotas().max_bytes {
+        Some(max) if extra > 0 => {
+            if get_usage(c, &user_id)? + extra > max as i64 {
+                Err(ServerError::quota_exceeded("bytes", max, "bytes of data"))
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+// Account `bytes` (negative when freed) to the user, in the transaction `pipe`
+pub fn transaction_charge(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    user_id: &UserId,
+    bytes: i64,
+) -> Result<()> {
+    if bytes != 0 {
+        backfill_usage(c, &user_id)?;
+        pipe.incr(&usage_key(&user_id), bytes).ignore();
+    }
+    Ok(())
+}
+
+// Users from before the usage was kept have no counter: it starts from what
+// they store, before anything more is charged to it
+fn backfill_usage(c: &mut Connection, user_id: &UserId) -> Result<i64> {
+    let usage_key = usage_key(&user_id);
+    let used: Option<i64> = c.get(&usage_key)?;
+    match used {
+        Some(used) => Ok(used),
+        None => {
+            let used = count_usage(c, &user_id)?;
+            // another request may have counted first
+            if c.set_nx(&usage_key, used)? {
+                Ok(used)
+            } else {
+                Ok(c.get(&usage_key)?)
+            }
+        }
+    }
+}
+
+// Everything the user stores, as `transaction_charge` accounts for it
+pub fn count_usage(c: &mut Connection, user_id: &UserId) -> Result<i64> {
+    Ok(db::stores::stored_bytes(c, &user_id)? + db::anywhere::stored_bytes(c, &user_id)?)
+}
+
+pub fn delete_usage(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&usage_key(&user_id))?)
+}
+
+pub fn get_usage(c: &mut Connection, user_id: &UserId) -> Result<i64> {
+    backfill_usage(c, &user_id)
+}
+
+fn quota_usage(quota: &str, used: u64, max: Option<u64>, warn_percent: u64) -> QuotaUsage {
+    QuotaUsage {
+        quota: quota.to_owned(),
+        used,
+        max,
+        warning: warn_percent > 0 && max.map_or(false, |max| used * 100 >= max * warn_percent),
+    }
+}
+
+// Where the user stands against the quotas of the whole account, the ones of
+// a store or an aisle are only known when adding to it
+pub fn get_user_quotas(c: &mut Connection, user_id: &UserId) -> Result<Vec<QuotaUsage>> {
+    let quotas = quotas();
+    let stores = db::stores::count_user_stores(c, &user_id)?;
+    let bytes = get_usage(c, &user_id)?.max(0) as u64;
+    Ok(vec![
+        quota_usage("stores", stores, quotas.max_stores, quotas.warn_percent),
+        quota_usage("bytes", bytes, quotas.max_bytes, quotas.warn_percent),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{self, aisles::tests::*, ids::tests::HASH_1, sessions::tests::AUTH, tests::*},
+        error::QUOTA_EXCEEDED,
+    };
+    use fake_redis::FakeCient as Client;
+
+    fn quota_status<T: std::fmt::Debug>(res: Result<T>) -> Option<warp::http::StatusCode> {
+        res.err().map(|e| e.status)
+    }
+
+    #[test]
+    fn count_quotas_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        set_quotas(Quotas {
+            max_stores: Some(1),
+            max_aisles: Some(1),
+            max_products: Some(1),
+            max_bytes: None,
+            warn_percent: 0,
+        });
+        let (store_id, aisle_id) = save_aisle_for_test(&mut c);
+        assert_eq!(
+            Some(QUOTA_EXCEEDED),
+            quota_status(db::stores::save_store(&mut c, &AUTH, "second"))
+        );
+        assert_eq!(
+            Some(QUOTA_EXCEEDED),
+            quota_status(db::aisles::save_aisle(&mut c, &AUTH, &store_id, "second"))
+        );
+        assert_eq!(
+            true,
+            db::products::save_product(&mut c, &AUTH, "first", &aisle_id).is_ok()
+        );
+        assert_eq!(
+            Some(QUOTA_EXCEEDED),
+            quota_status(db::products::save_product(
+                &mut c, &AUTH, "second", &aisle_id
+            ))
+        );
+        set_quotas(Quotas::default());
+    }
+
+    #[test]
+    fn bytes_quota_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        let (store_id, aisle_id) = save_aisle_for_test(&mut c);
+        let used = entity_size(db::stores::tests::STORE_TEST_NAME) + entity_size(NAME);
+        assert_eq!(Ok(used), get_usage(&mut c, &user_id));
+
+        set_quotas(Quotas {
+            max_bytes: Some(used as u64 + entity_size("milk") as u64),
+            ..Quotas::default()
+        });
+        let milk = db::products::save_product(&mut c, &AUTH, "milk", &aisle_id).unwrap();
+        assert_eq!(
+            Some(QUOTA_EXCEEDED),
+            quota_status(db::products::save_product(&mut c, &AUTH, "eggs", &aisle_id))
+        );
+        // growing a name counts too, shrinking it frees room
+        assert_eq!(
+            Some(QUOTA_EXCEEDED),
+            quota_status(db::aisles::edit_aisle(
+                &mut c,
+                &AUTH,
+                &aisle_id,
+                "Longer name"
+            ))
+        );
+        assert_eq!(
+            Ok(()),
+            db::stores::edit_store(&mut c, &AUTH, &store_id, "s")
+        );
+        assert_eq!(
+            Ok(()),
+            db::aisles::edit_aisle(&mut c, &AUTH, &aisle_id, "Aisle1 big")
+        );
+
+        // everything is given back on deletion
+        assert_eq!(
+            Ok(()),
+            db::products::delete_product(&mut c, &AUTH, &milk.id())
+        );
+        assert_eq!(
+            true,
+            db::products::save_product(&mut c, &AUTH, "eggs", &aisle_id).is_ok()
+        );
+        assert_eq!(Ok(()), db::stores::delete_store(&mut c, &AUTH, &store_id));
+        assert_eq!(Ok(0), get_usage(&mut c, &user_id));
+        set_quotas(Quotas::default());
+    }
+
+    #[test]
+    fn backfill_usage_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        let (_, aisle_id) = save_aisle_for_test(&mut c);
+        db::products::save_product(&mut c, &AUTH, "milk", &aisle_id).unwrap();
+        let used = get_usage(&mut c, &user_id).unwrap();
+
+        // as for an account from before the counter was kept
+        assert_eq!(Ok(()), delete_usage(&mut c, &user_id));
+        assert_eq!(Ok(used), get_usage(&mut c, &user_id));
+
+        assert_eq!(Ok(()), delete_usage(&mut c, &user_id));
+        db::products::save_product(&mut c, &AUTH, "eggs", &aisle_id).unwrap();
+        assert_eq!(Ok(used + entity_size("eggs")), get_usage(&mut c, &user_id));
+    }
+
+    #[test]
+    fn user_quotas_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        save_aisle_for_test(&mut c);
+        let used = entity_size(db::stores::tests::STORE_TEST_NAME) + entity_size(NAME);
+        set_quotas(Quotas {
+            max_stores: Some(2),
+            max_bytes: Some(used as u64 * 2),
+            warn_percent: 50,
+            ..Quotas::default()
+        });
+        assert_eq!(
+            Ok(vec![
+                quota_usage("stores", 1, Some(2), 50),
+                quota_usage("bytes", used as u64, Some(used as u64 * 2), 50),
+            ]),
+            get_user_quotas(&mut c, &user_id)
+        );
+        assert_eq!(
+            Ok(vec![true, true]),
+            get_user_quotas(&mut c, &user_id).map(|q| q.iter().map(|q| q.warning).collect())
+        );
+        set_quotas(Quotas {
+            max_stores: None,
+            max_bytes: Some(used as u64 * 2 + 1),
+            warn_percent: 50,
+            ..Quotas::default()
+        });
+        assert_eq!(
+            Ok(vec![false, false]),
+            get_user_quotas(&mut c, &user_id).map(|q| q.iter().map(|q| q.warning).collect())
+        );
+        set_quotas(Quotas::default());
+    }
+}
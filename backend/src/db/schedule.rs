@@ -0,0 +1,155 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    db::locks::{self, now_ms},
+    error::Result,
+};
+
+// Outcome of the runs of a scheduled task, shared by all the servers
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunStatus {
+    pub running: bool,
+    pub last_start_s: Option<u64>,
+    pub last_end_s: Option<u64>,
+    // error of the last run if it failed
+    pub last_error: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+    // runs not started because the previous one was still going
+    pub skipped: u64,
+}
+
+fn lock_key(name: &str) -> String {
+    format!("lock:schedule:{}", name)
+}
+
+fn status_key(name: &str) -> String {
+    format!("schedule:{}", name)
+}
+
+// Take the lease of task `name` for at most `max_run_ms`, so its runs never
+// overlap, whatever server starts them. None if a run holds it already.
+pub fn start_run(c: &mut Connection, name: &str, max_run_ms: u64) -> Result<Option<u64>> {
+    let lease = locks::try_acquire(c, &lock_key(name), max_run_ms)?;
+    if lease.is_some() {
+        redis::cmd("HSET")
+            .arg(status_key(name))
+            .arg("last_start_s")
+            .arg(now_ms() / 1000)
+            .query::<()>(c)?;
+    } else {
+        redis::cmd("HINCRBY")
+            .arg(status_key(name))
+            .arg("skipped")
+            .arg(1)
+            .query::<()>(c)?;
+    }
+    Ok(lease)
+}
+
+pub fn end_run(c: &mut Connection, name: &str, lease: u64, error: Option<&str>) -> Result<()> {
+    let key = status_key(name);
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(&key)
+        .arg("last_end_s")
+        .arg(now_ms() / 1000)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&key)
+        .arg("runs")
+        .arg(1)
+        .ignore();
+    match error {
+        Some(error) => pipe
+            .cmd("HSET")
+            .arg(&key)
+            .arg("last_error")
+            .arg(error)
+            .ignore()
+            .cmd("HINCRBY")
+            .arg(&key)
+            .arg("failures")
+            .arg(1)
+            .ignore(),
+        None => pipe.cmd("HDEL").arg(&key).arg("last_error").ignore(),
+    };
+    pipe.query::<()>(c)?;
+    locks::release(c, &lock_key(name), lease)
+}
+
+pub fn run_status(c: &mut Connection, name: &str) -> Result<RunStatus> {
+    let (mut fields, lease): (HashMap<String, String>, Option<u64>) = redis::pipe()
+        .cmd("HGETALL")
+        .arg(status_key(name))
+        .cmd("GET")
+        .arg(lock_key(name))
+        .query(c)?;
+    let number = |field: &str| fields.get(field).and_then(|v| v.parse::<u64>().ok());
+    Ok(RunStatus {
+        // an abandoned lease doesn't count
+        running: lease.map_or(false, |lease| lease >= now_ms()),
+        last_start_s: number("last_start_s"),
+        last_end_s: number("last_end_s"),
+        runs: number("runs").unwrap_or(0),
+        failures: number("failures").unwrap_or(0),
+        skipped: number("skipped").unwrap_or(0),
+        last_error: fields.remove("last_error"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn overlapping_runs_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        assert_eq!(Ok(RunStatus::default()), run_status(&mut c, "janitor"));
+        let lease = start_run(&mut c, "janitor", 60_000).unwrap().unwrap();
+        let status = run_status(&mut c, "janitor").unwrap();
+        assert_eq!(
+            (true, true),
+            (status.running, status.last_start_s.is_some())
+        );
+
+        // the next run comes while the first one is still going
+        assert_eq!(Ok(None), start_run(&mut c, "janitor", 60_000));
+        assert_eq!(Ok(()), end_run(&mut c, "janitor", lease, Some("Broken")));
+        let status = run_status(&mut c, "janitor").unwrap();
+        assert_eq!(false, status.running);
+        assert_eq!((1, 1, 1), (status.runs, status.failures, status.skipped));
+        assert_eq!(Some("Broken".to_owned()), status.last_error);
+
+        let lease = start_run(&mut c, "janitor", 60_000).unwrap().unwrap();
+        assert_eq!(Ok(()), end_run(&mut c, "janitor", lease, None));
+        let status = run_status(&mut c, "janitor").unwrap();
+        assert_eq!(
+            (2, 1, None),
+            (status.runs, status.failures, status.last_error)
+        );
+    }
+
+    #[test]
+    fn abandoned_run_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        // a server died during the run, its lease runs out
+        start_run(&mut c, "digest", 0).unwrap().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(false, run_status(&mut c, "digest").unwrap().running);
+        assert_eq!(true, start_run(&mut c, "digest", 60_000).unwrap().is_some());
+    }
+}
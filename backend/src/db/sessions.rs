@@ -1,22 +1,89 @@
+use std::{cell::RefCell, future::Future};
+
+use hex_view::HexView;
+use rand::Rng;
+
+#[cfg(not(test))]
+use crate::storage::Connection;
 #[cfg(not(test))]
-use redis::{self, transaction, Commands, Connection};
+use redis::{self, transaction, Commands};
 
 #[cfg(test)]
 use fake_redis::{transaction, FakeConnection as Connection};
 
 use crate::{
+    db,
     error::{self, Result, ServerError},
+    links::now_s,
     types::*,
 };
 
 const SESSIONS_LIST: &str = "sessions";
+const TOKEN_SECRET_KEY: &str = "token_secret";
+// when each session was opened, UNIX timestamp in seconds
+const SESSIONS_CREATED: &str = "sessions_created";
+
+tokio::task_local! {
+    // users of the tokens looked up while serving a request
+    static LOOKUPS: RefCell<Vec<(String, String)>>;
+}
+
+// Serve a request with its session lookups shared, the abuse guard and the
+// route then resolve the token once between them
+pub async fn share_lookups<F: Future>(f: F) -> F::Output {
+    LOOKUPS.scope(RefCell::new(vec![]), f).await
+}
 
 fn user_sessions_key(user_id: &UserId) -> String {
     format!("sessions:{}", **user_id)
 }
 
+// the token that replaced a rotated one, while the grace period lasts
+fn rotated_key(auth: &str) -> String {
+    format!("rotated:{}", auth)
+}
+
+// The token a request stands for: its own, or the one that replaced it if
+// it was rotated a moment ago and other requests made with it are in flight
+fn current_token(c: &mut Connection, auth: &Auth) -> Result<String> {
+    if c.hexists(SESSIONS_LIST, auth.0)? {
+        return Ok(auth.0.to_owned());
+    }
+    let successor: Option<String> = c.get(&rotated_key(auth.0))?;
+    Ok(successor.unwrap_or_else(|| auth.0.to_owned()))
+}
+
+// The key signing session tokens when `--token-secret-file` is not given,
+// made by the first instance to ask
+pub fn token_secret(c: &mut Connection) -> Result<Vec<u8>> {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill(&mut secret[..]);
+    let _: bool = c.set_nx(
+        TOKEN_SECRET_KEY,
+        format!("{:x}", HexView::from(&secret[..])),
+    )?;
+    let secret: String = c.get(TOKEN_SECRET_KEY)?;
+    Ok(secret.into_bytes())
+}
+
 pub fn get_user_id(c: &mut Connection, auth: &Auth) -> Result<UserId> {
-    let id = c.hget(SESSIONS_LIST, auth.0)?;
+    let known = LOOKUPS
+        .try_with(|lookups| {
+            lookups
+                .borrow()
+                .iter()
+                .find(|(token, _)| token == auth.0)
+                .map(|(_, id)| id.clone())
+        })
+        .ok()
+        .flatten();
+    if let Some(id) = known {
+        return Ok(UserId(id));
+    }
+    let token = current_token(c, auth)?;
+    let id: String = c.hget(SESSIONS_LIST, &token)?;
+    // outside of `share_lookups` there is nothing to keep it in
+    let _ = LOOKUPS.try_with(|lookups| lookups.borrow_mut().push((auth.0.to_owned(), id.clone())));
     Ok(UserId(id))
 }
 
@@ -28,8 +95,11 @@ pub fn store_session(c: &mut Connection, auth: &str, user_id: &UserId) -> Result
         ))
     } else {
         let user_session_key = user_sessions_key(user_id);
+        let now = now_s();
         transaction(c, &[SESSIONS_LIST, &user_session_key], |c, pipe| {
             pipe.hset(SESSIONS_LIST, auth, user_id.to_string())
+                .ignore()
+                .hset(SESSIONS_CREATED, auth, now)
                 .ignore()
                 .sadd(&user_session_key, auth)
                 .query(c)
@@ -40,9 +110,10 @@ pub fn store_session(c: &mut Connection, auth: &str, user_id: &UserId) -> Result
 }
 
 pub fn validate_session(c: &mut Connection, auth: &Auth) -> Result<()> {
-    if c.hexists(SESSIONS_LIST, auth.0)? {
+    let token = current_token(c, auth)?;
+    if c.hexists(SESSIONS_LIST, &token)? {
         let user_id = get_user_id(c, auth)?;
-        if c.sismember(&user_sessions_key(&user_id), auth.0)? {
+        if c.sismember(&user_sessions_key(&user_id), &token)? {
             Ok(())
         } else {
             Err(ServerError::new(
@@ -62,6 +133,8 @@ fn delete_session_with_connection(c: &mut Connection, auth: &Auth, user_id: &Use
         &[SESSIONS_LIST, &user_session_key],
         |c, pipe| {
             pipe.hdel(SESSIONS_LIST, auth.0)
+                .ignore()
+                .hdel(SESSIONS_CREATED, auth.0)
                 .ignore()
                 .srem(&user_session_key, auth.0)
                 .query(c)
@@ -72,7 +145,9 @@ fn delete_session_with_connection(c: &mut Connection, auth: &Auth, user_id: &Use
 pub fn delete_session(c: &mut Connection, auth: &Auth, wanted_user_id: &UserId) -> Result<()> {
     let user_id = get_user_id(c, auth)?;
     if user_id == *wanted_user_id {
-        delete_session_with_connection(c, &auth, &user_id)
+        let token = current_token(c, auth)?;
+        c.del(&rotated_key(auth.0))?;
+        delete_session_with_connection(c, &Auth(&token), &user_id)
     } else {
         Err(ServerError::new(
             error::UNAUTHORISED,
@@ -82,7 +157,7 @@ pub fn delete_session(c: &mut Connection, auth: &Auth, wanted_user_id: &UserId)
 }
 
 pub fn delete_all_user_sessions(c: &mut Connection, auth: &Auth) -> Result<()> {
-    let user_id = UserId(c.hget(SESSIONS_LIST, auth.0)?);
+    let user_id = get_user_id(c, auth)?;
     let all_user_sessions: Vec<String> = c.smembers(&user_sessions_key(&user_id))?;
     all_user_sessions
         .iter()
@@ -90,11 +165,46 @@ pub fn delete_all_user_sessions(c: &mut Connection, auth: &Auth) -> Result<()> {
         .collect()
 }
 
+// Seconds since the session was opened, `now` being a UNIX timestamp. The
+// sessions opened before this was kept start counting now.
+pub fn session_age(c: &mut Connection, auth: &Auth, now: u64) -> Result<u64> {
+    let token = current_token(c, auth)?;
+    if !c.hexists(SESSIONS_LIST, &token)? {
+        return Ok(0);
+    }
+    let created: Option<u64> = c.hget(SESSIONS_CREATED, &token)?;
+    match created {
+        Some(created) => Ok(now.saturating_sub(created)),
+        None => {
+            c.hset(SESSIONS_CREATED, &token, now)?;
+            Ok(0)
+        }
+    }
+}
+
+// Replace a session by a new one, after which the old token keeps working
+// for `grace_s` seconds only. A token already rotated can't be again.
+pub fn rotate_session(c: &mut Connection, auth: &Auth, grace_s: u64) -> Result<ConnectionToken> {
+    if !c.hexists(SESSIONS_LIST, auth.0)? {
+        return Err(ServerError::new(error::UNAUTHORISED, "Not logged in"));
+    }
+    let user_id = get_user_id(c, auth)?;
+    let token = db::users::open_session(c, &user_id)?;
+    delete_session_with_connection(c, auth, &user_id)?;
+    if grace_s > 0 {
+        let key = rotated_key(auth.0);
+        c.set(&key, token.session_token.as_str())?;
+        c.expire(&key, grace_s as usize)?;
+    }
+    Ok(token)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use crate::db::{ids::tests::*, tests::*};
     use fake_redis::FakeCient as Client;
+    use std::time::Duration;
 
     pub const AUTH: Auth = Auth("tokenauth");
     pub const AUTH2: Auth = Auth("anothertokenauth");
@@ -146,6 +256,21 @@ pub mod tests {
         assert_eq!(Ok(UserId(HASH_1.to_owned())), get_user_id(&mut c, &AUTH2));
     }
 
+    #[tokio::test]
+    async fn share_lookups_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        store_session_for_test(&mut c, &AUTH);
+        share_lookups(async {
+            assert_eq!(Ok(UserId(HASH_1.to_owned())), get_user_id(&mut c, &AUTH));
+            // the second lookup of the request doesn't reach the db
+            let _: bool = c.hdel(SESSIONS_LIST, AUTH.0).unwrap();
+            assert_eq!(Ok(UserId(HASH_1.to_owned())), get_user_id(&mut c, &AUTH));
+        })
+        .await;
+        assert_eq!(true, get_user_id(&mut c, &AUTH).is_err());
+    }
+
     #[test]
     fn delete_session_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -173,4 +298,35 @@ pub mod tests {
         assert_eq!(Ok(false), c.exists(SESSIONS_LIST));
         assert_eq!(Ok(false), c.exists(&user_sessions_key(&u)));
     }
+
+    #[test]
+    fn rotate_session_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        store_session_for_test(&mut c, &AUTH);
+        let user_id = UserId(HASH_1.to_owned());
+        let now = now_s();
+        assert_eq!(Ok(0), session_age(&mut c, &AUTH, now));
+        assert_eq!(Ok(90), session_age(&mut c, &AUTH, now + 90));
+
+        let token = rotate_session(&mut c, &AUTH, 30).unwrap();
+        let new = Auth(&token.session_token);
+        assert_eq!(Ok(()), validate_session(&mut c, &new));
+        assert_eq!(Ok(0), session_age(&mut c, &new, now));
+        // the old token still stands for the new session for a while
+        assert_eq!(Ok(()), validate_session(&mut c, &AUTH));
+        assert_eq!(Ok(UserId(HASH_1.to_owned())), get_user_id(&mut c, &AUTH));
+        assert_eq!(true, rotate_session(&mut c, &AUTH, 30).is_err());
+        c.advance_time(Duration::from_secs(30));
+        assert_eq!(true, validate_session(&mut c, &AUTH).is_err());
+
+        // logging out with the old token ends the new session
+        let token = rotate_session(&mut c, &new, 30).unwrap();
+        assert_eq!(Ok(()), delete_session(&mut c, &new, &user_id));
+        assert_eq!(
+            true,
+            validate_session(&mut c, &Auth(&token.session_token)).is_err()
+        );
+        assert_eq!(Ok(false), c.exists(SESSIONS_CREATED));
+    }
 }
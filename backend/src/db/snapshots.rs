@@ -0,0 +1,488 @@
+use std::{collections::HashMap, io::Read};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands, Pipeline};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection, FakePipeline as Pipeline};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        self,
+        aliases::normalized,
+        journal::{Change, Entity},
+    },
+    error::{self, Result, ServerError},
+    links::now_s,
+    types::*,
+};
+
+// the oldest snapshot of a store goes to make room for a new one past that
+pub const MAX_SNAPSHOTS: usize = 10;
+// stands for the store as it is now in `diff_snapshots`
+pub const LIVE: &str = "live";
+
+// The aisles and products of a store at a point in time, kept gzipped
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    name: String,
+    created_at: u64,
+    aisles: Vec<Aisle>,
+}
+
+impl Snapshot {
+    fn info(&self, snapshot_id: String) -> StoreSnapshot {
+        StoreSnapshot::new(
+            snapshot_id,
+            self.name.clone(),
+            self.created_at,
+            self.aisles.len() as u64,
+            self.aisles.iter().map(|a| a.products.len() as u64).sum(),
+        )
+    }
+}
+
+// snapshot id to the gzipped JSON of the snapshot
+fn snapshots_key(store_id: &StoreId) -> String {
+    format!("snapshots:{}", **store_id)
+}
+
+fn corrupted() -> ServerError {
+    ServerError::new(error::INTERNAL_ERROR, "Corrupted store snapshot")
+}
+
+fn compress(snapshot: &Snapshot) -> Result<Vec<u8>> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut gz, snapshot).map_err(|_| corrupted())?;
+    gz.finish().map_err(|_| corrupted())
+}
+
+fn decompress(raw: &[u8]) -> Result<Snapshot> {
+    let mut json = String::new();
+    GzDecoder::new(raw)
+        .read_to_string(&mut json)
+        .map_err(|_| corrupted())?;
+    serde_json::from_str(&json).map_err(|_| corrupted())
+}
+
+// gzipped by id
+fn raw_snapshots(c: &mut Connection, store_id: &StoreId) -> Result<HashMap<String, Vec<u8>>> {
+    Ok(redis::cmd("HGETALL")
+        .arg(snapshots_key(&store_id))
+        .query(c)?)
+}
+
+// with the bytes each one takes gzipped, charged to the owner's quota
+fn all_snapshots(c: &mut Connection, store_id: &StoreId) -> Result<Vec<(String, Snapshot, i64)>> {
+    raw_snapshots(c, &store_id)?
+        .into_iter()
+        .map(|(id, raw)| Ok((id, decompress(&raw)?, raw.len() as i64)))
+        .collect()
+}
+
+fn check_access(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<()> {
+    let owner_id = db::stores::get_store_owner(c, &store_id)?;
+    db::assert_owner_or_member_auth(c, &auth, &owner_id)
+}
+
+// Keep the aisles and products of the store as they are now
+pub fn create_snapshot(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    name: &str,
+) -> Result<StoreSnapshot> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let snapshot = Snapshot {
+            name: name.trim().to_owned(),
+            created_at: now_s(),
+            aisles: db::aisles::get_aisles_in_store(c, &store_id)?,
+        };
+        let snapshot_id = Uuid::new_v4().to_hyphenated().to_string();
+        let compressed = compress(&snapshot)?;
+        let mut existing = all_snapshots(c, &store_id)?;
+        existing.sort_by_key(|(_, s, _)| s.created_at);
+        let dropped = &existing[..existing.len().saturating_sub(MAX_SNAPSHOTS - 1)];
+        let freed: i64 = dropped.iter().map(|(_, _, size)| size).sum();
+        let growth = compressed.len() as i64 - freed;
+        db::quotas::check_bytes(c, &user_id, growth)?;
+        let snapshots_key = snapshots_key(&store_id);
+        transaction(c, &[&snapshots_key], |c, pipe| {
+            for (id, _, _) in dropped {
+                pipe.hdel(&snapshots_key, id).ignore();
+            }
+            db::quotas::transaction_charge(c, pipe, &user_id, growth)?;
+            pipe.hset(&snapshots_key, &snapshot_id, &compressed)
+                .ignore()
+                .query(c)
+        })?;
+        Ok(snapshot.info(snapshot_id))
+    })
+}
+
+// Newest first
+pub fn get_snapshots(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+) -> Result<Vec<StoreSnapshot>> {
+    check_access(c, &auth, &store_id)?;
+    let mut snapshots: Vec<StoreSnapshot> = all_snapshots(c, &store_id)?
+        .into_iter()
+        .map(|(id, s, _)| s.info(id))
+        .collect();
+    snapshots.sort_by(|a, b| (b.created_at, &a.snapshot_id).cmp(&(a.created_at, &b.snapshot_id)));
+    Ok(snapshots)
+}
+
+// The aisles of the store when the snapshot was made, not found if there is
+// no such snapshot. The caller checks the permission.
+pub fn get_snapshot_aisles(
+    c: &mut Connection,
+    store_id: &StoreId,
+    snapshot_id: &str,
+) -> Result<Vec<Aisle>> {
+    let raw: Option<Vec<u8>> = c.hget(&snapshots_key(&store_id), snapshot_id)?;
+    let raw = raw.ok_or_else(db::not_found)?;
+    Ok(decompress(&raw)?.aisles)
+}
+
+// Put the store back as it was when the snapshot was made: its aisles and
+// products replace the current ones, under new ids
+pub fn restore_snapshot(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    snapshot_id: &str,
+) -> Result<Store> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let aisles = get_snapshot_aisles(c, &store_id, snapshot_id)?;
+        let owner_id = db::stores::get_store_owner(c, &store_id)?;
+        let size = |aisles: &[Aisle]| -> i64 {
+            aisles
+                .iter()
+                .map(|a| {
+                    db::quotas::entity_size(&a.name)
+                        + a.products
+                            .iter()
+                            .map(|p| db::quotas::entity_size(&p.name))
+                            .sum::<i64>()
+                })
+                .sum()
+        };
+        let current = db::aisles::get_aisles_in_store(c, &store_id)?;
+        db::quotas::check_bytes(c, &owner_id, size(&aisles) - size(&current))?;
+        let snapshots_key = snapshots_key(&store_id);
+        transaction(c, &[&snapshots_key], |c, pipe| {
+            let freed = db::aisles::transaction_purge_aisles_in_store(c, pipe, &store_id)?;
+            db::quotas::transaction_charge(c, pipe, &owner_id, size(&aisles) - freed)?;
+            for aisle in &aisles {
+                let aisle_id = db::ids::get_next_aisle_id();
+                db::aisles::transaction_restore_aisle(pipe, &owner_id, &store_id, &aisle_id, aisle);
+                for product in &aisle.products {
+                    let product_id = db::ids::get_next_product_id();
+                    db::products::transaction_restore_product(
+                        pipe,
+                        &owner_id,
+                        &aisle_id,
+                        &product_id,
+                        product,
+                    );
+                }
+            }
+            db::journal::record(
+                c,
+                pipe,
+                &store_id,
+                Entity::Store,
+                Change::Replaced,
+                &store_id,
+            )?;
+            pipe.query(c)
+        })?;
+        db::stores::recount_items(c, &store_id)?;
+        info!("Store {} restored to snapshot {}", **store_id, snapshot_id);
+        db::stores::get_store(c, &store_id)
+    })
+}
+
+// What became of the store of snapshot `a` in snapshot `b`, or now if `b` is
+// `LIVE`
+pub fn diff_snapshots(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    a: &str,
+    b: &str,
+) -> Result<StoreDiff> {
+    check_access(c, &auth, &store_id)?;
+    let before = get_snapshot_aisles(c, &store_id, a)?;
+    let after = if b == LIVE {
+        db::aisles::get_aisles_in_store(c, &store_id)?
+    } else {
+        get_snapshot_aisles(c, &store_id, b)?
+    };
+    Ok(diff_aisles(&before, &after))
+}
+
+// The items of two states of the store which are the same: by the first of
+// `same` telling so, the others for what it left. Returns them as pairs,
+// then the removed and the added ones.
+fn pair_up<T: Copy>(
+    before: Vec<T>,
+    after: Vec<T>,
+    same: &[&dyn Fn(T, T) -> bool],
+) -> (Vec<(T, T)>, Vec<T>, Vec<T>) {
+    let (mut pairs, mut removed, mut added) = (vec![], before, after);
+    for same in same {
+        let mut unpaired = vec![];
+        for a in added {
+            match removed.iter().position(|b| same(*b, a)) {
+                Some(i) => pairs.push((removed.remove(i), a)),
+                None => unpaired.push(a),
+            }
+        }
+        added = unpaired;
+    }
+    (pairs, removed, added)
+}
+
+fn diffed_aisle(aisle: &Aisle) -> DiffedAisle {
+    DiffedAisle::new(aisle.name.clone(), aisle.sort_weight)
+}
+
+fn diffed_product((aisle, product): (&str, &Product)) -> DiffedProduct {
+    DiffedProduct::new(
+        aisle.to_owned(),
+        product.name.clone(),
+        product.quantity,
+        product.unit.clone(),
+        product.is_done,
+    )
+}
+
+// with the name of their aisle
+fn aisle_products(aisles: &[Aisle]) -> Vec<(&str, &Product)> {
+    aisles
+        .iter()
+        .flat_map(|a| a.products.iter().map(move |p| (a.name.as_str(), p)))
+        .collect()
+}
+
+// Aisles and products are the same by id, or by name for those a restore
+// gave new ids to
+fn diff_aisles(before: &[Aisle], after: &[Aisle]) -> StoreDiff {
+    let (aisles, removed, added) = pair_up::<&Aisle>(
+        before.iter().collect(),
+        after.iter().collect(),
+        &[
+            &|a: &Aisle, b: &Aisle| a.aisle_id == b.aisle_id,
+            &|a: &Aisle, b: &Aisle| normalized(&a.name) == normalized(&b.name),
+        ],
+    );
+    let mut diff = StoreDiff::new(
+        added.into_iter().map(diffed_aisle).collect(),
+        removed.into_iter().map(diffed_aisle).collect(),
+        aisles
+            .into_iter()
+            .map(|(a, b)| ChangedAisle::new(diffed_aisle(a), diffed_aisle(b)))
+            .filter(|c| c.before != c.after)
+            .collect(),
+        vec![],
+        vec![],
+        vec![],
+    );
+    let (products, removed, added) = pair_up(
+        aisle_products(before),
+        aisle_products(after),
+        &[
+            &|a: (&str, &Product), b: (&str, &Product)| a.1.product_id == b.1.product_id,
+            &|a: (&str, &Product), b: (&str, &Product)| {
+                normalized(&a.1.name) == normalized(&b.1.name)
+            },
+        ],
+    );
+    diff.added_products = added.into_iter().map(diffed_product).collect();
+    diff.removed_products = removed.into_iter().map(diffed_product).collect();
+    diff.changed_products = products
+        .into_iter()
+        .map(|(a, b)| ChangedProduct::new(diffed_product(a), diffed_product(b)))
+        .filter(|c| c.before != c.after)
+        .collect();
+    diff.sort();
+    diff
+}
+
+// What the snapshots of the store take of its owner's bytes quota
+pub fn stored_bytes(c: &mut Connection, store_id: &StoreId) -> Result<i64> {
+    Ok(raw_snapshots(c, &store_id)?
+        .values()
+        .map(|raw| raw.len() as i64)
+        .sum())
+}
+
+// to be used only in a transaction, doesn't execute the `pipe`. Returns the
+// bytes freed, for the caller to credit back
+pub fn transaction_purge_snapshots(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+) -> Result<i64> {
+    let freed = stored_bytes(c, &store_id)?;
+    pipe.del(&snapshots_key(&store_id)).ignore();
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        ids::tests::HASH_1, products::tests::save_product_for_test, sessions::tests::AUTH, tests::*,
+    };
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn snapshot_and_restore_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, product_id) = save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        let snapshot = create_snapshot(&mut c, &AUTH, &store_id, " Weekly ").unwrap();
+        assert_eq!(
+            ("Weekly", 1, 1),
+            (snapshot.name.as_str(), snapshot.aisles, snapshot.products)
+        );
+
+        let done = EditProduct::new(None, Some(3), None, Some(true));
+        db::products::modify_product(&mut c, &AUTH, &done, &product_id).unwrap();
+        db::aisles::save_aisle(&mut c, &AUTH, &store_id, "Dairy").unwrap();
+        let store = restore_snapshot(&mut c, &AUTH, &store_id, &snapshot.snapshot_id).unwrap();
+        assert_eq!(1, store.aisles.len());
+        let product = &store.aisles[0].products[0];
+        assert_eq!((1, false), (product.quantity, product.is_done));
+        assert_ne!(*product_id, product.product_id);
+        assert_eq!(
+            Ok(vec![snapshot.clone()]),
+            get_snapshots(&mut c, &AUTH, &store_id)
+        );
+        assert!(restore_snapshot(&mut c, &AUTH, &store_id, "nope").is_err());
+    }
+
+    #[test]
+    fn diff_test() {
+        let product = |id: &str, name: &str, quantity: u32| {
+            Product::new(
+                id.to_owned(),
+                name.to_owned(),
+                quantity,
+                false,
+                Unit::Unit,
+                1f32,
+            )
+        };
+        let before = vec![
+            Aisle::new(
+                "a1".to_owned(),
+                "Dairy".to_owned(),
+                1f32,
+                vec![product("p1", "Milk", 1), product("p2", "Butter", 1)],
+            ),
+            Aisle::new("a2".to_owned(), "Bakery".to_owned(), 2f32, vec![]),
+        ];
+        // restored in between, the ids changed
+        let after = vec![
+            Aisle::new(
+                "a3".to_owned(),
+                "dairy".to_owned(),
+                1f32,
+                vec![product("p3", "milk", 2), product("p4", "Cheese", 1)],
+            ),
+            Aisle::new("a4".to_owned(), "Produce".to_owned(), 3f32, vec![]),
+        ];
+        let diff = diff_aisles(&before, &after);
+        assert_eq!(
+            vec![DiffedAisle::new("Produce".to_owned(), 3f32)],
+            diff.added_aisles
+        );
+        assert_eq!(
+            vec![DiffedAisle::new("Bakery".to_owned(), 2f32)],
+            diff.removed_aisles
+        );
+        assert_eq!(1, diff.changed_aisles.len());
+        let names = |products: &[DiffedProduct]| -> Vec<String> {
+            products.iter().map(|p| p.name.clone()).collect()
+        };
+        assert_eq!(vec!["Cheese"], names(&diff.added_products));
+        assert_eq!(vec!["Butter"], names(&diff.removed_products));
+        assert_eq!(
+            vec![(1, 2)],
+            diff.changed_products
+                .iter()
+                .map(|c| (c.before.quantity, c.after.quantity))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(StoreDiff::default(), diff_aisles(&before, &before));
+    }
+
+    #[test]
+    fn snapshot_limit_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, _) = save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        let first = create_snapshot(&mut c, &AUTH, &store_id, "0").unwrap();
+        // older than the others
+        let mut raw = all_snapshots(&mut c, &store_id).unwrap();
+        let (id, mut oldest, _) = raw.remove(0);
+        oldest.created_at = 0;
+        c.hset(&snapshots_key(&store_id), &id, compress(&oldest).unwrap())
+            .unwrap();
+        for i in 1..=MAX_SNAPSHOTS {
+            create_snapshot(&mut c, &AUTH, &store_id, &i.to_string()).unwrap();
+        }
+        let snapshots = get_snapshots(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!(MAX_SNAPSHOTS, snapshots.len());
+        assert!(snapshots.iter().all(|s| s.snapshot_id != first.snapshot_id));
+    }
+
+    #[test]
+    fn snapshot_quota_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId(HASH_1.to_owned());
+        let (aisle_id, _) = save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        let used = db::quotas::get_usage(&mut c, &user_id).unwrap();
+        create_snapshot(&mut c, &AUTH, &store_id, "weekly").unwrap();
+        let kept: i64 = all_snapshots(&mut c, &store_id)
+            .unwrap()
+            .iter()
+            .map(|(_, _, size)| size)
+            .sum();
+        assert!(kept > 0);
+        assert_eq!(Ok(used + kept), db::quotas::get_usage(&mut c, &user_id));
+
+        db::quotas::set_quotas(db::quotas::Quotas {
+            max_bytes: Some((used + kept) as u64),
+            ..db::quotas::Quotas::default()
+        });
+        assert_eq!(
+            Err(error::QUOTA_EXCEEDED),
+            create_snapshot(&mut c, &AUTH, &store_id, "another").map_err(|e| e.status)
+        );
+        db::quotas::set_quotas(db::quotas::Quotas::default());
+
+        // given back with the store
+        assert_eq!(Ok(()), db::stores::delete_store(&mut c, &AUTH, &store_id));
+        assert_eq!(Ok(0), db::quotas::get_usage(&mut c, &user_id));
+    }
+}
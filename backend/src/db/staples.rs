@@ -0,0 +1,219 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, transaction, Commands};
+
+#[cfg(test)]
+use fake_redis::{transaction, FakeConnection as Connection};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    db::{
+        self,
+        aliases::{normalized, resolve},
+    },
+    error::{self, *},
+    types::*,
+};
+
+// Products a user's household always needs, to add to a store in one go,
+// as JSON by normalized name. The aisle is a name too, the stores don't share
+// theirs.
+fn staples_key(user_id: &UserId) -> String {
+    format!("staples:{}", **user_id)
+}
+
+pub fn get_staples(c: &mut Connection, user_id: &UserId) -> Result<Vec<Staple>> {
+    let raw: HashMap<String, String> = redis::cmd("HGETALL").arg(staples_key(&user_id)).query(c)?;
+    let mut staples = raw
+        .into_iter()
+        .map(|(name, raw)| {
+            serde_json::from_str(&raw)
+                .map(|staple| (name, staple))
+                .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted staples"))
+        })
+        .collect::<Result<Vec<(String, Staple)>>>()?;
+    staples.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(staples.into_iter().map(|(_, staple)| staple).collect())
+}
+
+// Replace the whole list, checked by the caller
+pub fn set_staples(c: &mut Connection, user_id: &UserId, staples: &[Staple]) -> Result<()> {
+    let staples_key = staples_key(&user_id);
+    transaction(c, &[&staples_key], |c, pipe| {
+        pipe.del(&staples_key).ignore();
+        for staple in staples {
+            let raw = serde_json::to_string(staple).expect("staples always serialize");
+            pipe.hset(&staples_key, &normalized(&staple.name), raw)
+                .ignore();
+        }
+        pipe.query(c)
+    })?;
+    Ok(())
+}
+
+// Replaces the staple of the same name
+pub fn add_staple(c: &mut Connection, user_id: &UserId, staple: &Staple) -> Result<()> {
+    let raw = serde_json::to_string(staple).expect("staples always serialize");
+    Ok(c.hset(&staples_key(&user_id), &normalized(&staple.name), raw)?)
+}
+
+pub fn delete_staple(c: &mut Connection, user_id: &UserId, name: &str) -> Result<()> {
+    Ok(c.hdel(&staples_key(&user_id), &normalized(name))?)
+}
+
+pub fn delete_staples(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&staples_key(&user_id))?)
+}
+
+// Add the user's staples the store has no product of, by name or alias, each
+// to the aisle of its aisle's name, made at the end of the store if missing
+pub fn add_staples(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<AddedStaples> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let aliases = db::aliases::get_aliases(c, &user_id)?;
+        let mut present = HashSet::new();
+        let mut aisle_ids = HashMap::new();
+        for aisle in db::aisles::get_aisles_in_store(c, &store_id)? {
+            present.extend(aisle.products.iter().map(|p| resolve(&aliases, &p.name)));
+            aisle_ids
+                .entry(normalized(&aisle.name))
+                .or_insert(aisle.aisle_id);
+        }
+        let mut added = AddedStaples::new(vec![], vec![]);
+        for staple in get_staples(c, &user_id)? {
+            if !present.insert(resolve(&aliases, &staple.name)) {
+                continue;
+            }
+            let aisle_id = match aisle_ids.get(&normalized(&staple.aisle)) {
+                Some(aisle_id) => AisleId(aisle_id.clone()),
+                None => {
+                    let aisle = db::aisles::insert_aisle(c, &user_id, &store_id, &staple.aisle)?;
+                    aisle_ids.insert(normalized(&staple.aisle), aisle.aisle_id.clone());
+                    let aisle_id = AisleId(aisle.aisle_id.clone());
+                    added.aisles.push(aisle);
+                    aisle_id
+                }
+            };
+            let product_id = db::ids::get_next_product_id();
+            let product = db::products::insert_product(
+                c,
+                &user_id,
+                &store_id,
+                &product_id,
+                &staple.name,
+                &aisle_id,
+            )?;
+            added
+                .products
+                .push(ProductChange::new(aisle_id.to_string(), product));
+        }
+        Ok(added)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{aisles::tests::save_aisle_for_test, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+    use warp::http::StatusCode;
+
+    #[test]
+    fn staples_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let user_id = UserId("1".to_owned());
+        let staples = vec![
+            Staple::new("Milk".to_owned(), "Dairy".to_owned()),
+            Staple::new("bread".to_owned(), "Bakery".to_owned()),
+        ];
+        assert_eq!(Ok(()), set_staples(&mut c, &user_id, &staples));
+        assert_eq!(
+            Ok(vec![staples[1].clone(), staples[0].clone()]),
+            get_staples(&mut c, &user_id)
+        );
+        let milk = Staple::new("milk ".to_owned(), "Fridge".to_owned());
+        assert_eq!(Ok(()), add_staple(&mut c, &user_id, &milk));
+        assert_eq!(
+            Ok(vec![staples[1].clone(), milk.clone()]),
+            get_staples(&mut c, &user_id)
+        );
+        assert_eq!(Ok(()), delete_staple(&mut c, &user_id, "BREAD"));
+        assert_eq!(Ok(vec![milk.clone()]), get_staples(&mut c, &user_id));
+        assert_eq!(Ok(()), delete_staples(&mut c, &user_id));
+        assert_eq!(Ok(vec![]), get_staples(&mut c, &user_id));
+    }
+
+    #[test]
+    fn add_staples_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, aisle_id) = save_aisle_for_test(&mut c);
+        let user_id = db::sessions::get_user_id(&mut c, &AUTH).unwrap();
+        let aisle = db::aisles::get_aisles_in_store(&mut c, &store_id).unwrap()[0]
+            .name
+            .clone();
+        db::products::save_product(&mut c, &AUTH, "Tomato", &aisle_id).unwrap();
+        let aliases = vec![("tomatoes".to_owned(), "tomato".to_owned())]
+            .into_iter()
+            .collect();
+        db::aliases::set_aliases(&mut c, &user_id, &aliases).unwrap();
+        let staples = vec![
+            Staple::new("Tomatoes".to_owned(), aisle.to_uppercase()),
+            Staple::new("Milk".to_owned(), "Dairy".to_owned()),
+            Staple::new("Bread".to_owned(), aisle.clone()),
+        ];
+        set_staples(&mut c, &user_id, &staples).unwrap();
+
+        let added = add_staples(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!(
+            vec!["Dairy"],
+            added
+                .aisles
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        let products: Vec<(&str, &str)> = added
+            .products
+            .iter()
+            .map(|p| (p.product.name.as_str(), p.aisle_id.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("Bread", aisle_id.as_str()),
+                ("Milk", added.aisles[0].aisle_id.as_str())
+            ],
+            products
+        );
+
+        let again = add_staples(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!(true, again.aisles.is_empty() && again.products.is_empty());
+    }
+
+    #[test]
+    fn add_staples_other_user_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, _) = save_aisle_for_test(&mut c);
+        let other_id = UserId("other".to_owned());
+        assert_eq!(
+            Ok(()),
+            db::sessions::store_session(&mut c, "other", &other_id)
+        );
+        set_staples(
+            &mut c,
+            &other_id,
+            &[Staple::new("Milk".to_owned(), "Dairy".to_owned())],
+        )
+        .unwrap();
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            add_staples(&mut c, &Auth("other"), &store_id)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+    }
+}
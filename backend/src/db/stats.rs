@@ -0,0 +1,308 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, Pipeline};
+
+#[cfg(test)]
+use fake_redis::{FakeConnection as Connection, FakePipeline as Pipeline};
+
+use std::collections::HashMap;
+
+use crate::{error::Result, types::*};
+
+const DAY_S: u64 = 24 * 3600;
+// marked done again within that long is the same shopping trip
+const SAME_TRIP_S: u64 = 12 * 3600;
+
+// Products added to a store, counted by name in a hash per week that expires
+// once out of the stats
+const WEEK_S: u64 = 7 * 24 * 3600;
+pub const STATS_WEEKS: u64 = 8;
+const MOST_ADDED_LEN: usize = 10;
+
+fn added_key(store_id: &StoreId, week: u64) -> String {
+    format!("added:{}:{}", **store_id, week)
+}
+
+// name to `<times>:<first bought>:<last bought>:<quantity>:<trips>`, the
+// quantity bought over that many of the trips, the ones from before it was
+// kept not counting
+fn purchases_key(store_id: &StoreId) -> String {
+    format!("purchases:{}", **store_id)
+}
+
+struct Purchases {
+    times: u64,
+    first: u64,
+    last: u64,
+    quantity: u64,
+    trips: u64,
+}
+
+impl Purchases {
+    fn parse(raw: &str) -> Option<Self> {
+        let mut parts = raw.splitn(5, ':').map(|p| p.parse().ok());
+        Some(Purchases {
+            times: parts.next()??,
+            first: parts.next()??,
+            last: parts.next()??,
+            quantity: parts.next().unwrap_or(Some(0))?,
+            trips: parts.next().unwrap_or(Some(0))?,
+        })
+    }
+
+    fn habit(self, name: String) -> PurchaseHabit {
+        let interval_s = if self.times > 1 {
+            Some((self.last - self.first) / (self.times - 1))
+        } else {
+            None
+        };
+        PurchaseHabit {
+            name,
+            times: self.times,
+            last_bought: self.last,
+            average_days: interval_s.map(|s| s as f32 / DAY_S as f32),
+            due_at: interval_s.map(|s| self.last + s),
+            average_quantity: if self.trips > 0 {
+                Some(self.quantity as f32 / self.trips as f32)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// the weeks in the stats, this one first
+fn weeks(now: u64) -> impl Iterator<Item = u64> {
+    let this_week = now / WEEK_S;
+    (0..STATS_WEEKS).filter_map(move |ago| this_week.checked_sub(ago))
+}
+
+// to be used in the transaction adding the product
+pub fn transaction_count_added(pipe: &mut Pipeline, store_id: &StoreId, name: &str, now: u64) {
+    let added_key = added_key(&store_id, now / WEEK_S);
+    pipe.hincr(&added_key, &name.trim().to_lowercase(), 1)
+        .ignore()
+        .expire(&added_key, (STATS_WEEKS * WEEK_S) as usize)
+        .ignore();
+}
+
+// to be used in the transaction marking the product done, under the store
+// lock
+pub fn transaction_count_purchase(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    name: &str,
+    quantity: u32,
+    now: u64,
+) -> Result<()> {
+    let purchases_key = purchases_key(&store_id);
+    let name = name.trim().to_lowercase();
+    let raw: Option<String> = redis::cmd("HGET").arg(&purchases_key).arg(&name).query(c)?;
+    let quantity = quantity as u64;
+    let purchases = match raw.as_deref().and_then(Purchases::parse) {
+        // more of it on the same trip
+        Some(p) if now < p.last + SAME_TRIP_S => Purchases {
+            quantity: p.quantity + quantity,
+            trips: p.trips.max(1),
+            ..p
+        },
+        Some(p) => Purchases {
+            times: p.times + 1,
+            first: p.first,
+            last: now,
+            quantity: p.quantity + quantity,
+            trips: p.trips + 1,
+        },
+        None => Purchases {
+            times: 1,
+            first: now,
+            last: now,
+            quantity,
+            trips: 1,
+        },
+    };
+    let raw = format!(
+        "{}:{}:{}:{}:{}",
+        purchases.times, purchases.first, purchases.last, purchases.quantity, purchases.trips
+    );
+    pipe.hset(&purchases_key, &name, raw).ignore();
+    Ok(())
+}
+
+// to be used only in a transaction, doesn't execute the `pipe`
+pub fn transaction_purge_stats(pipe: &mut Pipeline, store_id: &StoreId, now: u64) {
+    for week in weeks(now) {
+        pipe.del(&added_key(&store_id, week)).ignore();
+    }
+    pipe.del(&purchases_key(&store_id)).ignore();
+}
+
+pub fn get_purchase_habits(c: &mut Connection, store_id: &StoreId) -> Result<Vec<PurchaseHabit>> {
+    let raw: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(purchases_key(&store_id))
+        .query(c)?;
+    let mut habits: Vec<PurchaseHabit> = raw
+        .into_iter()
+        .filter_map(|(name, raw)| Purchases::parse(&raw).map(|p| p.habit(name)))
+        .collect();
+    habits.sort_by(|a, b| b.times.cmp(&a.times).then_with(|| a.name.cmp(&b.name)));
+    Ok(habits)
+}
+
+// How the product of that name, lowercased and resolved, is bought in the
+// store, None if never
+pub fn get_purchase_habit(
+    c: &mut Connection,
+    store_id: &StoreId,
+    name: &str,
+) -> Result<Option<PurchaseHabit>> {
+    let name = name.trim().to_lowercase();
+    let raw: Option<String> = redis::cmd("HGET")
+        .arg(purchases_key(&store_id))
+        .arg(&name)
+        .query(c)?;
+    Ok(raw
+        .as_deref()
+        .and_then(Purchases::parse)
+        .map(|p| p.habit(name)))
+}
+
+fn most_added(counts: HashMap<String, u64>) -> Vec<AddedProduct> {
+    let mut added: Vec<AddedProduct> = counts
+        .into_iter()
+        .map(|(name, times)| AddedProduct::new(name, times))
+        .collect();
+    added.sort_by(|a, b| b.times.cmp(&a.times).then_with(|| a.name.cmp(&b.name)));
+    added.truncate(MOST_ADDED_LEN);
+    added
+}
+
+// The products added most each week, and over all the weeks
+pub fn get_added(
+    c: &mut Connection,
+    store_id: &StoreId,
+    now: u64,
+) -> Result<(Vec<AddedProduct>, Vec<AddedInWeek>)> {
+    let mut total: HashMap<String, u64> = HashMap::new();
+    let mut by_week = vec![];
+    for week in weeks(now) {
+        let counts: HashMap<String, u64> = redis::cmd("HGETALL")
+            .arg(added_key(&store_id, week))
+            .query(c)?;
+        for (name, times) in &counts {
+            *total.entry(name.clone()).or_insert(0) += times;
+        }
+        by_week.push(AddedInWeek::new(week * WEEK_S, most_added(counts)));
+    }
+    Ok((most_added(total), by_week))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn get_added_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = StoreId::new("1".to_owned());
+        let now = 10 * WEEK_S + 1;
+        let mut pipe = Pipeline::new(c.db);
+        transaction_count_added(&mut pipe, &store_id, "Milk", now - WEEK_S);
+        transaction_count_added(&mut pipe, &store_id, "Eggs", now);
+        transaction_count_added(&mut pipe, &store_id, " milk", now);
+        transaction_count_added(&mut pipe, &store_id, "Bread", now);
+        assert_eq!(Ok(()), pipe.query(&c));
+
+        let (most, by_week) = get_added(&mut c, &store_id, now).unwrap();
+        assert_eq!(
+            vec![
+                AddedProduct::new("milk".to_owned(), 2),
+                AddedProduct::new("bread".to_owned(), 1),
+                AddedProduct::new("eggs".to_owned(), 1),
+            ],
+            most
+        );
+        assert_eq!(STATS_WEEKS as usize, by_week.len());
+        assert_eq!(10 * WEEK_S, by_week[0].week_start);
+        assert_eq!(3, by_week[0].products.len());
+        assert_eq!(
+            vec![AddedProduct::new("milk".to_owned(), 1)],
+            by_week[1].products
+        );
+        assert_eq!(true, by_week[2].products.is_empty());
+
+        let mut pipe = Pipeline::new(c.db);
+        transaction_purge_stats(&mut pipe, &store_id, now);
+        assert_eq!(Ok(()), pipe.query(&c));
+        assert_eq!(
+            Ok((vec![], by_week_empty(now))),
+            get_added(&mut c, &store_id, now)
+        );
+    }
+
+    #[test]
+    fn purchase_habits_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = StoreId::new("1".to_owned());
+        let start = 1_000_000;
+        // the second one is the same trip
+        for (name, quantity, at) in &[
+            ("Coffee", 1, start),
+            ("coffee", 1, start + 3600),
+            ("Coffee", 2, start + 2 * DAY_S),
+            ("Coffee", 2, start + 4 * DAY_S),
+            ("Salt", 1, start),
+        ] {
+            let mut pipe = Pipeline::new(c.db);
+            assert_eq!(
+                Ok(()),
+                transaction_count_purchase(&mut c, &mut pipe, &store_id, name, *quantity, *at)
+            );
+            assert_eq!(Ok(()), pipe.query(&c));
+        }
+
+        let habits = get_purchase_habits(&mut c, &store_id).unwrap();
+        assert_eq!(
+            vec![
+                PurchaseHabit {
+                    name: "coffee".to_owned(),
+                    times: 3,
+                    last_bought: start + 4 * DAY_S,
+                    average_days: Some(2f32),
+                    due_at: Some(start + 6 * DAY_S),
+                    average_quantity: Some(2f32),
+                },
+                PurchaseHabit {
+                    name: "salt".to_owned(),
+                    times: 1,
+                    last_bought: start,
+                    average_days: None,
+                    due_at: None,
+                    average_quantity: Some(1f32),
+                },
+            ],
+            habits
+        );
+        assert_eq!(
+            Ok(Some(habits[1].clone())),
+            get_purchase_habit(&mut c, &store_id, " SALT")
+        );
+        assert_eq!(Ok(None), get_purchase_habit(&mut c, &store_id, "pepper"));
+
+        // counted before the quantities were
+        let old = Purchases::parse("2:10:20").unwrap();
+        assert_eq!(None, old.habit("tea".to_owned()).average_quantity);
+    }
+
+    fn by_week_empty(now: u64) -> Vec<AddedInWeek> {
+        weeks(now)
+            .map(|week| AddedInWeek::new(week * WEEK_S, vec![]))
+            .collect()
+    }
+}
@@ -1,13 +1,34 @@
 #[cfg(not(test))]
-use redis::{transaction, Commands, Connection};
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{transaction, Commands, Pipeline};
 
 #[cfg(test)]
-use fake_redis::{transaction, FakeConnection as Connection};
+use fake_redis::{transaction, FakeConnection as Connection, FakePipeline as Pipeline};
+
+use std::collections::HashMap;
 
-use crate::{db, error::Result, types::*};
+use crate::{
+    db::{
+        self,
+        aliases::{normalized, resolve},
+        journal::{Change, Entity},
+    },
+    error::{self, Result, ServerError},
+    links::now_s,
+    types::*,
+};
 
 const STORE_NAME: &str = "name";
 const STORE_OWNER: &str = "owner_id";
+// products in the store and the ones not done, kept up to date by every
+// change, missing for stores from before they were
+const STORE_ITEMS: &str = "items";
+const STORE_ITEMS_LEFT: &str = "items_left";
+const STORE_UPDATED_AT: &str = "updated_at";
+// what new products start with, missing until set
+const STORE_DEFAULT_QTY: &str = "default_quantity";
+const STORE_DEFAULT_UNIT: &str = "default_unit";
 
 fn store_key(id: &StoreId) -> String {
     format!("store:{}", **id)
@@ -17,19 +38,78 @@ fn user_stores_list_key(user_id: &UserId) -> String {
     format!("stores:{}", **user_id)
 }
 
+// user id to how they left the store's list, apart from the store itself
+fn ui_state_key(id: &StoreId) -> String {
+    format!("ui_state:{}", **id)
+}
+
+// Not found if there is no such store
 pub fn get_store_owner(c: &mut Connection, store_id: &StoreId) -> Result<UserId> {
-    Ok(UserId(c.hget(&store_key(&store_id), STORE_OWNER)?))
+    let owner_id: Option<String> = c.hget(&store_key(&store_id), STORE_OWNER)?;
+    owner_id.map(UserId).ok_or_else(db::not_found)
 }
 
+// With the products the user needs anywhere, see `db::anywhere`
 pub fn list_store(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<Store> {
     let user_id = db::sessions::get_user_id(c, &auth)?;
-    let store_key = store_key(&store_id);
-    db::verify_permission(&user_id, &get_store_owner(c, &store_id)?)?;
-    Ok(Store::new(
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    let mut store = get_store(c, &store_id)?;
+    store.anywhere = db::anywhere::get_anywhere(c, &user_id)?;
+    Ok(store)
+}
+
+// The whole store, without checking who asks: callers must have done it
+pub fn get_store(c: &mut Connection, store_id: &StoreId) -> Result<Store> {
+    let mut store = Store::new(
         store_id.to_string(),
-        c.hget(&store_key, STORE_NAME)?,
+        c.hget(&store_key(&store_id), STORE_NAME)?,
         db::aisles::get_aisles_in_store(c, &store_id)?,
-    ))
+    );
+    store.defaults = get_product_defaults(c, &store_id)?;
+    Ok(store)
+}
+
+pub fn get_product_defaults(c: &mut Connection, store_id: &StoreId) -> Result<ProductDefaults> {
+    let store_key = store_key(&store_id);
+    let quantity: Option<u32> = c.hget(&store_key, STORE_DEFAULT_QTY)?;
+    let unit: Option<u32> = c.hget(&store_key, STORE_DEFAULT_UNIT)?;
+    let defaults = ProductDefaults::default();
+    Ok(ProductDefaults {
+        quantity: quantity.unwrap_or(defaults.quantity),
+        unit: unit.map_or(defaults.unit, Unit::from),
+    })
+}
+
+pub fn set_product_defaults(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    defaults: &ProductDefaults,
+) -> Result<()> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let store_key = store_key(&store_id);
+        transaction(c, &[&store_key], |c, pipe| {
+            db::journal::record(
+                c,
+                pipe,
+                &store_id,
+                Entity::Store,
+                Change::Updated,
+                &store_id,
+            )?;
+            pipe.hset(&store_key, STORE_DEFAULT_QTY, defaults.quantity)
+                .ignore()
+                .hset(
+                    &store_key,
+                    STORE_DEFAULT_UNIT,
+                    u32::from(defaults.unit.clone()),
+                )
+                .ignore()
+                .query(c)
+        })?;
+        Ok(())
+    })
 }
 
 pub fn save_store(c: &mut Connection, auth: &Auth, name: &str) -> Result<StoreId> {
@@ -37,11 +117,22 @@ pub fn save_store(c: &mut Connection, auth: &Auth, name: &str) -> Result<StoreId
     let user_id = db::sessions::get_user_id(c, &auth)?;
     let store_key = store_key(&store_id);
     let user_stores_key = user_stores_list_key(&user_id);
+    let size = db::quotas::entity_size(name);
+    db::quotas::check_bytes(c, &user_id, size)?;
     transaction(c, &[&store_key, &user_stores_key], |c, pipe| {
+        // counted under WATCH, two requests can't both take the last store
+        db::quotas::check_stores(c, &user_stores_key)?;
+        db::quotas::transaction_charge(c, pipe, &user_id, size)?;
         pipe.hset(&store_key, STORE_NAME, name)
             .ignore()
             .hset(&store_key, STORE_OWNER, user_id.to_string())
             .ignore()
+            .hset(&store_key, STORE_ITEMS, 0)
+            .ignore()
+            .hset(&store_key, STORE_ITEMS_LEFT, 0)
+            .ignore()
+            .hset(&store_key, STORE_UPDATED_AT, now_s())
+            .ignore()
             .sadd(&user_stores_key, store_id.to_string())
             .query(c)
     })?;
@@ -55,40 +146,606 @@ pub fn edit_store(
     store_id: &StoreId,
     new_name: &str,
 ) -> Result<()> {
-    let owner_id = get_store_owner(c, &store_id)?;
-    db::verify_permission_auth(c, &auth, &owner_id)?;
-    Ok(c.hset(&store_key(&store_id), STORE_NAME, new_name)?)
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let store_key = store_key(&store_id);
+        let old_name: String = c.hget(&store_key, STORE_NAME)?;
+        let growth = new_name.len() as i64 - old_name.len() as i64;
+        db::quotas::check_bytes(c, &user_id, growth)?;
+        transaction(c, &[&store_key], |c, pipe| {
+            db::journal::record(
+                c,
+                pipe,
+                &store_id,
+                Entity::Store,
+                Change::Updated,
+                &store_id,
+            )?;
+            db::quotas::transaction_charge(c, pipe, &user_id, growth)?;
+            pipe.hset(&store_key, STORE_NAME, new_name).query(c)
+        })?;
+        Ok(())
+    })
+}
+
+// The aisles and products must all be in one store of the user, which the
+// first of them tells; nothing changes otherwise
+pub fn edit_sort_weights(c: &mut Connection, auth: &Auth, data: &EditWeight) -> Result<()> {
+    let aisles = data.aisles.as_deref().unwrap_or(&[]);
+    let products = data.products.as_deref().unwrap_or(&[]);
+    let store_id = match (aisles.first(), products.first()) {
+        (Some(aisle), _) => db::aisles::find_aisle_store(c, &AisleId(aisle.id.clone()))?,
+        (None, Some(product)) => {
+            db::products::find_product_store(c, &ProductId(product.id.clone()))?
+        }
+        (None, None) => None,
+    }
+    .ok_or_else(db::not_found)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        for weight in aisles {
+            if !db::aisles::is_aisle_in_store(c, &AisleId(weight.id.clone()), &store_id)? {
+                return Err(db::not_found());
+            }
+        }
+        for weight in products {
+            let product_id = ProductId(weight.id.clone());
+            if db::products::find_product_store(c, &product_id)?.as_ref() != Some(&store_id) {
+                return Err(db::not_found());
+            }
+        }
+        let changes: Vec<_> = aisles
+            .iter()
+            .map(|weight| (Entity::Aisle, Change::Updated, weight.id.as_str()))
+            .chain(
+                products
+                    .iter()
+                    .map(|weight| (Entity::Product, Change::Updated, weight.id.as_str())),
+            )
+            .collect();
+        transaction(c, &[&store_key(&store_id)], |c, pipe| {
+            db::journal::record_all(c, pipe, &store_id, &changes)?;
+            for weight in aisles {
+                db::aisles::edit_aisle_sort_weight(pipe, weight);
+            }
+            for weight in products {
+                db::products::edit_product_sort_weight(pipe, weight);
+            }
+            pipe.query(c)
+        })?;
+        Ok(())
+    })
+}
+
+// changes since `since` version, or the whole store if the journal can't tell
+pub fn list_store_since(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    since: u64,
+) -> Result<VersionedStore> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    // read the version first: a change racing with us will be sent again next time
+    let version = db::journal::current_version(c, &store_id)?;
+    let entries = if since == 0 {
+        None
+    } else {
+        db::journal::entries_since(c, &store_id, since)?
+    };
+    let changes = match entries {
+        Some(entries) => StoreChanges::Delta(build_delta(c, &store_id, entries)?),
+        None => StoreChanges::Snapshot(list_store(c, &auth, &store_id)?),
+    };
+    Ok(VersionedStore::new(version, changes))
+}
+
+// journal entries since `since` version and the version the store is at,
+// None if the journal can't tell
+pub fn store_events(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    since: u64,
+) -> Result<(u64, Option<Vec<db::journal::Entry>>)> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    let version = db::journal::current_version(c, &store_id)?;
+    let entries = db::journal::entries_since(c, &store_id, since)?;
+    Ok((version, entries))
+}
+
+fn build_delta(
+    c: &mut Connection,
+    store_id: &StoreId,
+    entries: Vec<db::journal::Entry>,
+) -> Result<StoreDelta> {
+    // only the latest change of each entity matters
+    let mut latest = HashMap::new();
+    let mut order = vec![];
+    for entry in entries {
+        let key = (entry.entity, entry.id.clone());
+        if latest.insert(key.clone(), entry.change).is_none() {
+            order.push(key);
+        }
+    }
+    let mut delta = StoreDelta::default();
+    for (entity, id) in order {
+        let change = latest[&(entity, id.clone())];
+        match (entity, change) {
+            (Entity::Store, _) => {
+                delta.name = Some(c.hget(&store_key(&store_id), STORE_NAME)?);
+                delta.defaults = Some(get_product_defaults(c, &store_id)?);
+            }
+            (Entity::Aisle, Change::Deleted) => delta.deleted_aisles.push(id),
+            (Entity::Aisle, _) => match db::aisles::get_aisle_change(c, &AisleId(id.clone()))? {
+                Some(aisle) => delta.aisles.push(aisle),
+                None => delta.deleted_aisles.push(id),
+            },
+            (Entity::Product, Change::Deleted) => delta.deleted_products.push(id),
+            (Entity::Product, _) => {
+                match db::products::get_product_change(c, &ProductId(id.clone()))? {
+                    Some(product) => delta.products.push(product),
+                    None => delta.deleted_products.push(id),
+                }
+            }
+        }
+    }
+    Ok(delta)
+}
+
+pub fn count_user_stores(c: &mut Connection, user_id: &UserId) -> Result<u64> {
+    Ok(c.scard(&user_stores_list_key(&user_id))?)
+}
+
+// What the user's stores take of the bytes quota: their names, the ones of
+// their aisles and products, and their snapshots
+pub fn stored_bytes(c: &mut Connection, user_id: &UserId) -> Result<i64> {
+    let store_ids: Vec<String> = c.smembers(&user_stores_list_key(&user_id))?;
+    let mut bytes = 0;
+    for store_id in store_ids.into_iter().map(StoreId::new) {
+        let name: Option<String> = c.hget(&store_key(&store_id), STORE_NAME)?;
+        bytes += db::quotas::entity_size(&name.unwrap_or_default());
+        for aisle in db::aisles::get_aisles_in_store(c, &store_id)? {
+            bytes += db::quotas::entity_size(&aisle.name);
+            bytes += aisle
+                .products
+                .iter()
+                .map(|p| db::quotas::entity_size(&p.name))
+                .sum::<i64>();
+        }
+        bytes += db::snapshots::stored_bytes(c, &store_id)?;
+        bytes += db::products::removed_bytes(c, &store_id)?;
+    }
+    Ok(bytes)
 }
 
 pub fn get_all_stores(c: &mut Connection, auth: &Auth) -> Result<Vec<StoreLight>> {
     let user_id = db::sessions::get_user_id(c, &auth)?;
     let all_store_ids: Vec<String> = c.smembers(&user_stores_list_key(&user_id))?;
-    Ok(all_store_ids
+    all_store_ids
         .into_iter()
         .map(|id| {
+            let store_id = StoreId::new(id.to_owned());
+            let store_key = store_key(&store_id);
             let name: String = c
-                .hget(&store_key(&StoreId::new(id.to_owned())), STORE_NAME)
+                .hget(&store_key, STORE_NAME)
                 .expect("Db is corrupted? Should have a store name.");
-            StoreLight::new(name, id)
+            let (items, items_left) = get_or_count_items(c, &store_id)?;
+            let updated_at: Option<u64> = c.hget(&store_key, STORE_UPDATED_AT)?;
+            Ok(StoreLight::new(name, id, items, items_left, updated_at))
         })
-        .collect())
+        .collect()
 }
 
-pub fn delete_store(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<()> {
-    let owner_id = get_store_owner(c, &store_id)?;
-    db::verify_permission_auth(c, &auth, &owner_id)?;
+// The version each of the user's stores is at, which changes with anything
+// `get_all_stores` returns
+pub fn get_store_versions(c: &mut Connection, auth: &Auth) -> Result<HashMap<String, u64>> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let all_store_ids: Vec<String> = c.smembers(&user_stores_list_key(&user_id))?;
+    all_store_ids
+        .into_iter()
+        .map(|id| {
+            let version = db::journal::current_version(c, &StoreId::new(id.to_owned()))?;
+            Ok((id, version))
+        })
+        .collect()
+}
+
+fn get_item_counts(c: &mut Connection, store_id: &StoreId) -> Result<Option<(u64, u64)>> {
     let store_key = store_key(&store_id);
-    let user_stores_key = user_stores_list_key(&owner_id);
-    transaction(c, &[&store_key, &user_stores_key], |c, mut pipe| {
-        db::aisles::transaction_purge_aisles_in_store(c, &mut pipe, &store_id)?;
-        pipe.srem(&user_stores_key, store_id.to_string())
-            .ignore()
-            .del(&store_key)
-            .query(c)
-    })?;
+    let items: Option<u64> = c.hget(&store_key, STORE_ITEMS)?;
+    let items_left: Option<u64> = c.hget(&store_key, STORE_ITEMS_LEFT)?;
+    Ok(items.and_then(|items| items_left.map(|left| (items, left))))
+}
+
+fn get_or_count_items(c: &mut Connection, store_id: &StoreId) -> Result<(u64, u64)> {
+    match get_item_counts(c, &store_id)? {
+        Some(counts) => Ok(counts),
+        // may be a replica, the counts are only kept by `count_items`
+        None => db::aisles::count_items_in_store(c, &store_id),
+    }
+}
+
+pub fn get_store_stats(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<StoreStats> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    let (items, items_left) = get_or_count_items(c, &store_id)?;
+    let completed = if items == 0 {
+        0f32
+    } else {
+        (items - items_left) as f32 * 100f32 / items as f32
+    };
+    let (most_added, weeks) = db::stats::get_added(c, &store_id, now_s())?;
+    Ok(StoreStats {
+        items,
+        items_left,
+        completed,
+        changes: db::journal::current_version(c, &store_id)?,
+        aisles: db::aisles::get_aisle_stats(c, &store_id)?,
+        most_added,
+        weeks,
+    })
+}
+
+pub fn get_store_insights(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+) -> Result<StoreInsights> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    let (added, _) = db::stats::get_added(c, &store_id, now_s())?;
+    Ok(StoreInsights {
+        readded: added.into_iter().filter(|p| p.times > 1).collect(),
+        habits: db::stats::get_purchase_habits(c, &store_id)?,
+    })
+}
+
+pub fn get_ui_state(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<StoreUiState> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    let raw: Option<String> = c.hget(&ui_state_key(&store_id), &*user_id)?;
+    match raw {
+        Some(raw) => serde_json::from_str(&raw)
+            .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted UI state")),
+        None => Ok(StoreUiState::default()),
+    }
+}
+
+pub fn set_ui_state(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+    state: &StoreUiState,
+) -> Result<()> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let raw = serde_json::to_string(state).expect("UI state always serializes");
+        c.hset(&ui_state_key(&store_id), &*user_id, raw)?;
+        Ok(())
+    })
+}
+
+// Change the product counts of the store by the products added or removed
+// and the ones not done, in the transaction doing the change. The store lock
+// must be held: stores not counted yet are left to `count_items`.
+pub fn transaction_count_items(
+    c: &mut Connection,
+    pipe: &mut Pipeline,
+    store_id: &StoreId,
+    items: i64,
+    items_left: i64,
+) -> Result<()> {
+    if (items, items_left) == (0, 0) || get_item_counts(c, &store_id)?.is_none() {
+        return Ok(());
+    }
+    let store_key = store_key(&store_id);
+    pipe.hincr(&store_key, STORE_ITEMS, items)
+        .ignore()
+        .hincr(&store_key, STORE_ITEMS_LEFT, items_left)
+        .ignore();
     Ok(())
 }
 
+// Count the products of the store again after they all changed, if they are
+// counted. To be called with the store lock held
+pub fn recount_items(c: &mut Connection, store_id: &StoreId) -> Result<()> {
+    if get_item_counts(c, &store_id)?.is_some() {
+        let store_key = store_key(&store_id);
+        let (items, items_left) = db::aisles::count_items_in_store(c, &store_id)?;
+        c.hset(&store_key, STORE_ITEMS, items)?;
+        c.hset(&store_key, STORE_ITEMS_LEFT, items_left)?;
+    }
+    Ok(())
+}
+
+// Remember when the store changed, see `db::journal::record`
+pub fn transaction_touch(pipe: &mut Pipeline, store_id: &StoreId, now: u64) {
+    pipe.hset(&store_key(&store_id), STORE_UPDATED_AT, now)
+        .ignore();
+}
+
+// Count the products of a store from before the counts were kept, false if
+// it already was
+pub fn count_items(c: &mut Connection, store_id: &StoreId) -> Result<bool> {
+    db::locks::with_store_lock_unchecked(c, &store_id, |c| {
+        let store_key = store_key(&store_id);
+        if get_item_counts(c, &store_id)?.is_some() || !c.exists(&store_key)? {
+            return Ok(false);
+        }
+        let (items, items_left) = db::aisles::count_items_in_store(c, &store_id)?;
+        c.hset(&store_key, STORE_ITEMS, items)?;
+        c.hset(&store_key, STORE_ITEMS_LEFT, items_left)?;
+        Ok(true)
+    })
+}
+
+// Count the products of every store from before the counts were kept,
+// returns how many were
+pub fn count_all_items(c: &mut Connection) -> Result<u64> {
+    let mut counted = 0;
+    for store_id in get_all_store_ids(c)? {
+        if count_items(c, &store_id)? {
+            counted += 1;
+        }
+    }
+    Ok(counted)
+}
+
+// Every store of every user, for maintenance
+pub fn get_all_store_ids(c: &mut Connection) -> Result<Vec<StoreId>> {
+    let mut all = vec![];
+    for user_id in db::users::get_all_user_ids(c)? {
+        let store_ids: Vec<String> = c.smembers(&user_stores_list_key(&user_id))?;
+        all.extend(store_ids.into_iter().map(StoreId::new));
+    }
+    Ok(all)
+}
+
+// What deleting the store would remove
+pub fn get_store_removal(
+    c: &mut Connection,
+    auth: &Auth,
+    store_id: &StoreId,
+) -> Result<StoreRemoval> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &get_store_owner(c, &store_id)?)?;
+    store_removal(c, &store_id)
+}
+
+// What deleting all the stores of the user would remove, by name
+pub fn get_all_store_removals(c: &mut Connection, user_id: &UserId) -> Result<Vec<StoreRemoval>> {
+    let store_ids: Vec<String> = c.smembers(&user_stores_list_key(&user_id))?;
+    let mut removals = store_ids
+        .into_iter()
+        .map(|id| store_removal(c, &StoreId::new(id)))
+        .collect::<Result<Vec<_>>>()?;
+    removals.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(removals)
+}
+
+fn store_removal(c: &mut Connection, store_id: &StoreId) -> Result<StoreRemoval> {
+    let name: String = c.hget(&store_key(&store_id), STORE_NAME)?;
+    let aisles = db::aisles::get_aisles_in_store(c, &store_id)?;
+    Ok(StoreRemoval {
+        store_id: store_id.to_string(),
+        name,
+        aisles: aisles.len() as u64,
+        products: aisles.iter().map(|a| a.products.len() as u64).sum(),
+    })
+}
+
+pub fn delete_store(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<()> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let store_key = store_key(&store_id);
+        let user_stores_key = user_stores_list_key(&user_id);
+        transaction(c, &[&store_key, &user_stores_key], |c, mut pipe| {
+            let name: Option<String> = c.hget(&store_key, STORE_NAME)?;
+            let freed = db::quotas::entity_size(&name.unwrap_or_default())
+                + db::aisles::transaction_purge_aisles_in_store(c, &mut pipe, &store_id)?
+                + db::snapshots::transaction_purge_snapshots(c, &mut pipe, &store_id)?
+                + db::products::removed_bytes(c, &store_id)?;
+            db::quotas::transaction_charge(c, &mut pipe, &user_id, -freed)?;
+            db::journal::transaction_purge_journal(&mut pipe, &store_id);
+            db::stats::transaction_purge_stats(&mut pipe, &store_id, now_s());
+            pipe.srem(&user_stores_key, store_id.to_string())
+                .ignore()
+                .del(&ui_state_key(&store_id))
+                .ignore()
+                .del(&db::products::removed_products_key(&store_id))
+                .ignore()
+                .del(&store_key)
+                .query(c)
+        })?;
+        Ok(())
+    })
+}
+
+// A target aisle and what it gets from the aisles of the same name
+struct AisleMerge<'a> {
+    aliases: &'a HashMap<String, String>,
+    into: &'a Aisle,
+    from: Vec<&'a Aisle>,
+    // with the aisle they come from
+    moved: Vec<(&'a Aisle, &'a Product)>,
+    combined: Vec<(&'a Product, Vec<&'a Product>)>,
+}
+
+impl<'a> AisleMerge<'a> {
+    fn add(&mut self, from: &'a Aisle) {
+        self.from.push(from);
+        let aliases = self.aliases;
+        for product in &from.products {
+            let name = resolve(aliases, &product.name);
+            let namesake = self
+                .into
+                .products
+                .iter()
+                .find(|p| resolve(aliases, &p.name) == name && p.unit == product.unit);
+            match namesake {
+                Some(into) => match self
+                    .combined
+                    .iter_mut()
+                    .find(|(p, _)| p.product_id == into.product_id)
+                {
+                    Some((_, from)) => from.push(product),
+                    None => self.combined.push((into, vec![product])),
+                },
+                None => self.moved.push((from, product)),
+            }
+        }
+    }
+
+    fn report(&self) -> MergedAisle {
+        MergedAisle {
+            name: self.into.name.clone(),
+            moved_products: self.moved.iter().map(|(_, p)| p.name.clone()).collect(),
+            combined_products: self
+                .combined
+                .iter()
+                .flat_map(|(_, from)| from.iter().map(|p| p.name.clone()))
+                .collect(),
+        }
+    }
+}
+
+fn max_weight<'a>(weights: impl Iterator<Item = &'a f32>) -> f32 {
+    weights.fold(0f32, |max, w| max.max(*w))
+}
+
+// Move what `source` holds into `target` and delete `source`. Aisles of the
+// same name, once trimmed and lowercased, become one, and so do their products
+// of the same name, or aliases of it, and unit. With `dry_run` nothing changes, the plan is only
+// returned.
+pub fn merge_stores(
+    c: &mut Connection,
+    auth: &Auth,
+    target: &StoreId,
+    source: &StoreId,
+    dry_run: bool,
+) -> Result<StoreMerge> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::locks::with_store_locks(c, &user_id, &target, &source, |c| {
+        let aliases = db::aliases::get_aliases(c, &user_id)?;
+        let mut target_aisles = db::aisles::get_aisles_in_store(c, &target)?;
+        let mut source_aisles = db::aisles::get_aisles_in_store(c, &source)?;
+        // sets come in any order, the plan must not
+        target_aisles.sort_by(|a, b| a.aisle_id.cmp(&b.aisle_id));
+        source_aisles.sort_by(|a, b| a.aisle_id.cmp(&b.aisle_id));
+
+        let mut moved: Vec<&Aisle> = vec![];
+        let mut merges: Vec<AisleMerge> = vec![];
+        for from in &source_aisles {
+            let name = normalized(&from.name);
+            match target_aisles.iter().find(|a| normalized(&a.name) == name) {
+                Some(into) => match merges.iter_mut().find(|m| m.into.aisle_id == into.aisle_id) {
+                    Some(merge) => merge.add(from),
+                    None => {
+                        let mut merge = AisleMerge {
+                            aliases: &aliases,
+                            into,
+                            from: vec![],
+                            moved: vec![],
+                            combined: vec![],
+                        };
+                        merge.add(from);
+                        merges.push(merge);
+                    }
+                },
+                None => moved.push(from),
+            }
+        }
+        let plan = StoreMerge {
+            moved_aisles: moved.iter().map(|a| a.name.clone()).collect(),
+            merged_aisles: merges.iter().map(AisleMerge::report).collect(),
+        };
+        if dry_run {
+            return Ok(plan);
+        }
+
+        db::aisles::check_more_aisles(c, &target, moved.len() as u64)?;
+        for merge in &merges {
+            let products_key =
+                db::products::products_in_aisle_key(&AisleId(merge.into.aisle_id.clone()));
+            db::quotas::check_more_products(c, &products_key, merge.moved.len() as u64)?;
+        }
+        let store_name: Option<String> = c.hget(&store_key(&source), STORE_NAME)?;
+        let freed = db::quotas::entity_size(&store_name.unwrap_or_default())
+            + merges
+                .iter()
+                .flat_map(|m| m.from.iter())
+                .map(|a| db::quotas::entity_size(&a.name))
+                .sum::<i64>()
+            + merges
+                .iter()
+                .flat_map(|m| m.combined.iter().flat_map(|(_, from)| from.iter()))
+                .map(|p| db::quotas::entity_size(&p.name))
+                .sum::<i64>();
+
+        let target_key = store_key(&target);
+        let source_key = store_key(&source);
+        let user_stores_key = user_stores_list_key(&user_id);
+        transaction(
+            c,
+            &[&target_key, &source_key, &user_stores_key],
+            |c, pipe| {
+                let mut aisle_weight = max_weight(target_aisles.iter().map(|a| &a.sort_weight));
+                for aisle in &moved {
+                    aisle_weight += 1f32;
+                    let aisle_id = AisleId(aisle.aisle_id.clone());
+                    db::aisles::transaction_move_aisle(
+                        pipe,
+                        &aisle_id,
+                        &source,
+                        &target,
+                        aisle_weight,
+                    );
+                }
+                for merge in &merges {
+                    let into_id = AisleId(merge.into.aisle_id.clone());
+                    let mut weight = max_weight(merge.into.products.iter().map(|p| &p.sort_weight));
+                    for (from, product) in &merge.moved {
+                        weight += 1f32;
+                        db::products::transaction_move_product(
+                            pipe,
+                            &ProductId(product.product_id.clone()),
+                            &AisleId(from.aisle_id.clone()),
+                            &into_id,
+                            weight,
+                        );
+                    }
+                    for (into, from) in &merge.combined {
+                        db::products::transaction_combine_products(pipe, into, from);
+                    }
+                    for from in &merge.from {
+                        db::aisles::transaction_drop_emptied_aisle(
+                            pipe,
+                            &AisleId(from.aisle_id.clone()),
+                            &source,
+                        );
+                    }
+                }
+                // one entry for it all, clients following the journal take
+                // the merged store at once rather than every product moved
+                db::journal::record(c, pipe, &target, Entity::Store, Change::Replaced, &target)?;
+                let freed = freed + db::snapshots::transaction_purge_snapshots(c, pipe, &source)?;
+                db::quotas::transaction_charge(c, pipe, &user_id, -freed)?;
+                db::journal::transaction_purge_journal(pipe, &source);
+                db::stats::transaction_purge_stats(pipe, &source, now_s());
+                pipe.srem(&user_stores_key, source.to_string())
+                    .ignore()
+                    .del(&source_key)
+                    .query(c)
+            },
+        )?;
+        // combining may turn done products into ones left to buy, count again
+        recount_items(c, &target)?;
+        Ok(plan)
+    })
+}
+
 pub fn delete_all_user_stores(c: &mut Connection, auth: &Auth) -> Result<()> {
     let user_id = db::sessions::get_user_id(c, &auth)?;
     let user_stores_key = user_stores_list_key(&user_id);
@@ -107,6 +764,7 @@ pub mod tests {
     use db::{ids::tests::*, sessions::tests::*, tests::*, users::tests::*};
 
     use fake_redis::FakeCient as Client;
+    use warp::http::StatusCode;
 
     pub const STORE_TEST_NAME: &str = "storetest";
     const NEW_STORE_NAME: &str = "new_store_name";
@@ -152,6 +810,66 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn edit_sort_weights_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, aisle_id) = db::aisles::tests::save_aisle_for_test(&mut c);
+        let other_store = save_store(&mut c, &AUTH, NEW_STORE_NAME).unwrap();
+        let other_aisle = db::aisles::save_aisle(&mut c, &AUTH, &other_store, "Other").unwrap();
+        let weights = |ids: &[&str]| {
+            EditWeight::new(
+                Some(
+                    ids.iter()
+                        .map(|id| AisleItemWeight::new(id.to_string(), 7.0))
+                        .collect(),
+                ),
+                None,
+            )
+        };
+        let weight = |c: &mut Connection| get_store(c, &store_id).unwrap().aisles[0].sort_weight;
+
+        // an aisle of another store, or none at all, and nothing changes
+        let before = weight(&mut c);
+        let res = edit_sort_weights(&mut c, &AUTH, &weights(&[&aisle_id, &other_aisle.aisle_id]));
+        assert_eq!(Some(StatusCode::NOT_FOUND), res.err().map(|e| e.status));
+        let res = edit_sort_weights(&mut c, &AUTH, &weights(&[&aisle_id, "nope"]));
+        assert_eq!(Some(StatusCode::NOT_FOUND), res.err().map(|e| e.status));
+        assert_eq!(before, weight(&mut c));
+
+        assert_eq!(
+            Ok(()),
+            edit_sort_weights(&mut c, &AUTH, &weights(&[&aisle_id]))
+        );
+        assert_eq!(7.0, weight(&mut c));
+    }
+
+    #[test]
+    fn product_defaults_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, aisle_id) = db::aisles::tests::save_aisle_for_test(&mut c);
+        assert_eq!(
+            Ok(ProductDefaults::default()),
+            get_product_defaults(&mut c, &store_id)
+        );
+
+        let defaults = ProductDefaults {
+            quantity: 500,
+            unit: Unit::Gram,
+        };
+        assert_eq!(
+            Ok(()),
+            set_product_defaults(&mut c, &AUTH, &store_id, &defaults)
+        );
+        let product = db::products::save_product(&mut c, &AUTH, "Flour", &aisle_id).unwrap();
+        assert_eq!((500, Unit::Gram), (product.quantity, product.unit));
+        assert_eq!(
+            Ok(defaults),
+            get_store(&mut c, &store_id).map(|s| s.defaults)
+        );
+    }
+
     #[test]
     fn get_all_stores_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -159,11 +877,27 @@ pub mod tests {
         let store_id = save_store_for_test(&mut c);
         let store_id2 = save_store(&mut c, &AUTH, NEW_STORE_NAME).unwrap();
 
+        let stores = get_all_stores(&mut c, &AUTH).unwrap();
+        let updated_at: Vec<u64> = stores.iter().filter_map(|s| s.updated_at).collect();
+        assert_eq!(2, updated_at.len());
+        assert_eq!(true, updated_at.iter().all(|at| *at <= now_s()));
         let expected_stores = vec![
-            StoreLight::new(STORE_TEST_NAME.to_owned(), store_id.to_string()),
-            StoreLight::new(NEW_STORE_NAME.to_owned(), store_id2.to_string()),
+            StoreLight::new(
+                STORE_TEST_NAME.to_owned(),
+                store_id.to_string(),
+                0,
+                0,
+                Some(updated_at[0]),
+            ),
+            StoreLight::new(
+                NEW_STORE_NAME.to_owned(),
+                store_id2.to_string(),
+                0,
+                0,
+                Some(updated_at[1]),
+            ),
         ];
-        assert_eq!(Ok(expected_stores), get_all_stores(&mut c, &AUTH));
+        assert_eq!(expected_stores, stores);
     }
 
     #[test]
@@ -216,6 +950,90 @@ pub mod tests {
         assert_eq!(Ok(expected), list_store(&mut c, &AUTH, &store_id));
     }
 
+    #[test]
+    fn list_store_since_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+
+        let (store_id, aisle_id) = db::aisles::tests::save_aisle_for_test(&mut c);
+        let aid2 = db::aisles::tests::add_2nd_aisle(&mut c, &store_id);
+        let (p1, p2, _) = db::aisles::tests::fill_aisles(&mut c, &aisle_id, &aid2);
+        let res = list_store_since(&mut c, &AUTH, &store_id, 0).unwrap();
+        assert!(matches!(res.changes, StoreChanges::Snapshot(_)));
+        let version = res.version;
+
+        assert_eq!(Ok(()), edit_store(&mut c, &AUTH, &store_id, NEW_STORE_NAME));
+        let edit = EditProduct::new(None, Some(2), None, None);
+        assert_eq!(
+            Ok(()),
+            db::products::modify_product(&mut c, &AUTH, &edit, &p1)
+        );
+        assert_eq!(Ok(()), db::products::delete_product(&mut c, &AUTH, &p2));
+        assert_eq!(Ok(()), db::aisles::delete_aisle(&mut c, &AUTH, &aid2));
+
+        let expected = VersionedStore::new(
+            version + 4,
+            StoreChanges::Delta(StoreDelta {
+                name: Some(NEW_STORE_NAME.to_owned()),
+                defaults: Some(ProductDefaults::default()),
+                aisles: vec![],
+                products: vec![ProductChange::new(
+                    aisle_id.to_string(),
+                    Product::new(
+                        "".to_owned(),
+                        "product1".to_owned(),
+                        2,
+                        false,
+                        Unit::Unit,
+                        0f32,
+                    ),
+                )],
+                deleted_aisles: vec![aid2.to_string()],
+                deleted_products: vec![p2.to_string()],
+            }),
+        );
+        assert_eq!(
+            Ok(expected),
+            list_store_since(&mut c, &AUTH, &store_id, version)
+        );
+        assert_eq!(
+            Ok(VersionedStore::new(
+                version + 4,
+                StoreChanges::Delta(StoreDelta::default())
+            )),
+            list_store_since(&mut c, &AUTH, &store_id, version + 4)
+        );
+    }
+
+    #[test]
+    fn store_events_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+
+        let (store_id, aisle_id) = db::aisles::tests::save_aisle_for_test(&mut c);
+        let aid2 = db::aisles::tests::add_2nd_aisle(&mut c, &store_id);
+        let (p1, _, _) = db::aisles::tests::fill_aisles(&mut c, &aisle_id, &aid2);
+        let (version, _) = store_events(&mut c, &AUTH, &store_id, 0).unwrap();
+        assert_eq!(
+            Ok((version, Some(vec![]))),
+            store_events(&mut c, &AUTH, &store_id, version)
+        );
+
+        let edit = EditProduct::new(None, Some(2), None, None);
+        assert_eq!(
+            Ok(()),
+            db::products::modify_product(&mut c, &AUTH, &edit, &p1)
+        );
+        let (new_version, entries) = store_events(&mut c, &AUTH, &store_id, version).unwrap();
+        assert_eq!(version + 1, new_version);
+        let entries = entries.unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(new_version, entries[0].version);
+        assert_eq!(Entity::Product, entries[0].entity);
+        assert_eq!(Change::Updated, entries[0].change);
+        assert_eq!(p1.to_string(), entries[0].id);
+    }
+
     #[test]
     fn delete_store_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -262,4 +1080,226 @@ pub mod tests {
         );
         assert_eq!(Ok(false), c.exists(&db::aisles::tests::aisle_key(&aid2)));
     }
+
+    #[test]
+    fn item_counts_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, p1) = db::products::tests::save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        assert_eq!(Ok(Some((1, 1))), get_item_counts(&mut c, &store_id));
+
+        let p2 = db::products::save_product(&mut c, &AUTH, "p2", &aisle_id)
+            .unwrap()
+            .id();
+        let done = EditProduct::new(None, None, None, Some(true));
+        assert_eq!(
+            Ok(()),
+            db::products::modify_product(&mut c, &AUTH, &done, &p1)
+        );
+        // already done, nothing changes
+        assert_eq!(
+            Ok(()),
+            db::products::modify_product(&mut c, &AUTH, &done, &p1)
+        );
+        assert_eq!(Ok(Some((2, 1))), get_item_counts(&mut c, &store_id));
+        assert_eq!(Ok(()), db::products::delete_product(&mut c, &AUTH, &p2));
+        assert_eq!(Ok(Some((1, 0))), get_item_counts(&mut c, &store_id));
+
+        // a store from before the counts, counted on the fly then for good
+        let store_key = store_key(&store_id);
+        let _: () = c.hdel(&store_key, STORE_ITEMS).unwrap();
+        let stores = get_all_stores(&mut c, &AUTH).unwrap();
+        assert_eq!((1, 0), (stores[0].items, stores[0].items_left));
+        assert_eq!(Ok(None), get_item_counts(&mut c, &store_id));
+        assert_eq!(Ok(1), count_all_items(&mut c));
+        assert_eq!(Ok(Some((1, 0))), get_item_counts(&mut c, &store_id));
+        assert_eq!(Ok(false), count_items(&mut c, &store_id));
+
+        assert_eq!(Ok(()), db::aisles::delete_aisle(&mut c, &AUTH, &aisle_id));
+        assert_eq!(Ok(Some((0, 0))), get_item_counts(&mut c, &store_id));
+        assert_eq!(Ok(true), c.hexists(&store_key, STORE_UPDATED_AT));
+    }
+
+    #[test]
+    fn get_store_stats_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (aisle_id, p1) = db::products::tests::save_product_for_test(&mut c);
+        let store_id = db::aisles::get_aisle_store(&mut c, &aisle_id).unwrap();
+        db::products::save_product(&mut c, &AUTH, "Milk", &aisle_id).unwrap();
+        db::products::save_product(&mut c, &AUTH, "Eggs", &aisle_id).unwrap();
+        db::products::save_product(&mut c, &AUTH, "milk", &aisle_id).unwrap();
+        let done = EditProduct::new(None, None, None, Some(true));
+        db::products::modify_product(&mut c, &AUTH, &done, &p1).unwrap();
+
+        let stats = get_store_stats(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!((4, 3), (stats.items, stats.items_left));
+        assert_eq!(true, (stats.completed - 25f32).abs() < std::f32::EPSILON);
+        // the aisle, 4 products, 1 change to one
+        assert_eq!(6, stats.changes);
+        assert_eq!(
+            vec![AisleStats::new(
+                aisle_id.to_string(),
+                db::aisles::tests::NAME.to_owned(),
+                4,
+                3
+            )],
+            stats.aisles
+        );
+        assert_eq!(AddedProduct::new("milk".to_owned(), 2), stats.most_added[0]);
+        assert_eq!(3, stats.most_added.len());
+        assert_eq!(stats.most_added, stats.weeks[0].products);
+
+        let other = Auth("other");
+        let other_id = UserId("other".to_owned());
+        assert_eq!(
+            Ok(()),
+            db::sessions::store_session(&mut c, "other", &other_id)
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            get_store_stats(&mut c, &other, &store_id).map_err(|e| e.status)
+        );
+    }
+
+    #[test]
+    fn get_store_removal_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::aisles::tests::get_aisles_in_store_for_test(&mut c);
+        let removal = get_store_removal(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!((2, 3), (removal.aisles, removal.products));
+        assert_eq!(
+            Ok(vec![removal]),
+            get_all_store_removals(&mut c, &UserId(HASH_1.to_owned()))
+        );
+        assert_eq!(Ok(true), c.exists(&store_key(&store_id)));
+    }
+
+    #[test]
+    fn ui_state_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = save_store_for_test(&mut c);
+        assert_eq!(
+            Ok(StoreUiState::default()),
+            get_ui_state(&mut c, &AUTH, &store_id)
+        );
+        let state = StoreUiState {
+            collapsed_aisles: vec!["1".to_owned()],
+            hide_checked: true,
+        };
+        assert_eq!(Ok(()), set_ui_state(&mut c, &AUTH, &store_id, &state));
+        assert_eq!(Ok(state), get_ui_state(&mut c, &AUTH, &store_id));
+
+        let other = Auth("other");
+        let other_id = UserId("other".to_owned());
+        assert_eq!(
+            Ok(()),
+            db::sessions::store_session(&mut c, "other", &other_id)
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            set_ui_state(&mut c, &other, &store_id, &StoreUiState::default()).map_err(|e| e.status)
+        );
+
+        assert_eq!(Ok(()), delete_store(&mut c, &AUTH, &store_id));
+        assert_eq!(Ok(false), c.exists(&ui_state_key(&store_id)));
+    }
+
+    #[test]
+    fn merge_stores_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        db::users::tests::store_user_for_test(&mut c);
+        db::sessions::tests::store_session_for_test(&mut c, &AUTH);
+        let add_aisle = |c: &mut Connection, store_id: &StoreId, name: &str| {
+            AisleId(
+                db::aisles::save_aisle(c, &AUTH, &store_id, name)
+                    .unwrap()
+                    .aisle_id,
+            )
+        };
+        let add_product = |c: &mut Connection, aisle_id: &AisleId, name: &str| {
+            let product = db::products::save_product(c, &AUTH, name, &aisle_id).unwrap();
+            ProductId(product.product_id)
+        };
+        let target = save_store(&mut c, &AUTH, "Target").unwrap();
+        let dairy = add_aisle(&mut c, &target, "Dairy");
+        let milk = add_product(&mut c, &dairy, "Milk");
+        let done = EditProduct::new(None, None, None, Some(true));
+        db::products::modify_product(&mut c, &AUTH, &done, &milk).unwrap();
+        let source = save_store(&mut c, &AUTH, "Source").unwrap();
+        let other_dairy = add_aisle(&mut c, &source, " dairy");
+        add_product(&mut c, &other_dairy, "milk");
+        add_product(&mut c, &other_dairy, "Cheese");
+        let frozen = add_aisle(&mut c, &source, "Frozen");
+        add_product(&mut c, &frozen, "Peas");
+
+        let plan = StoreMerge {
+            moved_aisles: vec!["Frozen".to_owned()],
+            merged_aisles: vec![MergedAisle {
+                name: "Dairy".to_owned(),
+                moved_products: vec!["Cheese".to_owned()],
+                combined_products: vec!["milk".to_owned()],
+            }],
+        };
+        let sorted = |merge: Result<StoreMerge>| {
+            let mut merge = merge.unwrap();
+            merge.merged_aisles[0].moved_products.sort();
+            merge
+        };
+        let dry_run = merge_stores(&mut c, &AUTH, &target, &source, true);
+        assert_eq!(plan, sorted(dry_run));
+        assert_eq!(Ok(true), c.exists(&store_key(&source)));
+        let merge = merge_stores(&mut c, &AUTH, &target, &source, false);
+        assert_eq!(plan, sorted(merge));
+
+        assert_eq!(Ok(false), c.exists(&store_key(&source)));
+        assert_eq!(
+            Ok(false),
+            c.sismember(
+                &user_stores_list_key(&UserId(HASH_1.to_owned())),
+                source.to_string()
+            )
+        );
+        let store = get_store(&mut c, &target).unwrap();
+        let mut aisles: Vec<(String, Vec<(String, u32, bool)>)> = store
+            .aisles
+            .into_iter()
+            .map(|a| {
+                let mut products: Vec<_> = a
+                    .products
+                    .into_iter()
+                    .map(|p| (p.name, p.quantity, p.is_done))
+                    .collect();
+                products.sort_by(|a, b| a.0.cmp(&b.0));
+                (a.name, products)
+            })
+            .collect();
+        aisles.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            vec![
+                (
+                    "Dairy".to_owned(),
+                    vec![
+                        ("Cheese".to_owned(), 1, false),
+                        ("Milk".to_owned(), 2, false)
+                    ]
+                ),
+                ("Frozen".to_owned(), vec![("Peas".to_owned(), 1, false)]),
+            ],
+            aisles
+        );
+        assert_eq!(Ok(Some((3, 3))), get_item_counts(&mut c, &target));
+        assert_eq!(
+            Ok(false),
+            c.exists(&db::aisles::tests::aisle_key(&other_dairy))
+        );
+        assert_eq!(
+            Ok(target.to_string()),
+            c.hget(&db::aisles::tests::aisle_key(&frozen), "store_id")
+        );
+    }
 }
@@ -0,0 +1,350 @@
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::{self, Commands};
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use std::collections::{HashMap, HashSet};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+
+use crate::{
+    db::{self, aliases::normalized},
+    error::{self, *},
+    types::*,
+};
+
+pub const SHARED_TEMPLATE_TTL_S: u64 = 7 * 24 * 3600;
+
+// What a template code copies of a store: its name, and its aisles in order
+// with the names of their products in order
+#[derive(Debug, Serialize, Deserialize)]
+struct SharedLayout {
+    name: String,
+    aisles: Vec<(String, Vec<String>)>,
+}
+
+// Aisle layouts of a user's stores, as JSON by normalized name, to lay out
+// new stores the same way
+fn templates_key(user_id: &UserId) -> String {
+    format!("templates:{}", **user_id)
+}
+
+fn shared_key(code: &str) -> String {
+    format!("shared_template:{}", code)
+}
+
+pub fn get_templates(c: &mut Connection, user_id: &UserId) -> Result<Vec<AisleTemplate>> {
+    let raw: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(templates_key(&user_id))
+        .query(c)?;
+    let mut templates = raw
+        .into_iter()
+        .map(|(name, raw)| {
+            serde_json::from_str(&raw)
+                .map(|template| (name, template))
+                .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted templates"))
+        })
+        .collect::<Result<Vec<(String, AisleTemplate)>>>()?;
+    templates.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(templates
+        .into_iter()
+        .map(|(_, template)| template)
+        .collect())
+}
+
+pub fn get_template(
+    c: &mut Connection,
+    user_id: &UserId,
+    name: &str,
+) -> Result<Option<AisleTemplate>> {
+    let raw: Option<String> = c.hget(&templates_key(&user_id), &normalized(name))?;
+    raw.map(|raw| {
+        serde_json::from_str(&raw)
+            .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted templates"))
+    })
+    .transpose()
+}
+
+// The aisles of the store in their order, replaces the template of the same
+// name
+pub fn save_template(
+    c: &mut Connection,
+    auth: &Auth,
+    name: &str,
+    store_id: &StoreId,
+) -> Result<AisleTemplate> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    let mut aisles = db::aisles::get_aisles_in_store(c, &store_id)?;
+    aisles.sort();
+    let template = AisleTemplate::new(
+        name.to_owned(),
+        aisles.into_iter().map(|aisle| aisle.name).collect(),
+    );
+    put_template(c, &user_id, &template)?;
+    Ok(template)
+}
+
+// Replaces the template of the same name
+pub fn put_template(c: &mut Connection, user_id: &UserId, template: &AisleTemplate) -> Result<()> {
+    let raw = serde_json::to_string(template).expect("templates always serialize");
+    Ok(c.hset(&templates_key(&user_id), &normalized(&template.name), raw)?)
+}
+
+pub fn delete_template(c: &mut Connection, user_id: &UserId, name: &str) -> Result<()> {
+    Ok(c.hdel(&templates_key(&user_id), &normalized(name))?)
+}
+
+pub fn delete_templates(c: &mut Connection, user_id: &UserId) -> Result<()> {
+    Ok(c.del(&templates_key(&user_id))?)
+}
+
+// Add the aisles of the template the store has none of the name of, in the
+// template's order after the store's own
+pub fn apply_template(
+    c: &mut Connection,
+    auth: &Auth,
+    name: &str,
+    store_id: &StoreId,
+) -> Result<AddedAisles> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let template = get_template(c, &user_id, name)?
+        .ok_or_else(|| ServerError::new(StatusCode::NOT_FOUND, "No such template"))?;
+    db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        let mut present: HashSet<String> = db::aisles::get_aisles_in_store(c, &store_id)?
+            .iter()
+            .map(|aisle| normalized(&aisle.name))
+            .collect();
+        let mut added = AddedAisles::new(vec![]);
+        for aisle in &template.aisles {
+            if present.insert(normalized(aisle)) {
+                added
+                    .aisles
+                    .push(db::aisles::insert_aisle(c, &user_id, &store_id, aisle)?);
+            }
+        }
+        Ok(added)
+    })
+}
+
+// A code for other users to copy the store as it is now, as many times as
+// they like for SHARED_TEMPLATE_TTL_S seconds
+pub fn share_store(c: &mut Connection, auth: &Auth, store_id: &StoreId) -> Result<TemplateCode> {
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::assert_owner_or_member(&user_id, &db::stores::get_store_owner(c, &store_id)?)?;
+    let mut store = db::stores::get_store(c, &store_id)?;
+    store.aisles.sort();
+    let layout = SharedLayout {
+        name: store.name,
+        aisles: store
+            .aisles
+            .into_iter()
+            .map(|mut aisle| {
+                aisle.products.sort();
+                let products = aisle.products.into_iter().map(|p| p.name).collect();
+                (aisle.name, products)
+            })
+            .collect(),
+    };
+    let code = db::pairing::new_code();
+    let key = shared_key(&code);
+    let raw = serde_json::to_string(&layout).expect("layouts always serialize");
+    c.set(&key, raw)?;
+    c.expire(&key, SHARED_TEMPLATE_TTL_S as usize)?;
+    Ok(TemplateCode {
+        code,
+        expires_in_s: SHARED_TEMPLATE_TTL_S,
+    })
+}
+
+// A new store of the user laid out as the shared one, with its products left
+// to buy. Like the default aisles of a new store, a quota cutting the copy
+// short doesn't undo it.
+pub fn redeem_code(
+    c: &mut Connection,
+    auth: &Auth,
+    code: &str,
+    name: Option<&str>,
+) -> Result<StoreId> {
+    let raw: Option<String> = c.get(&shared_key(&code.trim().to_uppercase()))?;
+    let raw = raw.ok_or_else(|| {
+        ServerError::new(StatusCode::NOT_FOUND, "Invalid or expired template code")
+    })?;
+    let layout: SharedLayout = serde_json::from_str(&raw)
+        .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted template"))?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let store_id = db::stores::save_store(c, &auth, name.unwrap_or(&layout.name))?;
+    let copied = db::locks::with_store_lock(c, &user_id, &store_id, |c| {
+        for (aisle, products) in &layout.aisles {
+            let aisle = db::aisles::insert_aisle(c, &user_id, &store_id, aisle)?;
+            let aisle_id = AisleId(aisle.aisle_id);
+            for product in products {
+                let product_id = db::ids::get_next_product_id();
+                db::products::insert_product(
+                    c,
+                    &user_id,
+                    &store_id,
+                    &product_id,
+                    product,
+                    &aisle_id,
+                )?;
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = copied {
+        warn!(
+            "Copy of a shared template to {} cut short: {}",
+            *store_id, e.msg
+        );
+    }
+    Ok(store_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{aisles::tests::save_aisle_for_test, sessions::tests::AUTH, tests::*};
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn templates_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, _) = save_aisle_for_test(&mut c);
+        let user_id = db::sessions::get_user_id(&mut c, &AUTH).unwrap();
+        let aisle = db::aisles::get_aisles_in_store(&mut c, &store_id).unwrap()[0]
+            .name
+            .clone();
+        db::aisles::save_aisle(&mut c, &AUTH, &store_id, "Dairy").unwrap();
+
+        let template = save_template(&mut c, &AUTH, "Weekly", &store_id).unwrap();
+        assert_eq!(
+            AisleTemplate::new("Weekly".to_owned(), vec![aisle.clone(), "Dairy".to_owned()]),
+            template
+        );
+        let other = save_template(&mut c, &AUTH, "Another", &store_id).unwrap();
+        assert_eq!(
+            Ok(vec![other, template.clone()]),
+            get_templates(&mut c, &user_id)
+        );
+        assert_eq!(Ok(()), delete_template(&mut c, &user_id, "another "));
+        assert_eq!(Ok(vec![template]), get_templates(&mut c, &user_id));
+        assert_eq!(Ok(()), delete_templates(&mut c, &user_id));
+        assert_eq!(Ok(vec![]), get_templates(&mut c, &user_id));
+    }
+
+    #[test]
+    fn apply_template_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let (store_id, _) = save_aisle_for_test(&mut c);
+        let user_id = db::sessions::get_user_id(&mut c, &AUTH).unwrap();
+        let aisle = db::aisles::get_aisles_in_store(&mut c, &store_id).unwrap()[0]
+            .name
+            .clone();
+        let raw = serde_json::to_string(&AisleTemplate::new(
+            "Weekly".to_owned(),
+            vec![
+                "Bakery".to_owned(),
+                aisle.to_uppercase(),
+                "Dairy".to_owned(),
+            ],
+        ))
+        .unwrap();
+        let _: () = c.hset(&templates_key(&user_id), "weekly", raw).unwrap();
+
+        let added = apply_template(&mut c, &AUTH, "Weekly", &store_id).unwrap();
+        assert_eq!(
+            vec!["Bakery", "Dairy"],
+            added
+                .aisles
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        let mut aisles = db::aisles::get_aisles_in_store(&mut c, &store_id).unwrap();
+        aisles.sort();
+        assert_eq!(
+            vec![aisle.as_str(), "Bakery", "Dairy"],
+            aisles.iter().map(|a| a.name.as_str()).collect::<Vec<_>>()
+        );
+
+        let again = apply_template(&mut c, &AUTH, "Weekly", &store_id).unwrap();
+        assert_eq!(true, again.aisles.is_empty());
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            apply_template(&mut c, &AUTH, "Monthly", &store_id)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+    }
+
+    #[test]
+    fn shared_template_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let store_id = db::aisles::tests::get_aisles_in_store_for_test(&mut c);
+        let mut original = db::stores::get_store(&mut c, &store_id).unwrap();
+        original.aisles.sort();
+        let product_id = ProductId(original.aisles[0].products[0].product_id.clone());
+        let done = EditProduct::new(None, None, None, Some(true));
+        db::products::modify_product(&mut c, &AUTH, &done, &product_id).unwrap();
+
+        let shared = share_store(&mut c, &AUTH, &store_id).unwrap();
+        assert_eq!(SHARED_TEMPLATE_TTL_S, shared.expires_in_s);
+        let other_id = UserId("other".to_owned());
+        db::sessions::store_session(&mut c, "other", &other_id).unwrap();
+        let other = Auth("other");
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            share_store(&mut c, &other, &store_id)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+
+        let copy_id =
+            redeem_code(&mut c, &other, &shared.code.to_lowercase(), Some("Copy")).unwrap();
+        let mut copy = db::stores::list_store(&mut c, &other, &copy_id).unwrap();
+        copy.aisles.sort();
+        assert_eq!("Copy", copy.name);
+        let names = |store: &Store| {
+            store
+                .aisles
+                .iter()
+                .map(|aisle| {
+                    let mut products = aisle.products.clone();
+                    products.sort();
+                    let products: Vec<String> = products.into_iter().map(|p| p.name).collect();
+                    (aisle.name.clone(), products)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&original), names(&copy));
+        assert_eq!(
+            false,
+            copy.aisles
+                .iter()
+                .any(|aisle| aisle.products.iter().any(|p| p.is_done))
+        );
+
+        // redeemed as often as wanted until it expires
+        let again = redeem_code(&mut c, &other, &shared.code, None).unwrap();
+        assert_eq!(
+            original.name,
+            db::stores::get_store(&mut c, &again).unwrap().name
+        );
+        c.advance_time(std::time::Duration::from_secs(SHARED_TEMPLATE_TTL_S));
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            redeem_code(&mut c, &other, &shared.code, None)
+                .map(|_| ())
+                .map_err(|e| e.status)
+        );
+    }
+}
@@ -1,14 +1,22 @@
+use std::{collections::HashMap, sync::RwLock};
+
 use hex_view::HexView;
+use lazy_static::lazy_static;
 use rand::{self, Rng};
+use serde::{Deserialize, Serialize};
 
+#[cfg(not(test))]
+use crate::storage::Connection;
 #[cfg(test)]
-use fake_redis::FakeConnection as Connection;
+use fake_redis::{transaction, FakeConnection as Connection};
 #[cfg(not(test))]
-use redis::{self, Commands, Connection};
+use redis::{self, transaction, Commands};
 
 use crate::{
     db,
     error::{self, *},
+    links::{hmac, Blake2b256},
+    tokens,
     types::*,
 };
 
@@ -18,45 +26,130 @@ const USER_SALT_M: &str = "salt_mail";
 const USER_SALT_P: &str = "salt_password";
 const USER_NAME: &str = "username";
 const USERS_LIST: &str = "users";
+const USER_MAIL_INDEX: &str = "email_index";
+// JSON list of aisle names, see `PUT /user/default_aisles`
+const USER_DEFAULT_AISLES: &str = "default_aisles";
+// email index to user id, emails are only ever stored hashed
+const EMAILS_LIST: &str = "emails";
+const EMAIL_SECRET_KEY: &str = "email_secret";
+// hashed with the password of logins naming no one
+const UNKNOWN_USER_SALT: &str = "0000000000000000";
 
-fn user_key(user_id: &UserId) -> String {
-    format!("user:{}", **user_id)
+lazy_static! {
+    // from `--email-secret-file`, else made on first use and kept in the db
+    static ref EMAIL_SECRET: RwLock<Option<Vec<u8>>> = RwLock::new(None);
 }
 
-fn gen_auth(rng: &mut rand::rngs::ThreadRng) -> String {
-    let mut auth = [0u8; 32];
-    rng.fill(&mut auth[..]);
-    format!("{:x}", HexView::from(&auth))
+// Changing it makes the index miss every email registered before
+pub fn set_email_secret(secret: &[u8]) {
+    *EMAIL_SECRET.write().unwrap() = Some(secret.to_vec());
+}
+
+fn email_secret(c: &mut Connection) -> Result<Vec<u8>> {
+    if let Some(ref secret) = *EMAIL_SECRET.read().unwrap() {
+        return Ok(secret.clone());
+    }
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill(&mut secret[..]);
+    let _: bool = c.set_nx(
+        EMAIL_SECRET_KEY,
+        format!("{:x}", HexView::from(&secret[..])),
+    )?;
+    let secret: String = c.get(EMAIL_SECRET_KEY)?;
+    Ok(secret.into_bytes())
+}
+
+// The same for every spelling of an address, unlike the salted hash kept
+// with the user, and useless without the secret
+fn email_index(c: &mut Connection, email: &str) -> Result<String> {
+    let email = email.trim().to_lowercase();
+    let mac = hmac::<Blake2b256>(&email_secret(c)?, email.as_bytes());
+    Ok(format!("{:x}", HexView::from(&mac[..])))
+}
+
+fn user_key(user_id: &UserId) -> String {
+    format!("user:{}", **user_id)
 }
 
 pub fn save_user(c: &mut Connection, user: &User) -> Result<ConnectionToken> {
     let norm_username = user.username.to_lowercase();
-    if c.hexists(USERS_LIST, &norm_username)? {
-        Err(ServerError::new(
+    if username_taken(c, &user.username)? {
+        return Err(ServerError::new(
             error::USERNAME_TAKEN,
             &format!("Username {} is not available.", &user.username),
-        ))
+        ));
+    }
+    let user_id = db::ids::get_next_user_id(c)?;
+    let user_key = user_key(&user_id);
+    let email_index = email_index(c, &user.email)?;
+    let mut rng = rand::thread_rng();
+    let salt_mail = rng.gen::<u64>().to_string();
+    let salt_pwd = rng.gen::<u64>().to_string();
+    let hashed_pwd = SecretString::from(db::ids::hash(&user.password, &salt_pwd));
+    let hashed_mail = SecretString::from(db::ids::hash(&user.email, &salt_mail));
+    // the email and the username are claimed along with writing the user, or
+    // not at all
+    transaction(c, &[EMAILS_LIST, USERS_LIST], |c, pipe| {
+        if username_taken(c, &user.username)? {
+            return Err(ServerError::new(
+                error::USERNAME_TAKEN,
+                &format!("Username {} is not available.", &user.username),
+            )
+            .into());
+        }
+        if c.hexists(EMAILS_LIST, &email_index)? {
+            return Err(ServerError::new(error::EMAIL_TAKEN, "Email is already in use.").into());
+        }
+        pipe.hset(EMAILS_LIST, &email_index, user_id.to_string())
+            .ignore()
+            .hset(&user_key, USER_NAME, &user.username)
+            .ignore()
+            .hset(&user_key, USER_MAIL, &*hashed_mail)
+            .ignore()
+            .hset(&user_key, USER_PWD, &*hashed_pwd)
+            .ignore()
+            .hset(&user_key, USER_SALT_M, &salt_mail)
+            .ignore()
+            .hset(&user_key, USER_SALT_P, &salt_pwd)
+            .ignore()
+            .hset(&user_key, USER_MAIL_INDEX, &email_index)
+            .ignore()
+            .hset(USERS_LIST, &norm_username, user_id.to_string())
+            .ignore()
+            .query(c)
+    })?;
+    open_session(c, &user_id)
+}
+
+// Usernames are unique whatever their case
+pub fn username_taken(c: &mut Connection, username: &str) -> Result<bool> {
+    Ok(c.hexists(USERS_LIST, &username.to_lowercase())?)
+}
+
+// A new session of the user, besides the ones already open
+pub fn open_session(c: &mut Connection, user_id: &UserId) -> Result<ConnectionToken> {
+    let auth = tokens::mint();
+    db::sessions::store_session(c, &auth, user_id)?;
+    Ok(ConnectionToken::new(auth, user_id.to_string()))
+}
+
+// What deleting the user would remove
+pub fn get_user_removal(
+    c: &mut Connection,
+    auth: &Auth,
+    wanted_user_id: &UserId,
+) -> Result<UserRemoval> {
+    let user_id = db::sessions::get_user_id(c, auth)?;
+    if user_id == *wanted_user_id {
+        Ok(UserRemoval {
+            username: c.hget(&user_key(&user_id), USER_NAME)?,
+            stores: db::stores::get_all_store_removals(c, &user_id)?,
+        })
     } else {
-        let mut rng = rand::thread_rng();
-        let salt_mail = rng.gen::<u64>().to_string();
-        let salt_pwd = rng.gen::<u64>().to_string();
-        let hashed_pwd = db::ids::hash(&user.password, &salt_pwd);
-        let hashed_mail = db::ids::hash(&user.email, &salt_mail);
-        let user_id = db::ids::get_next_user_id(c)?;
-        c.hset_multiple(
-            &user_key(&user_id),
-            &[
-                (USER_NAME, &user.username),
-                (USER_MAIL, &hashed_mail),
-                (USER_PWD, &hashed_pwd),
-                (USER_SALT_M, &salt_mail),
-                (USER_SALT_P, &salt_pwd),
-            ],
-        )?;
-        c.hset(USERS_LIST, &norm_username, user_id.to_string())?;
-        let auth = gen_auth(&mut rng);
-        db::sessions::store_session(c, &auth, &user_id)?;
-        Ok(ConnectionToken::new(auth, user_id.to_string()))
+        Err(ServerError::new(
+            error::UNAUTHORISED,
+            "x-auth-token does not belong to this user",
+        ))
     }
 }
 
@@ -65,8 +158,18 @@ pub fn delete_user(c: &mut Connection, auth: &Auth, wanted_user_id: &UserId) ->
     if user_id == *wanted_user_id {
         let user_key = user_key(&user_id);
         let username: String = c.hget(&user_key, USER_NAME)?;
+        let email_index: Option<String> = c.hget(&user_key, USER_MAIL_INDEX)?;
         db::stores::delete_all_user_stores(c, &auth)?;
+        db::quotas::delete_usage(c, &user_id)?;
+        db::aliases::delete_aliases(c, &user_id)?;
+        db::staples::delete_staples(c, &user_id)?;
+        db::templates::delete_templates(c, &user_id)?;
+        db::placement::delete_history(c, &user_id)?;
+        db::anywhere::delete_anywhere(c, &user_id)?;
         c.hdel(USERS_LIST, &username.to_lowercase())?;
+        if let Some(email_index) = email_index {
+            c.hdel(EMAILS_LIST, &email_index)?;
+        }
         db::sessions::delete_all_user_sessions(c, auth)?;
         Ok(c.del(&user_key)?)
     } else {
@@ -77,22 +180,37 @@ pub fn delete_user(c: &mut Connection, auth: &Auth, wanted_user_id: &UserId) ->
     }
 }
 
+// The user a login names, by username or by email: usernames have no `@`
+fn find_user(c: &mut Connection, login: &str) -> Result<Option<UserId>> {
+    let user_id: Option<String> = if login.contains('@') {
+        let email_index = email_index(c, login)?;
+        c.hget(EMAILS_LIST, &email_index)?
+    } else {
+        c.hget(USERS_LIST, &login.to_lowercase())?
+    };
+    Ok(user_id.map(UserId))
+}
+
+// An unknown account and a wrong password get the same answer, in about the
+// same time
 pub fn login(c: &mut Connection, auth_info: &AuthInfo) -> Result<ConnectionToken> {
-    let user_id = UserId(
-        c.hget(USERS_LIST, &auth_info.username.to_lowercase())
-            .map_err(|_| {
-                ServerError::new(error::INVALID_USER_OR_PWD, "Invalid usename or password")
-            })?,
-    );
+    let user_id = match find_user(c, &auth_info.username)? {
+        Some(user_id) => user_id,
+        None => {
+            db::ids::hash(&auth_info.password, UNKNOWN_USER_SALT);
+            return Err(ServerError::new(
+                error::INVALID_USER_OR_PWD,
+                "Invalid usename or password",
+            ));
+        }
+    };
     let user_key = user_key(&user_id);
     let salt_pwd: String = c.hget(&user_key, USER_SALT_P)?;
     let stored_pwd: String = c.hget(&user_key, USER_PWD)?;
-    let hashed_pwd = db::ids::hash(&auth_info.password, &salt_pwd);
+    let stored_pwd = SecretString::from(stored_pwd);
+    let hashed_pwd = SecretString::from(db::ids::hash(&auth_info.password, &salt_pwd));
     if hashed_pwd == stored_pwd {
-        let mut rng = rand::thread_rng();
-        let auth = gen_auth(&mut rng);
-        db::sessions::store_session(c, &auth, &user_id)?;
-        Ok(ConnectionToken::new(auth, user_id.to_string()))
+        open_session(c, &user_id)
     } else {
         Err(ServerError::new(
             error::INVALID_USER_OR_PWD,
@@ -101,6 +219,86 @@ pub fn login(c: &mut Connection, auth_info: &AuthInfo) -> Result<ConnectionToken
     }
 }
 
+// Email of a user registered before the email index, from wherever the
+// admin found it
+#[derive(Deserialize)]
+pub struct KnownEmail {
+    pub username: String,
+    pub email: SecretString,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailIndexing {
+    Indexed,
+    AlreadyIndexed,
+    UnknownUser,
+    // not the email the user signed up with
+    Mismatch,
+    // another user has the same email
+    Conflict,
+}
+
+// Index the email of a user who signed up before the index existed, once
+// checked against the hash kept since. Duplicates can't be undone, the
+// second user stays out of the index.
+pub fn index_email(c: &mut Connection, known: &KnownEmail) -> Result<EmailIndexing> {
+    let user_id: Option<String> = c.hget(USERS_LIST, &known.username.to_lowercase())?;
+    let user_id = match user_id {
+        Some(user_id) => UserId(user_id),
+        None => return Ok(EmailIndexing::UnknownUser),
+    };
+    let user_key = user_key(&user_id);
+    if c.hexists(&user_key, USER_MAIL_INDEX)? {
+        return Ok(EmailIndexing::AlreadyIndexed);
+    }
+    let salt_mail: String = c.hget(&user_key, USER_SALT_M)?;
+    let stored_mail: String = c.hget(&user_key, USER_MAIL)?;
+    let stored_mail = SecretString::from(stored_mail);
+    if SecretString::from(db::ids::hash(&known.email, &salt_mail)) != stored_mail {
+        return Ok(EmailIndexing::Mismatch);
+    }
+    let email_index = email_index(c, &known.email)?;
+    let claimed: bool = c.hset_nx(EMAILS_LIST, &email_index, user_id.to_string())?;
+    if !claimed {
+        return Ok(EmailIndexing::Conflict);
+    }
+    c.hset(&user_key, USER_MAIL_INDEX, &email_index)?;
+    Ok(EmailIndexing::Indexed)
+}
+
+pub fn get_default_aisles(c: &mut Connection, user_id: &UserId) -> Result<Option<Vec<String>>> {
+    let raw: Option<String> = c.hget(&user_key(&user_id), USER_DEFAULT_AISLES)?;
+    raw.map(|raw| {
+        serde_json::from_str(&raw)
+            .map_err(|_| ServerError::new(error::INTERNAL_ERROR, "Corrupted default aisles"))
+    })
+    .transpose()
+}
+
+// None to go back to the server's
+pub fn set_default_aisles(
+    c: &mut Connection,
+    user_id: &UserId,
+    aisles: Option<&[String]>,
+) -> Result<()> {
+    let user_key = user_key(&user_id);
+    match aisles {
+        Some(aisles) => {
+            let raw = serde_json::to_string(aisles).expect("names always serialize");
+            c.hset(&user_key, USER_DEFAULT_AISLES, raw)?
+        }
+        None => c.hdel(&user_key, USER_DEFAULT_AISLES)?,
+    }
+    Ok(())
+}
+
+// Every user, for migrations
+pub fn get_all_user_ids(c: &mut Connection) -> Result<Vec<UserId>> {
+    let users: HashMap<String, String> = redis::cmd("HGETALL").arg(USERS_LIST).query(c)?;
+    Ok(users.into_iter().map(|(_, id)| UserId(id)).collect())
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -110,8 +308,10 @@ pub mod tests {
     pub fn gen_user() -> User {
         User {
             username: "toto".to_string(),
-            password: "pwd".to_string(),
-            email: "m@m.com".to_string(),
+            password: "pwd".into(),
+            email: "m@m.com".into(),
+            invite: None,
+            challenge: None,
         }
     }
 
@@ -135,7 +335,7 @@ pub mod tests {
         assert_eq!(Ok(true), c.exists(&format!("sessions:{}", HASH_1)));
         assert_eq!(
             Ok(true),
-            c.sismember(&format!("sessions:{}", HASH_1), token.session_token)
+            c.sismember(&format!("sessions:{}", HASH_1), &*token.session_token)
         );
         assert_eq!(Ok(1), c.get("next_user_id"));
         assert_eq!(Ok(true), c.hexists("users", "toto"));
@@ -174,17 +374,21 @@ pub mod tests {
 
         let login_data = AuthInfo {
             username: "toto".to_string(),
-            password: "pwd".to_string(),
+            password: "pwd".into(),
         };
         let res = login(&mut c, &login_data);
         if res.is_err() {
             dbg!(&res);
         }
         assert_eq!(true, res.is_ok());
+        let token = res.unwrap();
+        let printed = format!("{:?} {:?}", token, login_data);
+        assert_eq!(false, printed.contains(token.session_token.as_str()));
+        assert_eq!(false, printed.contains("pwd"));
 
         let login_data = AuthInfo {
             username: "toto".to_string(),
-            password: "pwdb".to_string(),
+            password: "pwdb".into(),
         };
         let res = login(&mut c, &login_data);
         if res.is_ok() {
@@ -194,7 +398,7 @@ pub mod tests {
 
         let login_data = AuthInfo {
             username: "tato".to_string(),
-            password: "pwd".to_string(),
+            password: "pwd".into(),
         };
         let res = login(&mut c, &login_data);
         if res.is_ok() {
@@ -203,6 +407,26 @@ pub mod tests {
         assert_eq!(false, res.is_ok());
     }
 
+    #[test]
+    fn login_by_email_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        store_user_for_test(&mut c);
+        let auth_info = |username: &str, password: &str| AuthInfo {
+            username: username.to_owned(),
+            password: password.into(),
+        };
+        let res = login(&mut c, &auth_info(" M@m.com", "pwd"));
+        assert_eq!(Some(HASH_1.to_owned()), res.ok().map(|token| token.user_id));
+
+        // no telling an unknown account from a wrong password
+        let unknown = login(&mut c, &auth_info("x@m.com", "pwd")).err();
+        let wrong = login(&mut c, &auth_info("m@m.com", "pwdb")).err();
+        assert_eq!(true, unknown.is_some());
+        assert_eq!(unknown, wrong);
+        assert_eq!(wrong, login(&mut c, &auth_info("tato", "pwd")).err());
+    }
+
     #[test]
     fn delete_user_test() {
         let client = Client::open(get_db_addr().as_str()).unwrap();
@@ -219,6 +443,7 @@ pub mod tests {
         store_user_for_test(&mut c); // create toto user as user:2
         let mut user = gen_user();
         user.username = "tata".to_string();
+        user.email = "t@m.com".into();
         let res = save_user(&mut c, &user); // create tata user as user:3
         if res.is_err() {
             dbg!(&res);
@@ -236,4 +461,91 @@ pub mod tests {
         assert_eq!(Ok(true), c.exists(&format!("user:{}", HASH_2)));
         assert_eq!(Ok(false), c.exists(&format!("user:{}", HASH_3)));
     }
+
+    #[test]
+    fn store_user_email_taken_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let token = store_user_for_test(&mut c);
+        let mut user = gen_user();
+        user.username = "tata".to_string();
+        // emails are compared trimmed and case insensitive
+        user.email = " M@m.COM".into();
+        let res = save_user(&mut c, &user);
+        assert_eq!(Some(error::EMAIL_TAKEN), res.err().map(|e| e.status));
+        assert_eq!(Ok(false), c.hexists(USERS_LIST, "tata"));
+        assert_eq!(Ok(false), c.exists(&format!("user:{}", HASH_2)));
+        // the raw address is nowhere
+        let index: String = c
+            .hget(&format!("user:{}", HASH_1), USER_MAIL_INDEX)
+            .unwrap();
+        assert_eq!(Ok(true), c.hexists(EMAILS_LIST, &index));
+        assert_eq!(false, index.contains("m@m.com"));
+
+        // deleting the user frees the email
+        let auth = Auth(&token.session_token);
+        assert_eq!(
+            Ok(()),
+            delete_user(&mut c, &auth, &UserId(HASH_1.to_owned()))
+        );
+        assert_eq!(Ok(false), c.exists(EMAILS_LIST));
+        assert_eq!(true, save_user(&mut c, &user).is_ok());
+    }
+
+    #[test]
+    fn index_email_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        store_user_for_test(&mut c);
+        let mut user = gen_user();
+        user.username = "tata".to_string();
+        user.email = "t@m.com".into();
+        save_user(&mut c, &user).unwrap();
+        // as if both signed up before the index, with the same email
+        let _: () = c.del(EMAILS_LIST).unwrap();
+        for user_id in &[HASH_1, HASH_2] {
+            let _: () = c
+                .hdel(&format!("user:{}", user_id), USER_MAIL_INDEX)
+                .unwrap();
+        }
+        let hashed_mail = mail_hash(&mut c, HASH_2, "m@m.com");
+        let _: () = c
+            .hset(&format!("user:{}", HASH_2), USER_MAIL, hashed_mail)
+            .unwrap();
+
+        let known = |username: &str, email: &str| KnownEmail {
+            username: username.to_owned(),
+            email: email.into(),
+        };
+        assert_eq!(
+            Ok(EmailIndexing::UnknownUser),
+            index_email(&mut c, &known("titi", "m@m.com"))
+        );
+        assert_eq!(
+            Ok(EmailIndexing::Mismatch),
+            index_email(&mut c, &known("toto", "x@m.com"))
+        );
+        assert_eq!(
+            Ok(EmailIndexing::Indexed),
+            index_email(&mut c, &known("ToTo", "m@m.com"))
+        );
+        assert_eq!(
+            Ok(EmailIndexing::AlreadyIndexed),
+            index_email(&mut c, &known("toto", "m@m.com"))
+        );
+        assert_eq!(
+            Ok(EmailIndexing::Conflict),
+            index_email(&mut c, &known("tata", "m@m.com"))
+        );
+        // the index now stops new duplicates
+        let mut user = gen_user();
+        user.username = "titi".to_string();
+        assert_eq!(false, save_user(&mut c, &user).is_ok());
+    }
+
+    // what signing up with `email` would have stored
+    fn mail_hash(c: &mut Connection, user_id: &str, email: &str) -> String {
+        let salt: String = c.hget(&format!("user:{}", user_id), USER_SALT_M).unwrap();
+        db::ids::hash(email, &salt)
+    }
 }
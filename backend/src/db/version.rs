@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+#[cfg(not(test))]
+use redis::Commands;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use serde::Serialize;
+
+use crate::error::Result;
+
+// The layout of the data this binary writes. Bump it along with a migration
+// when older binaries would misread what the new code stores.
+pub const SCHEMA_VERSION: u64 = 1;
+// The oldest layout this binary still reads and writes correctly. Keeping it
+// below `SCHEMA_VERSION` for a release lets old and new instances run side by
+// side during a deploy.
+pub const MIN_SCHEMA_VERSION: u64 = 1;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+// databases from before the layout was versioned
+const UNVERSIONED: u64 = 1;
+
+// What to do at startup when the database's layout is out of the binary's range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchemaMismatch {
+    Refuse,
+    // serve reads only, writes answer 503
+    ReadOnly,
+}
+
+impl Default for SchemaMismatch {
+    fn default() -> Self {
+        SchemaMismatch::Refuse
+    }
+}
+
+impl FromStr for SchemaMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(SchemaMismatch::Refuse),
+            "read-only" => Ok(SchemaMismatch::ReadOnly),
+            _ => Err(format!("{}: expected refuse or read-only", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SchemaStatus {
+    pub database: u64,
+    pub min_supported: u64,
+    pub max_supported: u64,
+    pub compatible: bool,
+}
+
+pub fn schema_status(c: &mut Connection) -> Result<SchemaStatus> {
+    let database: Option<u64> = c.get(SCHEMA_VERSION_KEY)?;
+    let database = database.unwrap_or(UNVERSIONED);
+    Ok(SchemaStatus {
+        database,
+        min_supported: MIN_SCHEMA_VERSION,
+        max_supported: SCHEMA_VERSION,
+        compatible: (MIN_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&database),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::*;
+    use fake_redis::FakeCient as Client;
+
+    #[test]
+    fn schema_status_test() {
+        let client = Client::open(get_db_addr().as_str()).unwrap();
+        let mut c = client.get_connection().unwrap();
+        let status = schema_status(&mut c).unwrap();
+        assert_eq!(UNVERSIONED, status.database);
+        assert!(status.compatible);
+
+        let _: () = c.set(SCHEMA_VERSION_KEY, SCHEMA_VERSION + 1).unwrap();
+        let status = schema_status(&mut c).unwrap();
+        assert_eq!(SCHEMA_VERSION + 1, status.database);
+        assert_eq!(SCHEMA_VERSION, status.max_supported);
+        assert!(!status.compatible);
+
+        let _: () = c.set(SCHEMA_VERSION_KEY, MIN_SCHEMA_VERSION - 1).unwrap();
+        assert!(!schema_status(&mut c).unwrap().compatible);
+    }
+
+    #[test]
+    fn schema_mismatch_test() {
+        assert_eq!(Ok(SchemaMismatch::ReadOnly), "read-only".parse());
+        assert_eq!(Ok(SchemaMismatch::Refuse), "refuse".parse());
+        assert!("ignore".parse::<SchemaMismatch>().is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+use warp::{
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    Reply,
+};
+
+use crate::{
+    db::{
+        abuse::{self, AbuseLimits, Ban, RateLimit},
+        sessions,
+    },
+    error::{self, ServerError},
+    types::*,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+impl warp::reject::Reject for Ban {}
+
+#[derive(Serialize)]
+struct BanReply<'a> {
+    error: &'static str,
+    reason: &'a str,
+    retry_after_s: u64,
+}
+
+// Who a request counts against: its address, and its user when it carries a
+// valid session token. A loopback address is the local proxy or the admin
+// more often than a client, it never counts.
+pub fn subjects(c: &mut Connection, ip: Option<IpAddr>, auth: Option<&str>) -> Vec<String> {
+    let mut subjects: Vec<String> = ip
+        .filter(|ip| !ip.is_loopback())
+        .map(|ip| format!("ip:{}", ip))
+        .into_iter()
+        .collect();
+    if let Some(user_id) = auth.and_then(|auth| sessions::get_user_id(c, &Auth(auth)).ok()) {
+        subjects.push(format!("user:{}", *user_id));
+    }
+    subjects
+}
+
+pub async fn check_bans(c: &mut Connection, subjects: &[String]) -> error::Result<Option<Ban>> {
+    for subject in subjects {
+        if let Some(ban) = abuse::get_ban(c, subject)? {
+            return Ok(Some(ban));
+        }
+    }
+    Ok(None)
+}
+
+// Client errors hint at probing, the server's own failures and 429s don't.
+// Gives where the user stands against the request limit, if there are a user
+// and a limit, whether bans are on or not.
+pub async fn record(
+    c: &mut Connection,
+    subjects: &[String],
+    status: StatusCode,
+    limits: &AbuseLimits,
+) -> error::Result<Option<RateLimit>> {
+    let is_error = status.is_client_error() && status != error::BANNED;
+    let mut rate_limit = None;
+    for subject in subjects {
+        let ban = abuse::record_request(c, subject, is_error, limits)?;
+        if !subject.starts_with("user:") || limits.max_requests == 0 {
+            continue;
+        }
+        rate_limit = Some(match ban {
+            Some(ban) => RateLimit {
+                limit: limits.max_requests,
+                remaining: 0,
+                reset_s: ban.expires_in_s,
+            },
+            None => abuse::rate_limit_status(c, subject, limits.max_requests)?,
+        });
+    }
+    Ok(rate_limit)
+}
+
+// For clients to slow down before being banned
+pub fn add_rate_limit_headers(res: &mut warp::reply::Response, rate_limit: &RateLimit) {
+    let headers = res.headers_mut();
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-limit"),
+        HeaderValue::from(rate_limit.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-remaining"),
+        HeaderValue::from(rate_limit.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-reset"),
+        HeaderValue::from(rate_limit.reset_s),
+    );
+}
+
+pub async fn list_bans(c: &mut Connection) -> error::Result<Vec<Ban>> {
+    abuse::list_bans(c)
+}
+
+pub async fn revoke_ban(subject: &str, c: &mut Connection) -> error::Result<()> {
+    if abuse::revoke_ban(c, subject)? {
+        Ok(())
+    } else {
+        Err(ServerError::new(StatusCode::NOT_FOUND, "No such ban"))
+    }
+}
+
+// 429 telling why and when to come back
+pub fn ban_reply(ban: &Ban) -> warp::reply::Response {
+    let body = BanReply {
+        error: "banned",
+        reason: &ban.reason,
+        retry_after_s: ban.expires_in_s,
+    };
+    let mut res = warp::reply::with_status(warp::reply::json(&body), error::BANNED).into_response();
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(ban.expires_in_s));
+    res
+}
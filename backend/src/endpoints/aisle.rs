@@ -1,7 +1,7 @@
 use crate::{db, error::Result, types::*};
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
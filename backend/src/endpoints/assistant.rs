@@ -0,0 +1,202 @@
+use std::{cmp::Ordering, fmt::Write};
+
+use warp::{http::header::AUTHORIZATION, Filter, Rejection};
+
+use crate::{
+    db,
+    endpoints::{HEADER_AUTH, INVALID_PARAMS},
+    error::{self, Result, ServerError},
+    links::percent_encode,
+    quick_add::{self, QuickItem},
+    types::*,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+// aisle created for the products added by voice to a store without any
+const DEFAULT_AISLE: &str = "Other";
+
+// Session token of an assistant request: assistants send the one they got
+// when linking as a bearer token, there is no cookie nor CSRF token
+pub fn bearer_auth() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(AUTHORIZATION.as_str())
+        .and(warp::header::optional::<String>(HEADER_AUTH))
+        .and_then(
+            |bearer: Option<String>, header: Option<String>| async move {
+                bearer
+                    .and_then(|b| b.strip_prefix("Bearer ").map(str::to_owned))
+                    .or(header)
+                    .ok_or_else(|| {
+                        warp::reject::custom(ServerError::new(error::UNAUTHORISED, "Not linked"))
+                    })
+            },
+        )
+}
+
+// Account linking: the user, logged in on the web app, is sent back to the
+// assistant with a session of their own. The redirect URI must be one of the
+// `assistant_redirect_uri` setting. Returns where to redirect.
+pub async fn link(
+    auth: String,
+    query: &LinkQuery,
+    redirect_uris: &[String],
+    c: &mut Connection,
+) -> Result<String> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if query.response_type != "token" {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            "Only the token response type is supported",
+        ));
+    }
+    if !redirect_uris.contains(&query.redirect_uri) {
+        return Err(ServerError::new(
+            error::PERMISSION_DENIED,
+            "Redirect URI not allowed",
+        ));
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let token = db::users::open_session(c, &user_id)?;
+    let mut location = format!(
+        "{}#access_token={}&token_type=Bearer",
+        query.redirect_uri,
+        percent_encode(&token.session_token)
+    );
+    if let Some(ref state) = query.state {
+        let _ = write!(location, "&state={}", percent_encode(state));
+    }
+    Ok(location)
+}
+
+// The store asked for, or the user's first one by name
+fn pick_store(c: &mut Connection, auth: &Auth, store_id: &Option<String>) -> Result<Option<Store>> {
+    let store_id = match store_id {
+        Some(store_id) => store_id.clone(),
+        None => match db::stores::get_all_stores(c, auth)?
+            .into_iter()
+            .min_by(|a, b| a.name.cmp(&b.name))
+        {
+            Some(store) => store.store_id,
+            None => return Ok(None),
+        },
+    };
+    db::stores::list_store(c, auth, &StoreId::new(store_id)).map(Some)
+}
+
+fn by_weight(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+fn say_item(name: &str, quantity: u32, unit: &Unit) -> String {
+    match unit {
+        Unit::Unit if quantity <= 1 => name.to_owned(),
+        Unit::Unit => format!("{} {}", quantity, name),
+        Unit::Gram if quantity % 1000 == 0 => format!("{} kilos of {}", quantity / 1000, name),
+        Unit::Gram => format!("{} grams of {}", quantity, name),
+        Unit::Ml if quantity % 1000 == 0 => format!("{} litres of {}", quantity / 1000, name),
+        Unit::Ml => format!("{} millilitres of {}", quantity, name),
+    }
+}
+
+// "Add two litres of milk to my list"
+pub async fn add(auth: String, data: &AssistantAdd, c: &mut Connection) -> Result<AssistantReply> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let QuickItem {
+        name,
+        quantity,
+        unit,
+    } = match quick_add::parse(&data.text) {
+        Some(item) => item,
+        None => {
+            return Ok(AssistantReply::new(
+                "Sorry, I did not get what to add.".to_owned(),
+            ))
+        }
+    };
+    let store = match pick_store(c, &auth, &data.store_id)? {
+        Some(store) => store,
+        None => {
+            return Ok(AssistantReply::new(
+                "You have no shopping list yet, create one in Efficio first.".to_owned(),
+            ))
+        }
+    };
+    let aisle_id = match store
+        .aisles
+        .iter()
+        .min_by(|a, b| by_weight(a.sort_weight, b.sort_weight))
+    {
+        Some(aisle) => aisle.aisle_id.clone(),
+        None => {
+            db::aisles::save_aisle(c, &auth, &StoreId::new(store.store_id), DEFAULT_AISLE)?.aisle_id
+        }
+    };
+    let aisle_id = AisleId(aisle_id);
+    let product = db::products::save_product(c, &auth, &name, &aisle_id)?;
+    // without a quantity said, the product keeps the store's defaults
+    let (quantity, unit) = if quantity == 1 && unit == Unit::Unit {
+        (product.quantity, product.unit)
+    } else {
+        if quantity != product.quantity || unit != product.unit {
+            let edit = EditProduct::new(None, Some(quantity), Some(unit.clone()), None);
+            db::products::modify_product(c, &auth, &edit, &ProductId(product.product_id))?;
+        }
+        (quantity, unit)
+    };
+    Ok(AssistantReply::new(format!(
+        "I added {} to {}.",
+        say_item(&name, quantity, &unit),
+        store.name
+    )))
+}
+
+// "What's on my list?", the products not done yet aisle by aisle
+pub async fn list(
+    auth: String,
+    data: &AssistantList,
+    c: &mut Connection,
+) -> Result<AssistantReply> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let mut store = match pick_store(c, &auth, &data.store_id)? {
+        Some(store) => store,
+        None => {
+            return Ok(AssistantReply::new(
+                "You have no shopping list yet.".to_owned(),
+            ))
+        }
+    };
+    store
+        .aisles
+        .sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+    let mut items = vec![];
+    for aisle in store.aisles.iter_mut() {
+        aisle
+            .products
+            .sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+        items.extend(
+            aisle
+                .products
+                .iter()
+                .filter(|p| !p.is_done)
+                .map(|p| say_item(&p.name, p.quantity, &p.unit)),
+        );
+    }
+    let speech = match items.split_last() {
+        None => format!("There is nothing left to buy at {}.", store.name),
+        Some((last, [])) => format!("At {} you need {}.", store.name, last),
+        Some((last, rest)) => format!(
+            "At {} you need {} and {}.",
+            store.name,
+            rest.join(", "),
+            last
+        ),
+    };
+    Ok(AssistantReply::new(speech))
+}
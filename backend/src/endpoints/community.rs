@@ -0,0 +1,130 @@
+use warp::http::StatusCode;
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use crate::{
+    db::{self, community::ReportedTemplate},
+    endpoints::{user, INVALID_PARAMS},
+    error::{self, Result, ServerError},
+    links,
+    types::*,
+};
+
+const MAX_RESULTS: usize = 50;
+const MAX_FIELD_LEN: usize = 100;
+const PUBLISHES_PER_DAY: u64 = 5;
+const REPORTS_PER_HOUR: u64 = 20;
+
+fn rate_limit(
+    c: &mut Connection,
+    bucket: &str,
+    user_id: &UserId,
+    max: u64,
+    window_s: u64,
+) -> Result<()> {
+    let subject = format!("user:{}", **user_id);
+    match db::abuse::rate_limit(c, bucket, &subject, max, window_s)? {
+        Some(retry_after_s) => Err(ServerError::new(
+            error::RATE_LIMITED,
+            &format!("Slow down, retry in {} seconds", retry_after_s),
+        )),
+        None => Ok(()),
+    }
+}
+
+pub async fn search(
+    auth: String,
+    query: &CommunityQuery,
+    c: &mut Connection,
+) -> Result<CommunityTemplates> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    Ok(CommunityTemplates {
+        templates: db::community::search(c, query.q.as_deref().unwrap_or(""), MAX_RESULTS)?,
+    })
+}
+
+// One of the user's templates, for anyone to find by supermarket or location
+pub async fn publish(
+    auth: String,
+    data: &PublishTemplate,
+    c: &mut Connection,
+) -> Result<CommunityTemplate> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    if data.supermarket.trim().is_empty()
+        || data.supermarket.len() > MAX_FIELD_LEN
+        || data.location.len() > MAX_FIELD_LEN
+    {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!(
+                "A supermarket is needed, it and the location of at most {} bytes",
+                MAX_FIELD_LEN
+            ),
+        ));
+    }
+    let template = db::templates::get_template(c, &user_id, &data.name)?
+        .ok_or_else(|| ServerError::new(StatusCode::NOT_FOUND, "No such template"))?;
+    rate_limit(
+        c,
+        "community_publish",
+        &user_id,
+        PUBLISHES_PER_DAY,
+        24 * 3600,
+    )?;
+    db::community::publish(
+        c,
+        &template,
+        &data.supermarket,
+        &data.location,
+        links::now_s(),
+    )
+}
+
+// Flag a template for moderation
+pub async fn report(auth: String, template_id: String, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    rate_limit(c, "community_report", &user_id, REPORTS_PER_HOUR, 3600)?;
+    db::community::report(c, &template_id, &user_id).map(|_| ())
+}
+
+// Copy a template of the directory to the user's own, replacing the one of
+// the same name
+pub async fn save(auth: String, template_id: String, c: &mut Connection) -> Result<AisleTemplate> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let published = db::community::get_template(c, &template_id)?.ok_or_else(db::not_found)?;
+    let template = AisleTemplate::new(published.name, published.aisles);
+    user::check_template_room(c, &user_id, &template.name)?;
+    db::templates::put_template(c, &user_id, &template)?;
+    Ok(template)
+}
+
+pub async fn reported(c: &mut Connection) -> Result<Vec<ReportedTemplate>> {
+    db::community::reported(c)
+}
+
+pub async fn restore(template_id: &str, c: &mut Connection) -> Result<()> {
+    if db::community::restore(c, template_id)? {
+        Ok(())
+    } else {
+        Err(ServerError::new(StatusCode::NOT_FOUND, "No such template"))
+    }
+}
+
+pub async fn remove(template_id: &str, c: &mut Connection) -> Result<()> {
+    if db::community::remove(c, template_id)? {
+        Ok(())
+    } else {
+        Err(ServerError::new(StatusCode::NOT_FOUND, "No such template"))
+    }
+}
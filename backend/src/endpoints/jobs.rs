@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    cron::Cron,
+    db::{
+        jobs::{self, DeadJob, QueueStats},
+        schedule::{self, RunStatus},
+    },
+    error, links,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+// dead jobs listed at once, the oldest first
+const DEAD_JOBS_LIST_LEN: usize = 100;
+
+#[derive(Serialize)]
+pub struct TaskReport {
+    name: String,
+    cron: Cron,
+    next_run_s: Option<u64>,
+    #[serde(flatten)]
+    status: RunStatus,
+}
+
+pub async fn queue_stats(queue: &str, c: &mut Connection) -> error::Result<QueueStats> {
+    jobs::stats(c, queue)
+}
+
+pub async fn dead_jobs(queue: &str, c: &mut Connection) -> error::Result<Vec<DeadJob>> {
+    jobs::dead_jobs(c, queue, DEAD_JOBS_LIST_LEN)
+}
+
+// When the scheduled tasks run next and how their last runs went
+pub async fn schedule_report(
+    schedule: BTreeMap<String, Cron>,
+    c: &mut Connection,
+) -> error::Result<Vec<TaskReport>> {
+    let now = links::now_s();
+    schedule
+        .into_iter()
+        .map(|(name, cron)| {
+            Ok(TaskReport {
+                status: schedule::run_status(c, &name)?,
+                next_run_s: cron.next_after(now),
+                name,
+                cron,
+            })
+        })
+        .collect()
+}
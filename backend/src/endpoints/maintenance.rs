@@ -0,0 +1,143 @@
+use serde::Serialize;
+use warp::{
+    http::{
+        header::{HeaderValue, RETRY_AFTER},
+        Method, StatusCode,
+    },
+    Reply,
+};
+
+use crate::{
+    config::{LiveConfig, ReadOnly},
+    db::version::{self, SchemaStatus},
+    error,
+    types::*,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+const DEFAULT_MESSAGE: &str = "Efficio is down for maintenance, please come back shortly";
+const DEFAULT_RETRY_AFTER_S: u64 = 60;
+const READ_ONLY_MESSAGE: &str = "Changes can't be saved for now, please try again shortly";
+
+// Rejects the requests that came during maintenance
+#[derive(Debug)]
+pub struct UnderMaintenance(pub Maintenance);
+
+impl warp::reject::Reject for UnderMaintenance {}
+
+// Rejects the writes that came while read-only
+#[derive(Debug)]
+pub struct ReadOnlyMode;
+
+impl warp::reject::Reject for ReadOnlyMode {}
+
+// Reply of GET and PUT /admin/read_only
+#[derive(Serialize)]
+pub struct ReadOnlyReport {
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub reasons: ReadOnly,
+}
+
+#[derive(Serialize)]
+pub struct SchemaReport {
+    #[serde(flatten)]
+    pub status: SchemaStatus,
+    pub read_only: bool,
+}
+
+#[derive(Serialize)]
+struct MaintenanceReply<'a> {
+    error: &'static str,
+    message: &'a str,
+    retry_after_s: u64,
+}
+
+// The admin API stays reachable to end maintenance, and health checks to
+// tell the instance still runs
+pub fn is_exempt(path: &str) -> bool {
+    !path.starts_with("/api/") || path.starts_with("/api/admin/") || path == "/api/health"
+}
+
+// Reads are still served while read-only
+pub fn is_read(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD || method == Method::OPTIONS
+}
+
+pub fn get(config: &LiveConfig) -> Maintenance {
+    config.maintenance().unwrap_or_default()
+}
+
+// Fills in the notice and delay left out, and returns what now applies
+pub fn set(config: &LiveConfig, mut maintenance: Maintenance) -> Maintenance {
+    if maintenance.enabled {
+        if maintenance.message.trim().is_empty() {
+            maintenance.message = DEFAULT_MESSAGE.to_owned();
+        }
+        if maintenance.retry_after_s == 0 {
+            maintenance.retry_after_s = DEFAULT_RETRY_AFTER_S;
+        }
+    } else {
+        maintenance = Maintenance::default();
+    }
+    config.set_maintenance(maintenance.clone());
+    maintenance
+}
+
+pub fn get_read_only(config: &LiveConfig) -> ReadOnlyReport {
+    let reasons = config.read_only();
+    ReadOnlyReport {
+        enabled: reasons.is_enabled(),
+        reasons,
+    }
+}
+
+// Only flips the switch, the setting and a schema mismatch keep applying
+pub fn set_read_only(config: &LiveConfig, data: ReadOnlySwitch) -> ReadOnlyReport {
+    config.set_read_only(data.enabled);
+    get_read_only(config)
+}
+
+pub fn health(config: &LiveConfig) -> Health {
+    Health::new(config.maintenance().is_some(), config.is_read_only())
+}
+
+// Read again, another instance may have migrated the database since startup
+pub async fn schema_report(config: &LiveConfig, c: &mut Connection) -> error::Result<SchemaReport> {
+    Ok(SchemaReport {
+        status: version::schema_status(c)?,
+        read_only: config.is_read_only(),
+    })
+}
+
+// 503 telling why and when to come back
+pub fn reply(maintenance: &Maintenance) -> warp::reply::Response {
+    unavailable(
+        "maintenance",
+        &maintenance.message,
+        maintenance.retry_after_s,
+    )
+}
+
+pub fn read_only_reply() -> warp::reply::Response {
+    unavailable("read_only", READ_ONLY_MESSAGE, DEFAULT_RETRY_AFTER_S)
+}
+
+fn unavailable(error: &'static str, message: &str, retry_after_s: u64) -> warp::reply::Response {
+    let body = MaintenanceReply {
+        error,
+        message,
+        retry_after_s,
+    };
+    let mut res =
+        warp::reply::with_status(warp::reply::json(&body), StatusCode::SERVICE_UNAVAILABLE)
+            .into_response();
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_s));
+    res
+}
@@ -0,0 +1,49 @@
+use std::fmt::Write;
+
+use log::*;
+
+use crate::{
+    db::events::{self, Event},
+    error, links,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+// Counting is no reason to fail what was counted
+pub fn record(c: &mut Connection, event: Event) {
+    if let Err(e) = events::count(c, event, links::now_s()) {
+        warn!("Could not count the {} event: {}", event.name(), e);
+    }
+}
+
+// GET /metrics
+// the domain events in Prometheus' text format, labelled by `event`
+pub async fn metrics(c: &mut Connection) -> error::Result<String> {
+    let counts = events::counts(c, links::now_s())?;
+    let mut text = String::new();
+    text.push_str("# HELP efficio_events_total Domain events since the first one\n");
+    text.push_str("# TYPE efficio_events_total counter\n");
+    for (event, total, _) in &counts {
+        let _ = writeln!(
+            text,
+            "efficio_events_total{{event=\"{}\"}} {}",
+            event.name(),
+            total
+        );
+    }
+    text.push_str("# HELP efficio_events_today Domain events since midnight UTC\n");
+    text.push_str("# TYPE efficio_events_today gauge\n");
+    for (event, _, today) in &counts {
+        let _ = writeln!(
+            text,
+            "efficio_events_today{{event=\"{}\"}} {}",
+            event.name(),
+            today
+        );
+    }
+    Ok(text)
+}
@@ -1,7 +1,7 @@
 use crate::{db, endpoints::INVALID_PARAMS, error, types::*};
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
@@ -18,20 +18,8 @@ pub async fn change_sort_weight(
         ))
     } else {
         let auth = Auth(&auth);
-        let mut pipe = redis::pipe();
-        pipe.atomic();
-        if let Some(ref aisles) = data.aisles {
-            aisles
-                .iter()
-                .try_for_each(|w| db::aisles::edit_aisle_sort_weight(c, &mut pipe, &auth, &w))?;
-        }
-        if let Some(ref products) = data.products {
-            products.iter().try_for_each(|w| {
-                db::products::edit_product_sort_weight(c, &mut pipe, &auth, &w)
-            })?;
-        }
-        pipe.query(c)?;
-        Ok(())
+        db::sessions::validate_session(c, &auth)?;
+        db::stores::edit_sort_weights(c, &auth, data)
     }
 }
 
@@ -1,11 +1,21 @@
 use warp::http::StatusCode;
 
+pub mod abuse;
 pub mod aisle;
+pub mod assistant;
+pub mod community;
+pub mod jobs;
+pub mod maintenance;
+pub mod metrics;
 pub mod misc;
+pub mod nutrition;
 pub mod product;
 pub mod routes;
 pub mod session;
 pub mod store;
+pub mod telemetry;
 pub mod user;
 
+pub use crate::types::{HEADER_AUTH, HEADER_NEW_AUTH};
+pub const HEADER_FORWARDED_FOR: &str = "x-forwarded-for";
 const INVALID_PARAMS: StatusCode = StatusCode::PRECONDITION_FAILED;
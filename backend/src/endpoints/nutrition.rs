@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use warp::http::StatusCode;
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+use crate::{
+    db,
+    endpoints::INVALID_PARAMS,
+    error::{Result, ServerError},
+    nutrition::{Lookup, NutritionSource},
+    types::*,
+};
+
+type Source = Option<Arc<dyn NutritionSource>>;
+
+fn source(source: Source) -> Result<Arc<dyn NutritionSource>> {
+    source.ok_or_else(|| ServerError::new(StatusCode::NOT_FOUND, "Nutrition lookups are off"))
+}
+
+// From the cache, else from the source
+async fn lookup(
+    source: &dyn NutritionSource,
+    lookup: &Lookup,
+    c: &mut Connection,
+) -> Result<Option<Nutrition>> {
+    if let Some(cached) = db::nutrition::get_cached(c, &lookup)? {
+        return Ok(cached);
+    }
+    let nutrition = source.lookup(&lookup).await?;
+    db::nutrition::cache(c, &lookup, &nutrition)?;
+    Ok(nutrition)
+}
+
+fn is_barcode(code: &str) -> bool {
+    (8..=14).contains(&code.len()) && code.chars().all(|c| c.is_ascii_digit())
+}
+
+pub async fn lookup_nutrition(
+    auth: String,
+    query: &NutritionQuery,
+    nutrition: Source,
+    c: &mut Connection,
+) -> Result<Nutrition> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let source = source(nutrition)?;
+    let query = match (&query.barcode, &query.name) {
+        (Some(code), None) if is_barcode(code) => Lookup::Barcode(code.clone()),
+        (None, Some(name)) if !name.trim().is_empty() => Lookup::Name(name.clone()),
+        _ => {
+            return Err(ServerError::new(
+                INVALID_PARAMS,
+                "Either a barcode of 8 to 14 digits or a name",
+            ))
+        }
+    };
+    lookup(&*source, &query, c)
+        .await?
+        .ok_or_else(|| ServerError::new(StatusCode::NOT_FOUND, "No nutrition facts"))
+}
+
+pub async fn product_nutrition(
+    auth: String,
+    product_id: String,
+    nutrition: Source,
+    c: &mut Connection,
+) -> Result<ProductNutrition> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let product_id = ProductId(product_id);
+    let name = db::nutrition::get_product_name(c, &auth, &product_id)?;
+    let source = source(nutrition)?;
+    let facts = lookup(&*source, &Lookup::Name(name.clone()), c).await?;
+    Ok(ProductNutrition::new(product_id.to_string(), name, facts))
+}
+
+fn mean(values: impl Iterator<Item = Option<f32>>) -> Option<f32> {
+    let (sum, count) = values
+        .flatten()
+        .fold((0f32, 0u32), |(sum, count), v| (sum + v, count + 1));
+    if count > 0 {
+        Some(sum / count as f32)
+    } else {
+        None
+    }
+}
+
+fn average(facts: &[&Nutrition]) -> Nutrition {
+    Nutrition {
+        name: None,
+        energy_kcal: mean(facts.iter().map(|n| n.energy_kcal)),
+        fat_g: mean(facts.iter().map(|n| n.fat_g)),
+        carbohydrates_g: mean(facts.iter().map(|n| n.carbohydrates_g)),
+        sugars_g: mean(facts.iter().map(|n| n.sugars_g)),
+        proteins_g: mean(facts.iter().map(|n| n.proteins_g)),
+        salt_g: mean(facts.iter().map(|n| n.salt_g)),
+    }
+}
+
+// The products left to buy, each looked up once by name
+pub async fn store_nutrition(
+    auth: String,
+    store_id: String,
+    nutrition: Source,
+    c: &mut Connection,
+) -> Result<StoreNutrition> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let store = db::stores::list_store(c, &auth, &StoreId::new(store_id))?;
+    let source = source(nutrition)?;
+    let mut products = vec![];
+    for product in store
+        .aisles
+        .into_iter()
+        .flat_map(|a| a.products)
+        .filter(|p| !p.is_done)
+    {
+        let facts = lookup(&*source, &Lookup::Name(product.name.clone()), c).await?;
+        products.push(ProductNutrition::new(
+            product.product_id,
+            product.name,
+            facts,
+        ));
+    }
+    let known: Vec<&Nutrition> = products
+        .iter()
+        .filter_map(|p| p.nutrition.as_ref())
+        .collect();
+    let unknown = (products.len() - known.len()) as u32;
+    let average = average(&known);
+    Ok(StoreNutrition::new(products, average, unknown))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_test() {
+        let milk = Nutrition {
+            energy_kcal: Some(64.0),
+            fat_g: Some(3.6),
+            ..Nutrition::default()
+        };
+        let bread = Nutrition {
+            energy_kcal: Some(266.0),
+            proteins_g: Some(8.0),
+            ..Nutrition::default()
+        };
+        let expected = Nutrition {
+            energy_kcal: Some(165.0),
+            fat_g: Some(3.6),
+            proteins_g: Some(8.0),
+            ..Nutrition::default()
+        };
+        assert_eq!(expected, average(&[&milk, &bread]));
+        assert_eq!(Nutrition::default(), average(&[]));
+    }
+}
@@ -1,7 +1,13 @@
-use crate::{db, endpoints::INVALID_PARAMS, error::*, types::*};
+use crate::{
+    db::{self, events::Event},
+    endpoints::{metrics, INVALID_PARAMS},
+    error::*,
+    retry::Retry,
+    types::*,
+};
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
@@ -17,6 +23,39 @@ pub async fn create_product(
     db::products::save_product(c, &auth, &data.name, &AisleId(aisle_id))
 }
 
+// Add a product in the aisle the user put it in before, see `db::placement`
+pub async fn create_product_in_store(
+    auth: String,
+    store_id: String,
+    data: &NameData,
+    c: &mut Connection,
+) -> Result<PlacedProduct> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::placement::save_product_in_store(c, &auth, &StoreId::new(store_id), &data.name)
+}
+
+// Out of its store, into every store of the user until checked
+pub async fn mark_anywhere(
+    auth: String,
+    product_id: String,
+    c: &mut Connection,
+) -> Result<Product> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::anywhere::mark_anywhere(c, &auth, &ProductId(product_id))
+}
+
+// Whether a failed edit can be sent again. What was bought is taken off the
+// quantity each time, replaying it would buy it twice.
+pub fn edit_retry(data: &EditProduct) -> Retry {
+    if data.bought.is_some() {
+        Retry::Never
+    } else {
+        Retry::Idempotent
+    }
+}
+
 pub async fn edit_product(
     auth: String,
     product_id: String,
@@ -30,13 +69,73 @@ pub async fn edit_product(
             INVALID_PARAMS,
             "At least a field must be present",
         ))
+    } else if data.bought.is_some()
+        && (data.bought == Some(0) || data.quantity.is_some() || data.is_done.is_some())
+    {
+        Err(ServerError::new(
+            INVALID_PARAMS,
+            "What was bought goes without a quantity or a state, and isn't 0",
+        ))
     } else {
-        db::products::modify_product(c, &auth, &data, &ProductId(product_id))
+        db::products::modify_product(c, &auth, &data, &ProductId(product_id))?;
+        if data.is_done == Some(true) || data.bought.is_some() {
+            metrics::record(c, Event::ProductChecked);
+        }
+        Ok(())
     }
 }
 
+// the most products a single request edits or deletes
+const MAX_BULK_PRODUCTS: usize = 500;
+
+fn bulk_product_ids(product_ids: &[String]) -> Result<Vec<ProductId>> {
+    if product_ids.is_empty() || product_ids.len() > MAX_BULK_PRODUCTS {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!(
+                "Between 1 and {} products can be changed at once",
+                MAX_BULK_PRODUCTS
+            ),
+        ));
+    }
+    Ok(product_ids.iter().cloned().map(ProductId).collect())
+}
+
+pub async fn set_products_done(
+    auth: String,
+    data: &ProductsDone,
+    c: &mut Connection,
+) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let product_ids = bulk_product_ids(&data.product_ids)?;
+    let checked = db::products::set_products_done(c, &auth, &product_ids, data.is_done)?;
+    for _ in 0..checked {
+        metrics::record(c, Event::ProductChecked);
+    }
+    Ok(())
+}
+
+pub async fn delete_products(auth: String, data: &ProductIds, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let product_ids = bulk_product_ids(&data.product_ids)?;
+    db::products::delete_products(c, &auth, &product_ids)
+}
+
+pub async fn product_history(
+    auth: String,
+    product_id: String,
+    c: &mut Connection,
+) -> Result<ProductHistory> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::products::get_product_history(c, &auth, &ProductId(product_id))
+}
+
 pub async fn delete_product(auth: String, product_id: String, c: &mut Connection) -> Result<()> {
     let auth = Auth(&auth);
     db::sessions::validate_session(c, &auth)?;
     db::products::delete_product(c, &auth, &ProductId(product_id))
 }
+
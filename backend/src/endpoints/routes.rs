@@ -1,16 +1,104 @@
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
+use hyper::{
+    body::Bytes,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request,
+};
 use log::*;
-use r2d2_redis::RedisConnectionManager;
-use warp::{self, path, Filter, Rejection, Reply};
+use serde::{de::DeserializeOwned, Serialize};
+use tower_service::Service;
+use uuid::Uuid;
+use warp::{
+    self,
+    http::{
+        header::{
+            HeaderValue, ACCEPT, ALLOW, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION,
+            VARY,
+        },
+        Method,
+    },
+    path,
+    path::{FullPath, Tail},
+    Filter, Rejection, Reply,
+};
 
-use crate::{cli::*, endpoints::*, error, types::*};
+use crate::{
+    archive::Archive,
+    breaker::CircuitBreaker,
+    cli::*,
+    config::{
+        Config, LiveConfig, DEFAULT_ABUSE_MAX_ERROR_RATIO, DEFAULT_ABUSE_MAX_REQUESTS,
+        DEFAULT_ABUSE_MIN_ERRORS,
+    },
+    conn_limit::ConnectionLimit,
+    db::{
+        self,
+        abuse::Ban,
+        journal::{self, Retention},
+        quotas::{self, Quotas},
+        users::KnownEmail,
+        version::{self, SchemaMismatch},
+    },
+    endpoints::*,
+    error, grpc,
+    jobs::{JobQueue, DEFAULT_QUEUE},
+    json_body, links, projection,
+    retry::{with_retry, Retry},
+    route_table::{self, MethodNotAllowed},
+    scheduler::Scheduler,
+    schema,
+    storage::{Connection, Pools, StorageKind, StorageManager},
+    tokens,
+    types::*,
+};
+
+#[cfg(unix)]
+use crate::systemd;
 
-const HEADER_AUTH: &str = "x-auth-token";
 const DEFAULT_DB_PORT: u32 = 6379;
 const DEFAULT_DB_HOST: &str = "redis://127.0.0.1";
+const DEFAULT_DB_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_BREAKER_COOLDOWN_S: u64 = 10;
+const DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT_S: u64 = 20;
+const DEFAULT_JOB_WORKERS: usize = 2;
+const DEFAULT_PRUNE_BANS_CRON: &str = "*/10 * * * *";
+const DEFAULT_TRIM_JOURNALS_CRON: &str = "0 * * * *";
+const DEFAULT_ARCHIVE_JOURNALS_CRON: &str = "30 * * * *";
+const DEFAULT_SEND_TELEMETRY_CRON: &str = "15 0 * * *";
+const DEFAULT_POW_DIFFICULTY: u8 = 20;
+const DEFAULT_SESSION_ROTATION_GRACE_S: u64 = 60;
+const MSGPACK_MIME: &str = "application/msgpack";
+
+pub use crate::storage::Pool;
+type PooledConnection = r2d2::PooledConnection<StorageManager>;
 
-type PooledConnection = r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>;
+// Peer of a connection accepted by `start_server`, warp only knows it when it
+// runs the server itself
+#[derive(Debug, Clone, Copy)]
+struct PeerAddr(SocketAddr);
+
+// Bounds every read and write on a pooled connection, a stuck Redis then
+// surfaces as a timeout instead of a blocked handler
+#[derive(Debug)]
+struct DbTimeout(Duration);
+
+impl<E: 'static> r2d2::CustomizeConnection<Connection, E> for DbTimeout {
+    fn on_acquire(&self, c: &mut Connection) -> Result<(), E> {
+        if let Err(e) = c.set_timeout(self.0) {
+            warn!("Could not set the database timeout: {}", e);
+        }
+        Ok(())
+    }
+}
 
 pub async fn start_server(opt: &Opt) -> error::Result<()> {
     let db_host = match opt.db_host {
@@ -22,26 +110,466 @@ pub async fn start_server(opt: &Opt) -> error::Result<()> {
         _ => DEFAULT_DB_PORT,
     };
     let db_num: u32 = if cfg!(debug_assertions) { 0 } else { 1 };
-    let redis_addr = format!("{}:{}/{}", db_host, db_port, db_num);
-
-    info!("DB address: {}", redis_addr);
-    let manager = RedisConnectionManager::new(redis_addr.as_str())?;
+    let db_timeout = Duration::from_millis(opt.db_timeout_ms.unwrap_or(DEFAULT_DB_TIMEOUT_MS));
+    let manager = match opt.storage {
+        Some(StorageKind::Memory) => {
+            warn!("In-memory storage, nothing will be kept after exit");
+            StorageManager::memory(db_num as i64)
+        }
+        _ => {
+            let redis_addr = format!("{}:{}/{}", db_host, db_port, db_num);
+            info!("DB address: {}", redis_addr);
+            StorageManager::redis(redis_addr.as_str())?
+        }
+    };
     debug!("Creating db connection pool");
-    let pool = r2d2::Pool::builder().max_size(15).build(manager)?;
+    let pool = build_pool(manager, db_timeout)?;
+    let replica = match (&opt.storage, &opt.db_replica_host) {
+        (Some(StorageKind::Memory), Some(_)) => {
+            warn!("In-memory storage has no replica, ignoring it");
+            None
+        }
+        (_, Some(replica_host)) => {
+            let replica_addr = format!(
+                "{}:{}/{}",
+                replica_host,
+                opt.db_replica_port.unwrap_or(db_port),
+                db_num
+            );
+            info!("DB replica address: {}", replica_addr);
+            Some(build_pool(
+                StorageManager::redis(replica_addr.as_str())?,
+                db_timeout,
+            )?)
+        }
+        _ => None,
+    };
 
-    let get_connection = warp::any()
-        .and_then(move || {
+    // the other instances of a blue/green deploy may run another version
+    let schema = version::schema_status(&mut *connection(&pool)?)?;
+    let schema_mismatch = !schema.compatible;
+    if schema_mismatch {
+        let mismatch = format!(
+            "The database's schema version {} is not within {}..={}",
+            schema.database, schema.min_supported, schema.max_supported
+        );
+        match opt.schema_mismatch.unwrap_or_default() {
+            SchemaMismatch::Refuse => {
+                return Err(error::ServerError::new(error::INTERNAL_ERROR, &mismatch))
+            }
+            SchemaMismatch::ReadOnly => warn!("{}, only serving reads", mismatch),
+        }
+    }
+
+    if let Some(ref path) = opt.link_secret_file {
+        links::set_secret(read_secret(path)?.as_bytes());
+    }
+    if let Some(ref path) = opt.email_secret_file {
+        db::users::set_email_secret(read_secret(path)?.as_bytes());
+    }
+    let token_secrets = if opt.token_secret_file.is_empty() {
+        vec![db::sessions::token_secret(&mut *connection(&pool)?)?]
+    } else {
+        opt.token_secret_file
+            .iter()
+            .map(|path| read_secret(path).map(String::into_bytes))
+            .collect::<error::Result<_>>()?
+    };
+    tokens::set_secrets(&token_secrets);
+
+    // features deferring work register their handlers here
+    if !schema_mismatch {
+        JobQueue::new(pool.clone(), DEFAULT_QUEUE)
+            .start(opt.job_workers.unwrap_or(DEFAULT_JOB_WORKERS));
+    }
+
+    let defaults = Quotas::default();
+    quotas::set_quotas(Quotas {
+        max_stores: limit(opt.max_stores, defaults.max_stores),
+        max_aisles: limit(opt.max_aisles, defaults.max_aisles),
+        max_products: limit(opt.max_products, defaults.max_products),
+        max_bytes: limit(opt.max_user_bytes, defaults.max_bytes),
+        warn_percent: opt.quota_warning_percent.unwrap_or(defaults.warn_percent),
+    });
+    let archive = match (&opt.archive_url, &opt.archive_bucket) {
+        (Some(url), Some(bucket)) => Some(Arc::new(Archive::new(
+            url,
+            bucket,
+            opt.archive_region.as_deref(),
+            opt.archive_key_id.as_deref().unwrap_or_default(),
+            &match opt.archive_secret_file {
+                Some(ref path) => read_secret(path)?,
+                None => String::new(),
+            },
+        ))),
+        (None, None) => None,
+        _ => {
+            return Err(error::ServerError::new(
+                error::INTERNAL_ERROR,
+                "--archive-url and --archive-bucket go together",
+            ))
+        }
+    };
+    let defaults = Retention::default();
+    journal::set_retention(Retention {
+        max_len: opt.journal_max_len.unwrap_or(defaults.max_len).max(1),
+        max_age_s: limit(opt.journal_max_age_s, defaults.max_age_s),
+        archive: archive.is_some(),
+    });
+
+    let base = Config {
+        log: std::env::var("RUST_LOG").unwrap_or_default(),
+        trusted_proxy: opt.trusted_proxy.clone(),
+        allow_ip: opt.allow_ip.clone(),
+        deny_ip: opt.deny_ip.clone(),
+        abuse_max_requests: opt.abuse_max_requests.unwrap_or(DEFAULT_ABUSE_MAX_REQUESTS),
+        abuse_max_error_ratio: opt
+            .abuse_max_error_ratio
+            .unwrap_or(DEFAULT_ABUSE_MAX_ERROR_RATIO),
+        abuse_min_errors: opt.abuse_min_errors.unwrap_or(DEFAULT_ABUSE_MIN_ERRORS),
+        abuse_ban_s: opt.abuse_ban_s.unwrap_or(0),
+        schedule: vec![
+            (
+                "prune_bans".to_owned(),
+                DEFAULT_PRUNE_BANS_CRON.parse().expect("invalid cron"),
+            ),
+            (
+                "trim_journals".to_owned(),
+                DEFAULT_TRIM_JOURNALS_CRON.parse().expect("invalid cron"),
+            ),
+            (
+                "send_telemetry".to_owned(),
+                DEFAULT_SEND_TELEMETRY_CRON.parse().expect("invalid cron"),
+            ),
+        ]
+        .into_iter()
+        .chain(archive.as_ref().map(|_| {
+            (
+                "archive_journals".to_owned(),
+                DEFAULT_ARCHIVE_JOURNALS_CRON.parse().expect("invalid cron"),
+            )
+        }))
+        .collect(),
+        assistant_redirect_uri: opt.assistant_redirect_uri.clone(),
+        registration: opt.registration.unwrap_or_default(),
+        registration_email_domain: opt.registration_email_domain.clone(),
+        challenge: opt.challenge.unwrap_or_default(),
+        challenge_site_key: opt.challenge_site_key.clone().unwrap_or_default(),
+        challenge_secret: match opt.challenge_secret_file {
+            Some(ref path) => read_secret(path)?,
+            None => String::new(),
+        },
+        pow_difficulty: opt.pow_difficulty.unwrap_or(DEFAULT_POW_DIFFICULTY),
+        session_rotate_after_s: opt.session_rotate_after_s.unwrap_or_default(),
+        session_rotation_grace_s: opt
+            .session_rotation_grace_s
+            .unwrap_or(DEFAULT_SESSION_ROTATION_GRACE_S),
+        default_aisles: opt.default_aisle.clone(),
+        nutrition: opt.nutrition.unwrap_or_default(),
+        nutrition_url: opt.nutrition_url.clone().unwrap_or_default(),
+        read_only: opt.read_only,
+        telemetry: opt.telemetry,
+        telemetry_url: opt.telemetry_url.clone().unwrap_or_default(),
+        community: opt.community,
+        admin_token: match opt.admin_token_file {
+            Some(ref path) => read_secret(path)?,
+            None => String::new(),
+        },
+    };
+    let config = Arc::new(match opt.config {
+        Some(ref path) => LiveConfig::with_file(base, path)?,
+        None => LiveConfig::new(base),
+    });
+    #[cfg(unix)]
+    tokio::spawn(config.clone().reload_on_sighup());
+    if schema_mismatch {
+        config.set_schema_mismatch();
+    }
+    if let Some(port) = opt.grpc_port {
+        tokio::spawn(grpc::serve(
+            pool.clone(),
+            config.clone(),
+            ([127, 0, 0, 1], port).into(),
+        ));
+    }
+    // tasks run at the times set by the `schedule` setting
+    let tasks_pool = pool.clone();
+    let scheduler = Scheduler::new(pool.clone(), config.clone())
+        .task("prune_bans", move || {
+            // expired bans are dropped from the list on the way
+            let pool = tasks_pool.clone();
+            async move { abuse::list_bans(&mut *connection(&pool)?).await.map(|_| ()) }
+        })
+        .task("trim_journals", {
             let pool = pool.clone();
-            async move {
-                match pool.get() {
-                    Ok(c) => Ok(c),
-                    Err(e) => Err(warp::reject::custom(error::ServerError::from(e))),
-                }
+            move || {
+                let pool = pool.clone();
+                async move { store::trim_journals(&mut *connection(&pool)?).await }
+            }
+        })
+        .task("send_telemetry", {
+            let (pool, config) = (pool.clone(), config.clone());
+            move || {
+                let (pool, config) = (pool.clone(), config.clone());
+                async move { telemetry::send(&config, &mut *connection(&pool)?).await }
             }
+        });
+    let scheduler = match archive {
+        Some(archive) => scheduler.task("archive_journals", {
+            let pool = pool.clone();
+            move || {
+                let (pool, archive) = (pool.clone(), archive.clone());
+                async move { store::archive_journals(&mut *connection(&pool)?, &archive).await }
+            }
+        }),
+        None => scheduler,
+    };
+    if !schema_mismatch {
+        scheduler.start();
+    }
+    let breaker = CircuitBreaker::new(Duration::from_secs(
+        opt.breaker_cooldown_s.unwrap_or(DEFAULT_BREAKER_COOLDOWN_S),
+    ));
+    let service = warp::service(routes(Pools::new(pool, replica), config, breaker));
+
+    #[cfg(unix)]
+    let listener = systemd::listener();
+    #[cfg(not(unix))]
+    let listener: Option<std::net::TcpListener> = None;
+    let builder = match listener {
+        Some(listener) => {
+            info!("Using the socket passed by systemd");
+            hyper::Server::from_tcp(listener)
+        }
+        None => hyper::Server::try_bind(&([127, 0, 0, 1], 3030).into()),
+    }
+    .map_err(|e| {
+        error::ServerError::new(error::INTERNAL_ERROR, &format!("Cannot listen: {}", e))
+    })?;
+
+    let limit = ConnectionLimit::new(opt.max_connections);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer = PeerAddr(conn.remote_addr());
+        let slot = limit.acquire();
+        let service = service.clone();
+        async move {
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    warn!("Too many connections, closing the one from {}", peer.0);
+                    return Err("connection limit reached");
+                }
+            };
+            Ok(service_fn(move |mut req: Request<Body>| {
+                // the connection counts until its last request is done
+                let _slot = &slot;
+                req.extensions_mut().insert(peer);
+                db::sessions::share_lookups(service.clone().call(req))
+            }))
+        }
+    });
+    let server = builder
+        .http1_only(opt.http1_only)
+        .http1_keepalive(!opt.no_keep_alive)
+        .http2_max_concurrent_streams(opt.http2_max_streams)
+        .http2_keep_alive_interval(opt.http2_keep_alive_s.map(Duration::from_secs))
+        .http2_keep_alive_timeout(Duration::from_secs(
+            opt.http2_keep_alive_timeout_s
+                .unwrap_or(DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT_S),
+        ))
+        .tcp_keepalive(opt.tcp_keepalive_s.map(Duration::from_secs))
+        .serve(make_service);
+
+    #[cfg(unix)]
+    {
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(systemd::watchdog(interval));
+        }
+        systemd::notify("READY=1");
+    }
+    info!("Efficio's ready for requests on {}...", server.local_addr());
+    server
+        .await
+        .map_err(|e| error::ServerError::new(error::INTERNAL_ERROR, &e.to_string()))
+}
+
+// 0 lifts the limit
+fn limit(opt: Option<u64>, default: Option<u64>) -> Option<u64> {
+    match opt {
+        Some(0) => None,
+        Some(max) => Some(max),
+        None => default,
+    }
+}
+
+fn build_pool(manager: StorageManager, db_timeout: Duration) -> error::Result<Pool> {
+    Ok(r2d2::Pool::builder()
+        .max_size(15)
+        .connection_timeout(db_timeout)
+        .connection_customizer(Box::new(DbTimeout(db_timeout)))
+        .build(manager)?)
+}
+
+pub fn routes(
+    pools: Pools,
+    config: Arc<LiveConfig>,
+    breaker: CircuitBreaker,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+    let breaker = Arc::new(breaker);
+    let client_ip = {
+        let config = config.clone();
+        warp::addr::remote()
+            .and(warp::ext::optional::<PeerAddr>())
+            .and(warp::header::optional::<String>(HEADER_FORWARDED_FOR))
+            .map(
+                move |remote: Option<SocketAddr>,
+                      peer: Option<PeerAddr>,
+                      forwarded_for: Option<String>| {
+                    remote.or(peer.map(|peer| peer.0)).map(|remote| {
+                        config
+                            .ip_policy()
+                            .client_ip(remote.ip(), forwarded_for.as_deref())
+                    })
+                },
+            )
+    };
+    // allow and deny lists apply before any routing
+    let check_ip = {
+        let config = config.clone();
+        client_ip
+            .clone()
+            .and_then(move |ip: Option<IpAddr>| {
+                let ip_policy = config.ip_policy();
+                async move {
+                    let allowed = match ip {
+                        Some(ip) => ip_policy.is_allowed(&ip),
+                        None => ip_policy.is_open(),
+                    };
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(error::ServerError::new(
+                            error::PERMISSION_DENIED,
+                            "Address not allowed",
+                        )))
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    // admin endpoints only answer the bearer of the admin token: behind the
+    // reverse proxy every client would look local
+    let admin_only = {
+        let config = config.clone();
+        warp::header::optional::<String>(AUTHORIZATION.as_str())
+            .and_then(move |authorization: Option<String>| {
+                let is_admin = config.is_admin(authorization.as_deref());
+                async move {
+                    if is_admin {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(error::ServerError::new(
+                            error::PERMISSION_DENIED,
+                            "Admin token required",
+                        )))
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    // the community directory is off unless configured, as if it didn't exist
+    let community_on = {
+        let config = config.clone();
+        warp::any()
+            .and_then(move || {
+                let on = config.community();
+                async move {
+                    if on {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(error::ServerError::new(
+                            StatusCode::NOT_FOUND,
+                            "Community templates are off",
+                        )))
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    let check_breaker = {
+        let breaker = breaker.clone();
+        warp::any()
+            .and_then(move || {
+                let breaker = breaker.clone();
+                async move {
+                    if breaker.is_open() {
+                        Err(warp::reject::custom(error::ServerError::db_unavailable()))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    // handlers that can be replayed get the pool and take a connection per attempt
+    let pool = pools.primary.clone();
+    let abuse_pool = pool.clone();
+    let get_pool = {
+        let pool = pool.clone();
+        check_breaker.clone().map(move || pool.clone()).boxed()
+    };
+    let get_pool = move || get_pool.clone();
+    // pure reads may be served by the replica
+    let get_pools = check_breaker.clone().map(move || pools.clone()).boxed();
+    let get_pools = move || get_pools.clone();
+
+    let get_connection = check_breaker
+        .and_then(move || {
+            let pool = pool.clone();
+            async move { connection(&pool).map_err(warp::reject::custom) }
         })
         .boxed();
     let get_connection = move || get_connection.clone();
 
+    // A route of the session's user, after `$filter` whose params are named
+    // in the call: `module::endpoint(auth, <params>, [&body,] connection)`
+    // replied with `$reply`, like `store::set_ui_state(store_id, &StoreUiState)`
+    macro_rules! authed_route {
+        ($filter:expr, $module:ident::$endpoint:ident($($param:ident,)* &$body:ty) => $reply:ident) => {
+            $filter
+                .and(warp::path::end())
+                .and(session::auth())
+                .and(json_body())
+                .and(get_connection())
+                .and_then(
+                    move |$($param: String,)* auth: String, data: $body, mut c: PooledConnection| async move {
+                        $module::$endpoint(auth, $($param,)* &data, &mut *c)
+                            .await
+                            .map($reply)
+                            .map_err(warp::reject::custom)
+                    },
+                )
+        };
+        ($filter:expr, $module:ident::$endpoint:ident($($param:ident),*) => $reply:ident) => {
+            $filter
+                .and(warp::path::end())
+                .and(session::auth())
+                .and(get_connection())
+                .and_then(
+                    move |$($param: String,)* auth: String, mut c: PooledConnection| async move {
+                        $module::$endpoint(auth, $($param,)* &mut *c)
+                            .await
+                            .map($reply)
+                            .map_err(warp::reject::custom)
+                    },
+                )
+        };
+    }
+
     // POST /nuke
     let nuke = warp::path("nuke")
         .and(warp::path::end())
@@ -51,263 +579,1673 @@ pub async fn start_server(opt: &Opt) -> error::Result<()> {
     // POST /user
     let create_user = warp::path("user")
         .and(warp::path::end())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(session::cookie_mode())
+        .and(client_ip.clone())
+        .and(get_connection())
+        .and_then({
+            let config = config.clone();
+            move |user: User, cookie_mode, ip: Option<IpAddr>, mut c: PooledConnection| {
+                let policy = config.registration_policy();
+                let challenge = config.challenge();
+                async move {
+                    user::create_user(&user, &policy, challenge, ip, &mut *c)
+                        .await
+                        .map(|token| session::token_reply(token, cookie_mode))
+                        .map_err(|e| match e {
+                            user::SignUpError::Refused(refused) => warp::reject::custom(refused),
+                            user::SignUpError::Failed(e) => warp::reject::custom(e),
+                        })
+                }
+            }
+        });
+
+    // GET /user/check?username=<username>
+    // live validation of the sign up form
+    let check_username = path!("user" / "check")
+        .and(warp::path::end())
+        .and(warp::query::<UsernameQuery>())
+        .and(client_ip.clone())
+        .and(get_connection())
+        .and_then(
+            move |query: UsernameQuery, ip: Option<IpAddr>, mut c: PooledConnection| async move {
+                user::check_username(&query.username, ip, &mut *c)
+                    .await
+                    .map(|check| warp::reply::json(&check))
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /user/default_aisles
+    // the aisles the user's new stores start with, null for the server's
+    let get_default_aisles = authed_route!(
+        path!("user" / "default_aisles"),
+        user::get_default_aisles() => reply_json
+    );
+
+    // PUT /user/default_aisles
+    let set_default_aisles = authed_route!(
+        path!("user" / "default_aisles"),
+        user::set_default_aisles(&DefaultAisles) => reply_empty
+    );
+
+    // GET /user/aliases
+    // names the user takes for the same product, see `db::aliases`
+    let get_aliases = authed_route!(
+        path!("user" / "aliases"),
+        user::get_aliases() => reply_json
+    );
+
+    // PUT /user/aliases
+    // replace them all
+    let set_aliases = authed_route!(
+        path!("user" / "aliases"),
+        user::set_aliases(&ProductAliases) => reply_empty
+    );
+
+    // GET /user/anywhere
+    // products needed from any store, also in every full read of a store
+    let get_anywhere = authed_route!(
+        path!("user" / "anywhere"),
+        user::get_anywhere() => reply_json
+    );
+
+    // DELETE /user/anywhere/<product id>
+    // checked in one store, gone from all
+    let check_anywhere = authed_route!(
+        path!("user" / "anywhere" / ..).and(id::<ProductId>()),
+        user::check_anywhere(product_id) => reply_empty
+    );
+
+    // GET /user/quota
+    // how close the account is to its quotas
+    let get_quotas = authed_route!(
+        path!("user" / "quota"),
+        user::get_quotas() => reply_json
+    );
+
+    // GET /user/staples
+    // products the household always needs, see POST /store/<id>/add_staples
+    let get_staples = authed_route!(
+        path!("user" / "staples"),
+        user::get_staples() => reply_json
+    );
+
+    // PUT /user/staples
+    // replace them all
+    let set_staples = authed_route!(
+        path!("user" / "staples"),
+        user::set_staples(&Staples) => reply_empty
+    );
+
+    // POST /user/staples
+    // add one, or replace the one of that name
+    let add_staple = authed_route!(
+        path!("user" / "staples"),
+        user::add_staple(&Staple) => reply_empty
+    );
+
+    // DELETE /user/staples?name=<name>
+    let delete_staple = path!("user" / "staples")
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(warp::query::<StapleQuery>())
         .and(get_connection())
-        .and_then(move |user: User, mut c: PooledConnection| async move {
-            user::create_user(&user, &mut *c)
+        .and_then(
+            move |auth: String, query: StapleQuery, mut c: PooledConnection| async move {
+                user::delete_staple(auth, &query.name, &mut *c)
+                    .await
+                    .map(reply_empty)
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /templates
+    // aisle layouts saved from the user's stores
+    let get_templates = authed_route!(
+        warp::path("templates"),
+        user::get_templates() => reply_json
+    );
+
+    // POST /templates
+    // save the aisles of a store, or replace the template of that name
+    let save_template = authed_route!(
+        warp::path("templates"),
+        user::save_template(&StoreTemplate) => reply_json
+    );
+
+    // DELETE /templates?name=<name>
+    let delete_template = warp::path("templates")
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(warp::query::<TemplateQuery>())
+        .and(get_connection())
+        .and_then(
+            move |auth: String, query: TemplateQuery, mut c: PooledConnection| async move {
+                user::delete_template(auth, &query.name, &mut *c)
+                    .await
+                    .map(reply_empty)
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // POST /templates/apply
+    // add the template's aisles a store lacks, returns the aisles added
+    let apply_template = authed_route!(
+        path!("templates" / "apply"),
+        user::apply_template(&StoreTemplate) => reply_json
+    );
+
+    // GET /community?q=<text>
+    // published templates of supermarkets or locations matching the text
+    let search_community = warp::path("community")
+        .and(warp::path::end())
+        .and(community_on.clone())
+        .and(session::auth())
+        .and(warp::query::<CommunityQuery>())
+        .and(get_connection())
+        .and_then(
+            move |auth: String, query: CommunityQuery, mut c: PooledConnection| async move {
+                community::search(auth, &query, &mut *c)
+                    .await
+                    .map(reply_json)
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // POST /community
+    // publish one of the user's templates, a few a day
+    let publish_template = authed_route!(
+        warp::path("community").and(community_on.clone()),
+        community::publish(&PublishTemplate) => reply_json
+    );
+
+    // POST /community/<id>/report
+    // enough reports hide the template until a moderator looks at it
+    let report_template = authed_route!(
+        warp::path("community")
+            .and(id::<Uuid>())
+            .and(warp::path("report"))
+            .and(community_on.clone()),
+        community::report(template_id) => reply_empty
+    );
+
+    // POST /community/<id>/save
+    // copy it to the user's templates
+    let save_community_template = authed_route!(
+        warp::path("community")
+            .and(id::<Uuid>())
+            .and(warp::path("save"))
+            .and(community_on.clone()),
+        community::save(template_id) => reply_json
+    );
+
+    // GET /admin/community
+    // the reported templates, hidden ones first, admins only
+    let admin_community = path!("admin" / "community")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |mut c: PooledConnection| async move {
+            community::reported(&mut *c)
+                .await
+                .map(|reported| warp::reply::json(&reported))
+                .map_err(warp::reject::custom)
+        });
+
+    // POST /admin/community/<id>/restore
+    // show a hidden template again and forget its reports, admins only
+    let restore_community_template = path!("admin" / "community" / String / "restore")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |id: String, mut c: PooledConnection| async move {
+            community::restore(&id, &mut *c)
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+        });
+
+    // DELETE /admin/community/<id>
+    // take a template out of the directory, admins only
+    let remove_community_template = path!("admin" / "community" / String)
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |id: String, mut c: PooledConnection| async move {
+            community::remove(&id, &mut *c)
                 .await
-                .map(|token| warp::reply::json(&token))
+                .map(|()| warp::reply())
                 .map_err(warp::reject::custom)
         });
 
+    // POST /templates/redeem
+    // a new store copied from a template code, without the products checked
+    let redeem_template = authed_route!(
+        path!("templates" / "redeem"),
+        user::redeem_template(&RedeemTemplate) => reply_json
+    );
+
     // POST /login
     let login = warp::path("login")
         .and(warp::path::end())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(session::cookie_mode())
         .and(get_connection())
         .and_then(
-            move |auth_info: AuthInfo, mut c: PooledConnection| async move {
+            move |auth_info: AuthInfo, cookie_mode, mut c: PooledConnection| async move {
                 session::login(&auth_info, &mut *c)
                     .await
-                    .map(|token| warp::reply::json(&token))
+                    .map(|token| session::token_reply(token, cookie_mode))
                     .map_err(warp::reject::custom)
             },
         );
 
+    // POST /user/pair
+    // a code for another device to log in as the user
+    let pair = path!("user" / "pair")
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(get_connection())
+        .and_then(move |auth: String, mut c: PooledConnection| async move {
+            session::pair(&auth, &mut *c)
+                .await
+                .map(|pairing| warp::reply::json(&pairing))
+                .map_err(warp::reject::custom)
+        });
+
+    // POST /user/pair/claim
+    // the other device trades the code for its own session
+    let claim_pairing =
+        path!("user" / "pair" / "claim")
+            .and(warp::path::end())
+            .and(json_body())
+            .and(session::cookie_mode())
+            .and(client_ip.clone())
+            .and(get_connection())
+            .and_then(
+                move |claim: PairingClaim,
+                      cookie_mode,
+                      ip: Option<IpAddr>,
+                      mut c: PooledConnection| async move {
+                    session::claim_pairing(&claim, ip, &mut *c)
+                        .await
+                        .map(|token| session::token_reply(token, cookie_mode))
+                        .map_err(warp::reject::custom)
+                },
+            );
+
     // POST /logout
     let logout = path!("logout" / String)
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(session::auth())
         .and(get_connection())
         .and_then(
             move |id: String, auth: String, mut c: PooledConnection| async move {
                 session::logout(&auth, &id, &mut *c)
                     .await
-                    .map(|()| warp::reply())
+                    .map(|()| session::clear_cookies(warp::reply().into_response()))
                     .map_err(warp::reject::custom)
             },
         );
 
     // DELETE /user
+    // ?dry_run=true to only get what would be removed
     let delete_user = path!("user" / String)
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(session::auth())
+        .and(warp::query::<DeleteQuery>())
         .and(get_connection())
         .and_then(
-            move |id: String, auth: String, mut c: PooledConnection| async move {
-                user::delete_user(&auth, &id, &mut *c)
-                    .await
-                    .map(|()| warp::reply())
-                    .map_err(warp::reject::custom)
+            move |id: String, auth: String, query: DeleteQuery, mut c: PooledConnection| async move {
+                if query.dry_run.unwrap_or(false) {
+                    user::user_removal(&auth, &id, &mut *c)
+                        .await
+                        .map(|removal| warp::reply::json(&removal).into_response())
+                } else {
+                    user::delete_user(&auth, &id, &mut *c)
+                        .await
+                        .map(|()| warp::reply().into_response())
+                }
+                .map_err(warp::reject::custom)
             },
         );
 
     // POST /store
-    let create_store = warp::path("store")
+    // with the user's default aisles, or the server's, unless `no_default_aisles`
+    let create_store = {
+        let config = config.clone();
+        warp::path("store")
+            .and(warp::path::end())
+            .and(session::auth())
+            .and(json_body())
+            .and(get_connection())
+            .and_then(move |auth, data: NewStore, mut c: PooledConnection| {
+                let default_aisles = config.default_aisles();
+                async move {
+                    store::create_store(auth, &data, &default_aisles, &mut *c)
+                        .await
+                        .map(|store_id| warp::reply::json(&store_id))
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // PUT /store/{id}
+    // rename, and set what the products created without a quantity start with
+    let edit_store = warp::path("store")
+        .and(id::<StoreId>())
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
-        .and(get_connection())
+        .and(session::auth())
+        .and(json_body())
+        .and(get_pool())
         .and_then(
-            move |auth, data: NameData, mut c: PooledConnection| async move {
-                store::create_store(auth, &data, &mut *c)
-                    .await
-                    .map(|store_id| warp::reply::json(&store_id))
-                    .map_err(warp::reject::custom)
+            move |id: String, auth: String, data: EditStore, pool: Pool| async move {
+                with_retry(Retry::Idempotent, || async {
+                    store::edit_store(auth.clone(), id.clone(), &data, &mut *connection(&pool)?)
+                        .await
+                })
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
             },
         );
 
-    // PUT /store/{id}
-    let edit_store = path!("store" / String)
+    // PUT /store/<id>/ui_state
+    let set_ui_state = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("ui_state")),
+        store::set_ui_state(store_id, &StoreUiState) => reply_empty
+    );
+
+    // POST /store/<id>/template_code
+    // a code for other users to copy the store, see POST /templates/redeem
+    let create_template_code = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("template_code")),
+        store::template_code(store_id) => reply_json
+    );
+
+    // POST /store/<id>/snapshot
+    // keep the store as it is now, the oldest of too many snapshots goes
+    let create_snapshot = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("snapshot")),
+        store::create_snapshot(store_id, &NameData) => reply_json
+    );
+
+    // GET /store/<id>/snapshots
+    let get_snapshots = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("snapshots")),
+        store::snapshots(store_id) => reply_json
+    );
+
+    // POST /store/<id>/snapshots/<snapshot id>/restore
+    // put the store back as it was then, returns it
+    let restore_snapshot = authed_route!(
+        warp::path("store")
+            .and(id::<StoreId>())
+            .and(warp::path("snapshots"))
+            .and(id::<Uuid>())
+            .and(warp::path("restore")),
+        store::restore_snapshot(store_id, snapshot_id) => reply_json
+    );
+
+    // GET /store/<id>/snapshots/<snapshot id>/diff/<snapshot id or "live">
+    // what changed in the store from one snapshot to the other, or to now
+    let snapshot_diff = authed_route!(
+        warp::path("store")
+            .and(id::<StoreId>())
+            .and(warp::path("snapshots"))
+            .and(id::<Uuid>())
+            .and(warp::path("diff"))
+            .and(warp::path::param::<String>()),
+        store::snapshot_diff(store_id, snapshot_id, other_id) => reply_json
+    );
+
+    // POST /store/<id>/add_staples
+    // add the user's staples the store lacks, returns what was added
+    let add_staples = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("add_staples")),
+        store::add_staples(store_id) => reply_json
+    );
+
+    // POST /store/<id>/crdt
+    // merge the client's state of the products into ours, returns the result
+    let merge_crdt_store = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("crdt")),
+        store::merge_crdt_store(store_id, &CrdtStore) => reply_json
+    );
+
+    // POST /store/<id>/export_link
+    // a short-lived signed URL to download the store without the session token
+    let create_export_link = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("export_link")),
+        store::export_link(store_id) => reply_json
+    );
+
+    // POST /store/<id>/share_link
+    // the unchecked products as a message to send to someone without an
+    // account, with a read-only link lasting a week
+    let create_share_link = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("share_link")),
+        store::share_link(store_id, &ShareParams) => reply_json
+    );
+
+    // POST /store/<id>/merge_from/<other id>
+    // move everything of the other store into this one and delete it,
+    // ?dry_run=true to only get what would be moved
+    let merge_stores = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("merge_from"))
+        .and(id::<StoreId>())
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(session::auth())
+        .and(warp::query::<MergeQuery>())
         .and(get_connection())
         .and_then(
-            move |id, auth, data: NameData, mut c: PooledConnection| async move {
-                store::edit_store(auth, id, &data, &mut *c)
+            move |store_id, other_id, auth, query: MergeQuery, mut c: PooledConnection| async move {
+                store::merge_stores(auth, store_id, other_id, &query, &mut *c)
                     .await
-                    .map(|()| warp::reply())
+                    .map(|merge| warp::reply::json(&merge))
                     .map_err(warp::reject::custom)
             },
         );
 
     // POST /store/<id>/aisle
-    let create_aisle = path!("store" / String / "aisle")
+    let create_aisle = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("aisle")),
+        aisle::create_aisle(store_id, &NameData) => reply_json
+    );
+
+    // PUT /aisle/<id>
+    let edit_aisle = warp::path("aisle")
+        .and(id::<AisleId>())
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(json_body())
+        .and(get_pool())
+        .and_then(
+            move |aisle_id: String, auth: String, data: NameData, pool: Pool| async move {
+                with_retry(Retry::Idempotent, || async {
+                    let mut c = connection(&pool)?;
+                    aisle::rename_aisle(auth.clone(), aisle_id.clone(), &data, &mut *c).await
+                })
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // POST /aisle/<id>/product
+    let create_product = authed_route!(
+        warp::path("aisle").and(id::<AisleId>()).and(warp::path("product")),
+        product::create_product(aisle_id, &NameData) => reply_json
+    );
+
+    // POST /store/<id>/product
+    // in the aisle the user put it in before, else the unsorted one
+    let create_product_in_store = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("product")),
+        product::create_product_in_store(store_id, &NameData) => reply_json
+    );
+
+    // GET /product/<id>/nutrition
+    // looked up by name, see `nutrition`
+    let product_nutrition = {
+        let config = config.clone();
+        warp::path("product")
+            .and(id::<ProductId>())
+            .and(warp::path("nutrition"))
+            .and(warp::path::end())
+            .and(session::auth())
+            .and(get_connection())
+            .and_then(move |product_id, auth, mut c: PooledConnection| {
+                let source = config.nutrition();
+                async move {
+                    nutrition::product_nutrition(auth, product_id, source, &mut *c)
+                        .await
+                        .map(reply_json)
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // GET /product/<id>/history
+    // its edits and how it is bought
+    let product_history = authed_route!(
+        warp::path("product")
+            .and(id::<ProductId>())
+            .and(warp::path("history")),
+        product::product_history(product_id) => reply_json
+    );
+
+    // GET /store/<id>/nutrition
+    // of the products left to buy
+    let store_nutrition = {
+        let config = config.clone();
+        warp::path("store")
+            .and(id::<StoreId>())
+            .and(warp::path("nutrition"))
+            .and(warp::path::end())
+            .and(session::auth())
+            .and(get_connection())
+            .and_then(move |store_id, auth, mut c: PooledConnection| {
+                let source = config.nutrition();
+                async move {
+                    nutrition::store_nutrition(auth, store_id, source, &mut *c)
+                        .await
+                        .map(reply_json)
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // GET /nutrition?barcode=<code> or ?name=<name>
+    let lookup_nutrition = {
+        let config = config.clone();
+        warp::path("nutrition")
+            .and(warp::path::end())
+            .and(session::auth())
+            .and(warp::query::<NutritionQuery>())
+            .and(get_connection())
+            .and_then(
+                move |auth, query: NutritionQuery, mut c: PooledConnection| {
+                    let source = config.nutrition();
+                    async move {
+                        nutrition::lookup_nutrition(auth, &query, source, &mut *c)
+                            .await
+                            .map(reply_json)
+                            .map_err(warp::reject::custom)
+                    }
+                },
+            )
+    };
+
+    // POST /product/<id>/anywhere
+    // out of its store, needed from any of the user's
+    let mark_anywhere = authed_route!(
+        warp::path("product").and(id::<ProductId>()).and(warp::path("anywhere")),
+        product::mark_anywhere(product_id) => reply_json
+    );
+
+    // PUT /product/<id>
+    let edit_product = warp::path("product")
+        .and(id::<ProductId>())
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(json_body())
+        .and(get_pool())
+        .and_then(
+            move |product_id: String, auth: String, data: EditProduct, pool: Pool| async move {
+                with_retry(product::edit_retry(&data), || async {
+                    let mut c = connection(&pool)?;
+                    product::edit_product(auth.clone(), product_id.clone(), &data, &mut *c).await
+                })
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // PUT /products/done
+    // checks off or back on all of them or none, at the till
+    let set_products_done = path!("products" / "done")
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(json_body())
+        .and(get_pool())
+        .and_then(
+            move |auth: String, data: ProductsDone, pool: Pool| async move {
+                with_retry(Retry::Idempotent, || async {
+                    let mut c = connection(&pool)?;
+                    product::set_products_done(auth.clone(), &data, &mut *c).await
+                })
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /store
+    let get_all_stores = warp::path("store")
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(warp::header::optional::<String>(ACCEPT.as_str()))
+        .and(get_pools())
+        .and_then(
+            move |auth: String, accept: Option<String>, pools: Pools| async move {
+                with_retry(Retry::Idempotent, || {
+                    pools.read_marked(
+                        |pool| {
+                            let auth = auth.clone();
+                            async move { store::stores_versions(auth, &mut *connection(&pool)?).await }
+                        },
+                        |pool| {
+                            let auth = auth.clone();
+                            async move { store::list_stores(auth, &mut *connection(&pool)?).await }
+                        },
+                    )
+                })
+                .await
+                .map(|stores| negotiated_reply(accept, &stores))
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /store/<id>
+    // ?fields=aisles.name,products.is_done to prune the response
+    // ?since_version=<version> to only get what changed since then, not along
+    // with fields
+    let list_store = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(warp::header::optional::<String>(ACCEPT.as_str()))
+        .and(warp::query::<StoreQuery>())
+        .and(get_pools())
+        .and_then(
+            move |store_id: String,
+                  auth: String,
+                  accept: Option<String>,
+                  query: StoreQuery,
+                  pools: Pools| async move {
+                let version = |pool: Pool| {
+                    let store_id = store_id.clone();
+                    async move { store::store_version(store_id, &mut *connection(&pool)?).await }
+                };
+                let read = |pool: Pool| {
+                    let (auth, store_id) = (auth.clone(), store_id.clone());
+                    async move { store::list_store(auth, store_id, &mut *connection(&pool)?).await }
+                };
+                let reply = with_retry(Retry::Idempotent, || async {
+                    match query {
+                        StoreQuery {
+                            since_version: Some(_),
+                            fields: Some(_),
+                        } => Err(error::ServerError::new(
+                            error::INVALID_QUERY,
+                            "fields can't be used with since_version",
+                        )),
+                        // a replica behind the version the client already has
+                        // would send it back in time
+                        StoreQuery {
+                            since_version: Some(since),
+                            ..
+                        } => pools
+                            .read(
+                                |pool| {
+                                    let (auth, store_id) = (auth.clone(), store_id.clone());
+                                    async move {
+                                        let mut c = connection(&pool)?;
+                                        store::list_store_since(auth, store_id, since, &mut *c)
+                                            .await
+                                    }
+                                },
+                                |store| store.version >= since,
+                            )
+                            .await
+                            .map(|store| negotiated_reply(accept.clone(), &store)),
+                        StoreQuery {
+                            fields: Some(ref fields),
+                            ..
+                        } => pools
+                            .read_marked(version, read)
+                            .await
+                            .and_then(|store| projection::project(&store, fields))
+                            .map(|store| negotiated_reply(accept.clone(), &store)),
+                        _ => pools
+                            .read_marked(version, read)
+                            .await
+                            .map(|store| negotiated_reply(accept.clone(), &store)),
+                    }
+                })
+                .await;
+                reply.map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /store/<id>/print
+    // the list as a printable page, ?include_done=true to keep the products done
+    let print_store = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("print"))
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(session::auth())
+        .and(warp::query::<PrintQuery>())
         .and(get_connection())
         .and_then(
-            move |store_id, auth, data: NameData, mut c: PooledConnection| async move {
-                aisle::create_aisle(auth, store_id, &data, &mut *c)
+            move |store_id, auth, query: PrintQuery, mut c: PooledConnection| async move {
+                store::print_store(auth, store_id, &query, &mut *c)
                     .await
-                    .map(|aisle| warp::reply::json(&aisle))
+                    .map(warp::reply::html)
                     .map_err(warp::reject::custom)
             },
         );
 
-    // PUT /aisle/<id>
-    let edit_aisle = path!("aisle" / String)
+    // GET /store/<id>/text
+    // the unchecked products as plain text, grouped by aisle
+    let text_store = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("text"))
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(session::auth())
         .and(get_connection())
+        .and_then(move |store_id, auth, mut c: PooledConnection| async move {
+            store::text_store(auth, store_id, &mut *c)
+                .await
+                .map_err(warp::reject::custom)
+        });
+
+    // GET /store/<id>/stats
+    // counts per aisle, share done and the products added most these weeks
+    let store_stats = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(session::auth())
+        .and(warp::header::optional::<String>(ACCEPT.as_str()))
+        .and(get_pools())
         .and_then(
-            move |aisle_id, auth, data: NameData, mut c: PooledConnection| async move {
-                aisle::rename_aisle(auth, aisle_id, &data, &mut *c)
-                    .await
-                    .map(|()| warp::reply())
-                    .map_err(warp::reject::custom)
+            move |store_id: String, auth: String, accept: Option<String>, pools: Pools| async move {
+                let version = |pool: Pool| {
+                    let store_id = store_id.clone();
+                    async move { store::store_version(store_id, &mut *connection(&pool)?).await }
+                };
+                with_retry(Retry::Idempotent, || {
+                    pools.read_marked(version, |pool| {
+                        let (auth, store_id) = (auth.clone(), store_id.clone());
+                        async move {
+                            store::store_stats(auth, store_id, &mut *connection(&pool)?).await
+                        }
+                    })
+                })
+                .await
+                .map(|stats| negotiated_reply(accept, &stats))
+                .map_err(warp::reject::custom)
             },
         );
 
-    // POST /aisle/<id>/product
-    let create_product = path!("aisle" / String / "product")
+    // GET /store/<id>/insights
+    // products added again and again, and how often they are bought
+    let store_insights = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("insights"))
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(session::auth())
+        .and(warp::header::optional::<String>(ACCEPT.as_str()))
+        .and(get_pools())
+        .and_then(
+            move |store_id: String, auth: String, accept: Option<String>, pools: Pools| async move {
+                let version = |pool: Pool| {
+                    let store_id = store_id.clone();
+                    async move { store::store_version(store_id, &mut *connection(&pool)?).await }
+                };
+                with_retry(Retry::Idempotent, || {
+                    pools.read_marked(version, |pool| {
+                        let (auth, store_id) = (auth.clone(), store_id.clone());
+                        async move {
+                            store::store_insights(auth, store_id, &mut *connection(&pool)?).await
+                        }
+                    })
+                })
+                .await
+                .map(|insights| negotiated_reply(accept, &insights))
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /store/<id>/ui_state
+    // collapsed aisles and whether to hide the products done, for this user
+    let get_ui_state = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("ui_state")),
+        store::ui_state(store_id) => reply_json
+    );
+
+    // GET /store/<id>/crdt
+    // the products with when each field was edited and the ids removed
+    let get_crdt_store = authed_route!(
+        warp::path("store").and(id::<StoreId>()).and(warp::path("crdt")),
+        store::crdt_store(store_id) => reply_json
+    );
+
+    // GET /store/<id>/export?expires=<timestamp>&sig=<signature>
+    // download link made by POST /store/<id>/export_link, no session needed
+    let export_store = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(warp::query::<ExportQuery>())
         .and(get_connection())
         .and_then(
-            move |aisle_id, auth, data: NameData, mut c: PooledConnection| async move {
-                product::create_product(auth, aisle_id, &data, &mut *c)
+            move |store_id: String, query: ExportQuery, mut c: PooledConnection| async move {
+                store::export_store(store_id.clone(), &query, &mut *c)
                     .await
-                    .map(|product| warp::reply::json(&product))
+                    .map(|store| {
+                        warp::reply::with_header(
+                            warp::reply::json(&store),
+                            CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"store-{}.json\"", store_id),
+                        )
+                    })
                     .map_err(warp::reject::custom)
             },
         );
 
-    // PUT /product/<id>
-    let edit_product = path!("product" / String)
+    // GET /store/<id>/shared?expires=<timestamp>&sig=<signature>
+    // the list as text for whoever got a link made by POST
+    // /store/<id>/share_link, no session needed
+    let shared_store = warp::path("store")
+        .and(id::<StoreId>())
+        .and(warp::path("shared"))
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(warp::query::<ExportQuery>())
         .and(get_connection())
         .and_then(
-            move |product_id, auth, data: EditProduct, mut c: PooledConnection| async move {
-                product::edit_product(auth, product_id, &data, &mut *c)
+            move |store_id, query: ExportQuery, mut c: PooledConnection| async move {
+                store::shared_store(store_id, &query, &mut *c)
                     .await
-                    .map(|()| warp::reply())
                     .map_err(warp::reject::custom)
             },
         );
 
-    // GET /store
-    let get_all_stores = warp::path("store")
+    // DELETE /product/<id>
+    let delete_product = authed_route!(
+        warp::path("product").and(id::<ProductId>()),
+        product::delete_product(product_id) => reply_empty
+    );
+
+    // DELETE /products
+    // all of them or none, for a multi-select
+    let delete_products = authed_route!(
+        warp::path("products"),
+        product::delete_products(&ProductIds) => reply_empty
+    );
+
+    // DELETE /aisle/<id>
+    let delete_aisle = authed_route!(
+        warp::path("aisle").and(id::<AisleId>()),
+        aisle::delete_aisle(aisle_id) => reply_empty
+    );
+
+    // DELETE /store/<id>
+    // ?dry_run=true to only get what would be removed
+    let delete_store = warp::path("store")
+        .and(id::<StoreId>())
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(session::auth())
+        .and(warp::query::<DeleteQuery>())
         .and(get_connection())
-        .and_then(move |auth, mut c: PooledConnection| async move {
-            store::list_stores(auth, &mut *c)
-                .await
-                .map(|stores| warp::reply::json(&stores))
+        .and_then(
+            move |store_id, auth, query: DeleteQuery, mut c: PooledConnection| async move {
+                if query.dry_run.unwrap_or(false) {
+                    store::store_removal(auth, store_id, &mut *c)
+                        .await
+                        .map(|removal| warp::reply::json(&removal).into_response())
+                } else {
+                    store::delete_store(auth, store_id, &mut *c)
+                        .await
+                        .map(|()| warp::reply().into_response())
+                }
                 .map_err(warp::reject::custom)
-        });
+            },
+        );
 
-    // GET /store/<id>
-    let list_store = path!("store" / String)
+    // PUT /sort_weight
+    let change_sort_weight = warp::path("sort_weight")
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(get_connection())
-        .and_then(move |store_id, auth, mut c: PooledConnection| async move {
-            store::list_store(auth, store_id, &mut *c)
+        .and(session::auth())
+        .and(json_body())
+        .and(get_pool())
+        .and_then(
+            move |auth: String, data: EditWeight, pool: Pool| async move {
+                with_retry(Retry::Idempotent, || async {
+                    misc::change_sort_weight(auth.clone(), &data, &mut *connection(&pool)?).await
+                })
                 .await
-                .map(|store| warp::reply::json(&store))
+                .map(|()| warp::reply())
                 .map_err(warp::reject::custom)
-        });
+            },
+        );
 
-    // DELETE /product/<id>
-    let delete_product = path!("product" / String)
+    // POST /assistant/add
+    // voice assistant intent, the product said like `2 litres of milk`
+    let assistant_add = path!("assistant" / "add")
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(assistant::bearer_auth())
+        .and(json_body())
         .and(get_connection())
         .and_then(
-            move |product_id, auth, mut c: PooledConnection| async move {
-                product::delete_product(auth, product_id, &mut *c)
+            move |auth, data: AssistantAdd, mut c: PooledConnection| async move {
+                assistant::add(auth, &data, &mut *c)
                     .await
-                    .map(|()| warp::reply())
+                    .map(|reply| warp::reply::json(&reply))
                     .map_err(warp::reject::custom)
             },
         );
 
-    // DELETE /aisle/<id>
-    let delete_aisle = path!("aisle" / String)
+    // POST /assistant/list
+    // voice assistant intent, what is left to buy
+    let assistant_list = path!("assistant" / "list")
+        .and(warp::path::end())
+        .and(assistant::bearer_auth())
+        .and(json_body())
+        .and(get_connection())
+        .and_then(
+            move |auth, data: AssistantList, mut c: PooledConnection| async move {
+                assistant::list(auth, &data, &mut *c)
+                    .await
+                    .map(|reply| warp::reply::json(&reply))
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /assistant/link?response_type=token&redirect_uri=<uri>&state=<state>
+    // account linking of a voice assistant, for a user logged in on the web app
+    let assistant_link = {
+        let config = config.clone();
+        path!("assistant" / "link")
+            .and(warp::path::end())
+            .and(session::auth())
+            .and(warp::query::<LinkQuery>())
+            .and(get_connection())
+            .and_then(move |auth, query: LinkQuery, mut c: PooledConnection| {
+                let redirect_uris = config.assistant_redirect_uri();
+                async move {
+                    assistant::link(auth, &query, &redirect_uris, &mut *c)
+                        .await
+                        .map(|location| {
+                            warp::reply::with_header(
+                                warp::reply::with_status(warp::reply(), StatusCode::FOUND),
+                                LOCATION,
+                                location,
+                            )
+                        })
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // POST /admin/invites
+    // mint an invite code for when registration is by invitation, admins only
+    let create_invite = path!("admin" / "invites")
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(admin_only.clone())
+        .and(json_body())
         .and(get_connection())
-        .and_then(move |aisle_id, auth, mut c: PooledConnection| async move {
-            aisle::delete_aisle(auth, aisle_id, &mut *c)
+        .and_then(
+            move |data: InviteData, mut c: PooledConnection| async move {
+                user::create_invite(&data, &mut *c)
+                    .await
+                    .map(|invite| warp::reply::json(&invite))
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // POST /admin/email_index
+    // index the emails of users who signed up before the email index, given
+    // as `[{"username", "email"}]`, admins only
+    let index_emails = path!("admin" / "email_index")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(json_body())
+        .and(get_connection())
+        .and_then(
+            move |known: Vec<KnownEmail>, mut c: PooledConnection| async move {
+                user::index_emails(&known, &mut *c)
+                    .await
+                    .map(|indexed| warp::reply::json(&indexed))
+                    .map_err(warp::reject::custom)
+            },
+        );
+
+    // GET /admin/journals
+    // sizes of the stores' change journals, admins only
+    let admin_journals = path!("admin" / "journals")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |mut c: PooledConnection| async move {
+            store::journal_stats(&mut *c)
                 .await
-                .map(|()| warp::reply())
+                .map(|stats| warp::reply::json(&stats))
                 .map_err(warp::reject::custom)
         });
 
-    // DELETE /store/<id>
-    let delete_store = path!("store" / String)
+    // POST /admin/store_counts
+    // count the products of stores from before their counts were kept,
+    // admins only
+    let count_store_items = path!("admin" / "store_counts")
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
+        .and(admin_only.clone())
         .and(get_connection())
-        .and_then(move |store_id, auth, mut c: PooledConnection| async move {
-            store::delete_store(auth, store_id, &mut *c)
+        .and_then(move |mut c: PooledConnection| async move {
+            store::count_store_items(&mut *c)
                 .await
-                .map(|()| warp::reply())
+                .map(|counted| warp::reply::json(&counted))
                 .map_err(warp::reject::custom)
         });
 
-    // PUT /sort_weight
-    let change_sort_weight = warp::path("sort_weight")
+    // DELETE /admin/invites/<code>
+    // revoke an invite, admins only
+    let revoke_invite = path!("admin" / "invites" / String)
         .and(warp::path::end())
-        .and(warp::header::<String>(HEADER_AUTH))
-        .and(warp::body::json())
+        .and(admin_only.clone())
         .and(get_connection())
-        .and_then(
-            move |auth, data: EditWeight, mut c: PooledConnection| async move {
-                misc::change_sort_weight(auth, &data, &mut *c)
-                    .await
-                    .map(|()| warp::reply())
-                    .map_err(warp::reject::custom)
-            },
-        );
+        .and_then(move |code: String, mut c: PooledConnection| async move {
+            user::revoke_invite(&code, &mut *c)
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+        });
 
     let post_routes = warp::post().and(
         create_product
+            .or(create_product_in_store)
+            .or(mark_anywhere)
             .or(create_aisle)
+            .or(create_export_link)
+            .or(create_share_link)
+            .or(create_template_code)
+            .or(create_snapshot)
+            .or(restore_snapshot)
+            .or(merge_stores)
+            .or(merge_crdt_store)
+            .or(add_staples)
+            .or(add_staple)
+            .or(save_template)
+            .or(apply_template)
+            .or(redeem_template)
+            .or(publish_template)
+            .or(report_template)
+            .or(save_community_template)
+            .or(restore_community_template)
             .or(create_store)
             .or(login)
             .or(create_user)
+            .or(pair)
+            .or(claim_pairing)
             .or(logout)
+            .or(assistant_add)
+            .or(assistant_list)
+            .or(create_invite)
+            .or(index_emails)
+            .or(count_store_items)
             .or(nuke),
     );
 
+    // GET /admin/maintenance
+    // whether the instance is in maintenance, admins only
+    let get_maintenance = {
+        let config = config.clone();
+        path!("admin" / "maintenance")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .map(move || warp::reply::json(&maintenance::get(&config)))
+    };
+
+    // PUT /admin/maintenance
+    // start or end maintenance, the other routes answer 503 meanwhile
+    let set_maintenance = {
+        let config = config.clone();
+        path!("admin" / "maintenance")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(json_body())
+            .map(move |data: Maintenance| warp::reply::json(&maintenance::set(&config, data)))
+    };
+
+    // GET /admin/schema
+    // the database's schema version against the range this binary supports,
+    // admins only
+    let admin_schema = {
+        let config = config.clone();
+        path!("admin" / "schema")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(get_connection())
+            .and_then(move |mut c: PooledConnection| {
+                let config = config.clone();
+                async move {
+                    maintenance::schema_report(&config, &mut *c)
+                        .await
+                        .map(|report| warp::reply::json(&report))
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // GET /admin/telemetry
+    // yesterday's anonymous counts, when telemetry is opted in, admins
+    // only
+    let admin_telemetry = {
+        let config = config.clone();
+        path!("admin" / "telemetry")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(get_connection())
+            .and_then(move |mut c: PooledConnection| {
+                let config = config.clone();
+                async move {
+                    telemetry::report(&config, &mut *c)
+                        .await
+                        .map(|report| warp::reply::json(&report))
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // GET /admin/read_only
+    // whether writes are turned away, and why, admins only
+    let get_read_only = {
+        let config = config.clone();
+        path!("admin" / "read_only")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .map(move || warp::reply::json(&maintenance::get_read_only(&config)))
+    };
+
+    // PUT /admin/read_only
+    // turn writes away or take them again, reads keep being served
+    let set_read_only = {
+        let config = config.clone();
+        path!("admin" / "read_only")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(json_body())
+            .map(move |data: ReadOnlySwitch| {
+                warp::reply::json(&maintenance::set_read_only(&config, data))
+            })
+    };
+
+    // GET /health
+    // for load balancers and monitoring, answers during maintenance
+    let health = {
+        let config = config.clone();
+        warp::path("health")
+            .and(warp::path::end())
+            .map(move || warp::reply::json(&maintenance::health(&config)))
+    };
+
     let put_routes = warp::put().and(
         change_sort_weight
             .or(edit_product)
+            .or(set_products_done)
             .or(edit_aisle)
-            .or(edit_store),
+            .or(edit_store)
+            .or(set_ui_state)
+            .or(set_default_aisles)
+            .or(set_aliases)
+            .or(set_staples)
+            .or(set_maintenance)
+            .or(set_read_only),
     );
 
-    let get_routes = warp::get().and(get_all_stores.or(list_store));
+    // GET /admin/config
+    // the running configuration and what the last reload changed, admins only
+    let admin_config = {
+        let config = config.clone();
+        path!("admin" / "config")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .map(move || warp::reply::json(&config.report()))
+    };
+
+    // GET /admin/bans
+    // clients currently banned for abuse, admins only
+    let admin_bans = path!("admin" / "bans")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |mut c: PooledConnection| async move {
+            abuse::list_bans(&mut *c)
+                .await
+                .map(|bans| warp::reply::json(&bans))
+                .map_err(warp::reject::custom)
+        });
+
+    // DELETE /admin/bans/<subject>
+    // lift a ban, the subject is `ip:<address>` or `user:<id>`
+    let revoke_ban = path!("admin" / "bans" / String)
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |subject: String, mut c: PooledConnection| async move {
+            abuse::revoke_ban(&subject, &mut *c)
+                .await
+                .map(|()| warp::reply())
+                .map_err(warp::reject::custom)
+        });
+
+    // GET /admin/schedule
+    // scheduled tasks with their next and last runs, admins only
+    let admin_schedule = {
+        let config = config.clone();
+        path!("admin" / "schedule")
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(get_connection())
+            .and_then(move |mut c: PooledConnection| {
+                let schedule = config.schedule();
+                async move {
+                    jobs::schedule_report(schedule, &mut *c)
+                        .await
+                        .map(|report| warp::reply::json(&report))
+                        .map_err(warp::reject::custom)
+                }
+            })
+    };
+
+    // GET /admin/jobs/<queue>
+    // state and counters of a background job queue, admins only
+    let admin_jobs = path!("admin" / "jobs" / String)
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |queue: String, mut c: PooledConnection| async move {
+            jobs::queue_stats(&queue, &mut *c)
+                .await
+                .map(|stats| warp::reply::json(&stats))
+                .map_err(warp::reject::custom)
+        });
+
+    // GET /admin/jobs/<queue>/dead
+    // jobs that ran out of attempts, admins only
+    let admin_dead_jobs = path!("admin" / "jobs" / String / "dead")
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(get_connection())
+        .and_then(move |queue: String, mut c: PooledConnection| async move {
+            jobs::dead_jobs(&queue, &mut *c)
+                .await
+                .map(|dead| warp::reply::json(&dead))
+                .map_err(warp::reject::custom)
+        });
+
+    // GET /challenge
+    // what to solve before POST /user, if anything
+    let get_challenge = {
+        let config = config.clone();
+        warp::path("challenge").and(warp::path::end()).map(move || {
+            let info = config
+                .challenge()
+                .map_or(ChallengeInfo::None, |challenge| challenge.issue());
+            warp::reply::json(&info)
+        })
+    };
+
+    // GET /schema
+    // JSON Schema of the request and response bodies
+    let api_schema = warp::path("schema")
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&schema::api_schema()));
+
+    // boxed, the type of all the routes grows too deep for the compiler
+    // otherwise
+    let get_routes = warp::get()
+        .and(
+            get_all_stores
+                .or(list_store)
+                .or(print_store)
+                .or(text_store)
+                .or(store_stats)
+                .or(store_insights)
+                .or(get_snapshots)
+                .or(snapshot_diff)
+                .or(get_ui_state)
+                .or(get_crdt_store)
+                .or(export_store)
+                .or(shared_store)
+                .or(assistant_link)
+                .or(api_schema)
+                .or(get_challenge)
+                .or(check_username)
+                .or(get_default_aisles)
+                .or(get_aliases)
+                .or(get_staples)
+                .or(get_quotas)
+                .or(get_templates)
+                .or(search_community)
+                .or(get_anywhere)
+                .or(product_nutrition)
+                .or(product_history)
+                .or(store_nutrition)
+                .or(lookup_nutrition)
+                .or(health)
+                .or(admin_config)
+                .or(get_maintenance)
+                .or(admin_schema)
+                .or(get_read_only)
+                .or(admin_telemetry)
+                .or(admin_bans)
+                .or(admin_jobs)
+                .or(admin_dead_jobs)
+                .or(admin_schedule)
+                .or(admin_journals)
+                .or(admin_community),
+        )
+        .map(Reply::into_response)
+        .boxed();
 
     let del_routes = warp::delete().and(
         delete_product
+            .or(delete_products)
             .or(delete_aisle)
             .or(delete_store)
-            .or(delete_user),
+            .or(delete_staple)
+            .or(delete_template)
+            .or(remove_community_template)
+            .or(check_anywhere)
+            .or(delete_user)
+            .or(revoke_ban)
+            .or(revoke_invite),
     );
 
     let get_index = warp::get()
         .and(warp::fs::dir("./static/"));
 
-    let routes = warp::path("api")
-        .and(get_routes.or(post_routes).or(put_routes).or(del_routes))
-        .or(get_index)
-        .recover(customize_error);
-    info!("Efficio's ready for requests...");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    Ok(())
+    // GET /metrics
+    // domain events in Prometheus' format, apart from the HTTP traffic,
+    // admins only
+    let get_metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(admin_only)
+        .and(get_connection())
+        .and_then(move |mut c: PooledConnection| async move {
+            metrics::metrics(&mut *c)
+                .await
+                .map_err(warp::reject::custom)
+        });
+
+    // banned clients are turned away, the others have their requests counted
+    // against their address and user
+    let abuse_guard = {
+        let (config, pool, breaker) = (config.clone(), abuse_pool.clone(), breaker.clone());
+        warp::header::optional::<String>(AUTHORIZATION.as_str())
+            .and(client_ip)
+            .and(session::optional_auth())
+            .and_then(
+                move |authorization: Option<String>, ip: Option<IpAddr>, auth: Option<String>| {
+                    let limits = config.abuse_limits();
+                    // the admin stays able to lift bans
+                    let is_admin = config.is_admin(authorization.as_deref());
+                    let (pool, breaker) = (pool.clone(), breaker.clone());
+                    async move {
+                        if !limits.is_counted() || breaker.is_open() || is_admin {
+                            return Ok(vec![]);
+                        }
+                        let mut c = match connection(&pool) {
+                            Ok(c) => c,
+                            Err(_) => return Ok(vec![]),
+                        };
+                        let subjects = abuse::subjects(&mut *c, ip, auth.as_deref());
+                        if !limits.is_enabled() {
+                            return Ok(subjects);
+                        }
+                        match abuse::check_bans(&mut *c, &subjects).await {
+                            Ok(Some(ban)) => Err(warp::reject::custom(ban)),
+                            Ok(None) => Ok(subjects),
+                            Err(e) => {
+                                warn!("Could not check bans: {}", e);
+                                Ok(subjects)
+                            }
+                        }
+                    }
+                },
+            )
+    };
+
+    // during maintenance only the exempt routes answer, and only reads when
+    // read-only
+    let maintenance_guard = {
+        let config = config.clone();
+        warp::path::full()
+            .and(warp::method())
+            .and_then(move |path: FullPath, method: Method| {
+                let maintenance = config.maintenance();
+                let read_only = config.is_read_only();
+                async move {
+                    if maintenance::is_exempt(path.as_str()) {
+                        return Ok(());
+                    }
+                    match maintenance {
+                        Some(m) => Err(warp::reject::custom(maintenance::UnderMaintenance(m))),
+                        None if read_only && !maintenance::is_read(&method) => {
+                            Err(warp::reject::custom(maintenance::ReadOnlyMode))
+                        }
+                        None => Ok(()),
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    let recover = {
+        let breaker = breaker.clone();
+        move |err: Rejection| {
+            let breaker = breaker.clone();
+            async move {
+                let db_failed = err
+                    .find::<error::ServerError>()
+                    .map_or(false, error::ServerError::is_db_unavailable);
+                // requests turned away while open don't count
+                if db_failed && !breaker.is_open() {
+                    breaker.record_failure();
+                }
+                customize_error(err).await
+            }
+        }
+    };
+
+    // a known path asked with a method it doesn't take
+    let wrong_method = warp::path::tail().and(warp::method()).and_then(
+        move |path: Tail, method: Method| async move {
+            let allowed = route_table::allowed_methods(path.as_str());
+            if allowed.is_empty() || allowed.contains(&method.as_str()) {
+                Err::<warp::reply::Response, _>(warp::reject::not_found())
+            } else {
+                Err(warp::reject::custom(MethodNotAllowed(allowed)))
+            }
+        },
+    );
+
+    let api = warp::path("api")
+        .and(
+            get_routes
+                .or(post_routes)
+                .or(put_routes)
+                .or(del_routes)
+                .or(wrong_method),
+        )
+        .or(get_metrics)
+        .or(get_index);
+
+    check_ip
+        .and(maintenance_guard)
+        .and(abuse_guard)
+        .and(session::auth_source())
+        .and(warp::method())
+        .and(api.recover(recover.clone()))
+        .and_then({
+            let breaker = breaker.clone();
+            move |subjects: Vec<String>, auth: Option<(String, bool)>, method: Method, reply| {
+                let limits = config.abuse_limits();
+                let rotation = config.session_rotation();
+                let read_only = config.is_read_only();
+                let (pool, breaker) = (abuse_pool.clone(), breaker.clone());
+                async move {
+                    let mut res = Reply::into_response(reply);
+                    // changes that went through tell how close the account is
+                    // to its quotas
+                    if let (Some((token, _)), true) = (
+                        &auth,
+                        (method == Method::POST || method == Method::PUT)
+                            && res.status().is_success()
+                            && !breaker.is_open(),
+                    ) {
+                        let warnings = match connection(&pool) {
+                            Ok(mut c) => user::quota_warnings(token, &mut *c).await,
+                            Err(e) => Err(e),
+                        };
+                        match warnings {
+                            Ok(warnings) if !warnings.is_empty() => {
+                                user::add_quota_warning_header(&mut res, &warnings)
+                            }
+                            Ok(_) => (),
+                            Err(e) => warn!("Could not check the quotas: {}", e),
+                        }
+                    }
+                    // old sessions get a new token along with a successful reply
+                    if let (Some((token, from_cookie)), true) = (
+                        auth,
+                        rotation.is_enabled()
+                            && res.status().is_success()
+                            && !breaker.is_open()
+                            && !read_only,
+                    ) {
+                        let rotated = match connection(&pool) {
+                            Ok(mut c) => session::rotate_if_due(&token, rotation, &mut *c).await,
+                            Err(e) => Err(e),
+                        };
+                        match rotated {
+                            Ok(Some(new)) => res = session::new_token_reply(res, &new, from_cookie),
+                            Ok(None) => (),
+                            Err(e) => warn!("Could not rotate the session: {}", e),
+                        }
+                    }
+                    if !subjects.is_empty() && !breaker.is_open() {
+                        let recorded = match connection(&pool) {
+                            Ok(mut c) => {
+                                abuse::record(&mut *c, &subjects, res.status(), &limits).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        match recorded {
+                            Ok(Some(rate_limit)) => {
+                                abuse::add_rate_limit_headers(&mut res, &rate_limit)
+                            }
+                            Ok(None) => (),
+                            Err(e) => {
+                                warn!("Could not record the request for abuse detection: {}", e)
+                            }
+                        }
+                    }
+                    Ok::<_, Rejection>(res)
+                }
+            }
+        })
+        .recover(recover)
+        .with(warp::log::custom(move |info| {
+            if !info.status().is_server_error() {
+                breaker.record_success();
+            }
+        }))
+}
+
+fn read_secret(path: &Path) -> error::Result<String> {
+    std::fs::read_to_string(path)
+        .map(|secret| secret.trim().to_owned())
+        .map_err(|e| {
+            error::ServerError::new(
+                error::INTERNAL_ERROR,
+                &format!("Cannot read {}: {}", path.display(), e),
+            )
+        })
+}
+
+fn connection(pool: &Pool) -> error::Result<PooledConnection> {
+    pool.get().map_err(error::ServerError::from)
+}
+
+// A path segment holding a store, aisle or product id: malformed ones are
+// turned away before looking anything up
+fn id<T: FromStr>() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    warp::path::param::<String>().and_then(|raw: String| async move {
+        match raw.parse::<T>() {
+            Ok(_) => Ok(raw),
+            Err(_) => Err(warp::reject::custom(error::ServerError::new(
+                error::MALFORMED_ID,
+                "Malformed id",
+            ))),
+        }
+    })
+}
+
+// The JSON body, see `json_body`
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Copy
+{
+    warp::header::optional::<String>(CONTENT_TYPE.as_str())
+        .and(warp::body::bytes())
+        .and_then(|content_type: Option<String>, body: Bytes| async move {
+            json_body::check_content_type(content_type.as_deref()).map_err(warp::reject::custom)?;
+            json_body::parse(&body)
+                .map_err(|e| warp::reject::custom(json_body::InvalidBodyRejection(e)))
+        })
+}
+
+fn reply_json<T: Serialize>(data: T) -> warp::reply::Response {
+    warp::reply::json(&data).into_response()
+}
+
+fn reply_empty(_: ()) -> warp::reply::Response {
+    warp::reply().into_response()
+}
+
+// JSON unless the client explicitly accepts MessagePack. Either way the reply
+// varies with Accept, so caches don't serve one format to the other clients.
+fn negotiated_reply<T: Serialize>(accept: Option<String>, data: &T) -> warp::reply::Response {
+    let mut res = if accept.map_or(false, |a| a.contains(MSGPACK_MIME)) {
+        match rmp_serde::to_vec_named(data) {
+            Ok(bytes) => {
+                let mut res = warp::reply::Response::new(bytes.into());
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static(MSGPACK_MIME));
+                res
+            }
+            Err(e) => warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response(),
+        }
+    } else {
+        warp::reply::json(data).into_response()
+    };
+    res.headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    res
 }
 
-async fn customize_error(err: Rejection) -> Result<impl Reply, Infallible> {
+async fn customize_error(err: Rejection) -> Result<warp::reply::Response, Infallible> {
+    if let Some(ban) = err.find::<Ban>() {
+        return Ok(abuse::ban_reply(ban));
+    }
+    if let Some(maintenance::UnderMaintenance(m)) = err.find() {
+        return Ok(maintenance::reply(m));
+    }
+    if err.find::<maintenance::ReadOnlyMode>().is_some() {
+        return Ok(maintenance::read_only_reply());
+    }
+    if let Some(refused) = err.find::<user::RegistrationRefused>() {
+        return Ok(user::refused_reply(refused));
+    }
+    if let Some(MethodNotAllowed(allowed)) = err.find() {
+        let mut res =
+            warp::reply::with_status("Method not allowed", StatusCode::METHOD_NOT_ALLOWED)
+                .into_response();
+        if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+            res.headers_mut().insert(ALLOW, allow);
+        }
+        return Ok(res);
+    }
+    if let Some(json_body::InvalidBodyRejection(invalid)) = err.find() {
+        let reply = warp::reply::json(invalid);
+        return Ok(warp::reply::with_status(reply, error::INVALID_BODY).into_response());
+    }
+    if let Some(error::ServerError {
+        status,
+        quota: Some(quota),
+        ..
+    }) = err.find()
+    {
+        let reply = warp::reply::json(quota);
+        return Ok(warp::reply::with_status(reply, *status).into_response());
+    }
     let (code, message) = match err.find::<error::ServerError>() {
         Some(server_error) => (server_error.status, server_error.msg.to_owned()),
         _ => (
@@ -315,5 +2253,5 @@ async fn customize_error(err: Rejection) -> Result<impl Reply, Infallible> {
             "UNHANDLED REJECTION".to_string(),
         ),
     };
-    Ok(warp::reply::with_status(message, code))
+    Ok(warp::reply::with_status(message, code).into_response())
 }
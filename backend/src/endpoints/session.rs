@@ -1,17 +1,45 @@
+use hex_view::HexView;
+use rand::Rng;
+use warp::{
+    http::{
+        header::{HeaderValue, SET_COOKIE},
+        Method,
+    },
+    Filter, Rejection, Reply,
+};
+
+use std::net::IpAddr;
+
 use crate::{
-    db::{sessions, users},
-    error::Result,
+    config::SessionRotation,
+    db::{abuse, events::Event, pairing, sessions, users},
+    endpoints::{metrics, HEADER_AUTH, HEADER_NEW_AUTH},
+    error::{self, Result, ServerError},
+    links::{constant_time_eq, now_s},
+    tokens,
     types::*,
 };
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
 
+// Browsers can ask for the session to be kept in an HttpOnly cookie instead of
+// handing the token to scripts. Requests authenticated by that cookie must echo
+// the CSRF cookie in HEADER_CSRF (double-submit), except for reads.
+pub const HEADER_SESSION_MODE: &str = "x-session-mode";
+pub const SESSION_MODE_COOKIE: &str = "cookie";
+pub const HEADER_CSRF: &str = "x-csrf-token";
+pub const COOKIE_SESSION: &str = "efficio_session";
+pub const COOKIE_CSRF: &str = "efficio_csrf";
+const PAIRING_CLAIMS_PER_MINUTE: u64 = 10;
+
 pub async fn login(auth_info: &AuthInfo, c: &mut Connection) -> Result<ConnectionToken> {
-    users::login(c, &auth_info)
+    let token = users::login(c, &auth_info)?;
+    metrics::record(c, Event::Login);
+    Ok(token)
 }
 
 pub async fn logout(auth: &str, user_id: &str, c: &mut Connection) -> Result<()> {
@@ -20,3 +48,184 @@ pub async fn logout(auth: &str, user_id: &str, c: &mut Connection) -> Result<()>
     sessions::delete_session(c, &auth, &UserId(user_id.to_owned()))?;
     Ok(())
 }
+
+// A device the user is logged in on lets another one in, without typing the
+// password there
+pub async fn pair(auth: &str, c: &mut Connection) -> Result<PairingCode> {
+    let auth = Auth(&auth);
+    sessions::validate_session(c, &auth)?;
+    pairing::mint_code(c, &auth)
+}
+
+// Rate limited by address, codes are short enough to be typed
+pub async fn claim_pairing(
+    claim: &PairingClaim,
+    ip: Option<IpAddr>,
+    c: &mut Connection,
+) -> Result<ConnectionToken> {
+    let subject = ip.map_or_else(|| "unknown".to_owned(), |ip| format!("ip:{}", ip));
+    if let Some(retry_after_s) =
+        abuse::rate_limit(c, "pairing_claim", &subject, PAIRING_CLAIMS_PER_MINUTE, 60)?
+    {
+        return Err(ServerError::new(
+            error::RATE_LIMITED,
+            &format!("Too many attempts, retry in {} seconds", retry_after_s),
+        ));
+    }
+    pairing::claim_code(c, &claim.code)
+}
+
+// A new token for sessions older than the rotation age, the old one keeps
+// working for the grace period so requests already sent with it go through
+pub async fn rotate_if_due(
+    auth: &str,
+    rotation: SessionRotation,
+    c: &mut Connection,
+) -> Result<Option<ConnectionToken>> {
+    let auth = Auth(&auth);
+    if !rotation.is_enabled() || sessions::session_age(c, &auth, now_s())? < rotation.after_s {
+        return Ok(None);
+    }
+    sessions::rotate_session(c, &auth, rotation.grace_s).map(Some)
+}
+
+// Session token of the request, from the auth header or the session cookie
+pub fn auth() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>(HEADER_AUTH))
+        .and(warp::cookie::optional(COOKIE_SESSION))
+        .and(warp::cookie::optional(COOKIE_CSRF))
+        .and(warp::header::optional::<String>(HEADER_CSRF))
+        .and_then(
+            |method, header, cookie, csrf_cookie, csrf_header| async move {
+                session_token(method, header, cookie, csrf_cookie, csrf_header)
+                    .map_err(warp::reject::custom)
+            },
+        )
+}
+
+// Session token of the request if there is one and it is well formed, without
+// the CSRF check: it only tells who is calling, not what they may do
+pub fn optional_auth() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    auth_source().map(|source: Option<(String, bool)>| source.map(|(token, _)| token))
+}
+
+// Same, along with whether the token came from the session cookie
+pub fn auth_source() -> impl Filter<Extract = (Option<(String, bool)>,), Error = Rejection> + Clone
+{
+    warp::header::optional::<String>(HEADER_AUTH)
+        .and(warp::cookie::optional(COOKIE_SESSION))
+        .map(|header: Option<String>, cookie: Option<String>| {
+            header
+                .map(|token| (token, false))
+                .or_else(|| cookie.map(|token| (token, true)))
+                .filter(|(token, _)| tokens::is_well_formed(token))
+        })
+}
+
+// Whether the client asked for a cookie session on login or sign up
+pub fn cookie_mode() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(HEADER_SESSION_MODE)
+        .map(|mode: Option<String>| mode.map_or(false, |m| m == SESSION_MODE_COOKIE))
+}
+
+fn session_token(
+    method: Method,
+    header: Option<String>,
+    cookie: Option<String>,
+    csrf_cookie: Option<String>,
+    csrf_header: Option<String>,
+) -> Result<String> {
+    // garbage is turned away before looking anything up
+    if let Some(token) = header.as_ref().or_else(|| cookie.as_ref()) {
+        if !tokens::is_well_formed(token) {
+            return Err(ServerError::new(
+                error::UNAUTHORISED,
+                "Invalid session token",
+            ));
+        }
+    }
+    match (header, cookie) {
+        (Some(token), _) => Ok(token),
+        (None, Some(token)) => {
+            let safe = method == Method::GET || method == Method::HEAD;
+            match (csrf_cookie, csrf_header) {
+                _ if safe => Ok(token),
+                (Some(expected), Some(given))
+                    if !expected.is_empty() && constant_time_eq(&expected, &given) =>
+                {
+                    Ok(token)
+                }
+                _ => Err(ServerError::new(
+                    error::PERMISSION_DENIED,
+                    "Missing or invalid CSRF token",
+                )),
+            }
+        }
+        (None, None) => Err(ServerError::new(error::UNAUTHORISED, "Not logged in")),
+    }
+}
+
+// Reply to a login or sign up: the token itself, or cookies in cookie mode
+pub fn token_reply(token: ConnectionToken, cookie_mode: bool) -> warp::reply::Response {
+    if !cookie_mode {
+        return warp::reply::json(&token).into_response();
+    }
+    let mut csrf = [0u8; 32];
+    rand::thread_rng().fill(&mut csrf[..]);
+    let session = CookieSession::new(token.user_id.clone(), format!("{:x}", HexView::from(&csrf)));
+    let mut res = warp::reply::json(&session).into_response();
+    append_cookies(
+        &mut res,
+        &[
+            session_cookie(&token),
+            format!(
+                "{}={}; Path=/; Secure; SameSite=Strict",
+                COOKIE_CSRF, session.csrf_token
+            ),
+        ],
+    );
+    res
+}
+
+// Hands a rotated token to the client the way it sent the old one, the CSRF
+// cookie stays as it is
+pub fn new_token_reply(
+    mut res: warp::reply::Response,
+    token: &ConnectionToken,
+    from_cookie: bool,
+) -> warp::reply::Response {
+    if from_cookie {
+        append_cookies(&mut res, &[session_cookie(token)]);
+    } else if let Ok(value) = HeaderValue::from_str(&token.session_token) {
+        res.headers_mut().insert(HEADER_NEW_AUTH, value);
+    }
+    res
+}
+
+fn session_cookie(token: &ConnectionToken) -> String {
+    format!(
+        "{}={}; Path=/api; HttpOnly; Secure; SameSite=Strict",
+        COOKIE_SESSION, &*token.session_token
+    )
+}
+
+// Logging out also drops the session cookies, if any
+pub fn clear_cookies(mut res: warp::reply::Response) -> warp::reply::Response {
+    append_cookies(
+        &mut res,
+        &[
+            format!("{}=; Path=/api; Max-Age=0", COOKIE_SESSION),
+            format!("{}=; Path=/; Max-Age=0", COOKIE_CSRF),
+        ],
+    );
+    res
+}
+
+fn append_cookies(res: &mut warp::reply::Response, cookies: &[String]) {
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(cookie) {
+            res.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+}
@@ -1,26 +1,80 @@
-use crate::{db, error::Result, types::*};
+use std::collections::HashMap;
+
+use log::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    archive::Archive,
+    db::{self, events::Event},
+    endpoints::{metrics, INVALID_PARAMS},
+    error::{self, Result, ServerError},
+    links, print,
+    types::*,
+};
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
 
-pub async fn create_store(auth: String, data: &NameData, c: &mut Connection) -> Result<StoreId> {
+// the ids of aisles since deleted stay until the client sends them no more
+const MAX_COLLAPSED_AISLES: usize = 500;
+const MAX_ID_LEN: usize = 64;
+const MAX_ORIGIN_LEN: usize = 200;
+// journal entries per archived object
+const ARCHIVE_BATCH: u64 = 10_000;
+
+// `server_aisles` are the default aisles of users with none of their own
+pub async fn create_store(
+    auth: String,
+    data: &NewStore,
+    server_aisles: &[String],
+    c: &mut Connection,
+) -> Result<StoreId> {
     let auth = Auth(&auth);
     db::sessions::validate_session(c, &auth)?;
-    db::stores::save_store(c, &auth, &data.name)
+    let store_id = db::stores::save_store(c, &auth, &data.name)?;
+    metrics::record(c, Event::StoreCreated);
+    if data.no_default_aisles {
+        return Ok(store_id);
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let aisles = db::users::get_default_aisles(c, &user_id)?;
+    // the store is there, a quota cutting the aisles short is no reason to
+    // fail its creation
+    for name in aisles.as_deref().unwrap_or(server_aisles) {
+        if let Err(e) = db::aisles::save_aisle(c, &auth, &store_id, name) {
+            warn!("Default aisles of store {} cut short: {}", *store_id, e.msg);
+            break;
+        }
+    }
+    Ok(store_id)
 }
 
 pub async fn edit_store(
     auth: String,
     id: String,
-    data: &NameData,
+    data: &EditStore,
     c: &mut Connection,
 ) -> Result<()> {
     let auth = Auth(&auth);
     db::sessions::validate_session(c, &auth)?;
-    db::stores::edit_store(c, &auth, &StoreId::new(id), &data.name)
+    if data.defaults.as_ref().map_or(false, |d| d.quantity == 0) {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            "The default quantity is at least 1",
+        ));
+    }
+    let store_id = StoreId::new(id);
+    if let Some(name) = &data.name {
+        db::stores::edit_store(c, &auth, &store_id, name)?;
+    }
+    if let Some(defaults) = &data.defaults {
+        db::stores::set_product_defaults(c, &auth, &store_id, defaults)?;
+    }
+    Ok(())
 }
 
 pub async fn list_stores(auth: String, c: &mut Connection) -> Result<StoreLightList> {
@@ -29,14 +83,360 @@ pub async fn list_stores(auth: String, c: &mut Connection) -> Result<StoreLightL
     Ok(StoreLightList::new(db::stores::get_all_stores(c, &auth)?))
 }
 
+// what changes with any change to the user's stores, to tell a replica that
+// is behind
+pub async fn stores_versions(auth: String, c: &mut Connection) -> Result<HashMap<String, u64>> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::get_store_versions(c, &auth)
+}
+
+pub async fn store_version(store_id: String, c: &mut Connection) -> Result<u64> {
+    db::journal::current_version(c, &StoreId::new(store_id))
+}
+
 pub async fn list_store(auth: String, store_id: String, c: &mut Connection) -> Result<Store> {
     let auth = Auth(&auth);
     db::sessions::validate_session(c, &auth)?;
     db::stores::list_store(c, &auth, &StoreId::new(store_id))
 }
 
+pub async fn print_store(
+    auth: String,
+    store_id: String,
+    query: &PrintQuery,
+    c: &mut Connection,
+) -> Result<String> {
+    let store = list_store(auth, store_id, c).await?;
+    Ok(print::store_html(
+        &store,
+        query.include_done.unwrap_or(false),
+    ))
+}
+
+pub async fn text_store(auth: String, store_id: String, c: &mut Connection) -> Result<String> {
+    let store = list_store(auth, store_id, c).await?;
+    Ok(print::store_text(&store))
+}
+
+fn is_origin(origin: &str) -> bool {
+    origin.len() <= MAX_ORIGIN_LEN
+        && (origin.starts_with("https://") || origin.starts_with("http://"))
+        && !origin.contains(|c: char| c.is_whitespace() || c == '?' || c == '#')
+}
+
+pub async fn share_link(
+    auth: String,
+    store_id: String,
+    data: &ShareParams,
+    c: &mut Connection,
+) -> Result<ShareLink> {
+    if !is_origin(&data.origin) {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            "The origin is an http or https URL without query",
+        ));
+    }
+    let store = list_store(auth, store_id, c).await?;
+    Ok(links::share_link(
+        &store.store_id,
+        &print::store_text(&store),
+        &data.origin,
+        links::now_s(),
+    ))
+}
+
+// The signature stands for the session token
+pub async fn shared_store(
+    store_id: String,
+    query: &ExportQuery,
+    c: &mut Connection,
+) -> Result<String> {
+    links::verify_share(&store_id, query.expires, &query.sig, links::now_s())?;
+    let store_id = StoreId::new(store_id);
+    let owner_id = db::stores::get_store_owner(c, &store_id)?;
+    let mut store = db::stores::get_store(c, &store_id)?;
+    store.anywhere = db::anywhere::get_anywhere(c, &owner_id)?;
+    Ok(print::store_text(&store))
+}
+
+pub async fn list_store_since(
+    auth: String,
+    store_id: String,
+    since: u64,
+    c: &mut Connection,
+) -> Result<VersionedStore> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::list_store_since(c, &auth, &StoreId::new(store_id), since)
+}
+
+pub async fn store_events(
+    auth: String,
+    store_id: String,
+    since: u64,
+    c: &mut Connection,
+) -> Result<(u64, Option<Vec<db::journal::Entry>>)> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::store_events(c, &auth, &StoreId::new(store_id), since)
+}
+
+pub async fn store_stats(auth: String, store_id: String, c: &mut Connection) -> Result<StoreStats> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::get_store_stats(c, &auth, &StoreId::new(store_id))
+}
+
+pub async fn store_insights(
+    auth: String,
+    store_id: String,
+    c: &mut Connection,
+) -> Result<StoreInsights> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::get_store_insights(c, &auth, &StoreId::new(store_id))
+}
+
+pub async fn ui_state(auth: String, store_id: String, c: &mut Connection) -> Result<StoreUiState> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::get_ui_state(c, &auth, &StoreId::new(store_id))
+}
+
+pub async fn set_ui_state(
+    auth: String,
+    store_id: String,
+    data: &StoreUiState,
+    c: &mut Connection,
+) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if data.collapsed_aisles.len() > MAX_COLLAPSED_AISLES
+        || data.collapsed_aisles.iter().any(|id| id.len() > MAX_ID_LEN)
+    {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!("At most {} aisle ids", MAX_COLLAPSED_AISLES),
+        ));
+    }
+    db::stores::set_ui_state(c, &auth, &StoreId::new(store_id), data)
+}
+
+pub async fn crdt_store(auth: String, store_id: String, c: &mut Connection) -> Result<CrdtStore> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::crdt::get_crdt_store(c, &auth, &StoreId::new(store_id))
+}
+
+// For friends to copy the layout of the store to their own
+// the longest name of a snapshot, in bytes
+const MAX_SNAPSHOT_NAME_LEN: usize = 100;
+
+pub async fn create_snapshot(
+    auth: String,
+    store_id: String,
+    data: &NameData,
+    c: &mut Connection,
+) -> Result<StoreSnapshot> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if data.name.trim().is_empty() || data.name.len() > MAX_SNAPSHOT_NAME_LEN {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!(
+                "A snapshot needs a name of at most {} bytes",
+                MAX_SNAPSHOT_NAME_LEN
+            ),
+        ));
+    }
+    db::snapshots::create_snapshot(c, &auth, &StoreId::new(store_id), &data.name)
+}
+
+pub async fn snapshots(
+    auth: String,
+    store_id: String,
+    c: &mut Connection,
+) -> Result<StoreSnapshots> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    Ok(StoreSnapshots {
+        snapshots: db::snapshots::get_snapshots(c, &auth, &StoreId::new(store_id))?,
+    })
+}
+
+pub async fn restore_snapshot(
+    auth: String,
+    store_id: String,
+    snapshot_id: String,
+    c: &mut Connection,
+) -> Result<Store> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::snapshots::restore_snapshot(c, &auth, &StoreId::new(store_id), &snapshot_id)
+}
+
+// `other_id` is a snapshot too, or "live" for the store as it is now
+pub async fn snapshot_diff(
+    auth: String,
+    store_id: String,
+    snapshot_id: String,
+    other_id: String,
+    c: &mut Connection,
+) -> Result<StoreDiff> {
+    if other_id != db::snapshots::LIVE && other_id.parse::<Uuid>().is_err() {
+        return Err(ServerError::new(error::MALFORMED_ID, "Malformed id"));
+    }
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::snapshots::diff_snapshots(c, &auth, &StoreId::new(store_id), &snapshot_id, &other_id)
+}
+
+pub async fn template_code(
+    auth: String,
+    store_id: String,
+    c: &mut Connection,
+) -> Result<TemplateCode> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::templates::share_store(c, &auth, &StoreId::new(store_id))
+}
+
+// Bootstrap the list with the user's staples
+pub async fn add_staples(
+    auth: String,
+    store_id: String,
+    c: &mut Connection,
+) -> Result<AddedStaples> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::staples::add_staples(c, &auth, &StoreId::new(store_id))
+}
+
+pub async fn merge_crdt_store(
+    auth: String,
+    store_id: String,
+    data: &CrdtStore,
+    c: &mut Connection,
+) -> Result<CrdtStore> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    // the ids are made by the clients
+    let ids = data
+        .products
+        .iter()
+        .flat_map(|p| vec![&p.product_id, &p.aisle_id])
+        .chain(data.removed.iter());
+    if ids.clone().any(|id| id.parse::<ProductId>().is_err()) {
+        return Err(ServerError::new(INVALID_PARAMS, "Malformed id"));
+    }
+    db::crdt::merge_crdt_store(c, &auth, &StoreId::new(store_id), data)
+}
+
+pub async fn merge_stores(
+    auth: String,
+    store_id: String,
+    other_id: String,
+    query: &MergeQuery,
+    c: &mut Connection,
+) -> Result<StoreMerge> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if store_id == other_id {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            "Can't merge a store into itself",
+        ));
+    }
+    db::stores::merge_stores(
+        c,
+        &auth,
+        &StoreId::new(store_id),
+        &StoreId::new(other_id),
+        query.dry_run.unwrap_or(false),
+    )
+}
+
 pub async fn delete_store(auth: String, store_id: String, c: &mut Connection) -> Result<()> {
     let auth = Auth(&auth);
     db::sessions::validate_session(c, &auth)?;
     db::stores::delete_store(c, &auth, &StoreId::new(store_id))
 }
+
+pub async fn store_removal(
+    auth: String,
+    store_id: String,
+    c: &mut Connection,
+) -> Result<StoreRemoval> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::stores::get_store_removal(c, &auth, &StoreId::new(store_id))
+}
+
+pub async fn export_link(auth: String, store_id: String, c: &mut Connection) -> Result<ExportLink> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let store_id = StoreId::new(store_id);
+    let owner_id = db::stores::get_store_owner(c, &store_id)?;
+    db::assert_owner_or_member_auth(c, &auth, &owner_id)?;
+    Ok(links::export_link(&store_id, links::now_s()))
+}
+
+// The signature stands for the session token
+pub async fn export_store(
+    store_id: String,
+    query: &ExportQuery,
+    c: &mut Connection,
+) -> Result<Store> {
+    links::verify_export(&store_id, query.expires, &query.sig, links::now_s())?;
+    db::stores::get_store(c, &StoreId::new(store_id))
+}
+
+#[derive(Serialize)]
+pub struct CountedStores {
+    pub counted: u64,
+}
+
+// GET /admin/journals
+pub async fn journal_stats(c: &mut Connection) -> Result<db::journal::JournalStats> {
+    db::journal::stats(c)
+}
+
+// the `trim_journals` scheduled task
+pub async fn trim_journals(c: &mut Connection) -> Result<()> {
+    let expired = db::journal::trim_journals(c, links::now_s())?;
+    if expired > 0 {
+        info!("Dropped {} expired journal entries", expired);
+    }
+    Ok(())
+}
+
+// the `archive_journals` scheduled task, sends what `trim_journals` left in
+// batches, an upload failing leaves its batch for the next run
+pub async fn archive_journals(c: &mut Connection, archive: &Archive) -> Result<()> {
+    let mut archived = 0;
+    loop {
+        let (entries, read) = db::journal::archived(c, ARCHIVE_BATCH)?;
+        if read == 0 {
+            break;
+        }
+        if !entries.is_empty() {
+            archive
+                .put_jsonl("journal", &entries, links::now_s())
+                .await?;
+        }
+        db::journal::drop_archived(c, read)?;
+        archived += entries.len();
+    }
+    if archived > 0 {
+        info!("Archived {} journal entries", archived);
+    }
+    Ok(())
+}
+
+// POST /admin/store_counts
+pub async fn count_store_items(c: &mut Connection) -> Result<CountedStores> {
+    Ok(CountedStores {
+        counted: db::stores::count_all_items(c)?,
+    })
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+
+use log::*;
+use serde::Serialize;
+use warp::http::StatusCode;
+
+use crate::{
+    config::LiveConfig,
+    cron::civil_from_days,
+    db::events,
+    error::{self, Result, ServerError},
+    links,
+};
+
+#[cfg(not(test))]
+use crate::storage::Connection;
+
+#[cfg(test)]
+use fake_redis::FakeConnection as Connection;
+
+const DAY_S: u64 = 24 * 3600;
+
+// The counts of a whole day, with nothing telling users, stores or addresses
+// apart
+#[derive(Debug, Serialize)]
+pub struct TelemetryReport {
+    // `2020-06-15`, UTC
+    pub day: String,
+    pub version: &'static str,
+    pub events: BTreeMap<&'static str, u64>,
+}
+
+// yesterday's, the last complete day
+fn build_report(c: &mut Connection) -> Result<TelemetryReport> {
+    let day = (links::now_s() / DAY_S).saturating_sub(1);
+    let (year, month, day_of_month) = civil_from_days(day);
+    Ok(TelemetryReport {
+        day: format!("{}-{:02}-{:02}", year, month, day_of_month),
+        version: env!("CARGO_PKG_VERSION"),
+        events: events::day_counts(c, day)?
+            .into_iter()
+            .map(|(event, count)| (event.name(), count))
+            .collect(),
+    })
+}
+
+// GET /admin/telemetry
+pub async fn report(config: &LiveConfig, c: &mut Connection) -> Result<TelemetryReport> {
+    if config.telemetry().is_none() {
+        return Err(ServerError::new(StatusCode::NOT_FOUND, "Telemetry is off"));
+    }
+    build_report(c)
+}
+
+// the `send_telemetry` scheduled task, a no-op unless telemetry is opted in
+// with a URL
+pub async fn send(config: &LiveConfig, c: &mut Connection) -> Result<()> {
+    let url = match config.telemetry() {
+        Some(url) if !url.is_empty() => url,
+        _ => return Ok(()),
+    };
+    let report = build_report(c)?;
+    let failed = |e: &dyn std::fmt::Display| {
+        warn!("Could not send the telemetry to {}: {}", url, e);
+        ServerError::new(error::INTERNAL_ERROR, "Could not send the telemetry")
+    };
+    reqwest::Client::new()
+        .post(&url)
+        .json(&report)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| failed(&e))?;
+    info!("Telemetry of {} sent", report.day);
+    Ok(())
+}
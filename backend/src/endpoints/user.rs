@@ -1,26 +1,409 @@
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
+
 use lazy_static::lazy_static;
+use log::*;
 use regex::Regex;
+use serde::Serialize;
+use warp::{
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    Reply,
+};
 
 #[cfg(not(test))]
-use redis::Connection;
+use crate::storage::Connection;
 
 #[cfg(test)]
 use fake_redis::FakeConnection as Connection;
 
 use crate::{
-    db,
-    endpoints::INVALID_PARAMS,
-    error::{Result, ServerError},
+    challenge::Challenge,
+    config::{Registration, RegistrationPolicy},
+    db::{
+        self,
+        events::Event,
+        invites::Invite,
+        users::{EmailIndexing, KnownEmail},
+    },
+    endpoints::{metrics, INVALID_PARAMS},
+    error::{self, Result, ServerError},
     types::*,
 };
 
 const MIN_ENTROPY_SCORE: u8 = 2;
+const MAX_USERNAME_LEN: usize = 32;
+const USERNAME_CHECKS_PER_MINUTE: u64 = 30;
+const DEFAULT_INVITE_USES: u32 = 1;
+const DEFAULT_INVITE_TTL_S: u64 = 7 * 24 * 3600;
+const MAX_DEFAULT_AISLES: usize = 50;
+const MAX_ALIASES: usize = 500;
+const MAX_STAPLES: usize = 200;
+const MAX_TEMPLATES: usize = 50;
+
+// A sign up the registration policy turned away, replied as JSON so that
+// clients can tell the cases apart
+#[derive(Debug, Serialize)]
+pub struct RegistrationRefused {
+    pub error: &'static str,
+    pub message: &'static str,
+}
+
+impl warp::reject::Reject for RegistrationRefused {}
+
+pub fn refused_reply(refused: &RegistrationRefused) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(refused), error::PERMISSION_DENIED).into_response()
+}
+
+#[derive(Debug)]
+pub enum SignUpError {
+    Refused(RegistrationRefused),
+    Failed(ServerError),
+}
 
-pub async fn create_user(user: &User, c: &mut Connection) -> Result<ConnectionToken> {
+impl From<ServerError> for SignUpError {
+    fn from(err: ServerError) -> Self {
+        SignUpError::Failed(err)
+    }
+}
+
+fn refuse<T>(error: &'static str, message: &'static str) -> std::result::Result<T, SignUpError> {
+    Err(SignUpError::Refused(RegistrationRefused { error, message }))
+}
+
+// Invites are only used up once everything else checked out, and given back
+// if the user could not be saved
+pub async fn create_user(
+    user: &User,
+    policy: &RegistrationPolicy,
+    challenge: Option<Arc<dyn Challenge>>,
+    ip: Option<IpAddr>,
+    c: &mut Connection,
+) -> std::result::Result<ConnectionToken, SignUpError> {
+    if policy.mode == Registration::Closed {
+        return refuse("registration_closed", "Registration is closed");
+    }
+    if let Some(challenge) = challenge {
+        match user.challenge {
+            None => return refuse("challenge_required", "A challenge answer is required"),
+            Some(ref answer) => {
+                if !challenge.verify(answer, ip).await? {
+                    return refuse("challenge_failed", "Challenge answer is wrong");
+                }
+            }
+        }
+    }
     validate_email(&user.email)?;
+    if !policy.allows_email(&user.email) {
+        return refuse("email_domain_not_allowed", "Email domain not allowed");
+    }
     validate_password(&user)?;
     validate_username(&user.username)?;
-    db::users::save_user(c, &user)
+    let invite = match (policy.mode, &user.invite) {
+        (Registration::Invite, None) => {
+            return refuse("invite_required", "An invite code is required")
+        }
+        (Registration::Invite, Some(code)) => {
+            if !db::invites::use_invite(c, code)? {
+                return refuse("invite_invalid", "Invite code is invalid or used up");
+            }
+            Some(code)
+        }
+        _ => None,
+    };
+    let token = db::users::save_user(c, &user).map_err(|e| {
+        if let Some(code) = invite {
+            if let Err(e) = db::invites::return_invite(c, code) {
+                warn!("Could not give back invite: {}", e);
+            }
+        }
+        e
+    })?;
+    metrics::record(c, Event::Registration);
+    Ok(token)
+}
+
+// POST /admin/invites
+pub async fn create_invite(data: &InviteData, c: &mut Connection) -> Result<Invite> {
+    if data.uses == Some(0) || data.ttl_s == Some(0) {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            "An invite needs uses and time left",
+        ));
+    }
+    db::invites::create_invite(
+        c,
+        data.uses.unwrap_or(DEFAULT_INVITE_USES),
+        data.ttl_s.unwrap_or(DEFAULT_INVITE_TTL_S),
+    )
+}
+
+#[derive(Serialize)]
+pub struct IndexedEmail {
+    pub username: String,
+    pub result: EmailIndexing,
+}
+
+// POST /admin/email_index
+pub async fn index_emails(known: &[KnownEmail], c: &mut Connection) -> Result<Vec<IndexedEmail>> {
+    known
+        .iter()
+        .map(|known| {
+            Ok(IndexedEmail {
+                username: known.username.clone(),
+                result: db::users::index_email(c, known)?,
+            })
+        })
+        .collect()
+}
+
+pub async fn get_default_aisles(auth: String, c: &mut Connection) -> Result<DefaultAisles> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(DefaultAisles {
+        aisles: db::users::get_default_aisles(c, &user_id)?,
+    })
+}
+
+pub async fn set_default_aisles(
+    auth: String,
+    data: &DefaultAisles,
+    c: &mut Connection,
+) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if let Some(ref aisles) = data.aisles {
+        if aisles.len() > MAX_DEFAULT_AISLES || aisles.iter().any(|a| a.trim().is_empty()) {
+            return Err(ServerError::new(
+                INVALID_PARAMS,
+                &format!("At most {} aisles, all named", MAX_DEFAULT_AISLES),
+            ));
+        }
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::users::set_default_aisles(c, &user_id, data.aisles.as_deref())
+}
+
+pub async fn get_aliases(auth: String, c: &mut Connection) -> Result<ProductAliases> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(ProductAliases {
+        aliases: db::aliases::get_aliases(c, &user_id)?.into_iter().collect(),
+    })
+}
+
+// The aliases replace the user's, none may stand for another alias
+pub async fn set_aliases(auth: String, data: &ProductAliases, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let aliases: BTreeMap<String, String> = data
+        .aliases
+        .iter()
+        .map(|(alias, name)| {
+            (
+                db::aliases::normalized(alias),
+                db::aliases::normalized(name),
+            )
+        })
+        .collect();
+    let invalid = aliases.iter().any(|(alias, name)| {
+        alias.is_empty() || name.is_empty() || alias == name || aliases.contains_key(name)
+    });
+    if aliases.len() > MAX_ALIASES || invalid {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!(
+                "At most {} aliases, of named products that aren't aliases",
+                MAX_ALIASES
+            ),
+        ));
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::aliases::set_aliases(c, &user_id, &aliases)
+}
+
+pub async fn get_anywhere(auth: String, c: &mut Connection) -> Result<AnywhereProducts> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(AnywhereProducts {
+        products: db::anywhere::get_anywhere(c, &user_id)?,
+    })
+}
+
+// The product needed anywhere was checked in one of the stores
+pub async fn check_anywhere(auth: String, product_id: String, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::anywhere::check_anywhere(c, &auth, &ProductId(product_id))
+}
+
+pub async fn get_staples(auth: String, c: &mut Connection) -> Result<Staples> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(Staples {
+        staples: db::staples::get_staples(c, &user_id)?,
+    })
+}
+
+fn too_many_staples() -> ServerError {
+    ServerError::new(
+        INVALID_PARAMS,
+        &format!(
+            "At most {} staples, all named and in a named aisle",
+            MAX_STAPLES
+        ),
+    )
+}
+
+fn is_named(staple: &Staple) -> bool {
+    !staple.name.trim().is_empty() && !staple.aisle.trim().is_empty()
+}
+
+// The staples replace the user's, the last of a name wins
+pub async fn set_staples(auth: String, data: &Staples, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    if data.staples.len() > MAX_STAPLES || !data.staples.iter().all(is_named) {
+        return Err(too_many_staples());
+    }
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::staples::set_staples(c, &user_id, &data.staples)
+}
+
+pub async fn add_staple(auth: String, data: &Staple, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    let name = db::aliases::normalized(&data.name);
+    let others = db::staples::get_staples(c, &user_id)?
+        .iter()
+        .filter(|s| db::aliases::normalized(&s.name) != name)
+        .count();
+    if others >= MAX_STAPLES || !is_named(data) {
+        return Err(too_many_staples());
+    }
+    db::staples::add_staple(c, &user_id, data)
+}
+
+// Deleting a staple the user doesn't have is fine, it was maybe deleted
+// from another device
+pub async fn delete_staple(auth: String, name: &str, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::staples::delete_staple(c, &user_id, name)
+}
+
+pub async fn get_quotas(auth: String, c: &mut Connection) -> Result<UserQuotas> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(UserQuotas {
+        quotas: db::quotas::get_user_quotas(c, &user_id)?,
+    })
+}
+
+// The quotas of the account past their warning share, for clients to warn
+// before creations start failing
+pub async fn quota_warnings(auth: &str, c: &mut Connection) -> Result<Vec<String>> {
+    let user_id = db::sessions::get_user_id(c, &Auth(auth))?;
+    Ok(db::quotas::get_user_quotas(c, &user_id)?
+        .into_iter()
+        .filter(|quota| quota.warning)
+        .map(|quota| quota.quota)
+        .collect())
+}
+
+// Along the reply to a change, like `X-Quota-Warning: stores, bytes`
+pub fn add_quota_warning_header(res: &mut warp::reply::Response, warnings: &[String]) {
+    if let Ok(value) = HeaderValue::from_str(&warnings.join(", ")) {
+        res.headers_mut()
+            .insert(HeaderName::from_static("x-quota-warning"), value);
+    }
+}
+
+pub async fn get_templates(auth: String, c: &mut Connection) -> Result<AisleTemplates> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    Ok(AisleTemplates {
+        templates: db::templates::get_templates(c, &user_id)?,
+    })
+}
+
+// Whether the user may have one more template of that name, replacing one
+// of the same name is fine
+pub(super) fn check_template_room(c: &mut Connection, user_id: &UserId, name: &str) -> Result<()> {
+    let name = db::aliases::normalized(name);
+    let others = db::templates::get_templates(c, &user_id)?
+        .iter()
+        .filter(|t| db::aliases::normalized(&t.name) != name)
+        .count();
+    if others >= MAX_TEMPLATES || name.is_empty() {
+        return Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!("At most {} templates, all named", MAX_TEMPLATES),
+        ));
+    }
+    Ok(())
+}
+
+// Save the aisle layout of a store, or replace the template of that name
+pub async fn save_template(
+    auth: String,
+    data: &StoreTemplate,
+    c: &mut Connection,
+) -> Result<AisleTemplate> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    check_template_room(c, &user_id, &data.name)?;
+    db::templates::save_template(c, &auth, &data.name, &StoreId::new(data.store_id.clone()))
+}
+
+pub async fn delete_template(auth: String, name: &str, c: &mut Connection) -> Result<()> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let user_id = db::sessions::get_user_id(c, &auth)?;
+    db::templates::delete_template(c, &user_id, name)
+}
+
+// Lay out a new or existing store after a template, the aisles it already
+// has are kept where they are
+pub async fn apply_template(
+    auth: String,
+    data: &StoreTemplate,
+    c: &mut Connection,
+) -> Result<AddedAisles> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::templates::apply_template(c, &auth, &data.name, &StoreId::new(data.store_id.clone()))
+}
+
+// A store of the user copied from a template code of someone else's
+pub async fn redeem_template(
+    auth: String,
+    data: &RedeemTemplate,
+    c: &mut Connection,
+) -> Result<StoreId> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    let store_id = db::templates::redeem_code(c, &auth, &data.code, data.name.as_deref())?;
+    metrics::record(c, Event::StoreCreated);
+    Ok(store_id)
+}
+
+pub async fn revoke_invite(code: &str, c: &mut Connection) -> Result<()> {
+    if db::invites::revoke_invite(c, code)? {
+        Ok(())
+    } else {
+        Err(ServerError::new(StatusCode::NOT_FOUND, "No such invite"))
+    }
 }
 
 pub async fn delete_user(auth: &str, user_id: &str, c: &mut Connection) -> Result<()> {
@@ -29,6 +412,12 @@ pub async fn delete_user(auth: &str, user_id: &str, c: &mut Connection) -> Resul
     db::users::delete_user(c, &auth, &UserId(user_id.to_string()))
 }
 
+pub async fn user_removal(auth: &str, user_id: &str, c: &mut Connection) -> Result<UserRemoval> {
+    let auth = Auth(&auth);
+    db::sessions::validate_session(c, &auth)?;
+    db::users::get_user_removal(c, &auth, &UserId(user_id.to_string()))
+}
+
 fn validate_email(mail: &str) -> Result<()> {
     if !validator::validate_email(mail) {
         Err(ServerError::new(INVALID_PARAMS, "Email field is invalid"))
@@ -60,17 +449,65 @@ fn validate_password(user: &User) -> Result<()> {
     }
 }
 
-fn validate_username(username: &str) -> Result<()> {
+// What is wrong with a username, nothing when it is valid
+fn username_problems(username: &str) -> Vec<&'static str> {
     lazy_static! {
         static ref VALID_USERNAME_RE: Regex =
             Regex::new(r"^[a-zA-Z][0-9a-zA-Z_]*$").expect("Error in compiling username regex");
     }
 
-    if !VALID_USERNAME_RE.is_match(username) {
-        Err(ServerError::new(INVALID_PARAMS, "Invalid username"))
-    } else {
+    let mut problems = vec![];
+    if username.is_empty() {
+        problems.push("Username is empty");
+    } else if username.chars().count() > MAX_USERNAME_LEN {
+        problems.push("Username is longer than 32 characters");
+    }
+    if !username.is_empty() && !VALID_USERNAME_RE.is_match(username) {
+        problems
+            .push("Username must start with a letter, followed by letters, digits or underscores");
+    }
+    problems
+}
+
+fn validate_username(username: &str) -> Result<()> {
+    let problems = username_problems(username);
+    if problems.is_empty() {
         Ok(())
+    } else {
+        Err(ServerError::new(
+            INVALID_PARAMS,
+            &format!("Invalid username: {}", problems.join(", ")),
+        ))
+    }
+}
+
+// GET /user/check, rate limited by address as it tells which usernames exist
+pub async fn check_username(
+    username: &str,
+    ip: Option<IpAddr>,
+    c: &mut Connection,
+) -> Result<UsernameCheck> {
+    let subject = ip.map_or_else(|| "unknown".to_owned(), |ip| format!("ip:{}", ip));
+    if let Some(retry_after_s) = db::abuse::rate_limit(
+        c,
+        "username_check",
+        &subject,
+        USERNAME_CHECKS_PER_MINUTE,
+        60,
+    )? {
+        return Err(ServerError::new(
+            error::RATE_LIMITED,
+            &format!("Too many checks, retry in {} seconds", retry_after_s),
+        ));
     }
+    let problems = username_problems(username);
+    let valid = problems.is_empty();
+    Ok(UsernameCheck {
+        valid,
+        // invalid usernames can't have been registered
+        available: valid && !db::users::username_taken(c, username)?,
+        problems: problems.into_iter().map(str::to_owned).collect(),
+    })
 }
 
 #[cfg(test)]
@@ -93,5 +530,9 @@ mod tests {
         assert_eq!(false, validate_username("_").is_ok());
         assert_eq!(false, validate_username("1").is_ok());
         assert_eq!(false, validate_username("42").is_ok());
+        assert_eq!(true, validate_username(&"t".repeat(32)).is_ok());
+        assert_eq!(false, validate_username(&"t".repeat(33)).is_ok());
+        assert_eq!(false, validate_username("").is_ok());
+        assert_eq!(2, username_problems(&"_".repeat(33)).len());
     }
 }
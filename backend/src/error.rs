@@ -1,20 +1,54 @@
 use std::fmt::Display;
 
-use redis::RedisError;
-use serde::Serialize;
+use redis::{ErrorKind, RedisError};
+use serde::{Deserialize, Serialize};
 use warp::http::StatusCode;
 
+use crate::redact::redact;
+
 pub const USERNAME_TAKEN: StatusCode = StatusCode::NOT_ACCEPTABLE;
+pub const EMAIL_TAKEN: StatusCode = StatusCode::NOT_ACCEPTABLE;
 pub const INVALID_USER_OR_PWD: StatusCode = StatusCode::BAD_REQUEST;
 pub const UNAUTHORISED: StatusCode = StatusCode::UNAUTHORIZED;
 pub const PERMISSION_DENIED: StatusCode = StatusCode::FORBIDDEN;
 pub const INTERNAL_ERROR: StatusCode = StatusCode::INTERNAL_SERVER_ERROR;
+pub const STORE_BUSY: StatusCode = StatusCode::SERVICE_UNAVAILABLE;
+pub const DB_UNAVAILABLE: StatusCode = StatusCode::SERVICE_UNAVAILABLE;
+pub const QUOTA_EXCEEDED: StatusCode = StatusCode::CONFLICT;
+pub const BANNED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+pub const RATE_LIMITED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+pub const MALFORMED_ID: StatusCode = StatusCode::BAD_REQUEST;
+pub const INVALID_QUERY: StatusCode = StatusCode::BAD_REQUEST;
+pub const INVALID_BODY: StatusCode = StatusCode::UNPROCESSABLE_ENTITY;
+pub const UNSUPPORTED_BODY: StatusCode = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+pub const NUTRITION_UNAVAILABLE: StatusCode = StatusCode::BAD_GATEWAY;
+
+const DB_UNAVAILABLE_MSG: &str = "Database unavailable, try again later";
 
 #[derive(Debug, Clone, Serialize, PartialEq)]
 pub struct ServerError {
     #[serde(skip)]
     pub status: StatusCode,
     pub msg: String,
+    // set when the account hit one of its limits, replied as is
+    #[serde(skip)]
+    pub quota: Option<QuotaExceeded>,
+}
+
+// The reply to a change the account's quotas don't leave room for
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QuotaExceeded {
+    pub error: String,
+    pub quota: String,
+    pub limit: u64,
+}
+
+// A ServerError returned from inside a transaction, carried through Redis
+#[derive(Serialize, Deserialize)]
+struct CarriedError {
+    status: u16,
+    msg: String,
+    quota: Option<QuotaExceeded>,
 }
 
 impl std::error::Error for ServerError {}
@@ -29,44 +63,127 @@ impl Display for ServerError {
 
 impl From<RedisError> for ServerError {
     fn from(err: RedisError) -> Self {
-        ServerError {
-            status: INTERNAL_ERROR,
-            msg: err.to_string(),
+        match err.kind() {
+            // one of ours, coming back from a transaction
+            ErrorKind::ExtensionError => {
+                let detail = err.to_string();
+                let detail = detail.trim_start_matches(": ");
+                match serde_json::from_str::<CarriedError>(detail) {
+                    Ok(carried) => ServerError {
+                        status: StatusCode::from_u16(carried.status).unwrap_or(INTERNAL_ERROR),
+                        msg: carried.msg,
+                        quota: carried.quota,
+                    },
+                    Err(_) => ServerError::new(INTERNAL_ERROR, detail),
+                }
+            }
+            // a replica being promoted or a restarted server still loading its data
+            ErrorKind::BusyLoadingError => ServerError::db_unavailable(),
+            _ if err.is_timeout() || err.is_connection_refusal() || err.is_connection_dropped() => {
+                log::error!("Redis unreachable: {}", err);
+                ServerError::db_unavailable()
+            }
+            _ => {
+                log::error!("Redis error: {}", redact(&err.to_string()));
+                ServerError::new(
+                    INTERNAL_ERROR,
+                    &format!("Database error: {}", err.category()),
+                )
+            }
         }
     }
 }
 
+// The status and quota are kept, see `From<RedisError>`
 impl From<ServerError> for RedisError {
     fn from(err: ServerError) -> Self {
-        (redis::ErrorKind::ExtensionError, "", err.msg).into()
+        let carried = CarriedError {
+            status: err.status.as_u16(),
+            msg: err.msg,
+            quota: err.quota,
+        };
+        let detail = serde_json::to_string(&carried).expect("errors always serialize");
+        (redis::ErrorKind::ExtensionError, "", detail).into()
     }
 }
 
+// The pool only fails when no connection could be had within the time budget
 impl From<r2d2::Error> for ServerError {
     fn from(err: r2d2::Error) -> Self {
-        ServerError {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            msg: err.to_string(),
-        }
+        ServerError::from(&err)
     }
 }
 
 impl From<&r2d2::Error> for ServerError {
     fn from(err: &r2d2::Error) -> Self {
-        ServerError {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            msg: err.to_string(),
-        }
+        log::error!("No database connection: {}", err);
+        ServerError::db_unavailable()
     }
 }
 
 pub type Result<T> = std::result::Result<T, ServerError>;
 
 impl ServerError {
+    // The message ends up in responses and logs, secrets are stripped here
     pub fn new(status: StatusCode, msg: &str) -> Self {
         ServerError {
             status,
-            msg: msg.to_owned(),
+            msg: redact(msg),
+            quota: None,
         }
     }
+
+    // Redis timed out or can't be reached
+    pub fn db_unavailable() -> Self {
+        ServerError::new(DB_UNAVAILABLE, DB_UNAVAILABLE_MSG)
+    }
+
+    pub fn is_db_unavailable(&self) -> bool {
+        self.status == DB_UNAVAILABLE && self.msg == DB_UNAVAILABLE_MSG
+    }
+
+    // The account hit the `limit` of one of its quotas, `what` tells what it
+    // counts. A client error: retrying doesn't help until something is freed.
+    pub fn quota_exceeded(quota: &str, limit: u64, what: &str) -> Self {
+        let mut err = ServerError::new(
+            QUOTA_EXCEEDED,
+            &format!("Quota exceeded: at most {} {}", limit, what),
+        );
+        err.quota = Some(QuotaExceeded {
+            error: "quota_exceeded".to_owned(),
+            quota: quota.to_owned(),
+            limit,
+        });
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn db_unavailable_test() {
+        let timeout: RedisError =
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "read timed out").into();
+        let err = ServerError::from(timeout);
+        assert_eq!(DB_UNAVAILABLE, err.status);
+        assert_eq!(true, err.is_db_unavailable());
+
+        let refused: RedisError =
+            std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused").into();
+        assert_eq!(true, ServerError::from(refused).is_db_unavailable());
+
+        // a busy store is also a 503, but not the database's fault
+        let busy = ServerError::new(STORE_BUSY, "Store is being modified, try again");
+        assert_eq!(false, busy.is_db_unavailable());
+    }
+
+    #[test]
+    fn error_through_transaction_test() {
+        let quota = ServerError::quota_exceeded("stores", 3, "stores");
+        assert_eq!(quota, ServerError::from(RedisError::from(quota.clone())));
+        let other = ServerError::new(StatusCode::NOT_FOUND, "Not found");
+        assert_eq!(other, ServerError::from(RedisError::from(other.clone())));
+    }
 }
@@ -0,0 +1,520 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::*;
+use tokio::sync::mpsc;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use warp::http::StatusCode;
+
+use crate::{
+    config::LiveConfig,
+    db::{
+        self,
+        journal::{Change, Entity, Entry},
+    },
+    endpoints::{self, user::SignUpError, HEADER_AUTH, HEADER_FORWARDED_FOR},
+    error::{self, ServerError},
+    storage::{Pool, StorageManager},
+    tokens,
+    types::*,
+};
+
+mod proto {
+    tonic::include_proto!("efficio");
+}
+
+use proto::efficio_server::{Efficio, EfficioServer};
+
+type PooledConnection = r2d2::PooledConnection<StorageManager>;
+type GrpcResult<T> = Result<Response<T>, Status>;
+
+// how often the journal of a watched store is read
+const WATCH_POLL: Duration = Duration::from_secs(1);
+// events a client watching a store hasn't read yet
+const WATCH_BUFFER: usize = 64;
+
+pub async fn serve(pool: Pool, config: Arc<LiveConfig>, addr: SocketAddr) {
+    info!("gRPC API listening on {}", addr);
+    // the gRPC API has no admin calls nor health check, all of it closes
+    // during maintenance
+    let interceptor = {
+        let (pool, config) = (pool.clone(), config.clone());
+        move |req: Request<()>| {
+            if let Some(m) = config.maintenance() {
+                return Err(Status::unavailable(m.message));
+            }
+            guard(&pool, &config, &req)?;
+            Ok(req)
+        }
+    };
+    let service = EfficioService { pool, config };
+    if let Err(e) = Server::builder()
+        .add_service(EfficioServer::with_interceptor(service, interceptor))
+        .serve(addr)
+        .await
+    {
+        error!("gRPC server stopped: {}", e);
+    }
+}
+
+// The allow and deny lists and the bans of the HTTP API. Calls count against
+// the request limit, but not as errors: how a call ends is unknown here.
+fn guard(pool: &Pool, config: &LiveConfig, req: &Request<()>) -> Result<(), Status> {
+    let ip_policy = config.ip_policy();
+    let forwarded_for = req
+        .metadata()
+        .get(HEADER_FORWARDED_FOR)
+        .and_then(|v| v.to_str().ok());
+    let ip = req
+        .remote_addr()
+        .map(|addr| ip_policy.client_ip(addr.ip(), forwarded_for));
+    let allowed = match ip {
+        Some(ip) => ip_policy.is_allowed(&ip),
+        None => ip_policy.is_open(),
+    };
+    if !allowed {
+        return Err(Status::permission_denied("Address not allowed"));
+    }
+    let limits = config.abuse_limits();
+    if !limits.is_enabled() {
+        return Ok(());
+    }
+    // without the database nobody can be told apart, let the call fail later
+    let mut c = match pool.get() {
+        Ok(c) => c,
+        Err(_) => return Ok(()),
+    };
+    let auth = req
+        .metadata()
+        .get(HEADER_AUTH)
+        .and_then(|v| v.to_str().ok());
+    let subjects = endpoints::abuse::subjects(&mut *c, ip, auth);
+    for subject in &subjects {
+        match db::abuse::get_ban(&mut *c, subject) {
+            Ok(Some(ban)) => {
+                return Err(Status::resource_exhausted(format!(
+                    "Banned for {}s: {}",
+                    ban.expires_in_s, ban.reason
+                )))
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Could not check bans: {}", e),
+        }
+    }
+    for subject in &subjects {
+        if let Err(e) = db::abuse::record_request(&mut *c, subject, false, &limits) {
+            warn!("Could not record the request for abuse detection: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// The events of the store after `since` and the version the client is at
+// once it has them. A client the journal can't bring up to date, or starting
+// from nothing, is told to get the whole store.
+async fn store_events(
+    pool: &Pool,
+    auth: &str,
+    store_id: &str,
+    since: u64,
+) -> Result<(Vec<proto::StoreEvent>, u64), Status> {
+    let mut c = pool.get().map_err(ServerError::from)?;
+    let (version, entries) =
+        endpoints::store::store_events(auth.to_owned(), store_id.to_owned(), since, &mut *c)
+            .await?;
+    let entries = match entries {
+        Some(entries) if since > 0 || version == 0 => entries,
+        _ => vec![Entry {
+            version,
+            entity: Entity::Store,
+            change: Change::Replaced,
+            id: store_id.to_owned(),
+            at: 0,
+        }],
+    };
+    let version = entries.iter().map(|e| e.version).fold(version, u64::max);
+    Ok((entries.into_iter().map(From::from).collect(), version))
+}
+
+// Resolves to whether the client still listens, the events it hasn't read
+// aside
+struct Listening<'a, T>(&'a mut mpsc::Sender<T>);
+
+impl<T> Future for Listening<'_, T> {
+    type Output = bool;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<bool> {
+        self.0.poll_ready(cx).map(|ready| ready.is_ok())
+    }
+}
+
+struct EfficioService {
+    pool: Pool,
+    config: Arc<LiveConfig>,
+}
+
+impl EfficioService {
+    fn connection(&self) -> Result<PooledConnection, Status> {
+        self.pool.get().map_err(|e| ServerError::from(e).into())
+    }
+
+    // for the calls changing data, turned away while read-only
+    fn write_connection(&self) -> Result<PooledConnection, Status> {
+        if self.config.is_read_only() {
+            Err(Status::unavailable(
+                "Read-only, changes can't be saved for now",
+            ))
+        } else {
+            self.connection()
+        }
+    }
+}
+
+fn auth<T>(request: &Request<T>) -> Result<String, Status> {
+    request
+        .metadata()
+        .get(HEADER_AUTH)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .ok_or_else(|| Status::unauthenticated("Not logged in"))
+        .and_then(|token| {
+            if tokens::is_well_formed(&token) {
+                Ok(token)
+            } else {
+                Err(Status::unauthenticated("Invalid session token"))
+            }
+        })
+}
+
+impl From<ServerError> for Status {
+    fn from(err: ServerError) -> Self {
+        let code = match err.status {
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_ACCEPTABLE => Code::AlreadyExists,
+            StatusCode::BAD_REQUEST | StatusCode::PRECONDITION_FAILED => Code::InvalidArgument,
+            StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            error::QUOTA_EXCEEDED => Code::ResourceExhausted,
+            _ => Code::Internal,
+        };
+        Status::new(code, err.msg)
+    }
+}
+
+impl From<SignUpError> for Status {
+    fn from(err: SignUpError) -> Self {
+        match err {
+            SignUpError::Refused(refused) => Status::permission_denied(refused.message),
+            SignUpError::Failed(err) => err.into(),
+        }
+    }
+}
+
+impl From<ConnectionToken> for proto::ConnectionToken {
+    fn from(token: ConnectionToken) -> Self {
+        proto::ConnectionToken {
+            session_token: token.session_token.as_str().to_owned(),
+            user_id: token.user_id,
+        }
+    }
+}
+
+impl From<Product> for proto::Product {
+    fn from(product: Product) -> Self {
+        proto::Product {
+            product_id: product.product_id,
+            name: product.name,
+            quantity: product.quantity,
+            is_done: product.is_done,
+            unit: u32::from(product.unit) as i32,
+            sort_weight: product.sort_weight,
+        }
+    }
+}
+
+impl From<Aisle> for proto::Aisle {
+    fn from(aisle: Aisle) -> Self {
+        proto::Aisle {
+            aisle_id: aisle.aisle_id,
+            name: aisle.name,
+            sort_weight: aisle.sort_weight,
+            products: aisle.products.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+impl From<Entry> for proto::StoreEvent {
+    fn from(entry: Entry) -> Self {
+        let entity = match entry.entity {
+            Entity::Store => proto::Entity::Store,
+            Entity::Aisle => proto::Entity::Aisle,
+            Entity::Product => proto::Entity::Product,
+        };
+        let change = match entry.change {
+            Change::Updated => proto::Change::Updated,
+            Change::Deleted => proto::Change::Deleted,
+            Change::Replaced => proto::Change::Replaced,
+        };
+        proto::StoreEvent {
+            version: entry.version,
+            entity: entity as i32,
+            change: change as i32,
+            id: entry.id,
+            at: entry.at,
+        }
+    }
+}
+
+impl From<Store> for proto::Store {
+    fn from(store: Store) -> Self {
+        proto::Store {
+            store_id: store.store_id,
+            name: store.name,
+            aisles: store.aisles.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+impl From<StoreLightList> for proto::StoreLightList {
+    fn from(list: StoreLightList) -> Self {
+        proto::StoreLightList {
+            stores: list
+                .stores
+                .into_iter()
+                .map(|s| proto::StoreLight {
+                    name: s.name,
+                    store_id: s.store_id,
+                    items: s.items,
+                    items_left: s.items_left,
+                    updated_at: s.updated_at.unwrap_or(0),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Efficio for EfficioService {
+    async fn create_user(
+        &self,
+        request: Request<proto::CreateUserRequest>,
+    ) -> GrpcResult<proto::ConnectionToken> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        let data = request.into_inner();
+        let user = User {
+            username: data.username,
+            email: data.email.into(),
+            password: data.password.into(),
+            invite: Some(data.invite).filter(|invite| !invite.is_empty()),
+            challenge: Some(data.challenge).filter(|answer| !answer.is_empty()),
+        };
+        let policy = self.config.registration_policy();
+        let challenge = self.config.challenge();
+        let mut c = self.write_connection()?;
+        let token = endpoints::user::create_user(&user, &policy, challenge, ip, &mut *c).await?;
+        Ok(Response::new(token.into()))
+    }
+
+    async fn login(
+        &self,
+        request: Request<proto::LoginRequest>,
+    ) -> GrpcResult<proto::ConnectionToken> {
+        let data = request.into_inner();
+        let auth_info = AuthInfo {
+            username: data.username,
+            password: data.password.into(),
+        };
+        let mut c = self.write_connection()?;
+        let token = endpoints::session::login(&auth_info, &mut *c).await?;
+        Ok(Response::new(token.into()))
+    }
+
+    async fn logout(&self, request: Request<proto::UserIdRequest>) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let mut c = self.write_connection()?;
+        endpoints::session::logout(&auth, &request.get_ref().user_id, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<proto::UserIdRequest>,
+    ) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let mut c = self.write_connection()?;
+        endpoints::user::delete_user(&auth, &request.get_ref().user_id, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn create_store(
+        &self,
+        request: Request<proto::NameRequest>,
+    ) -> GrpcResult<proto::StoreId> {
+        let auth = auth(&request)?;
+        let data = NewStore {
+            name: request.into_inner().name,
+            no_default_aisles: false,
+        };
+        let default_aisles = self.config.default_aisles();
+        let mut c = self.write_connection()?;
+        let store_id =
+            endpoints::store::create_store(auth, &data, &default_aisles, &mut *c).await?;
+        Ok(Response::new(proto::StoreId {
+            store_id: store_id.to_string(),
+        }))
+    }
+
+    async fn edit_store(&self, request: Request<proto::RenameRequest>) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let proto::RenameRequest { id, name } = request.into_inner();
+        let mut c = self.write_connection()?;
+        let data = EditStore {
+            name: Some(name),
+            defaults: None,
+        };
+        endpoints::store::edit_store(auth, id, &data, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn list_stores(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> GrpcResult<proto::StoreLightList> {
+        let auth = auth(&request)?;
+        let mut c = self.connection()?;
+        let stores = endpoints::store::list_stores(auth, &mut *c).await?;
+        Ok(Response::new(stores.into()))
+    }
+
+    async fn get_store(&self, request: Request<proto::IdRequest>) -> GrpcResult<proto::Store> {
+        let auth = auth(&request)?;
+        let mut c = self.connection()?;
+        let store = endpoints::store::list_store(auth, request.into_inner().id, &mut *c).await?;
+        Ok(Response::new(store.into()))
+    }
+
+    async fn delete_store(&self, request: Request<proto::IdRequest>) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let mut c = self.write_connection()?;
+        endpoints::store::delete_store(auth, request.into_inner().id, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn create_aisle(
+        &self,
+        request: Request<proto::CreateRequest>,
+    ) -> GrpcResult<proto::Aisle> {
+        let auth = auth(&request)?;
+        let proto::CreateRequest { parent_id, name } = request.into_inner();
+        let mut c = self.write_connection()?;
+        let aisle =
+            endpoints::aisle::create_aisle(auth, parent_id, &NameData { name }, &mut *c).await?;
+        Ok(Response::new(aisle.into()))
+    }
+
+    async fn rename_aisle(
+        &self,
+        request: Request<proto::RenameRequest>,
+    ) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let proto::RenameRequest { id, name } = request.into_inner();
+        let mut c = self.write_connection()?;
+        endpoints::aisle::rename_aisle(auth, id, &NameData { name }, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn delete_aisle(&self, request: Request<proto::IdRequest>) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let mut c = self.write_connection()?;
+        endpoints::aisle::delete_aisle(auth, request.into_inner().id, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn create_product(
+        &self,
+        request: Request<proto::CreateRequest>,
+    ) -> GrpcResult<proto::Product> {
+        let auth = auth(&request)?;
+        let proto::CreateRequest { parent_id, name } = request.into_inner();
+        let mut c = self.write_connection()?;
+        let product =
+            endpoints::product::create_product(auth, parent_id, &NameData { name }, &mut *c)
+                .await?;
+        Ok(Response::new(product.into()))
+    }
+
+    async fn edit_product(
+        &self,
+        request: Request<proto::EditProductRequest>,
+    ) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let data = request.into_inner();
+        let mut edit = EditProduct::new(
+            data.name,
+            data.quantity,
+            data.unit.map(Unit::from),
+            data.is_done,
+        );
+        edit.bought = data.bought;
+        edit.edited_at = data.edited_at;
+        let mut c = self.write_connection()?;
+        endpoints::product::edit_product(auth, data.id, &edit, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn delete_product(&self, request: Request<proto::IdRequest>) -> GrpcResult<proto::Empty> {
+        let auth = auth(&request)?;
+        let mut c = self.write_connection()?;
+        endpoints::product::delete_product(auth, request.into_inner().id, &mut *c).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    type WatchStoreStream = mpsc::Receiver<Result<proto::StoreEvent, Status>>;
+
+    // Backed by the store's journal, read every `WATCH_POLL` until the
+    // client hangs up or its session ends
+    async fn watch_store(
+        &self,
+        request: Request<proto::WatchStoreRequest>,
+    ) -> GrpcResult<Self::WatchStoreStream> {
+        let auth = auth(&request)?;
+        let proto::WatchStoreRequest {
+            store_id,
+            since_version,
+        } = request.into_inner();
+        let pool = self.pool.clone();
+        // a store the user can't see is refused before the stream starts
+        let mut polled = Ok(store_events(&pool, &auth, &store_id, since_version).await?);
+        let (mut tx, rx) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let since = match polled {
+                    Ok((events, version)) => {
+                        for event in events {
+                            if tx.send(Ok(event)).await.is_err() {
+                                return;
+                            }
+                        }
+                        version
+                    }
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                };
+                tokio::time::delay_for(WATCH_POLL).await;
+                if !Listening(&mut tx).await {
+                    return;
+                }
+                polled = store_events(&pool, &auth, &store_id, since).await;
+            }
+        });
+        Ok(Response::new(rx))
+    }
+}
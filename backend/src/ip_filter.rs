@@ -0,0 +1,223 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+// `10.0.0.0/8`, `fd00::/8` or a single address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|e| format!("{}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{}: invalid prefix length", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Who may talk to the server, and which peers are reverse proxies whose
+// X-Forwarded-For header can be believed
+#[derive(Debug, Clone, Default)]
+pub struct IpPolicy {
+    trusted_proxies: Vec<Cidr>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+fn any_contains(list: &[Cidr], ip: &IpAddr) -> bool {
+    list.iter().any(|cidr| cidr.contains(ip))
+}
+
+// IPv4 clients reaching a dual stack socket show up as ::ffff:a.b.c.d
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(to_ipv4(v6)),
+            _ => ip,
+        },
+        v4 => v4,
+    }
+}
+
+fn to_ipv4(v6: Ipv6Addr) -> Ipv4Addr {
+    let [.., a, b, c, d] = v6.octets();
+    Ipv4Addr::new(a, b, c, d)
+}
+
+impl IpPolicy {
+    pub fn new(trusted_proxies: Vec<Cidr>, allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        IpPolicy {
+            trusted_proxies,
+            allow,
+            deny,
+        }
+    }
+
+    // The peer address, unless it is a trusted proxy: then the right-most entry of
+    // X-Forwarded-For that is not a trusted proxy itself. Anything left of it
+    // could have been written by the client.
+    pub fn client_ip(&self, remote: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = canonical(remote);
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                if !any_contains(&self.trusted_proxies, &client) {
+                    break;
+                }
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => client = canonical(ip),
+                    Err(_) => break,
+                }
+            }
+        }
+        client
+    }
+
+    // Deny wins over allow, an empty allow list lets everyone in
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        !any_contains(&self.deny, ip) && (self.allow.is_empty() || any_contains(&self.allow, ip))
+    }
+
+    // Client address isn't known, only let it through if no allow list is set
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_test() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert_eq!(true, net.contains(&ip("10.1.255.3")));
+        assert_eq!(false, net.contains(&ip("10.2.0.1")));
+        assert_eq!(false, net.contains(&ip("::1")));
+
+        let single: Cidr = "192.168.0.7".parse().unwrap();
+        assert_eq!(true, single.contains(&ip("192.168.0.7")));
+        assert_eq!(false, single.contains(&ip("192.168.0.8")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(true, all.contains(&ip("203.0.113.9")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert_eq!(true, v6.contains(&ip("fd12::1")));
+        assert_eq!(false, v6.contains(&ip("fe80::1")));
+
+        assert_eq!(true, "10.0.0.0/33".parse::<Cidr>().is_err());
+        assert_eq!(true, "not an ip".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn client_ip_test() {
+        let policy = IpPolicy::new(cidrs(&["10.0.0.0/8"]), vec![], vec![]);
+        // direct connection, the header is ignored
+        assert_eq!(
+            ip("203.0.113.9"),
+            policy.client_ip(ip("203.0.113.9"), Some("1.2.3.4"))
+        );
+        // behind our proxies, the spoofed left-most entry is ignored
+        assert_eq!(
+            ip("203.0.113.9"),
+            policy.client_ip(ip("10.0.0.2"), Some("1.2.3.4, 203.0.113.9, 10.0.0.1"))
+        );
+        // proxy without header, or with garbage in it
+        assert_eq!(ip("10.0.0.2"), policy.client_ip(ip("10.0.0.2"), None));
+        assert_eq!(
+            ip("10.0.0.2"),
+            policy.client_ip(ip("10.0.0.2"), Some("unknown"))
+        );
+        assert_eq!(
+            ip("203.0.113.9"),
+            policy.client_ip(ip("::ffff:203.0.113.9"), None)
+        );
+    }
+
+    #[test]
+    fn allow_deny_test() {
+        let policy = IpPolicy::default();
+        assert_eq!(true, policy.is_allowed(&ip("203.0.113.9")));
+        assert_eq!(true, policy.is_open());
+
+        let policy = IpPolicy::new(
+            vec![],
+            cidrs(&["10.0.0.0/8", "127.0.0.1"]),
+            cidrs(&["10.6.0.0/16"]),
+        );
+        assert_eq!(true, policy.is_allowed(&ip("10.1.2.3")));
+        assert_eq!(true, policy.is_allowed(&ip("127.0.0.1")));
+        assert_eq!(false, policy.is_allowed(&ip("10.6.2.3")));
+        assert_eq!(false, policy.is_allowed(&ip("203.0.113.9")));
+        assert_eq!(false, policy.is_open());
+
+        let policy = IpPolicy::new(vec![], vec![], cidrs(&["203.0.113.0/24"]));
+        assert_eq!(false, policy.is_allowed(&ip("203.0.113.9")));
+        assert_eq!(true, policy.is_allowed(&ip("198.51.100.1")));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use log::*;
+use serde::Serialize;
+
+use crate::{
+    db::jobs::{self, Job, RetryPolicy},
+    error::{self, ServerError},
+    storage::Pool,
+};
+
+// Queue of the jobs deferred by the request handlers
+pub const DEFAULT_QUEUE: &str = "default";
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+// jobs taken at once by a worker
+const BATCH_SIZE: usize = 10;
+
+pub type JobFuture = Pin<Box<dyn Future<Output = error::Result<()>> + Send>>;
+type Handler = Arc<dyn Fn(Job) -> JobFuture + Send + Sync>;
+
+// Deferred work kept in a Redis stream and run by a pool of worker tasks.
+// Each kind of job has its handler, a job failing or whose worker died is
+// retried by any worker after a delay, and goes to the dead-letter queue
+// once out of attempts. Handlers must be idempotent, a job may run twice.
+#[derive(Clone)]
+pub struct JobQueue {
+    name: String,
+    pool: Pool,
+    policy: RetryPolicy,
+    poll_interval: Duration,
+    handlers: HashMap<String, Handler>,
+}
+
+impl JobQueue {
+    pub fn new(pool: Pool, name: &str) -> Self {
+        JobQueue {
+            name: name.to_owned(),
+            pool,
+            policy: RetryPolicy::default(),
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // How long idle workers wait before looking for jobs again
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn handler<F, Fut>(mut self, kind: &str, f: F) -> Self
+    where
+        F: Fn(Job) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = error::Result<()>> + Send + 'static,
+    {
+        self.handlers
+            .insert(kind.to_owned(), Arc::new(move |job| Box::pin(f(job))));
+        self
+    }
+
+    // Queue a job, its payload is handed to the handler as JSON
+    pub fn enqueue<T: Serialize>(&self, kind: &str, payload: &T) -> error::Result<String> {
+        let payload = serde_json::to_string(payload)
+            .map_err(|e| ServerError::new(error::INTERNAL_ERROR, &e.to_string()))?;
+        jobs::enqueue(&mut *self.pool.get()?, &self.name, kind, &payload)
+    }
+
+    // Spawn `workers` tasks running the jobs until the process exits
+    pub fn start(self, workers: usize) {
+        if workers == 0 {
+            return;
+        }
+        info!(
+            "Starting {} workers on the {} job queue",
+            workers, self.name
+        );
+        let queue = Arc::new(self);
+        for n in 0..workers {
+            let consumer = format!("{}-{}", std::process::id(), n);
+            tokio::spawn(queue.clone().work(consumer));
+        }
+    }
+
+    async fn work(self: Arc<Self>, consumer: String) {
+        let mut has_group = false;
+        loop {
+            let next = self
+                .pool
+                .get()
+                .map_err(ServerError::from)
+                .and_then(|mut c| {
+                    if !has_group {
+                        jobs::create_group(&mut *c, &self.name)?;
+                        has_group = true;
+                    }
+                    // retries first, they have been waiting the longest
+                    let mut next =
+                        jobs::reclaim(&mut *c, &self.name, &consumer, &self.policy, BATCH_SIZE)?;
+                    if next.is_empty() {
+                        next = jobs::claim(&mut *c, &self.name, &consumer, BATCH_SIZE)?;
+                    }
+                    Ok(next)
+                });
+            match next {
+                Ok(next) if !next.is_empty() => {
+                    for job in next {
+                        self.run(job).await;
+                    }
+                }
+                Ok(_) => tokio::time::delay_for(self.poll_interval).await,
+                Err(e) => {
+                    warn!("Could not get jobs from the {} queue: {}", self.name, e);
+                    tokio::time::delay_for(self.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    async fn run(&self, job: Job) {
+        debug!("Running job {} of kind {}", job.id, job.kind);
+        let res = match self.handlers.get(&job.kind) {
+            Some(handler) => handler(job.clone()).await,
+            None => Err(ServerError::new(
+                error::INTERNAL_ERROR,
+                &format!("No handler for jobs of kind {}", job.kind),
+            )),
+        };
+        let recorded = self
+            .pool
+            .get()
+            .map_err(ServerError::from)
+            .and_then(|mut c| match res {
+                Ok(()) => jobs::complete(&mut *c, &self.name, &job),
+                Err(e) => jobs::fail(&mut *c, &self.name, &job, &e.msg),
+            });
+        // the job stays pending and is run again
+        if let Err(e) = recorded {
+            error!("Could not record the outcome of job {}: {}", job.id, e);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use serde::de::DeserializeOwned;
+use serde_path_to_error::Path;
+
+use crate::{
+    error::{self, Result, ServerError},
+    types::InvalidBody,
+};
+
+const JSON_MIME: &str = "application/json";
+
+// JSON endpoints refuse other content types, a missing one is taken for JSON
+pub fn check_content_type(content_type: Option<&str>) -> Result<()> {
+    match content_type.map(|c| c.split(';').next().unwrap_or_default().trim()) {
+        Some(mime) if !mime.eq_ignore_ascii_case(JSON_MIME) => Err(ServerError::new(
+            error::UNSUPPORTED_BODY,
+            &format!(
+                "Unsupported content type `{}`, accepted: {}",
+                mime, JSON_MIME
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The body of a JSON endpoint, or what is wrong with it
+pub fn parse<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, InvalidBody> {
+    let mut de = serde_json::Deserializer::from_slice(body);
+    let value = serde_path_to_error::deserialize(&mut de)
+        .map_err(|e| invalid_body(Some(e.path()), e.inner()))?;
+    // anything after the value, which is a syntax error
+    de.end().map_err(|e| invalid_body(None, &e))?;
+    Ok(value)
+}
+
+// Read serde's message, whose wording is the same for every format. `path`
+// is where it stopped, like `products[0].name`, with no segment at the top
+fn invalid_body(path: Option<&Path>, e: &serde_json::Error) -> InvalidBody {
+    let msg = e.to_string();
+    if e.is_syntax() || e.is_eof() {
+        return InvalidBody::new(String::new(), None, "syntax".to_owned(), msg);
+    }
+    // an empty path is written `.`
+    let path = match path {
+        Some(path) if path.iter().next().is_some() => path.to_string(),
+        _ => String::new(),
+    };
+    let expected = msg
+        .rsplitn(2, " at line ")
+        .last()
+        .and_then(|m| m.splitn(2, ", expected ").nth(1))
+        .map(str::to_owned);
+    let quoted = || msg.split('`').nth(1).unwrap_or_default();
+    let (field, constraint) = if msg.starts_with("missing field") {
+        let field = match path.as_str() {
+            "" => quoted().to_owned(),
+            path => format!("{}.{}", path, quoted()),
+        };
+        (field, "required")
+    } else if msg.starts_with("unknown field") {
+        (path, "unknown")
+    } else if msg.starts_with("duplicate field") {
+        (path, "duplicate")
+    } else if msg.starts_with("invalid type") {
+        (path, "type")
+    } else if msg.starts_with("invalid length") {
+        (path, "length")
+    } else {
+        (path, "value")
+    };
+    InvalidBody::new(field, expected, constraint.to_owned(), msg)
+}
+
+// A body rejected by `parse`, replied with `error::INVALID_BODY`
+#[derive(Debug)]
+pub struct InvalidBodyRejection(pub InvalidBody);
+
+impl warp::reject::Reject for InvalidBodyRejection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+
+    fn invalid<T: DeserializeOwned>(body: &str) -> (String, Option<String>, String) {
+        let e = parse::<T>(body.as_bytes()).err().unwrap();
+        (e.field, e.expected, e.constraint)
+    }
+
+    fn expected(
+        field: &str,
+        expected: Option<&str>,
+        constraint: &str,
+    ) -> (String, Option<String>, String) {
+        (
+            field.to_owned(),
+            expected.map(str::to_owned),
+            constraint.to_owned(),
+        )
+    }
+
+    #[test]
+    fn check_content_type_test() {
+        assert_eq!(Ok(()), check_content_type(None));
+        assert_eq!(
+            Ok(()),
+            check_content_type(Some("Application/JSON; charset=utf-8"))
+        );
+        assert_eq!(
+            Err(error::UNSUPPORTED_BODY),
+            check_content_type(Some("text/plain")).map_err(|e| e.status)
+        );
+    }
+
+    #[test]
+    fn parse_test() {
+        let data = parse::<NameData>(br#"{"name": "milk"}"#);
+        assert_eq!(Ok("milk".to_owned()), data.map(|d| d.name));
+
+        assert_eq!(
+            expected("name", Some("a string"), "type"),
+            invalid::<NameData>(r#"{"name": 1}"#)
+        );
+        assert_eq!(
+            expected("nam", Some("`name`"), "unknown"),
+            invalid::<NameData>(r#"{"nam": "milk"}"#)
+        );
+        assert_eq!(
+            expected("name", None, "required"),
+            invalid::<NameData>(r#"{}"#)
+        );
+        assert_eq!(
+            expected("", None, "syntax"),
+            invalid::<NameData>(r#"{"name": "#)
+        );
+        assert_eq!(
+            expected("", None, "syntax"),
+            invalid::<NameData>(r#"{"name": "milk"} {}"#)
+        );
+        assert_eq!(
+            expected("quantity", Some("u32"), "value"),
+            invalid::<EditProduct>(r#"{"quantity": -1}"#)
+        );
+
+        let body = r#"{"products": [], "removed": ["a", "b"]}"#;
+        assert!(parse::<CrdtStore>(body.as_bytes()).is_ok());
+        let body = r#"{
+            "products": [{}],
+            "removed": ["a", 2]
+        }"#;
+        assert_eq!(
+            expected("products[0].product_id", None, "required"),
+            invalid::<CrdtStore>(body)
+        );
+        let body = r#"{"removed": ["a", 2]}"#;
+        assert_eq!(
+            expected("removed[1]", Some("a string"), "type"),
+            invalid::<CrdtStore>(body)
+        );
+        let body = r#"{"products": [{"product_id": "p\"1", "aisle_id": "a", "name": {"value": 1"#;
+        assert_eq!(
+            expected("products[0].name.value", Some("a string"), "type"),
+            invalid::<CrdtStore>(body)
+        );
+    }
+}
@@ -0,0 +1,34 @@
+pub mod archive;
+pub mod breaker;
+pub mod challenge;
+#[cfg(not(test))]
+pub mod cli;
+pub mod config;
+pub mod conn_limit;
+pub mod cron;
+pub mod db;
+#[cfg(not(test))]
+pub mod endpoints;
+pub mod error;
+#[cfg(not(test))]
+mod grpc;
+pub mod ip_filter;
+#[cfg(not(test))]
+pub mod jobs;
+pub mod json_body;
+pub mod links;
+pub mod nutrition;
+pub mod print;
+pub mod projection;
+pub mod quick_add;
+pub mod redact;
+pub mod retry;
+pub mod route_table;
+#[cfg(not(test))]
+pub mod scheduler;
+pub mod schema;
+pub mod storage;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tokens;
+pub mod types;
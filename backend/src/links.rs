@@ -0,0 +1,242 @@
+use std::{
+    fmt::Write,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use blake2::{digest::consts::U32, Blake2b};
+use hex_view::HexView;
+use hmac::{
+    digest::{core_api::BlockSizeUser, Digest},
+    Mac, SimpleHmac,
+};
+use lazy_static::lazy_static;
+use rand::Rng;
+
+use crate::{
+    error::{self, ServerError},
+    types::{ExportLink, ShareLink},
+};
+
+const MAC_LEN: usize = 32;
+pub const EXPORT_LINK_TTL_S: u64 = 300;
+// long enough for whoever the list is sent to to do the shopping
+pub const SHARE_LINK_TTL_S: u64 = 7 * 24 * 3600;
+
+lazy_static! {
+    // random unless set: links then die with the process and only work on the
+    // instance that made them
+    static ref SECRET: RwLock<Vec<u8>> = {
+        let mut secret = vec![0u8; MAC_LEN];
+        rand::thread_rng().fill(&mut secret[..]);
+        RwLock::new(secret)
+    };
+}
+
+pub fn set_secret(secret: &[u8]) {
+    *SECRET.write().unwrap() = secret.to_vec();
+}
+
+pub fn now_s() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// What our own signatures and the email index are made with
+pub(crate) type Blake2b256 = Blake2b<U32>;
+
+// HMAC (RFC 2104) over the hash `D`
+pub(crate) fn hmac<D: Digest + BlockSizeUser + Default>(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut mac = SimpleHmac::<D>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(msg);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// `kind` is the route the link is for, so that a link can't be used on
+// another one
+fn signature(kind: &str, store_id: &str, expires_at: u64) -> String {
+    let msg = format!("{}:{}:{}", kind, store_id, expires_at);
+    let mac = hmac::<Blake2b256>(&SECRET.read().unwrap(), msg.as_bytes());
+    format!("{:x}", HexView::from(&mac[..]))
+}
+
+fn link_url(kind: &str, store_id: &str, expires_at: u64) -> String {
+    format!(
+        "/api/store/{}/{}?expires={}&sig={}",
+        store_id,
+        kind,
+        expires_at,
+        signature(kind, store_id, expires_at)
+    )
+}
+
+fn verify(kind: &str, store_id: &str, expires_at: u64, sig: &str, now: u64) -> error::Result<()> {
+    if now >= expires_at {
+        Err(ServerError::new(error::UNAUTHORISED, "Link expired"))
+    } else if constant_time_eq(&signature(kind, store_id, expires_at), sig) {
+        Ok(())
+    } else {
+        Err(ServerError::new(error::UNAUTHORISED, "Invalid link"))
+    }
+}
+
+pub fn export_link(store_id: &str, now: u64) -> ExportLink {
+    let expires_at = now + EXPORT_LINK_TTL_S;
+    ExportLink {
+        url: link_url("export", store_id, expires_at),
+        expires_at,
+    }
+}
+
+pub fn verify_export(store_id: &str, expires_at: u64, sig: &str, now: u64) -> error::Result<()> {
+    verify("export", store_id, expires_at, sig, now)
+}
+
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => {
+                let _ = write!(encoded, "%{:02X}", b);
+            }
+        }
+    }
+    encoded
+}
+
+// The list as text followed by a read-only link to its latest state, with
+// links opening it in a new text message. `origin` is the web app's, which
+// the server can't know behind a proxy.
+pub fn share_link(store_id: &str, list: &str, origin: &str, now: u64) -> ShareLink {
+    let expires_at = now + SHARE_LINK_TTL_S;
+    let url = format!(
+        "{}{}",
+        origin.trim_end_matches('/'),
+        link_url("shared", store_id, expires_at)
+    );
+    let text = format!("{}\n\n{}", list, url);
+    let message = percent_encode(&text);
+    ShareLink {
+        sms: format!("sms:?body={}", message),
+        whatsapp: format!("https://wa.me/?text={}", message),
+        url,
+        text,
+        expires_at,
+    }
+}
+
+pub fn verify_share(store_id: &str, expires_at: u64, sig: &str, now: u64) -> error::Result<()> {
+    verify("shared", store_id, expires_at, sig, now)
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sig(link: &ExportLink) -> &str {
+        link.url.rsplit("sig=").next().unwrap()
+    }
+
+    fn hex(bytes: Vec<u8>) -> String {
+        format!("{:x}", HexView::from(&bytes[..]))
+    }
+
+    #[test]
+    fn hmac_test() {
+        // same as Python's hmac.new(key, msg, lambda: hashlib.blake2b(digest_size=32))
+        assert_eq!(
+            "bb3e1cd6f38b5df1cb87983ec29d6116587c1b9bf6e5cd167ac7f2bc741d3817",
+            hex(hmac::<Blake2b256>(
+                b"key",
+                b"The quick brown fox jumps over the lazy dog"
+            ))
+        );
+        // a key longer than a block is hashed first
+        assert_eq!(
+            "79959adcbb87266c5ceeec73fc0600e5e4e1b8ae8490fd28b26fa10acb976014",
+            hex(hmac::<Blake2b256>(&[0xaa; 131], b"msg"))
+        );
+        assert_eq!(
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+            hex(hmac::<sha2::Sha256>(
+                b"key",
+                b"The quick brown fox jumps over the lazy dog"
+            ))
+        );
+    }
+
+    #[test]
+    fn export_link_test() {
+        let link = export_link("42", 1000);
+        assert_eq!(1000 + EXPORT_LINK_TTL_S, link.expires_at);
+        assert_eq!(
+            true,
+            link.url
+                .starts_with("/api/store/42/export?expires=1300&sig=")
+        );
+        assert_eq!(
+            Ok(()),
+            verify_export("42", link.expires_at, sig(&link), 1299)
+        );
+
+        let status = |res: error::Result<()>| res.err().map(|e| e.msg);
+        assert_eq!(
+            Some("Link expired".to_owned()),
+            status(verify_export("42", link.expires_at, sig(&link), 1300))
+        );
+        // another store, or a pushed back expiry, need another signature
+        assert_eq!(
+            Some("Invalid link".to_owned()),
+            status(verify_export("43", link.expires_at, sig(&link), 1000))
+        );
+        assert_eq!(
+            Some("Invalid link".to_owned()),
+            status(verify_export("42", link.expires_at + 1, sig(&link), 1000))
+        );
+    }
+
+    #[test]
+    fn share_link_test() {
+        let link = share_link(
+            "42",
+            "Market\n\nDairy:\n- Milk",
+            "https://efficio.app/",
+            1000,
+        );
+        let expires_at = 1000 + SHARE_LINK_TTL_S;
+        assert_eq!(expires_at, link.expires_at);
+        let prefix = format!(
+            "https://efficio.app/api/store/42/shared?expires={}&sig=",
+            expires_at
+        );
+        assert_eq!(true, link.url.starts_with(&prefix));
+        assert_eq!(
+            format!("Market\n\nDairy:\n- Milk\n\n{}", link.url),
+            link.text
+        );
+        assert_eq!(
+            true,
+            link.whatsapp.starts_with(
+                "https://wa.me/?text=Market%0A%0ADairy%3A%0A-%20Milk%0A%0Ahttps%3A%2F%2F"
+            )
+        );
+        assert_eq!(true, link.sms.starts_with("sms:?body=Market%0A"));
+
+        let sig = link.url.rsplit("sig=").next().unwrap();
+        assert_eq!(Ok(()), verify_share("42", expires_at, sig, 1000));
+        // not a download link
+        assert_eq!(true, verify_export("42", expires_at, sig, 1000).is_err());
+    }
+}
@@ -1,17 +1,24 @@
-#[cfg(not(test))]
-mod cli;
-mod db;
-#[cfg(not(test))]
-mod endpoints;
-mod error;
-mod types;
+use efficio_server::{cli, db, endpoints, error, redact};
 
-#[cfg(not(test))]
-#[tokio::main]
-async fn main() -> error::Result<()> {
-    pretty_env_logger::init_timed();
+fn main() -> error::Result<()> {
+    redact::init_logger();
 
     log::info!("Starting Efficio…");
     let opt: cli::Opt = argh::from_env();
-    endpoints::routes::start_server(&opt).await
+    let workers = num_cpus::get().max(1);
+    tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .core_threads(workers)
+        .enable_all()
+        .build()
+        .map_err(|e| {
+            error::ServerError::new(
+                error::INTERNAL_ERROR,
+                &format!("Cannot start the runtime: {}", e),
+            )
+        })?
+        .block_on(async {
+            db::locks::mark_workers(workers).await;
+            endpoints::routes::start_server(&opt).await
+        })
 }
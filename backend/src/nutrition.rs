@@ -0,0 +1,231 @@
+use std::{fmt, future::Future, pin::Pin, str::FromStr, sync::Arc};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::{self, ServerError},
+    types::Nutrition,
+};
+
+const OPEN_FOOD_FACTS_URL: &str = "https://world.openfoodfacts.org";
+
+pub type LookupFuture = Pin<Box<dyn Future<Output = error::Result<Option<Nutrition>>> + Send>>;
+
+// What to look a product up by: products only have a name, barcodes come
+// from clients scanning one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    Barcode(String),
+    Name(String),
+}
+
+impl Lookup {
+    // the same for names differing only in case or surrounding spaces
+    pub fn cache_key(&self) -> String {
+        match self {
+            Lookup::Barcode(code) => format!("barcode:{}", code),
+            Lookup::Name(name) => format!("name:{}", name.trim().to_lowercase()),
+        }
+    }
+}
+
+// Where nutrition facts come from, None when the source knows nothing of the
+// product
+pub trait NutritionSource: fmt::Debug + Send + Sync {
+    fn lookup(&self, lookup: &Lookup) -> LookupFuture;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NutritionKind {
+    None,
+    OpenFoodFacts,
+}
+
+impl Default for NutritionKind {
+    fn default() -> Self {
+        NutritionKind::None
+    }
+}
+
+impl FromStr for NutritionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NutritionKind::None),
+            "openfoodfacts" => Ok(NutritionKind::OpenFoodFacts),
+            _ => Err(format!("{}: expected none or openfoodfacts", s)),
+        }
+    }
+}
+
+// The source of the settings, None if lookups are off. An empty `url` is the
+// source's public instance.
+pub fn build(kind: NutritionKind, url: &str) -> Option<Arc<dyn NutritionSource>> {
+    match kind {
+        NutritionKind::None => None,
+        NutritionKind::OpenFoodFacts => {
+            let url = if url.is_empty() {
+                OPEN_FOOD_FACTS_URL
+            } else {
+                url
+            };
+            Some(Arc::new(OpenFoodFacts::new(url)))
+        }
+    }
+}
+
+// The Open Food Facts database, by barcode or by its search's best match for
+// the name
+#[derive(Debug)]
+pub struct OpenFoodFacts {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl OpenFoodFacts {
+    pub fn new(url: &str) -> Self {
+        OpenFoodFacts {
+            url: url.trim_end_matches('/').to_owned(),
+            http: reqwest::Client::new(),
+        }
+    }
+}
+
+// numbers are sometimes sent as strings
+fn number(value: &Value) -> Option<f32> {
+    match value {
+        Value::Number(n) => n.as_f64().map(|n| n as f32),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// The facts of an Open Food Facts product, None without any
+fn parse_product(product: &Value) -> Option<Nutrition> {
+    let nutriments = product.get("nutriments")?;
+    let per_100g = |field: &str| nutriments.get(&format!("{}_100g", field)).and_then(number);
+    let nutrition = Nutrition {
+        name: product
+            .get("product_name")
+            .and_then(Value::as_str)
+            .filter(|name| !name.trim().is_empty())
+            .map(str::to_owned),
+        energy_kcal: per_100g("energy-kcal"),
+        fat_g: per_100g("fat"),
+        carbohydrates_g: per_100g("carbohydrates"),
+        sugars_g: per_100g("sugars"),
+        proteins_g: per_100g("proteins"),
+        salt_g: per_100g("salt"),
+    };
+    if nutrition.has_facts() {
+        Some(nutrition)
+    } else {
+        None
+    }
+}
+
+// `{"status": 1, "product": {..}}` for a barcode, `{"products": [..]}` for a
+// search
+fn parse_reply(reply: &Value) -> Option<Nutrition> {
+    match reply.get("products") {
+        Some(products) => products.as_array()?.iter().find_map(parse_product),
+        None if reply.get("status").and_then(Value::as_i64) == Some(1) => {
+            parse_product(reply.get("product")?)
+        }
+        None => None,
+    }
+}
+
+impl NutritionSource for OpenFoodFacts {
+    fn lookup(&self, lookup: &Lookup) -> LookupFuture {
+        let request = match lookup {
+            Lookup::Barcode(code) => self
+                .http
+                .get(&format!("{}/api/v0/product/{}.json", self.url, code)),
+            Lookup::Name(name) => self
+                .http
+                .get(&format!("{}/cgi/search.pl", self.url))
+                .query(&[
+                    ("search_terms", name.trim()),
+                    ("search_simple", "1"),
+                    ("action", "process"),
+                    ("json", "1"),
+                    ("page_size", "5"),
+                ]),
+        };
+        Box::pin(async move {
+            let failed = |e: reqwest::Error| {
+                warn!("Could not look up nutrition facts: {}", e);
+                ServerError::new(
+                    error::NUTRITION_UNAVAILABLE,
+                    "Could not look up nutrition facts",
+                )
+            };
+            let reply: Value = request
+                .send()
+                .await
+                .map_err(failed)?
+                .error_for_status()
+                .map_err(failed)?
+                .json()
+                .await
+                .map_err(failed)?;
+            Ok(parse_reply(&reply))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_reply_test() {
+        let product = json!({
+            "product_name": "Nutella",
+            "nutriments": {
+                "energy-kcal_100g": 539,
+                "fat_100g": "30.9",
+                "sugars_100g": 56.3,
+                "salt_100g": null
+            }
+        });
+        let expected = Nutrition {
+            name: Some("Nutella".to_owned()),
+            energy_kcal: Some(539.0),
+            fat_g: Some(30.9),
+            sugars_g: Some(56.3),
+            ..Nutrition::default()
+        };
+        assert_eq!(
+            Some(expected.clone()),
+            parse_reply(&json!({ "status": 1, "product": product }))
+        );
+        assert_eq!(None, parse_reply(&json!({ "status": 0 })));
+
+        // the first search result with facts
+        let bare = json!({ "product_name": "Nutella", "nutriments": {} });
+        assert_eq!(
+            Some(expected),
+            parse_reply(&json!({ "products": [bare, product] }))
+        );
+        assert_eq!(None, parse_reply(&json!({ "products": [] })));
+    }
+
+    #[test]
+    fn cache_key_test() {
+        assert_eq!(
+            "name:nutella",
+            Lookup::Name(" Nutella".to_owned()).cache_key()
+        );
+        assert_eq!(
+            "barcode:3017620422003",
+            Lookup::Barcode("3017620422003".to_owned()).cache_key()
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::{cmp::Ordering, fmt::Write};
+
+use crate::types::*;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+h1{font-size:1.6em}h2{font-size:1.2em;border-bottom:1px solid #888;margin-top:1.2em}\
+ul{list-style:none;padding:0;columns:2}li{margin:.3em 0;break-inside:avoid}\
+.done{text-decoration:line-through;color:#666}\
+@media print{body{margin:0}}";
+
+fn by_weight(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn quantity(product: &Product) -> String {
+    match product.unit {
+        Unit::Unit if product.quantity <= 1 => String::new(),
+        Unit::Unit => format!("{} × ", product.quantity),
+        Unit::Gram => format!("{} g ", product.quantity),
+        Unit::Ml => format!("{} ml ", product.quantity),
+    }
+}
+
+// Standalone page listing the products aisle by aisle in the order of the
+// app, with a box to tick on paper. Products already done are left out
+// unless `include_done`, aisles with nothing left too.
+pub fn store_html(store: &Store, include_done: bool) -> String {
+    let name = escape(&store.name);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        name, STYLE, name
+    );
+    let mut aisles: Vec<&Aisle> = store.aisles.iter().collect();
+    aisles.sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+    for aisle in aisles {
+        let mut products: Vec<&Product> = aisle
+            .products
+            .iter()
+            .filter(|p| include_done || !p.is_done)
+            .collect();
+        if products.is_empty() {
+            continue;
+        }
+        products.sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+        let _ = write!(html, "<h2>{}</h2>\n<ul>\n", escape(&aisle.name));
+        for product in products {
+            let (class, checked) = if product.is_done {
+                (" class=\"done\"", " checked")
+            } else {
+                ("", "")
+            };
+            let _ = writeln!(
+                html,
+                "<li{}><label><input type=\"checkbox\"{}> {}{}</label></li>",
+                class,
+                checked,
+                quantity(product),
+                escape(&product.name)
+            );
+        }
+        html.push_str("</ul>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+// What is left to buy, aisle by aisle then the products needed anywhere,
+// short enough to paste in a text message
+pub fn store_text(store: &Store) -> String {
+    let mut text = store.name.clone();
+    let mut aisles: Vec<&Aisle> = store.aisles.iter().collect();
+    aisles.sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+    for aisle in aisles {
+        let mut products: Vec<&Product> = aisle.products.iter().filter(|p| !p.is_done).collect();
+        if products.is_empty() {
+            continue;
+        }
+        products.sort_by(|a, b| by_weight(a.sort_weight, b.sort_weight));
+        let _ = write!(text, "\n\n{}:", aisle.name);
+        for product in products {
+            let _ = write!(text, "\n- {}{}", quantity(product), product.name);
+        }
+    }
+    let anywhere: Vec<&Product> = store.anywhere.iter().filter(|p| !p.is_done).collect();
+    if !anywhere.is_empty() {
+        text.push_str("\n\nAnywhere:");
+        for product in anywhere {
+            let _ = write!(text, "\n- {}{}", quantity(product), product.name);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(name: &str, quantity: u32, unit: Unit, is_done: bool, sort_weight: f32) -> Product {
+        Product {
+            product_id: name.to_owned(),
+            name: name.to_owned(),
+            quantity,
+            is_done,
+            unit,
+            sort_weight,
+        }
+    }
+
+    fn aisle(name: &str, sort_weight: f32, products: Vec<Product>) -> Aisle {
+        Aisle {
+            aisle_id: name.to_owned(),
+            name: name.to_owned(),
+            sort_weight,
+            products,
+        }
+    }
+
+    #[test]
+    fn store_html_test() {
+        let store = Store::new(
+            "s1".to_owned(),
+            "Tom & Jerry's <market>".to_owned(),
+            vec![
+                aisle(
+                    "Dairy",
+                    2.0,
+                    vec![
+                        product("Milk", 500, Unit::Ml, false, 2.0),
+                        product("Eggs", 12, Unit::Unit, false, 1.0),
+                    ],
+                ),
+                aisle(
+                    "Fruits",
+                    1.0,
+                    vec![product("Apple", 1, Unit::Unit, true, 1.0)],
+                ),
+                aisle("Empty", 3.0, vec![]),
+            ],
+        );
+        let html = store_html(&store, false);
+        assert_eq!(
+            true,
+            html.contains("<h1>Tom &amp; Jerry&#39;s &lt;market&gt;</h1>")
+        );
+        assert_eq!(false, html.contains("Apple"));
+        assert_eq!(false, html.contains("Fruits"));
+        assert_eq!(false, html.contains("Empty"));
+        let eggs = html.find("12 × Eggs").unwrap();
+        assert_eq!(true, eggs < html.find("500 ml Milk").unwrap());
+
+        let html = store_html(&store, true);
+        assert_eq!(
+            true,
+            html.contains(
+                "<li class=\"done\"><label><input type=\"checkbox\" checked> Apple</label></li>"
+            )
+        );
+        assert_eq!(
+            true,
+            html.find("Fruits").unwrap() < html.find("Dairy").unwrap()
+        );
+    }
+
+    #[test]
+    fn store_text_test() {
+        let store = Store::new(
+            "s1".to_owned(),
+            "Market".to_owned(),
+            vec![
+                aisle(
+                    "Dairy",
+                    2.0,
+                    vec![
+                        product("Milk", 500, Unit::Ml, false, 2.0),
+                        product("Eggs", 12, Unit::Unit, false, 1.0),
+                    ],
+                ),
+                aisle(
+                    "Fruits",
+                    1.0,
+                    vec![product("Apple", 1, Unit::Unit, true, 1.0)],
+                ),
+                aisle(
+                    "Bakery",
+                    0.0,
+                    vec![product("Bread", 1, Unit::Unit, false, 1.0)],
+                ),
+            ],
+        );
+        assert_eq!(
+            "Market\n\nBakery:\n- Bread\n\nDairy:\n- 12 × Eggs\n- 500 ml Milk",
+            store_text(&store)
+        );
+
+        let mut store = store;
+        store.anywhere = vec![product("Stamps", 10, Unit::Unit, false, 0.0)];
+        assert_eq!(
+            true,
+            store_text(&store).ends_with("500 ml Milk\n\nAnywhere:\n- 10 × Stamps")
+        );
+    }
+}
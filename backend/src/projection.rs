@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::{self, Result, ServerError};
+
+// Tree of selected fields, a leaf keeps the whole value
+#[derive(Debug, Default)]
+struct Selection(BTreeMap<String, Selection>);
+
+impl Selection {
+    fn parse(fields: &str) -> Self {
+        let mut root = Selection::default();
+        for path in fields.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            path.split('.').fold(&mut root, |selection, part| {
+                selection.0.entry(part.to_owned()).or_default()
+            });
+        }
+        root
+    }
+
+    fn get<'a>(&'a self, key: &str, root: &'a Selection) -> Option<&'a Selection> {
+        // a path starting with a collection name applies wherever that collection appears
+        self.0
+            .get(key)
+            .or_else(|| root.0.get(key).filter(|s| !s.0.is_empty()))
+    }
+}
+
+// Serialize `data` and keep only the fields listed in `fields`, a comma separated
+// list of dotted paths like `aisles.name,products.is_done`. An empty list keeps
+// everything.
+pub fn project<T: Serialize>(data: &T, fields: &str) -> Result<Value> {
+    let value = serde_json::to_value(data)
+        .map_err(|e| ServerError::new(error::INTERNAL_ERROR, &e.to_string()))?;
+    let root = Selection::parse(fields);
+    if root.0.is_empty() {
+        Ok(value)
+    } else {
+        Ok(prune(value, &root, &root))
+    }
+}
+
+fn prune(value: Value, selection: &Selection, root: &Selection) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|v| prune(v, selection, root))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter_map(|(k, v)| {
+                    selection.get(&k, root).map(|sub| {
+                        if sub.0.is_empty() {
+                            (k, v)
+                        } else {
+                            (k, prune(v, sub, root))
+                        }
+                    })
+                })
+                .collect(),
+        ),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use serde_json::json;
+
+    fn store() -> Store {
+        Store::new(
+            "s1".to_owned(),
+            "MyStore".to_owned(),
+            vec![Aisle::new(
+                "a1".to_owned(),
+                "Aisle1".to_owned(),
+                1f32,
+                vec![Product::new(
+                    "p1".to_owned(),
+                    "product1".to_owned(),
+                    1,
+                    true,
+                    Unit::Unit,
+                    1f32,
+                )],
+            )],
+        )
+    }
+
+    #[test]
+    fn project_nested_collections_test() {
+        let expected = json!({
+            "aisles": [{ "name": "Aisle1", "products": [{ "is_done": true }] }]
+        });
+        assert_eq!(
+            Ok(expected),
+            project(&store(), "aisles.name,products.is_done")
+        );
+    }
+
+    #[test]
+    fn project_top_level_fields_test() {
+        let expected = json!({ "store_id": "s1", "name": "MyStore" });
+        assert_eq!(Ok(expected), project(&store(), "store_id, name"));
+        // a leaf collection is kept as a whole
+        let expected = json!({ "aisles": serde_json::to_value(&store().aisles).unwrap() });
+        assert_eq!(Ok(expected), project(&store(), "aisles"));
+    }
+
+    #[test]
+    fn project_empty_fields_test() {
+        assert_eq!(
+            serde_json::to_value(&store()).ok(),
+            project(&store(), "").ok()
+        );
+        assert_eq!(Ok(json!({})), project(&store(), "unknown"));
+    }
+}
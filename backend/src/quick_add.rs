@@ -0,0 +1,117 @@
+use crate::types::Unit;
+
+// A product typed or said in one go, like `2 litres of milk`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuickItem {
+    pub name: String,
+    pub quantity: u32,
+    pub unit: Unit,
+}
+
+const NUMBERS: &[&str] = &[
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    "eleven", "twelve",
+];
+
+fn number(word: &str) -> Option<u32> {
+    match word {
+        "a" | "an" => Some(1),
+        "dozen" => Some(12),
+        _ => word
+            .parse()
+            .ok()
+            .or_else(|| NUMBERS.iter().position(|n| *n == word).map(|n| n as u32)),
+    }
+}
+
+// Unit of a word and what to multiply the quantity by to get it
+fn unit(word: &str) -> Option<(Unit, u32)> {
+    match word {
+        "g" | "gram" | "grams" | "gramme" | "grammes" => Some((Unit::Gram, 1)),
+        "kg" | "kilo" | "kilos" | "kilogram" | "kilograms" => Some((Unit::Gram, 1000)),
+        "ml" | "millilitre" | "millilitres" | "milliliter" | "milliliters" => Some((Unit::Ml, 1)),
+        "cl" | "centilitre" | "centilitres" | "centiliter" | "centiliters" => Some((Unit::Ml, 10)),
+        "l" | "litre" | "litres" | "liter" | "liters" => Some((Unit::Ml, 1000)),
+        _ => None,
+    }
+}
+
+// `milk`, `3 eggs`, `two kilos of potatoes`, `500g flour`. The quantity is 1
+// when there is none, None if nothing is left for the name.
+pub fn parse(text: &str) -> Option<QuickItem> {
+    let text = text.trim().to_lowercase();
+    let mut words: Vec<String> = text.split_whitespace().map(str::to_owned).collect();
+    // `500g` is `500 g`
+    if let Some(first) = words.first().cloned() {
+        if let Some(split) = first.find(|c: char| !c.is_ascii_digit()).filter(|i| *i > 0) {
+            if unit(&first[split..]).is_some() {
+                words.splice(
+                    0..1,
+                    vec![first[..split].to_owned(), first[split..].to_owned()],
+                );
+            }
+        }
+    }
+
+    let mut rest = &words[..];
+    let mut quantity = 1;
+    if let Some(n) = rest.first().and_then(|w| number(w)) {
+        quantity = n;
+        rest = &rest[1..];
+    }
+    let mut item_unit = Unit::Unit;
+    if let Some((u, factor)) = rest.first().and_then(|w| unit(w)) {
+        item_unit = u;
+        quantity = quantity.saturating_mul(factor);
+        rest = &rest[1..];
+    }
+    if rest.first().map_or(false, |w| w == "of") {
+        rest = &rest[1..];
+    }
+    if rest.is_empty() || quantity == 0 {
+        return None;
+    }
+    Some(QuickItem {
+        name: rest.join(" "),
+        quantity,
+        unit: item_unit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str, quantity: u32, unit: Unit) -> Option<QuickItem> {
+        Some(QuickItem {
+            name: name.to_owned(),
+            quantity,
+            unit,
+        })
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(item("milk", 1, Unit::Unit), parse("Milk"));
+        assert_eq!(item("eggs", 3, Unit::Unit), parse("3 eggs"));
+        assert_eq!(item("apples", 2, Unit::Unit), parse("  two apples "));
+        assert_eq!(
+            item("bottle of wine", 1, Unit::Unit),
+            parse("a bottle of wine")
+        );
+        assert_eq!(
+            item("potatoes", 2000, Unit::Gram),
+            parse("two kilos of potatoes")
+        );
+        assert_eq!(item("flour", 500, Unit::Gram), parse("500g flour"));
+        assert_eq!(
+            item("oat milk", 1000, Unit::Ml),
+            parse("1 litre of oat milk")
+        );
+        // not a unit, part of the name
+        assert_eq!(item("7up", 1, Unit::Unit), parse("7up"));
+        assert_eq!(None, parse("two"));
+        assert_eq!(None, parse("0 eggs"));
+        assert_eq!(None, parse(" "));
+    }
+}
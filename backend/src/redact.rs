@@ -0,0 +1,179 @@
+use std::{panic, sync::RwLock};
+
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+use pretty_env_logger::env_logger::filter::{self, Filter};
+use regex::Regex;
+
+lazy_static! {
+    // `password=…`, `"pwd": "…"`, `token: …` and friends, the key is kept
+    static ref KEY_VALUE: Regex = Regex::new(
+        r#"(?i)("?\b(?:password|passwd|pwd|secret|session_token|token|x-auth-token|auth|email|mail)"?\s*[:=]\s*"?)[^\s",}&]+"#
+    )
+    .unwrap();
+    static ref EMAIL: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap();
+    // session tokens and argon2 hashes are long hex strings
+    static ref HEX_SECRET: Regex = Regex::new(r"\b[0-9a-fA-F]{32,}\b").unwrap();
+    // a Redis command with its arguments, up to the end of the line
+    static ref REDIS_COMMAND: Regex = Regex::new(
+        r"\b(?:SET|GET|SETNX|GETSET|DEL|EXISTS|INCR|INCRBY|EXPIRE|PEXPIRE|TTL|HSET|HSETNX|HINCRBY|HMSET|HGET|HGETALL|HDEL|HEXISTS|SADD|SREM|SISMEMBER|SMEMBERS|SCARD|RPUSH|LRANGE|LREM|LTRIM|XADD|XRANGE|XDEL|XGROUP|XREADGROUP|XACK|XPENDING|XCLAIM|MULTI|EXEC|WATCH|EVAL)\b [^\r\n]*"
+    )
+    .unwrap();
+    // RUST_LOG style filters, they can be swapped while running
+    static ref LOG_FILTER: RwLock<Filter> = RwLock::new(filter::Builder::new().build());
+}
+
+pub const REDACTED: &str = "<redacted>";
+
+// Strip passwords, emails, session tokens and Redis commands from a message
+// before it leaves the server, be it in a response, a log line or a panic report
+pub fn redact(msg: &str) -> String {
+    let msg = REDIS_COMMAND.replace_all(msg, "<redis command>");
+    let msg = KEY_VALUE.replace_all(&msg, format!("${{1}}{}", REDACTED).as_str());
+    let msg = EMAIL.replace_all(&msg, "<email>");
+    HEX_SECRET.replace_all(&msg, REDACTED).into_owned()
+}
+
+// Wraps the actual logger so every record is redacted before being written
+pub struct RedactingLogger<L: Log>(pub L);
+
+impl<L: Log> Log for RedactingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        LOG_FILTER.read().unwrap().enabled(metadata) && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !LOG_FILTER.read().unwrap().matches(record) || !self.0.enabled(record.metadata()) {
+            return;
+        }
+        let msg = redact(&record.args().to_string());
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("{}", msg))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+// Replace the filters of the installed logger, like `info,efficio_server=debug`
+pub fn set_log_filters(filters: &str) {
+    let filter = filter::Builder::new().parse(filters).build();
+    log::set_max_level(filter.filter());
+    *LOG_FILTER.write().unwrap() = filter;
+}
+
+pub fn init_logger() {
+    // the inner logger lets everything through, `LOG_FILTER` does the filtering
+    let logger = pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    set_log_filters(&std::env::var("RUST_LOG").unwrap_or_default());
+    if log::set_boxed_logger(Box::new(RedactingLogger(logger))).is_err() {
+        eprintln!("A logger was already installed");
+    }
+    install_panic_hook();
+}
+
+// Panic payloads often carry a formatted value, report them redacted
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|s| (*s).to_owned())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<Any>".to_owned());
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        let thread = std::thread::current();
+        let msg = format!(
+            "thread '{}' panicked at '{}', {}",
+            thread.name().unwrap_or("<unnamed>"),
+            redact(&payload),
+            location
+        );
+        log::error!("{}", msg);
+        eprintln!("{}", msg);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{self, ServerError};
+
+    const TOKEN: &str = "6f1c2a9e0b4d7f3a8c5e1b2d9a0f4c7e6f1c2a9e0b4d7f3a8c5e1b2d9a0f4c7e";
+
+    #[test]
+    fn redact_secrets_test() {
+        assert_eq!(
+            "login failed for <email>",
+            redact("login failed for toto@efficio.app")
+        );
+        assert_eq!(
+            format!("x-auth-token: {} unknown", REDACTED),
+            redact(&format!("x-auth-token: {} unknown", TOKEN))
+        );
+        assert_eq!(
+            format!(r#"{{"username":"toto","password":"{}"}}"#, REDACTED),
+            redact(r#"{"username":"toto","password":"hunter2"}"#)
+        );
+        assert_eq!(
+            format!("session {} expired", REDACTED),
+            redact(&format!("session {} expired", TOKEN))
+        );
+        assert_eq!(
+            "failed: <redis command>",
+            redact("failed: HSET user:1 password s3cr3t")
+        );
+        // ordinary messages go through untouched
+        assert_eq!(
+            "Username toto is not available.",
+            redact("Username toto is not available.")
+        );
+    }
+
+    #[test]
+    fn server_error_redacted_test() {
+        let err = ServerError::new(
+            error::INTERNAL_ERROR,
+            &format!("Auth {} already exists for toto@efficio.app", TOKEN),
+        );
+        assert_eq!(false, err.msg.contains(TOKEN));
+        assert_eq!(false, err.msg.contains("toto@efficio.app"));
+        assert_eq!(false, err.to_string().contains(TOKEN));
+
+        let redis_err: redis::RedisError = (
+            redis::ErrorKind::ResponseError,
+            "WRONGTYPE",
+            "HGET sessions password=hunter2".to_owned(),
+        )
+            .into();
+        let err = ServerError::from(redis_err);
+        assert_eq!(error::INTERNAL_ERROR, err.status);
+        assert_eq!(false, err.msg.contains("hunter2"));
+        assert_eq!(false, err.msg.contains("HGET"));
+    }
+
+    #[test]
+    fn server_error_round_trip_test() {
+        // our own messages survive the trip through a redis transaction
+        let err = ServerError::new(error::STORE_BUSY, "Store is being modified, try again");
+        let redis_err: redis::RedisError = err.into();
+        assert_eq!(
+            "Store is being modified, try again",
+            ServerError::from(redis_err).msg
+        );
+    }
+}
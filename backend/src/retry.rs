@@ -0,0 +1,140 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::*;
+use rand::Rng;
+
+use crate::error::Result;
+
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY_MS: u64 = 50;
+const MAX_DELAY_MS: u64 = 1000;
+
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static RETRIES_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+// Whether an operation can be replayed when Redis was briefly unreachable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retry {
+    // reads, and writes setting a value whatever the current state is
+    Idempotent,
+    // creations and deletions, replaying one could apply it twice
+    Never,
+}
+
+// Number of attempts replayed since startup
+pub fn retry_count() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+// Number of operations that still failed after their last attempt
+pub fn exhausted_count() -> u64 {
+    RETRIES_EXHAUSTED.load(Ordering::Relaxed)
+}
+
+// Exponential backoff with full jitter, so retrying clients don't hit a
+// recovering Redis all at once
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_DELAY_MS);
+    Duration::from_millis(rand::thread_rng().gen_range(0, ceiling + 1))
+}
+
+// Run `op`, replaying it on transient database errors if `retry` allows it.
+// `op` must get a fresh connection each time, a failed one may be broken.
+pub async fn with_retry<T, F, Fut>(retry: Retry, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if retry == Retry::Idempotent && e.is_db_unavailable() => {
+                if attempt >= MAX_ATTEMPTS {
+                    RETRIES_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                let delay = backoff(attempt);
+                warn!(
+                    "Database unavailable, attempt {} in {:?}",
+                    attempt + 1,
+                    delay
+                );
+                tokio::time::delay_for(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{self, ServerError};
+    use std::cell::Cell;
+
+    #[test]
+    fn backoff_test() {
+        for attempt in 0..20 {
+            let ceiling = (BASE_DELAY_MS << attempt.min(16)).min(MAX_DELAY_MS);
+            assert_eq!(true, backoff(attempt) <= Duration::from_millis(ceiling));
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_idempotent_test() {
+        let calls = Cell::new(0);
+        let res = with_retry(Retry::Idempotent, || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call < MAX_ATTEMPTS {
+                    Err(ServerError::db_unavailable())
+                } else {
+                    Ok(call)
+                }
+            }
+        })
+        .await;
+        assert_eq!(Ok(MAX_ATTEMPTS), res);
+
+        // gives up after the last attempt
+        calls.set(0);
+        let res: Result<()> = with_retry(Retry::Idempotent, || {
+            calls.set(calls.get() + 1);
+            async { Err(ServerError::db_unavailable()) }
+        })
+        .await;
+        assert_eq!(Err(ServerError::db_unavailable()), res);
+        assert_eq!(MAX_ATTEMPTS, calls.get());
+    }
+
+    #[tokio::test]
+    async fn no_retry_test() {
+        let calls = Cell::new(0);
+        let res: Result<()> = with_retry(Retry::Never, || {
+            calls.set(calls.get() + 1);
+            async { Err(ServerError::db_unavailable()) }
+        })
+        .await;
+        assert_eq!(true, res.is_err());
+        assert_eq!(1, calls.get());
+
+        // only transient errors are replayed
+        calls.set(0);
+        let res: Result<()> = with_retry(Retry::Idempotent, || {
+            calls.set(calls.get() + 1);
+            async { Err(ServerError::new(error::PERMISSION_DENIED, "Nope")) }
+        })
+        .await;
+        assert_eq!(true, res.is_err());
+        assert_eq!(1, calls.get());
+    }
+}
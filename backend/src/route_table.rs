@@ -0,0 +1,141 @@
+// Every route under /api, as its method and path, where `{store}`, `{aisle}`
+// and the like stand for any one segment. The routes themselves are built in
+// `endpoints::routes`, this table tells which methods a path takes for 405
+// replies, lists them in the schema, and the authorization tests check they
+// call every one of them.
+pub const ROUTES: &[(&str, &str)] = &[
+    ("POST", "nuke"),
+    ("POST", "user"),
+    ("GET", "user/check"),
+    ("GET", "user/default_aisles"),
+    ("PUT", "user/default_aisles"),
+    ("GET", "user/aliases"),
+    ("PUT", "user/aliases"),
+    ("GET", "user/anywhere"),
+    ("DELETE", "user/anywhere/{product}"),
+    ("GET", "user/quota"),
+    ("GET", "user/staples"),
+    ("PUT", "user/staples"),
+    ("POST", "user/staples"),
+    ("DELETE", "user/staples"),
+    ("GET", "templates"),
+    ("POST", "templates"),
+    ("DELETE", "templates"),
+    ("POST", "templates/apply"),
+    ("POST", "templates/redeem"),
+    ("GET", "community"),
+    ("POST", "community"),
+    ("POST", "community/{template}/report"),
+    ("POST", "community/{template}/save"),
+    ("POST", "user/pair"),
+    ("POST", "user/pair/claim"),
+    ("DELETE", "user/{user}"),
+    ("POST", "login"),
+    ("POST", "logout/{user}"),
+    ("GET", "store"),
+    ("POST", "store"),
+    ("GET", "store/{store}"),
+    ("PUT", "store/{store}"),
+    ("DELETE", "store/{store}"),
+    ("GET", "store/{store}/print"),
+    ("GET", "store/{store}/text"),
+    ("GET", "store/{store}/stats"),
+    ("GET", "store/{store}/insights"),
+    ("GET", "store/{store}/ui_state"),
+    ("PUT", "store/{store}/ui_state"),
+    ("GET", "store/{store}/crdt"),
+    ("POST", "store/{store}/crdt"),
+    ("POST", "store/{store}/add_staples"),
+    ("GET", "store/{store}/nutrition"),
+    ("POST", "store/{store}/export_link"),
+    ("GET", "store/{store}/export"),
+    ("POST", "store/{store}/share_link"),
+    ("POST", "store/{store}/template_code"),
+    ("POST", "store/{store}/snapshot"),
+    ("GET", "store/{store}/snapshots"),
+    ("POST", "store/{store}/snapshots/{snapshot}/restore"),
+    ("GET", "store/{store}/snapshots/{snapshot}/diff/{other}"),
+    ("GET", "store/{store}/shared"),
+    ("POST", "store/{store}/merge_from/{other_store}"),
+    ("POST", "store/{store}/aisle"),
+    ("POST", "store/{store}/product"),
+    ("PUT", "aisle/{aisle}"),
+    ("DELETE", "aisle/{aisle}"),
+    ("POST", "aisle/{aisle}/product"),
+    ("POST", "product/{product}/anywhere"),
+    ("GET", "product/{product}/nutrition"),
+    ("GET", "product/{product}/history"),
+    ("GET", "nutrition"),
+    ("PUT", "product/{product}"),
+    ("PUT", "products/done"),
+    ("DELETE", "product/{product}"),
+    ("DELETE", "products"),
+    ("PUT", "sort_weight"),
+    ("POST", "assistant/add"),
+    ("POST", "assistant/list"),
+    ("GET", "assistant/link"),
+    ("GET", "challenge"),
+    ("GET", "schema"),
+    ("GET", "health"),
+    ("GET", "admin/config"),
+    ("GET", "admin/maintenance"),
+    ("PUT", "admin/maintenance"),
+    ("GET", "admin/schema"),
+    ("GET", "admin/read_only"),
+    ("PUT", "admin/read_only"),
+    ("GET", "admin/telemetry"),
+    ("GET", "admin/bans"),
+    ("DELETE", "admin/bans/{subject}"),
+    ("POST", "admin/invites"),
+    ("DELETE", "admin/invites/{code}"),
+    ("POST", "admin/email_index"),
+    ("POST", "admin/store_counts"),
+    ("GET", "admin/schedule"),
+    ("GET", "admin/journals"),
+    ("GET", "admin/jobs/{queue}"),
+    ("GET", "admin/jobs/{queue}/dead"),
+    ("GET", "admin/community"),
+    ("POST", "admin/community/{template}/restore"),
+    ("DELETE", "admin/community/{template}"),
+];
+
+// A request to a known path with another method, replied 405 with them
+#[derive(Debug)]
+pub struct MethodNotAllowed(pub Vec<&'static str>);
+
+impl warp::reject::Reject for MethodNotAllowed {}
+
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let path = path.trim_matches('/');
+    pattern.split('/').count() == path.split('/').count()
+        && pattern
+            .split('/')
+            .zip(path.split('/'))
+            .all(|(p, s)| p == s || (p.starts_with('{') && !s.is_empty()))
+}
+
+// The methods of the routes of `path`, relative to /api
+pub fn allowed_methods(path: &str) -> Vec<&'static str> {
+    let mut methods = vec![];
+    for (method, pattern) in ROUTES {
+        if matches(pattern, path) && !methods.contains(method) {
+            methods.push(*method);
+        }
+    }
+    methods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_methods_test() {
+        assert_eq!(vec!["GET", "POST"], allowed_methods("store"));
+        assert_eq!(vec!["GET", "POST"], allowed_methods("/store/s1/crdt/"));
+        assert_eq!(vec!["GET", "PUT", "DELETE"], allowed_methods("store/s1"));
+        assert_eq!(vec!["POST"], allowed_methods("store/s1/merge_from/s2"));
+        assert_eq!(Vec::<&str>::new(), allowed_methods("store/s1/nothing"));
+        assert_eq!(Vec::<&str>::new(), allowed_methods("store//crdt"));
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use log::*;
+
+use crate::{
+    config::LiveConfig,
+    cron::Cron,
+    db::schedule,
+    error::{self, ServerError},
+    jobs::JobFuture,
+    links::now_s,
+    storage::Pool,
+};
+
+// How often the schedule is looked at, it has a minute resolution
+const TICK_MS: u64 = 1000;
+// a run lasting longer than that no longer prevents the next one
+const MAX_RUN_MS: u64 = 3600 * 1000;
+
+type Task = Arc<dyn Fn() -> JobFuture + Send + Sync>;
+
+// Runs tasks when their cron expression of the `schedule` setting says so.
+// The setting is read at each tick, a reload applies at once. A run still
+// going when the next one is due makes that one skipped, on any server.
+pub struct Scheduler {
+    pool: Pool,
+    config: Arc<LiveConfig>,
+    tasks: HashMap<String, Task>,
+}
+
+impl Scheduler {
+    pub fn new(pool: Pool, config: Arc<LiveConfig>) -> Self {
+        Scheduler {
+            pool,
+            config,
+            tasks: HashMap::new(),
+        }
+    }
+
+    pub fn task<F, Fut>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = error::Result<()>> + Send + 'static,
+    {
+        self.tasks
+            .insert(name.to_owned(), Arc::new(move || Box::pin(f())));
+        self
+    }
+
+    pub fn start(self) {
+        for name in self.config.schedule().keys() {
+            if !self.tasks.contains_key(name) {
+                warn!("No task named {}, its schedule is ignored", name);
+            }
+        }
+        tokio::spawn(Arc::new(self).run());
+    }
+
+    async fn run(self: Arc<Self>) {
+        // next run of each task, for the expression it was computed with
+        let mut next_runs: HashMap<String, (Cron, Option<u64>)> = HashMap::new();
+        loop {
+            let now = now_s();
+            let schedule = self.config.schedule();
+            next_runs.retain(|name, _| schedule.contains_key(name));
+            for (name, cron) in schedule {
+                if !self.tasks.contains_key(&name) {
+                    continue;
+                }
+                let next = next_runs
+                    .get(&name)
+                    .filter(|(previous, _)| *previous == cron)
+                    .map(|(_, next)| *next);
+                match next {
+                    Some(Some(next)) if next <= now => {
+                        tokio::spawn(self.clone().run_task(name.clone()));
+                    }
+                    Some(_) => continue,
+                    // a new task or expression is counted from now
+                    None => {}
+                }
+                let next = cron.next_after(now);
+                next_runs.insert(name, (cron, next));
+            }
+            tokio::time::delay_for(Duration::from_millis(TICK_MS)).await;
+        }
+    }
+
+    async fn run_task(self: Arc<Self>, name: String) {
+        let lease = match self
+            .pool
+            .get()
+            .map_err(ServerError::from)
+            .and_then(|mut c| schedule::start_run(&mut *c, &name, MAX_RUN_MS))
+        {
+            Ok(Some(lease)) => lease,
+            Ok(None) => {
+                warn!("Task {} is still running, skipping this run", name);
+                return;
+            }
+            Err(e) => {
+                error!("Could not start task {}: {}", name, e);
+                return;
+            }
+        };
+        info!("Running task {}", name);
+        let res = (self.tasks[&name])().await;
+        if let Err(ref e) = res {
+            error!("Task {} failed: {}", name, e);
+        }
+        let error = res.err().map(|e| e.msg);
+        let ended = self
+            .pool
+            .get()
+            .map_err(ServerError::from)
+            .and_then(|mut c| schedule::end_run(&mut *c, &name, lease, error.as_deref()));
+        if let Err(e) = ended {
+            error!("Could not record the end of task {}: {}", name, e);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema, Map};
+use serde::Serialize;
+
+use crate::{route_table, types::*};
+
+// JSON Schema of the bodies of the public API, by route. Derived from the
+// types the handlers use, so it can't drift from what the server sends.
+// Schemas refer to each other through `definitions`, `routes` lists them all.
+#[derive(Serialize)]
+pub struct ApiSchema {
+    routes: Vec<String>,
+    requests: BTreeMap<&'static str, Schema>,
+    responses: BTreeMap<&'static str, Schema>,
+    definitions: Map<String, Schema>,
+}
+
+struct Builder {
+    gen: SchemaGenerator,
+    requests: BTreeMap<&'static str, Schema>,
+    responses: BTreeMap<&'static str, Schema>,
+}
+
+impl Builder {
+    fn request<T: JsonSchema>(mut self, route: &'static str) -> Self {
+        let schema = self.gen.subschema_for::<T>();
+        self.requests.insert(route, schema);
+        self
+    }
+
+    fn response<T: JsonSchema>(mut self, route: &'static str) -> Self {
+        let schema = self.gen.subschema_for::<T>();
+        self.responses.insert(route, schema);
+        self
+    }
+}
+
+pub fn api_schema() -> ApiSchema {
+    let builder = Builder {
+        gen: SchemaGenerator::default(),
+        requests: BTreeMap::new(),
+        responses: BTreeMap::new(),
+    }
+    .response::<Health>("GET /health")
+    .response::<ChallengeInfo>("GET /challenge")
+    .response::<UsernameCheck>("GET /user/check?username=<username>")
+    .request::<User>("POST /user")
+    .response::<ConnectionToken>("POST /user")
+    .response::<PairingCode>("POST /user/pair")
+    .request::<PairingClaim>("POST /user/pair/claim")
+    .response::<ConnectionToken>("POST /user/pair/claim")
+    .response::<DefaultAisles>("GET /user/default_aisles")
+    .request::<DefaultAisles>("PUT /user/default_aisles")
+    .response::<ProductAliases>("GET /user/aliases")
+    .request::<ProductAliases>("PUT /user/aliases")
+    .response::<AnywhereProducts>("GET /user/anywhere")
+    .response::<UserQuotas>("GET /user/quota")
+    .response::<Staples>("GET /user/staples")
+    .request::<Staples>("PUT /user/staples")
+    .request::<Staple>("POST /user/staples")
+    .response::<AisleTemplates>("GET /templates")
+    .request::<StoreTemplate>("POST /templates")
+    .response::<AisleTemplate>("POST /templates")
+    .request::<StoreTemplate>("POST /templates/apply")
+    .response::<AddedAisles>("POST /templates/apply")
+    .request::<RedeemTemplate>("POST /templates/redeem")
+    .response::<StoreId>("POST /templates/redeem")
+    .response::<CommunityTemplates>("GET /community?q=<text>")
+    .request::<PublishTemplate>("POST /community")
+    .response::<CommunityTemplate>("POST /community")
+    .response::<AisleTemplate>("POST /community/<id>/save")
+    .request::<AuthInfo>("POST /login")
+    .response::<ConnectionToken>("POST /login")
+    // with the `x-session-mode: cookie` header
+    .response::<CookieSession>("POST /login, cookie mode")
+    .response::<StoreLightList>("GET /store")
+    .request::<NewStore>("POST /store")
+    .response::<StoreId>("POST /store")
+    .response::<Store>("GET /store/<id>")
+    .response::<VersionedStore>("GET /store/<id>?since_version=<version>")
+    .request::<EditStore>("PUT /store/<id>")
+    .response::<StoreStats>("GET /store/<id>/stats")
+    .response::<StoreInsights>("GET /store/<id>/insights")
+    .response::<StoreUiState>("GET /store/<id>/ui_state")
+    .request::<StoreUiState>("PUT /store/<id>/ui_state")
+    .response::<CrdtStore>("GET /store/<id>/crdt")
+    .request::<CrdtStore>("POST /store/<id>/crdt")
+    .response::<CrdtStore>("POST /store/<id>/crdt")
+    .response::<AddedStaples>("POST /store/<id>/add_staples")
+    .response::<StoreNutrition>("GET /store/<id>/nutrition")
+    .response::<ExportLink>("POST /store/<id>/export_link")
+    .response::<StoreMerge>("POST /store/<id>/merge_from/<other id>")
+    .response::<StoreRemoval>("DELETE /store/<id>?dry_run=true")
+    .response::<UserRemoval>("DELETE /user/<id>?dry_run=true")
+    .response::<Store>("GET /store/<id>/export")
+    .request::<ShareParams>("POST /store/<id>/share_link")
+    .response::<ShareLink>("POST /store/<id>/share_link")
+    .response::<TemplateCode>("POST /store/<id>/template_code")
+    .request::<NameData>("POST /store/<id>/snapshot")
+    .response::<StoreSnapshot>("POST /store/<id>/snapshot")
+    .response::<StoreSnapshots>("GET /store/<id>/snapshots")
+    .response::<Store>("POST /store/<id>/snapshots/<id>/restore")
+    .response::<StoreDiff>("GET /store/<id>/snapshots/<id>/diff/<id>")
+    .request::<NameData>("POST /store/<id>/aisle")
+    .response::<Aisle>("POST /store/<id>/aisle")
+    .request::<NameData>("PUT /aisle/<id>")
+    .request::<NameData>("POST /aisle/<id>/product")
+    .response::<Product>("POST /aisle/<id>/product")
+    .request::<NameData>("POST /store/<id>/product")
+    .response::<PlacedProduct>("POST /store/<id>/product")
+    .response::<Product>("POST /product/<id>/anywhere")
+    .response::<ProductNutrition>("GET /product/<id>/nutrition")
+    .response::<ProductHistory>("GET /product/<id>/history")
+    .response::<Nutrition>("GET /nutrition?barcode=<code> or ?name=<name>")
+    .request::<EditProduct>("PUT /product/<id>")
+    .request::<ProductsDone>("PUT /products/done")
+    .request::<ProductIds>("DELETE /products")
+    .request::<EditWeight>("PUT /sort_weight")
+    .request::<AssistantAdd>("POST /assistant/add")
+    .response::<AssistantReply>("POST /assistant/add")
+    .request::<AssistantList>("POST /assistant/list")
+    .response::<AssistantReply>("POST /assistant/list")
+    // with a 422, to any request with a JSON body
+    .response::<InvalidBody>("invalid body");
+    ApiSchema {
+        routes: route_table::ROUTES
+            .iter()
+            .map(|(method, path)| format!("{} /{}", method, path))
+            .collect(),
+        requests: builder.requests,
+        responses: builder.responses,
+        definitions: builder.gen.into_definitions(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_routes_test() {
+        let schema = api_schema();
+        for route in schema.requests.keys().chain(schema.responses.keys()) {
+            if *route == "invalid body" {
+                continue;
+            }
+            // `GET /store/<id>?since_version=<version>, for example`
+            let route = route.split(|c| c == '?' || c == ',').next().unwrap();
+            let mut parts = route.splitn(2, " /");
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+            assert_eq!(
+                true,
+                route_table::ROUTES
+                    .iter()
+                    .any(|(m, p)| *m == method && route_table::matches(p, path)),
+                "{}",
+                route
+            );
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::{future::Future, str::FromStr, time::Duration};
+
+use fake_redis::MemoryConnection;
+use log::*;
+use redis::{ConnectionInfo, ConnectionLike, IntoConnectionInfo, RedisError, RedisResult, Value};
+
+use crate::error;
+
+pub type Pool = r2d2::Pool<StorageManager>;
+
+// Writes always go to the primary, pure reads to the replica when there is one
+#[derive(Clone)]
+pub struct Pools {
+    pub primary: Pool,
+    pub replica: Option<Pool>,
+}
+
+impl Pools {
+    pub fn new(primary: Pool, replica: Option<Pool>) -> Self {
+        Pools { primary, replica }
+    }
+
+    // Run the read `op` against the replica, and again against the primary if it
+    // failed or if `is_fresh` says the replica lags behind what the client has
+    // already seen. A replica that has not caught up with a just created session
+    // or store fails the read, so errors fall back too.
+    pub async fn read<T, F, Fut>(&self, op: F, is_fresh: impl Fn(&T) -> bool) -> error::Result<T>
+    where
+        F: Fn(Pool) -> Fut,
+        Fut: Future<Output = error::Result<T>>,
+    {
+        if let Some(ref replica) = self.replica {
+            match op(replica.clone()).await {
+                Ok(res) if is_fresh(&res) => return Ok(res),
+                Ok(_) => debug!("Replica is behind, reading from the primary"),
+                Err(e) => debug!("Replica read failed, reading from the primary: {}", e),
+            }
+        }
+        op(self.primary.clone()).await
+    }
+
+    // Like `read`, for reads the client has no version of its own to check
+    // against: the replica is used only if `mark`, e.g. the version of the store
+    // read, is the same there as on the primary. `mark` is read before `op` so
+    // what `op` reads is at least as recent.
+    pub async fn read_marked<T, M, G, GFut, F, Fut>(&self, mark: G, op: F) -> error::Result<T>
+    where
+        M: PartialEq,
+        G: Fn(Pool) -> GFut,
+        GFut: Future<Output = error::Result<M>>,
+        F: Fn(Pool) -> Fut,
+        Fut: Future<Output = error::Result<T>>,
+    {
+        if self.replica.is_none() {
+            return op(self.primary.clone()).await;
+        }
+        let primary_mark = mark(self.primary.clone()).await?;
+        self.read(
+            |pool| {
+                let (mark, op) = (mark(pool.clone()), op(pool));
+                async move { Ok((mark.await?, op.await?)) }
+            },
+            |(mark, _)| *mark == primary_mark,
+        )
+        .await
+        .map(|(_, res)| res)
+    }
+}
+
+// Where the data lives, `--storage memory` needs no Redis at all but forgets
+// everything on exit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Redis,
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(StorageKind::Redis),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!("unknown storage {}, expected redis or memory", s)),
+        }
+    }
+}
+
+// Connection handed to the db layer, both speak the Redis protocol
+pub enum Connection {
+    Redis(redis::Connection),
+    Memory(MemoryConnection),
+}
+
+impl Connection {
+    // Bounds reads and writes, only meaningful over the network
+    pub fn set_timeout(&self, timeout: Duration) -> RedisResult<()> {
+        match self {
+            Connection::Redis(c) => {
+                c.set_read_timeout(Some(timeout))?;
+                c.set_write_timeout(Some(timeout))
+            }
+            Connection::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match self {
+            Connection::Redis(c) => c.req_packed_command(cmd),
+            Connection::Memory(c) => c.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        match self {
+            Connection::Redis(c) => c.req_packed_commands(cmd, offset, count),
+            Connection::Memory(c) => c.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Redis(c) => c.get_db(),
+            Connection::Memory(c) => c.get_db(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageManager {
+    Redis(ConnectionInfo),
+    Memory(i64),
+}
+
+impl StorageManager {
+    pub fn redis<T: IntoConnectionInfo>(params: T) -> RedisResult<Self> {
+        Ok(StorageManager::Redis(params.into_connection_info()?))
+    }
+
+    pub fn memory(db: i64) -> Self {
+        StorageManager::Memory(db)
+    }
+}
+
+impl r2d2::ManageConnection for StorageManager {
+    type Connection = Connection;
+    type Error = RedisError;
+
+    fn connect(&self) -> RedisResult<Connection> {
+        match self {
+            StorageManager::Redis(info) => Ok(Connection::Redis(
+                redis::Client::open(info.clone())?.get_connection()?,
+            )),
+            StorageManager::Memory(db) => Ok(Connection::Memory(MemoryConnection::new(*db))),
+        }
+    }
+
+    fn is_valid(&self, c: &mut Connection) -> RedisResult<()> {
+        redis::cmd("PING").query(c)
+    }
+
+    fn has_broken(&self, c: &mut Connection) -> bool {
+        match c {
+            Connection::Redis(c) => !c.is_open(),
+            Connection::Memory(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Result, ServerError};
+
+    // well above the db numbers handed out to the db tests
+    fn pool(db: i64) -> Pool {
+        r2d2::Pool::builder()
+            .max_size(1)
+            .build(StorageManager::memory(db))
+            .unwrap()
+    }
+
+    async fn get(pool: Pool) -> Result<Option<u64>> {
+        redis::cmd("GET")
+            .arg("version")
+            .query(&mut *pool.get()?)
+            .map_err(ServerError::from)
+    }
+
+    fn set(pool: &Pool, version: u64) {
+        redis::cmd("SET")
+            .arg("version")
+            .arg(version)
+            .query::<()>(&mut *pool.get().unwrap())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replica_read_test() {
+        let (primary, replica) = (pool(1000), pool(1001));
+        set(&primary, 3);
+        set(&replica, 2);
+
+        let pools = Pools::new(primary.clone(), None);
+        assert_eq!(Ok(Some(3)), pools.read(get, |_| true).await);
+
+        let pools = Pools::new(primary, Some(replica));
+        assert_eq!(Ok(Some(2)), pools.read(get, |_| true).await);
+        // stale replica, the primary answers
+        assert_eq!(Ok(Some(3)), pools.read(get, |v| v.unwrap_or(0) >= 3).await);
+    }
+
+    #[tokio::test]
+    async fn replica_error_fallback_test() {
+        let primary = pool(1002);
+        set(&primary, 5);
+        let pools = Pools::new(primary, Some(pool(1003)));
+        let res = pools
+            .read(
+                |pool| async move {
+                    match get(pool).await? {
+                        Some(v) => Ok(v),
+                        None => Err(ServerError::new(error::UNAUTHORISED, "Unknown")),
+                    }
+                },
+                |_| true,
+            )
+            .await;
+        assert_eq!(Ok(5), res);
+    }
+
+    #[tokio::test]
+    async fn replica_marked_read_test() {
+        let (primary, replica) = (pool(1004), pool(1005));
+        for (pool, data) in &[(&primary, "primary"), (&replica, "replica")] {
+            redis::cmd("SET")
+                .arg("data")
+                .arg(*data)
+                .query::<()>(&mut *pool.get().unwrap())
+                .unwrap();
+        }
+        let data = |pool: Pool| async move {
+            redis::cmd("GET")
+                .arg("data")
+                .query::<String>(&mut *pool.get()?)
+                .map_err(ServerError::from)
+        };
+        set(&primary, 3);
+        set(&replica, 3);
+
+        let pools = Pools::new(primary.clone(), None);
+        assert_eq!(Ok("primary".to_owned()), pools.read_marked(get, data).await);
+
+        let pools = Pools::new(primary.clone(), Some(replica.clone()));
+        assert_eq!(Ok("replica".to_owned()), pools.read_marked(get, data).await);
+        // the replica is behind, the primary answers
+        set(&replica, 2);
+        assert_eq!(Ok("primary".to_owned()), pools.read_marked(get, data).await);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    env,
+    net::TcpListener,
+    os::unix::{io::FromRawFd, net::UnixDatagram},
+    time::Duration,
+};
+
+use log::*;
+
+// First file descriptor passed by systemd, after stdin, stdout and stderr
+const LISTEN_FDS_START: i32 = 3;
+
+// Whether a LISTEN_PID or WATCHDOG_PID variable is meant for this process
+fn for_us(pid: Option<String>) -> bool {
+    pid.map_or(true, |pid| {
+        pid.parse::<u32>().ok() == Some(std::process::id())
+    })
+}
+
+// The listening socket systemd passed us if the service is socket activated.
+// The variables are removed so children don't pick the socket up too.
+pub fn listener() -> Option<TcpListener> {
+    let pid = env::var("LISTEN_PID").ok()?;
+    let fds = env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    if !for_us(Some(pid)) || fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!(
+            "{} sockets passed by systemd, only the first one is used",
+            fds
+        );
+    }
+    // systemd hands over ownership of the descriptors starting at 3
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+// sd_notify: tell systemd about our state, like `READY=1`. Does nothing if
+// the service isn't run by systemd with a notification socket.
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    if socket.to_string_lossy().starts_with('@') {
+        warn!("Abstract notification sockets are not supported");
+        return;
+    }
+    if let Err(e) = UnixDatagram::unbound().and_then(|s| s.send_to(state.as_bytes(), &socket)) {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+fn watchdog_interval_from(usec: Option<String>, pid: Option<String>) -> Option<Duration> {
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    if !for_us(pid) {
+        return None;
+    }
+    // ping twice per period, a late ping would get us killed
+    Some(Duration::from_micros(usec / 2))
+}
+
+// How often to ping the watchdog, if `WatchdogSec=` is set for the service
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        env::var("WATCHDOG_USEC").ok(),
+        env::var("WATCHDOG_PID").ok(),
+    )
+}
+
+// Pings come from the runtime serving requests, a stuck one stops them
+pub async fn watchdog(interval: Duration) {
+    loop {
+        notify("WATCHDOG=1");
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_test() {
+        let us = Some(std::process::id().to_string());
+        assert_eq!(
+            Some(Duration::from_secs(15)),
+            watchdog_interval_from(Some("30000000".to_owned()), us.clone())
+        );
+        assert_eq!(
+            Some(Duration::from_millis(500)),
+            watchdog_interval_from(Some("1000000".to_owned()), None)
+        );
+        assert_eq!(None, watchdog_interval_from(None, us.clone()));
+        assert_eq!(None, watchdog_interval_from(Some("0".to_owned()), us));
+        assert_eq!(
+            None,
+            watchdog_interval_from(Some("1000000".to_owned()), Some("1".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::RwLock;
+
+use blake2::{digest::consts::U4, Blake2b, Digest};
+use hex_view::HexView;
+use lazy_static::lazy_static;
+use rand::Rng;
+use zeroize::Zeroize;
+
+use crate::{
+    links::{constant_time_eq, hmac, Blake2b256},
+    types::SecretString,
+};
+
+// Session tokens are `<key id>.<random>.<mac>`: made up ones are turned away
+// without a lookup, and the key id tells which key signed a token so new
+// keys can come in without logging everyone out
+const RANDOM_LEN: usize = 32;
+const MAC_LEN: usize = 16;
+// bytes of key id
+type KeyIdLen = U4;
+// tokens from before, plain random hex
+const LEGACY_LEN: usize = 64;
+
+lazy_static! {
+    // random unless set, like export links' secret
+    static ref KEYS: RwLock<Keyring> = {
+        let mut secret = vec![0u8; 32];
+        rand::thread_rng().fill(&mut secret[..]);
+        RwLock::new(Keyring::new(&[secret]))
+    };
+}
+
+// The first secret signs new tokens, the others only check the ones they
+// signed
+pub fn set_secrets(secrets: &[Vec<u8>]) {
+    *KEYS.write().unwrap() = Keyring::new(secrets);
+}
+
+pub fn mint() -> SecretString {
+    KEYS.read().unwrap().mint()
+}
+
+// Whether the token is worth looking up
+pub fn is_well_formed(token: &str) -> bool {
+    KEYS.read().unwrap().check(token)
+}
+
+#[derive(Debug)]
+struct Keyring {
+    // key id and secret
+    keys: Vec<(String, Vec<u8>)>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    format!("{:x}", HexView::from(bytes))
+}
+
+fn key_id(secret: &[u8]) -> String {
+    hex(&Blake2b::<KeyIdLen>::digest(secret))
+}
+
+impl Keyring {
+    fn new(secrets: &[Vec<u8>]) -> Self {
+        Keyring {
+            keys: secrets
+                .iter()
+                .map(|secret| (key_id(secret), secret.clone()))
+                .collect(),
+        }
+    }
+
+    fn sign(secret: &[u8], msg: &str) -> String {
+        hex(&hmac::<Blake2b256>(secret, format!("session:{}", msg).as_bytes())[..MAC_LEN])
+    }
+
+    fn mint(&self) -> SecretString {
+        let (id, secret) = &self.keys[0];
+        let mut random = [0u8; RANDOM_LEN];
+        rand::thread_rng().fill(&mut random[..]);
+        let msg = format!("{}.{}", id, hex(&random));
+        random.zeroize();
+        let mac = Keyring::sign(secret, &msg);
+        format!("{}.{}", msg, mac).into()
+    }
+
+    fn check(&self, token: &str) -> bool {
+        if token.len() == LEGACY_LEN && token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return true;
+        }
+        let (msg, mac) = match token.rfind('.') {
+            Some(i) => (&token[..i], &token[i + 1..]),
+            None => return false,
+        };
+        let (id, random) = match msg.find('.') {
+            Some(i) => (&msg[..i], &msg[i + 1..]),
+            None => return false,
+        };
+        if random.len() != RANDOM_LEN * 2 || mac.len() != MAC_LEN * 2 {
+            return false;
+        }
+        match self.keys.iter().find(|(key_id, _)| key_id == id) {
+            Some((_, secret)) => constant_time_eq(&Keyring::sign(secret, msg), mac),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_test() {
+        let keys = Keyring::new(&[b"secret".to_vec()]);
+        let token = keys.mint();
+        assert_eq!(true, keys.check(&token));
+        assert_ne!(*keys.mint(), *token);
+        assert_eq!(true, token.starts_with(&format!("{}.", key_id(b"secret"))));
+
+        // tampered with, or signed by a key it does not know
+        let forged = format!("{}0", &token[..token.len() - 1]);
+        assert_eq!(token.ends_with('0'), keys.check(&forged));
+        let other = Keyring::new(&[b"other".to_vec()]);
+        assert_eq!(false, other.check(&token));
+        assert_eq!(false, keys.check(&other.mint()));
+        assert_eq!(false, keys.check("garbage"));
+        assert_eq!(false, keys.check(".."));
+        assert_eq!(true, keys.check(&"ab".repeat(32)));
+
+        // a new key signs, the old one still checks
+        let rotated = Keyring::new(&[b"other".to_vec(), b"secret".to_vec()]);
+        assert_eq!(true, rotated.check(&token));
+        assert_eq!(true, other.check(&rotated.mint()));
+    }
+}
@@ -0,0 +1,2227 @@
+// Endpoint level tests, against the in-memory storage by default, see
+// `common` to run them on Redis
+mod common;
+
+use std::time::Duration;
+
+use reqwest::{header::SET_COOKIE, Method, StatusCode};
+use serde_json::json;
+
+use common::*;
+use efficio_server::{
+    challenge::{solve_pow, ChallengeKind},
+    config::{
+        Config, Registration, DEFAULT_ABUSE_MAX_ERROR_RATIO, DEFAULT_ABUSE_MAX_REQUESTS,
+        DEFAULT_ABUSE_MIN_ERRORS,
+    },
+    db::jobs::RetryPolicy,
+    endpoints::{product, session::*, HEADER_AUTH, HEADER_NEW_AUTH},
+    error::{self, ServerError},
+    jobs::JobQueue,
+    nutrition::NutritionKind,
+    retry::Retry,
+    types::*,
+};
+
+#[tokio::test]
+async fn create_user_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    assert!(!user.auth.is_empty());
+    assert!(!user.user_id.is_empty());
+
+    let mut body = json!({
+        "username": user.username,
+        "email": "other@efficio.app",
+        "password": PASSWORD,
+    });
+    let request = server.request(Method::POST, "user").json(&body);
+    assert_eq!(StatusCode::NOT_ACCEPTABLE, status(request).await);
+
+    body["username"] = json!(unique_username());
+    body["password"] = json!("1234");
+    let request = server.request(Method::POST, "user").json(&body);
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+
+    body["password"] = json!(PASSWORD);
+    body["email"] = json!("not an email");
+    let request = server.request(Method::POST, "user").json(&body);
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+
+    // one account per email
+    body["email"] = json!(format!("{}@EFFICIO.app", user.username));
+    let request = server.request(Method::POST, "user").json(&body);
+    assert_eq!(StatusCode::NOT_ACCEPTABLE, status(request).await);
+
+    let known = json!([
+        { "username": user.username, "email": format!("{}@efficio.app", user.username) },
+        { "username": user.username, "email": "other@efficio.app" },
+        { "username": unique_username(), "email": "other@efficio.app" },
+    ]);
+    let request = server.admin(Method::POST, "admin/email_index").json(&known);
+    let indexed = json_body(request).await;
+    assert_eq!(json!("already_indexed"), indexed[0]["result"]);
+    assert_eq!(json!("already_indexed"), indexed[1]["result"]);
+    assert_eq!(json!("unknown_user"), indexed[2]["result"]);
+}
+
+#[tokio::test]
+async fn check_username_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client();
+
+    let check = client.check_username(&unique_username()).await.unwrap();
+    assert_eq!(true, check.valid && check.available);
+    let check = client
+        .check_username(&user.username.to_uppercase())
+        .await
+        .unwrap();
+    assert_eq!((true, false), (check.valid, check.available));
+    let check = client.check_username("42 is not valid").await.unwrap();
+    assert_eq!((false, false), (check.valid, check.available));
+    assert_eq!(1, check.problems.len());
+
+    // the same address can't go through the whole dictionary
+    let mut limited = false;
+    for _ in 0..30 {
+        let request = server
+            .request(Method::GET, "user/check")
+            .query(&[("username", "toto")]);
+        if status(request).await == StatusCode::TOO_MANY_REQUESTS {
+            limited = true;
+            break;
+        }
+    }
+    assert_eq!(true, limited);
+}
+
+#[tokio::test]
+async fn login_logout_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+
+    let body = json!({ "username": user.username, "password": "wrong password" });
+    let request = server.request(Method::POST, "login").json(&body);
+    assert_eq!(StatusCode::BAD_REQUEST, status(request).await);
+
+    let body = json!({ "username": user.username, "password": PASSWORD });
+    let token = json_body(server.request(Method::POST, "login").json(&body)).await;
+    assert_eq!(json!(user.user_id), token["user_id"]);
+    assert_ne!(json!(user.auth), token["session_token"]);
+
+    let email = format!("{}@efficio.app", user.username);
+    let body = json!({ "username": email, "password": PASSWORD });
+    let token = json_body(server.request(Method::POST, "login").json(&body)).await;
+    assert_eq!(json!(user.user_id), token["user_id"]);
+    let body = json!({ "username": "nobody@efficio.app", "password": PASSWORD });
+    let res = server
+        .request(Method::POST, "login")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    assert_eq!("Invalid usename or password", res.text().await.unwrap());
+
+    let path = format!("logout/{}", user.user_id);
+    let request = server.authed(Method::POST, &path, &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let request = server.authed(Method::GET, "store", &user);
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn pairing_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let phone = server.client().with_token(&user.auth);
+    let pairing = phone.pair().await.unwrap();
+
+    let mut tv = server.client();
+    let token = tv.claim_pairing(&pairing.code).await.unwrap();
+    assert_eq!(user.user_id, token.user_id);
+    assert_eq!(true, tv.stores().await.is_ok());
+    // the code is spent
+    let mut other = server.client();
+    assert_eq!(true, other.claim_pairing(&pairing.code).await.is_err());
+    assert_eq!(true, server.client().pair().await.is_err());
+}
+
+#[tokio::test]
+async fn session_rotation_test() {
+    let server = spawn_server_with(Config {
+        session_rotate_after_s: 2,
+        session_rotation_grace_s: 60,
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let other = server.create_user().await;
+    let client = server.client().with_token(&other.auth);
+
+    tokio::time::delay_for(Duration::from_millis(2100)).await;
+    let res = server
+        .authed(Method::GET, "store", &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    let new = res.headers()[HEADER_NEW_AUTH].to_str().unwrap().to_owned();
+    assert_ne!(user.auth, new);
+    let request = server
+        .request(Method::GET, "store")
+        .header(HEADER_AUTH, new.as_str());
+    let res = request.send().await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(None, res.headers().get(HEADER_NEW_AUTH));
+    // requests still using the old token go through for the grace period
+    let res = server
+        .authed(Method::GET, "store", &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(None, res.headers().get(HEADER_NEW_AUTH));
+
+    // the client follows along
+    assert_eq!(true, client.stores().await.is_ok());
+    assert_ne!(Some(other.auth.clone()), client.token());
+    assert_eq!(true, client.stores().await.is_ok());
+}
+
+#[tokio::test]
+async fn malformed_token_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    assert_eq!(3, user.auth.split('.').count());
+    let forged = format!("{}.{}", &user.auth[..user.auth.len() - 33], "0".repeat(32));
+    for token in &["garbage", forged.as_str()] {
+        let request = server
+            .request(Method::GET, "store")
+            .header(HEADER_AUTH, *token);
+        let res = request.send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        assert_eq!(
+            true,
+            res.text().await.unwrap().contains("Invalid session token")
+        );
+    }
+}
+
+#[tokio::test]
+async fn malformed_id_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let routes = [
+        (Method::GET, "store/MyStore".to_owned()),
+        (Method::GET, "store/1/stats".to_owned()),
+        (Method::POST, format!("store/{}/merge_from/other", store_id)),
+        (Method::DELETE, "aisle/1".to_owned()),
+        (Method::DELETE, "product/%20".to_owned()),
+    ];
+    for (method, path) in &routes {
+        let request = server.authed(method.clone(), path, &user);
+        assert_eq!(StatusCode::BAD_REQUEST, status(request).await, "{}", path);
+    }
+    // well formed but unknown, looked up
+    let path = format!("store/{}", store_id.replace(|c| c != '-', "0"));
+    let request = server.authed(Method::GET, &path, &user);
+    assert_ne!(StatusCode::BAD_REQUEST, status(request).await);
+}
+
+#[tokio::test]
+async fn delete_user_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let other = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+
+    // what would go, and nothing goes
+    let store = StoreRemoval {
+        store_id: store_id.clone(),
+        name: "MyStore".to_owned(),
+        aisles: 1,
+        products: 1,
+    };
+    assert_eq!(store, client.store_removal(&store_id).await.unwrap());
+    assert_eq!(
+        UserRemoval {
+            username: user.username.clone(),
+            stores: vec![store],
+        },
+        client.user_removal(&user.user_id).await.unwrap()
+    );
+    assert_eq!(1, client.store(&store_id).await.unwrap().aisles.len());
+
+    let path = format!("user/{}", user.user_id);
+    let request = server.authed(Method::DELETE, &path, &other);
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+    let request = server.authed(Method::DELETE, &format!("{}?dry_run=true", path), &other);
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+    let request = server.authed(Method::DELETE, &path, &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    let body = json!({ "username": user.username, "password": PASSWORD });
+    let request = server.request(Method::POST, "login").json(&body);
+    assert_eq!(StatusCode::BAD_REQUEST, status(request).await);
+}
+
+#[tokio::test]
+async fn store_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+
+    let mut stores = json_body(server.authed(Method::GET, "store", &user)).await;
+    let updated_at = stores["stores"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("updated_at");
+    assert_eq!(true, updated_at.unwrap().is_u64());
+    assert_eq!(
+        json!({ "stores": [{ "name": "MyStore", "store_id": store_id, "items": 0, "items_left": 0 }] }),
+        stores
+    );
+
+    let path = format!("store/{}", store_id);
+    let request = server
+        .authed(Method::PUT, &path, &user)
+        .json(&json!({ "name": "Renamed" }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let store = json_body(server.authed(Method::GET, &path, &user)).await;
+    assert_eq!(
+        json!({ "store_id": store_id, "name": "Renamed", "aisles": [] }),
+        store
+    );
+
+    // the name stays when only the defaults are set
+    let defaults = json!({ "quantity": 500, "unit": 1 });
+    let request = server
+        .authed(Method::PUT, &path, &user)
+        .json(&json!({ "defaults": defaults }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let store = json_body(server.authed(Method::GET, &path, &user)).await;
+    assert_eq!(json!("Renamed"), store["name"]);
+    assert_eq!(defaults, store["defaults"]);
+    let request = server
+        .authed(Method::PUT, &path, &user)
+        .json(&json!({ "defaults": { "quantity": 0, "unit": 0 } }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+
+    let request = server.authed(Method::DELETE, &path, &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let stores = json_body(server.authed(Method::GET, "store", &user)).await;
+    assert_eq!(json!({ "stores": [] }), stores);
+}
+
+#[tokio::test]
+async fn export_link_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+
+    let path = format!("store/{}/export_link", store_id);
+    let intruder = server.create_user().await;
+    let request = server.authed(Method::POST, &path, &intruder);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let link = json_body(server.authed(Method::POST, &path, &user)).await;
+
+    // no session token needed, the signature stands for it
+    let url = link["url"].as_str().unwrap().trim_start_matches("/api/");
+    let res = server.request(Method::GET, url).send().await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        format!("attachment; filename=\"store-{}.json\"", store_id),
+        res.headers()["content-disposition"]
+    );
+    let store: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(json!("MyStore"), store["name"]);
+
+    let tampered = url.replace("sig=", "sig=0");
+    let request = server.request(Method::GET, &tampered);
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn product_defaults_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    assert_eq!((1, Unit::Unit), (milk.quantity, milk.unit));
+
+    let defaults = ProductDefaults {
+        quantity: 250,
+        unit: Unit::Gram,
+    };
+    client
+        .set_product_defaults(&store_id, &defaults)
+        .await
+        .unwrap();
+    let butter = client
+        .create_product(&aisle.aisle_id, "Butter")
+        .await
+        .unwrap();
+    assert_eq!((250, Unit::Gram), (butter.quantity, butter.unit));
+    // the products there already keep theirs
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(defaults, store.defaults);
+    let milk = store.aisles[0].products.iter().find(|p| p.name == "Milk");
+    assert_eq!(Some(1), milk.map(|p| p.quantity));
+
+    // a quantity said overrides them, none keeps them
+    let add = |text: &str| AssistantAdd {
+        text: text.to_owned(),
+        store_id: Some(store_id.clone()),
+    };
+    let reply = client.assistant_add(&add("cheese")).await.unwrap();
+    assert_eq!("I added 250 grams of cheese to MyStore.", reply.speech);
+    let reply = client.assistant_add(&add("2 eggs")).await.unwrap();
+    assert_eq!("I added 2 eggs to MyStore.", reply.speech);
+}
+
+#[tokio::test]
+async fn share_link_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    let eggs = client
+        .create_product(&aisle.aisle_id, "Eggs")
+        .await
+        .unwrap();
+    let done = EditProduct::new(None, None, None, Some(true));
+    client.edit_product(&eggs.product_id, &done).await.unwrap();
+
+    let text = client.text_store(&store_id).await.unwrap();
+    assert_eq!("MyStore\n\nDairy:\n- Milk", text);
+
+    let path = format!("store/{}/share_link", store_id);
+    let request = server
+        .authed(Method::POST, &path, &user)
+        .json(&json!({ "origin": "javascript:alert(1)" }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    let link = client
+        .share_link(&store_id, "https://efficio.app")
+        .await
+        .unwrap();
+    assert_eq!(format!("{}\n\n{}", text, link.url), link.text);
+    assert_eq!(
+        true,
+        link.whatsapp.starts_with("https://wa.me/?text=MyStore%0A")
+    );
+
+    // no session token needed, and the text is the list as it is now
+    let url = link.url.trim_start_matches("https://efficio.app/api/");
+    let milk = &client.store(&store_id).await.unwrap().aisles[0].products;
+    let milk = milk.iter().find(|p| p.name == "Milk").unwrap();
+    client.edit_product(&milk.product_id, &done).await.unwrap();
+    let res = server.request(Method::GET, url).send().await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        true,
+        res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain")
+    );
+    assert_eq!("MyStore", res.text().await.unwrap());
+
+    // a download link is no share link
+    let export = client.export_link(&store_id).await.unwrap();
+    let sig = export.url.rsplit("sig=").next().unwrap();
+    let expires = export.expires_at;
+    let path = format!("store/{}/shared?expires={}&sig={}", store_id, expires, sig);
+    let request = server.request(Method::GET, &path);
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn ui_state_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    assert_eq!(
+        StoreUiState::default(),
+        client.ui_state(&store_id).await.unwrap()
+    );
+
+    // the same from another device, and out of the store's data
+    let state = StoreUiState {
+        collapsed_aisles: vec![aisle.aisle_id],
+        hide_checked: true,
+    };
+    client.set_ui_state(&store_id, &state).await.unwrap();
+    let other_device = server.client().with_token(&user.auth);
+    assert_eq!(state, other_device.ui_state(&store_id).await.unwrap());
+    assert_eq!(1, client.store(&store_id).await.unwrap().aisles.len());
+
+    let path = format!("store/{}/ui_state", store_id);
+    let request = server
+        .authed(Method::PUT, &path, &user)
+        .json(&json!({ "collapsed_aisles": vec!["1"; 501] }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn negotiated_reply_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    for accept in &["application/json", "application/msgpack"] {
+        let res = server
+            .authed(Method::GET, "store", &user)
+            .header("accept", *accept)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*accept, res.headers()["content-type"]);
+        // shared caches must key the reply on the format asked for
+        assert_eq!("accept", res.headers()["vary"]);
+    }
+}
+
+#[tokio::test]
+async fn crdt_store_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    let offline = client.crdt_store(&store_id).await.unwrap();
+    assert_eq!(1, offline.products.len());
+    assert!(offline.removed.is_empty());
+
+    // edited on the server, then offline before it synced
+    let edit = EditProduct::new(None, Some(2), None, None);
+    client.edit_product(&milk.product_id, &edit).await.unwrap();
+    let mut offline = offline;
+    offline.products[0].quantity = Stamped::new(3, 1);
+    offline.products[0].is_done = Stamped::new(true, 1);
+    let merged = client.merge_crdt_store(&store_id, &offline).await.unwrap();
+    assert_eq!(2, merged.products[0].quantity.value);
+    assert!(merged.products[0].is_done.value);
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(2, store.aisles[0].products[0].quantity);
+    assert!(store.aisles[0].products[0].is_done);
+
+    // removed on the server, the client's copy doesn't come back
+    client.delete_product(&milk.product_id).await.unwrap();
+    let merged = client.merge_crdt_store(&store_id, &merged).await.unwrap();
+    assert!(merged.products.is_empty());
+    assert_eq!(vec![milk.product_id], merged.removed);
+
+    let path = format!("store/{}/crdt", store_id);
+    let request = server
+        .authed(Method::POST, &path, &user)
+        .json(&json!({ "removed": ["1"] }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn json_body_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let request = server
+        .authed(Method::POST, "store", &user)
+        .header("content-type", "text/plain")
+        .body(r#"{"name": "MyStore"}"#);
+    assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status(request).await);
+
+    let request = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": 1 }));
+    let res = request.send().await.unwrap();
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    let invalid: InvalidBody = res.json().await.unwrap();
+    assert_eq!(
+        ("name", Some("a string"), "type"),
+        (
+            invalid.field.as_str(),
+            invalid.expected.as_deref(),
+            invalid.constraint.as_str()
+        )
+    );
+}
+
+#[tokio::test]
+async fn method_not_allowed_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let path = format!("store/{}/crdt", store_id);
+    let res = server
+        .authed(Method::PUT, &path, &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+    assert_eq!("GET, POST", res.headers()["allow"]);
+
+    let request = server.authed(Method::GET, "store/1/nothing", &user);
+    assert_ne!(StatusCode::METHOD_NOT_ALLOWED, status(request).await);
+}
+
+#[tokio::test]
+async fn print_store_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "Corner <shop>").await;
+    let request = server
+        .authed(Method::POST, &format!("store/{}/aisle", store_id), &user)
+        .json(&json!({ "name": "Dairy" }));
+    let aisle_id = json_body(request).await["aisle_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let request = server
+        .authed(Method::POST, &format!("aisle/{}/product", aisle_id), &user)
+        .json(&json!({ "name": "Milk" }));
+    json_body(request).await;
+
+    let res = server
+        .authed(Method::GET, &format!("store/{}/print", store_id), &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        true,
+        res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+    );
+    let html = res.text().await.unwrap();
+    assert_eq!(true, html.contains("<h1>Corner &lt;shop&gt;</h1>"));
+    assert_eq!(true, html.contains("Milk</label>"));
+    let request = server.request(Method::GET, &format!("store/{}/print", store_id));
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn default_aisles_test() {
+    let server = spawn_server_with(Config {
+        default_aisles: vec!["Produce".to_owned(), "Dairy".to_owned()],
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let aisle_names = |store: Store| {
+        let mut names: Vec<String> = store.aisles.into_iter().map(|a| a.name).collect();
+        names.sort();
+        names
+    };
+
+    let store_id = client.create_store("Server's").await.unwrap().store_id;
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(vec!["Dairy", "Produce"], aisle_names(store));
+    let empty = NewStore {
+        name: "Empty".to_owned(),
+        no_default_aisles: true,
+    };
+    let store_id = client.create_store_with(&empty).await.unwrap().store_id;
+    assert_eq!(
+        true,
+        client.store(&store_id).await.unwrap().aisles.is_empty()
+    );
+
+    // the user's own, even none, come first
+    assert_eq!(None, client.default_aisles().await.unwrap().aisles);
+    let own = vec!["Frozen".to_owned()];
+    client.set_default_aisles(Some(own.clone())).await.unwrap();
+    assert_eq!(Some(own), client.default_aisles().await.unwrap().aisles);
+    let store_id = client.create_store("Own").await.unwrap().store_id;
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(vec!["Frozen"], aisle_names(store));
+    client.set_default_aisles(Some(vec![])).await.unwrap();
+    let store_id = client.create_store("None").await.unwrap().store_id;
+    assert_eq!(
+        true,
+        client.store(&store_id).await.unwrap().aisles.is_empty()
+    );
+    client.set_default_aisles(None).await.unwrap();
+    let store_id = client.create_store("Back").await.unwrap().store_id;
+    assert_eq!(2, client.store(&store_id).await.unwrap().aisles.len());
+
+    let request = server
+        .authed(Method::PUT, "user/default_aisles", &user)
+        .json(&json!({ "aisles": [" "] }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn merge_stores_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("Home").await.unwrap().store_id;
+    let other_id = client.create_store("Holiday").await.unwrap().store_id;
+    let aisle = client.create_aisle(&other_id, "Bakery").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Bread")
+        .await
+        .unwrap();
+    let version = client.store_since(&store_id, 0).await.unwrap().version;
+
+    let plan = client
+        .merge_store(&store_id, &other_id, true)
+        .await
+        .unwrap();
+    assert_eq!(vec!["Bakery"], plan.moved_aisles);
+    assert_eq!(2, client.stores().await.unwrap().stores.len());
+    assert_eq!(
+        plan,
+        client
+            .merge_store(&store_id, &other_id, false)
+            .await
+            .unwrap()
+    );
+    let stores = client.stores().await.unwrap().stores;
+    assert_eq!(
+        vec![store_id.clone()],
+        stores.into_iter().map(|s| s.store_id).collect::<Vec<_>>()
+    );
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!("Bread", store.aisles[0].products[0].name);
+    // a client syncing gets the merged store in one go
+    let synced = client.store_since(&store_id, version).await.unwrap();
+    assert_eq!(version + 1, synced.version);
+    assert_eq!(StoreChanges::Snapshot(store), synced.changes);
+
+    let path = format!("store/{}/merge_from/{}", store_id, store_id);
+    let request = server.authed(Method::POST, &path, &user);
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn store_fields_since_version_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let path = format!("store/{}", store_id);
+    let request = server
+        .authed(Method::GET, &path, &user)
+        .query(&[("since_version", "0"), ("fields", "name")]);
+    assert_eq!(StatusCode::BAD_REQUEST, status(request).await);
+
+    let request = server
+        .authed(Method::GET, &path, &user)
+        .query(&[("fields", "name")]);
+    assert_eq!(json!({ "name": "MyStore" }), json_body(request).await);
+}
+
+#[tokio::test]
+async fn store_stats_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Cheese")
+        .await
+        .unwrap();
+    client
+        .create_product(&aisle.aisle_id, "milk")
+        .await
+        .unwrap();
+    let done = EditProduct::new(None, None, None, Some(true));
+    client.edit_product(&milk.product_id, &done).await.unwrap();
+
+    let stats = client.store_stats(&store_id).await.unwrap();
+    assert_eq!((3, 2), (stats.items, stats.items_left));
+    assert_eq!(
+        vec![AisleStats::new(aisle.aisle_id, "Dairy".to_owned(), 3, 2)],
+        stats.aisles
+    );
+    assert_eq!(
+        vec![
+            AddedProduct::new("milk".to_owned(), 2),
+            AddedProduct::new("cheese".to_owned(), 1),
+        ],
+        stats.most_added
+    );
+
+    let insights = client.store_insights(&store_id).await.unwrap();
+    assert_eq!(
+        vec![AddedProduct::new("milk".to_owned(), 2)],
+        insights.readded
+    );
+    assert_eq!(1, insights.habits.len());
+    assert_eq!(
+        (1, None),
+        (insights.habits[0].times, insights.habits[0].due_at)
+    );
+
+    let intruder = server.create_user().await;
+    let request = server.authed(Method::GET, &format!("store/{}/stats", store_id), &intruder);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+}
+
+#[tokio::test]
+async fn aliases_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    assert_eq!(true, client.aliases().await.unwrap().aliases.is_empty());
+    let mut aliases = ProductAliases::default();
+    aliases
+        .aliases
+        .insert("Tomatoes ".to_owned(), "Tomato".to_owned());
+    client.set_aliases(&aliases).await.unwrap();
+    let stored = client.aliases().await.unwrap();
+    assert_eq!(Some(&"tomato".to_owned()), stored.aliases.get("tomatoes"));
+
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Produce").await.unwrap();
+    for name in &["Tomatoes", "tomato"] {
+        client.create_product(&aisle.aisle_id, name).await.unwrap();
+    }
+    let stats = client.store_stats(&store_id).await.unwrap();
+    assert_eq!(
+        vec![AddedProduct::new("tomato".to_owned(), 2)],
+        stats.most_added
+    );
+
+    // an alias may not stand for another one, or for itself
+    for body in &[
+        json!({ "aliases": { "tomatoes": "tomato", "tomato": "tomatoe" } }),
+        json!({ "aliases": { "Tomato": "tomato" } }),
+        json!({ "aliases": { "tomatoes": " " } }),
+    ] {
+        let request = server.authed(Method::PUT, "user/aliases", &user).json(body);
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    }
+    client
+        .set_aliases(&ProductAliases::default())
+        .await
+        .unwrap();
+    assert_eq!(true, client.aliases().await.unwrap().aliases.is_empty());
+}
+
+#[tokio::test]
+async fn create_product_in_store_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+
+    let placed = client
+        .create_product_in_store(&store_id, "milk")
+        .await
+        .unwrap();
+    assert_eq!(AisleGuess::History, placed.guess);
+    assert_eq!(aisle.aisle_id, placed.aisle_id);
+    assert_eq!("Dairy", placed.aisle_name);
+    let placed = client
+        .create_product_in_store(&store_id, "Bread")
+        .await
+        .unwrap();
+    assert_eq!(AisleGuess::NewUnsorted, placed.guess);
+    assert_eq!("Unsorted", placed.aisle_name);
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(2, store.aisles.len());
+}
+
+#[tokio::test]
+async fn nutrition_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let request = server.authed(Method::GET, "nutrition?name=nutella", &user);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+
+    let (food_facts, lookups) = spawn_food_facts();
+    let server = spawn_server_with(Config {
+        nutrition: NutritionKind::OpenFoodFacts,
+        nutrition_url: food_facts,
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let nutella = client.nutrition(Some(NUTELLA_BARCODE), None).await.unwrap();
+    assert_eq!(Some("Nutella".to_owned()), nutella.name);
+    assert_eq!(Some(539.0), nutella.energy_kcal);
+    for path in &["nutrition?barcode=12", "nutrition", "nutrition?name=%20"] {
+        let request = server.authed(Method::GET, path, &user);
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    }
+
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Breakfast").await.unwrap();
+    let product = client
+        .create_product(&aisle.aisle_id, "Nutella")
+        .await
+        .unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Dragon fruit")
+        .await
+        .unwrap();
+    let facts = client.product_nutrition(&product.product_id).await.unwrap();
+    assert_eq!(Some(nutella.clone()), facts.nutrition);
+    let summary = client.store_nutrition(&store_id).await.unwrap();
+    assert_eq!(2, summary.products.len());
+    assert_eq!(1, summary.unknown);
+    assert_eq!(nutella.energy_kcal, summary.average.energy_kcal);
+
+    // both names were looked up once, the barcode too
+    assert_eq!(3, lookups.load(std::sync::atomic::Ordering::SeqCst));
+    client.store_nutrition(&store_id).await.unwrap();
+    assert_eq!(3, lookups.load(std::sync::atomic::Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn anywhere_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let other_store = client.create_store("Other").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+
+    let marked = client.mark_anywhere(&milk.product_id).await.unwrap();
+    assert_eq!("Milk", marked.name);
+    for id in &[&store_id, &other_store] {
+        let store = client.store(id).await.unwrap();
+        assert_eq!(
+            vec![milk.product_id.clone()],
+            store
+                .anywhere
+                .iter()
+                .map(|p| p.product_id.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(true, store.aisles.iter().all(|a| a.products.is_empty()));
+    }
+    assert_eq!(1, client.anywhere().await.unwrap().products.len());
+
+    client.check_anywhere(&milk.product_id).await.unwrap();
+    assert_eq!(
+        true,
+        client
+            .store(&other_store)
+            .await
+            .unwrap()
+            .anywhere
+            .is_empty()
+    );
+    assert_eq!(true, client.anywhere().await.unwrap().products.is_empty());
+}
+
+#[tokio::test]
+async fn quotas_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    client.create_store("Market").await.unwrap();
+    let quotas = client.quotas().await.unwrap().quotas;
+    assert_eq!(
+        vec!["stores", "bytes"],
+        quotas.iter().map(|q| q.quota.as_str()).collect::<Vec<_>>()
+    );
+    assert_eq!(1, quotas[0].used);
+    assert_eq!(true, quotas[1].used > 0);
+    assert_eq!(true, quotas.iter().all(|q| !q.warning));
+
+    // far from the default quotas, changes go through without a warning
+    let request = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": "Other" }));
+    let res = request.send().await.unwrap();
+    assert_eq!(true, res.status().is_success());
+    assert_eq!(None, res.headers().get("x-quota-warning"));
+}
+
+#[tokio::test]
+async fn quota_exceeded_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    for i in 0..100 {
+        client.create_store(&format!("Store {}", i)).await.unwrap();
+    }
+    let res = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": "One too many" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::CONFLICT, res.status());
+    assert_eq!(
+        json!({ "error": "quota_exceeded", "quota": "stores", "limit": 100 }),
+        res.json::<serde_json::Value>().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn staples_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let staples = Staples {
+        staples: vec![
+            Staple::new("Milk".to_owned(), "Dairy".to_owned()),
+            Staple::new("Tomato".to_owned(), "Produce".to_owned()),
+        ],
+    };
+    client.set_staples(&staples).await.unwrap();
+    let bread = Staple::new("Bread".to_owned(), "Bakery".to_owned());
+    client.add_staple(&bread).await.unwrap();
+    client.delete_staple("milk").await.unwrap();
+    assert_eq!(
+        vec![bread, staples.staples[1].clone()],
+        client.staples().await.unwrap().staples
+    );
+
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "produce").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "tomato")
+        .await
+        .unwrap();
+    let added = client.add_staples(&store_id).await.unwrap();
+    assert_eq!(1, added.aisles.len());
+    assert_eq!("Bakery", added.aisles[0].name);
+    assert_eq!(1, added.products.len());
+    assert_eq!("Bread", added.products[0].product.name);
+    assert_eq!(added.aisles[0].aisle_id, added.products[0].aisle_id);
+    let added = client.add_staples(&store_id).await.unwrap();
+    assert_eq!(true, added.aisles.is_empty() && added.products.is_empty());
+
+    for body in &[
+        json!({ "name": " ", "aisle": "Bakery" }),
+        json!({ "name": "Bread", "aisle": "" }),
+    ] {
+        let request = server
+            .authed(Method::POST, "user/staples", &user)
+            .json(body);
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    }
+}
+
+#[tokio::test]
+async fn templates_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    for name in &["Produce", "Dairy", "Bakery"] {
+        client.create_aisle(&store_id, name).await.unwrap();
+    }
+    let template = client.save_template("Weekly", &store_id).await.unwrap();
+    assert_eq!(vec!["Produce", "Dairy", "Bakery"], template.aisles);
+    client.save_template("Other", &store_id).await.unwrap();
+    client.delete_template("other").await.unwrap();
+    assert_eq!(vec![template], client.templates().await.unwrap().templates);
+
+    let other_id = client.create_store("Other").await.unwrap().store_id;
+    client.create_aisle(&other_id, "dairy").await.unwrap();
+    let added = client.apply_template("weekly", &other_id).await.unwrap();
+    assert_eq!(
+        vec!["Produce", "Bakery"],
+        added
+            .aisles
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+    );
+    let added = client.apply_template("Weekly", &other_id).await.unwrap();
+    assert_eq!(true, added.aisles.is_empty());
+
+    let request = server
+        .authed(Method::POST, "templates/apply", &user)
+        .json(&json!({ "name": "Monthly", "store_id": other_id }));
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let request = server
+        .authed(Method::POST, "templates", &user)
+        .json(&json!({ "name": " ", "store_id": store_id }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn snapshots_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    let monday = client.create_snapshot(&store_id, "Monday").await.unwrap();
+    assert_eq!((1, 1), (monday.aisles, monday.products));
+
+    client.delete_aisle(&aisle.aisle_id).await.unwrap();
+    client.create_aisle(&store_id, "Bakery").await.unwrap();
+    let store = client
+        .restore_snapshot(&store_id, &monday.snapshot_id)
+        .await
+        .unwrap();
+    assert_eq!(1, store.aisles.len());
+    assert_eq!("Dairy", store.aisles[0].name);
+    assert_eq!(1, store.aisles[0].products.len());
+    assert_eq!("Milk", store.aisles[0].products[0].name);
+    let snapshots = client.snapshots(&store_id).await.unwrap().snapshots;
+    assert_eq!(vec![monday.clone()], snapshots);
+
+    let aisle_id = &store.aisles[0].aisle_id;
+    client.create_product(aisle_id, "Butter").await.unwrap();
+    let diff = client
+        .snapshot_diff(&store_id, &monday.snapshot_id, "live")
+        .await
+        .unwrap();
+    assert_eq!(1, diff.added_products.len());
+    assert_eq!("Butter", diff.added_products[0].name);
+    assert!(diff.removed_products.is_empty() && diff.changed_products.is_empty());
+    assert!(diff.added_aisles.is_empty() && diff.removed_aisles.is_empty());
+    let path = format!(
+        "store/{}/snapshots/{}/diff/yesterday",
+        store_id, monday.snapshot_id
+    );
+    let request = server.authed(Method::GET, &path, &user);
+    assert_eq!(StatusCode::BAD_REQUEST, status(request).await);
+
+    let path = format!("store/{}/snapshot", store_id);
+    let request = server
+        .authed(Method::POST, &path, &user)
+        .json(&json!({ "name": " " }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+}
+
+#[tokio::test]
+async fn product_history_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    let edit = EditProduct::new(None, Some(2), None, Some(true));
+    client.edit_product(&milk.product_id, &edit).await.unwrap();
+    let history = client.product_history(&milk.product_id).await.unwrap();
+    assert_eq!(milk.product_id, history.product_id);
+    assert_eq!(vec!["quantity", "is_done"], history.edits[0].fields);
+    let purchases = history.purchases.unwrap();
+    assert_eq!(
+        ("milk", Some(2f32)),
+        (purchases.name.as_str(), purchases.average_quantity)
+    );
+}
+
+#[tokio::test]
+async fn set_products_done_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let mut ids = vec![];
+    for name in &["Milk", "Butter", "Cheese"] {
+        let product = client.create_product(&aisle.aisle_id, name).await.unwrap();
+        ids.push(product.product_id);
+    }
+    client
+        .set_products_done(&[&ids[0], &ids[2]], true)
+        .await
+        .unwrap();
+    let store = client.store(&store_id).await.unwrap();
+    let mut done: Vec<&str> = store.aisles[0]
+        .products
+        .iter()
+        .filter(|p| p.is_done)
+        .map(|p| p.name.as_str())
+        .collect();
+    done.sort();
+    assert_eq!(vec!["Cheese", "Milk"], done);
+
+    let request = server
+        .authed(Method::PUT, "products/done", &user)
+        .json(&json!({ "product_ids": [ids[1], "missing"], "is_done": true }));
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let store = client.store(&store_id).await.unwrap();
+    let butter = store.aisles[0].products.iter().find(|p| p.name == "Butter");
+    assert_eq!(Some(false), butter.map(|p| p.is_done));
+}
+
+#[tokio::test]
+async fn delete_products_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("MyStore").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let mut ids = vec![];
+    for name in &["Milk", "Butter", "Cheese"] {
+        let product = client.create_product(&aisle.aisle_id, name).await.unwrap();
+        ids.push(product.product_id);
+    }
+    let request = server
+        .authed(Method::DELETE, "products", &user)
+        .json(&json!({ "product_ids": [ids[0], "missing"] }));
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let request = server
+        .authed(Method::DELETE, "products", &user)
+        .json(&json!({ "product_ids": [] }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(3, store.aisles[0].products.len());
+
+    client.delete_products(&[&ids[0], &ids[2]]).await.unwrap();
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!(
+        vec!["Butter"],
+        store.aisles[0]
+            .products
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn shared_template_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("Market").await.unwrap().store_id;
+    let aisle = client.create_aisle(&store_id, "Produce").await.unwrap();
+    let product = client
+        .create_product(&aisle.aisle_id, "Apples")
+        .await
+        .unwrap();
+    client
+        .edit_product(
+            &product.product_id,
+            &EditProduct::new(None, None, None, Some(true)),
+        )
+        .await
+        .unwrap();
+    let shared = client.template_code(&store_id).await.unwrap();
+
+    let friend = server.create_user().await;
+    let friend_client = server.client().with_token(&friend.auth);
+    let copy_id = friend_client
+        .redeem_template(&shared.code, None)
+        .await
+        .unwrap()
+        .store_id;
+    let copy = friend_client.store(&copy_id).await.unwrap();
+    assert_eq!("Market", copy.name);
+    assert_eq!(1, copy.aisles.len());
+    assert_eq!("Produce", copy.aisles[0].name);
+    assert_eq!(1, copy.aisles[0].products.len());
+    assert_eq!("Apples", copy.aisles[0].products[0].name);
+    assert_eq!(false, copy.aisles[0].products[0].is_done);
+    // the copy is the friend's own
+    assert_eq!(true, client.store(&copy_id).await.is_err());
+
+    let request = server
+        .authed(Method::POST, "templates/redeem", &friend)
+        .json(&json!({ "code": "NOPE2345" }));
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+}
+
+#[tokio::test]
+async fn assistant_test() {
+    let redirect_uri = "https://assistant.example/link";
+    let server = spawn_server_with(Config {
+        assistant_redirect_uri: vec![redirect_uri.to_owned()],
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let link = |redirect_uri: &str| {
+        client
+            .get(&server.url("assistant/link"))
+            .header(HEADER_AUTH, user.auth.as_str())
+            .query(&[
+                ("response_type", "token"),
+                ("redirect_uri", redirect_uri),
+                ("state", "a b"),
+            ])
+    };
+    let res = link(redirect_uri).send().await.unwrap();
+    assert_eq!(StatusCode::FOUND, res.status());
+    let location = res.headers()["location"].to_str().unwrap();
+    let prefix = format!("{}#access_token=", redirect_uri);
+    assert_eq!(true, location.starts_with(&prefix));
+    assert_eq!(true, location.ends_with("&token_type=Bearer&state=a%20b"));
+    let token = location[prefix.len()..]
+        .split('&')
+        .next()
+        .unwrap()
+        .to_owned();
+    assert_ne!(user.auth, token);
+    assert_eq!(
+        StatusCode::FORBIDDEN,
+        status(link("https://evil.example")).await
+    );
+
+    let bearer = |path: &str| {
+        server
+            .request(Method::POST, path)
+            .header("authorization", format!("Bearer {}", token))
+    };
+    let body = json_body(bearer("assistant/list").json(&json!({}))).await;
+    assert_eq!("You have no shopping list yet.", body["speech"]);
+    server.create_store(&user, "Market").await;
+    let request = bearer("assistant/add").json(&json!({ "text": "two litres of milk" }));
+    assert_eq!(
+        "I added 2 litres of milk to Market.",
+        json_body(request).await["speech"]
+    );
+    let request = bearer("assistant/add").json(&json!({ "text": "3 eggs" }));
+    json_body(request).await;
+    let body = json_body(bearer("assistant/list").json(&json!({}))).await;
+    assert_eq!(
+        "At Market you need 2 litres of milk and 3 eggs.",
+        body["speech"]
+    );
+    let request = server
+        .request(Method::POST, "assistant/list")
+        .json(&json!({}));
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn api_schema_test() {
+    let server = spawn_server();
+    let body = json_body(server.request(Method::GET, "schema")).await;
+    assert_eq!(false, body["requests"]["POST /user"].is_null());
+    assert_eq!(false, body["requests"]["PUT /product/<id>"].is_null());
+    assert_eq!(false, body["responses"]["GET /store/<id>"].is_null());
+    assert_eq!(true, body["requests"]["GET /store"].is_null());
+    assert_eq!(true, body["definitions"].is_object());
+}
+
+#[tokio::test]
+async fn client_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let mut client = server.client();
+    let token = client
+        .login(&AuthInfo {
+            username: user.username.clone(),
+            password: PASSWORD.into(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(user.user_id, token.user_id);
+
+    let store_id = client
+        .create_store("Market")
+        .await
+        .unwrap()
+        .store_id
+        .clone();
+    let aisle = client.create_aisle(&store_id, "Dairy").await.unwrap();
+    let milk = client
+        .create_product(&aisle.aisle_id, "Milk")
+        .await
+        .unwrap();
+    let version = client.store_since(&store_id, 0).await.unwrap().version;
+    let edit = EditProduct::new(None, Some(2), Some(Unit::Ml), Some(true));
+    client.edit_product(&milk.product_id, &edit).await.unwrap();
+    client
+        .rename_aisle(&aisle.aisle_id, "Fridge")
+        .await
+        .unwrap();
+
+    let store = client.store(&store_id).await.unwrap();
+    assert_eq!("Fridge", store.aisles[0].name);
+    assert_eq!(Unit::Ml, store.aisles[0].products[0].unit);
+    match client
+        .store_since(&store_id, version)
+        .await
+        .unwrap()
+        .changes
+    {
+        StoreChanges::Delta(delta) => {
+            assert_eq!(1, delta.aisles.len());
+            assert_eq!(true, delta.products[0].product.is_done);
+        }
+        StoreChanges::Snapshot(_) => panic!("expected a delta"),
+    }
+    let link = client.export_link(&store_id).await.unwrap();
+    assert_eq!(store, server.client().export(&link).await.unwrap());
+    assert_eq!(1, client.stores().await.unwrap().stores.len());
+
+    client.delete_store(&store_id).await.unwrap();
+    match client.store(&store_id).await {
+        Err(efficio_client::Error::Api { status, .. }) => assert_ne!(StatusCode::OK, status),
+        _ => panic!("the store is gone"),
+    }
+    client.logout(&user.user_id).await.unwrap();
+    assert_eq!(None, client.token());
+}
+
+#[tokio::test]
+async fn auth_header_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let other = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let path = format!("store/{}", store_id);
+
+    let request = server
+        .request(Method::GET, &path)
+        .header(HEADER_AUTH, "not a session");
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+    let request = server.authed(Method::GET, &path, &other);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let request = server.authed(Method::DELETE, &path, &other);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+}
+
+#[tokio::test]
+async fn aisle_and_product_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+
+    let request = server
+        .authed(Method::POST, &format!("store/{}/aisle", store_id), &user)
+        .json(&json!({ "name": "Fruits" }));
+    let aisle = json_body(request).await;
+    let aisle_id = aisle["aisle_id"].as_str().unwrap().to_owned();
+    assert_eq!(json!("Fruits"), aisle["name"]);
+    assert_eq!(json!(1f32), aisle["sort_weight"]);
+    assert_eq!(json!([]), aisle["products"]);
+
+    let request = server
+        .authed(Method::POST, &format!("aisle/{}/product", aisle_id), &user)
+        .json(&json!({ "name": "Apple" }));
+    let product = json_body(request).await;
+    let product_id = product["product_id"].as_str().unwrap().to_owned();
+    assert_eq!(
+        json!({
+            "product_id": product_id,
+            "name": "Apple",
+            "quantity": 1,
+            "is_done": false,
+            "unit": 0,
+            "sort_weight": 1f32,
+        }),
+        product
+    );
+
+    let product_path = format!("product/{}", product_id);
+    let request = server
+        .authed(Method::PUT, &product_path, &user)
+        .json(&json!({}));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+
+    // 2 of 6 bought, the other 4 still to buy
+    let request = server
+        .authed(Method::PUT, &product_path, &user)
+        .json(&json!({ "quantity": 6 }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let request = server
+        .authed(Method::PUT, &product_path, &user)
+        .json(&json!({ "bought": 2 }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let store_path = format!("store/{}", store_id);
+    let store = json_body(server.authed(Method::GET, &store_path, &user)).await;
+    let product = &store["aisles"][0]["products"][0];
+    assert_eq!(
+        (&json!(4), &json!(false)),
+        (&product["quantity"], &product["is_done"])
+    );
+    for body in &[
+        json!({ "bought": 0 }),
+        json!({ "bought": 1, "quantity": 1 }),
+    ] {
+        let request = server.authed(Method::PUT, &product_path, &user).json(body);
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    }
+
+    let request = server
+        .authed(Method::PUT, &product_path, &user)
+        .json(&json!({ "quantity": 3, "is_done": true }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let request = server
+        .authed(Method::PUT, &format!("aisle/{}", aisle_id), &user)
+        .json(&json!({ "name": "Fruits & Veggies" }));
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    let store = json_body(server.authed(Method::GET, &store_path, &user)).await;
+    let aisle = &store["aisles"][0];
+    assert_eq!(json!("Fruits & Veggies"), aisle["name"]);
+    assert_eq!(json!(3), aisle["products"][0]["quantity"]);
+    assert_eq!(json!(true), aisle["products"][0]["is_done"]);
+
+    let request = server.authed(Method::DELETE, &product_path, &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let store = json_body(server.authed(Method::GET, &store_path, &user)).await;
+    assert_eq!(json!([]), store["aisles"][0]["products"]);
+
+    let request = server.authed(Method::DELETE, &format!("aisle/{}", aisle_id), &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let store = json_body(server.authed(Method::GET, &store_path, &user)).await;
+    assert_eq!(json!([]), store["aisles"]);
+}
+
+#[tokio::test]
+async fn replayed_bought_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let request = server
+        .authed(Method::POST, &format!("store/{}/aisle", store_id), &user)
+        .json(&json!({ "name": "Fruits" }));
+    let aisle_id = json_body(request).await["aisle_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let request = server
+        .authed(Method::POST, &format!("aisle/{}/product", aisle_id), &user)
+        .json(&json!({ "name": "Apple" }));
+    let product_id = json_body(request).await["product_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let product_path = format!("product/{}", product_id);
+    let quantity = EditProduct::new(None, Some(6), None, None);
+    assert_eq!(Retry::Idempotent, product::edit_retry(&quantity));
+    let mut bought = EditProduct::new(None, None, None, None);
+    bought.bought = Some(2);
+    assert_eq!(Retry::Never, product::edit_retry(&bought));
+    let request = server
+        .authed(Method::PUT, &product_path, &user)
+        .json(&json!({ "quantity": 6 }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    // the same purchase sent again is bought again, so it is never retried
+    let store_path = format!("store/{}", store_id);
+    for left in &[4, 2] {
+        let request = server
+            .authed(Method::PUT, &product_path, &user)
+            .json(&json!({ "bought": 2 }));
+        assert_eq!(StatusCode::OK, status(request).await);
+        let store = json_body(server.authed(Method::GET, &store_path, &user)).await;
+        assert_eq!(json!(left), store["aisles"][0]["products"][0]["quantity"]);
+    }
+}
+
+#[tokio::test]
+async fn sort_weight_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let request = server
+        .authed(Method::POST, &format!("store/{}/aisle", store_id), &user)
+        .json(&json!({ "name": "Fruits" }));
+    let aisle_id = json_body(request).await["aisle_id"].clone();
+
+    let request = server
+        .authed(Method::PUT, "sort_weight", &user)
+        .json(&json!({ "aisles": [] }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    let request = server
+        .authed(Method::PUT, "sort_weight", &user)
+        .json(&json!({ "aisles": [{ "id": aisle_id, "sort_weight": 42f32 }] }));
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    let path = format!("store/{}", store_id);
+    let store = json_body(server.authed(Method::GET, &path, &user)).await;
+    assert_eq!(json!(42f32), store["aisles"][0]["sort_weight"]);
+
+    // with an aisle of someone else, all of it is refused
+    let intruder = server.create_user().await;
+    let other_store = server.create_store(&intruder, "Theirs").await;
+    let request = server
+        .authed(
+            Method::POST,
+            &format!("store/{}/aisle", other_store),
+            &intruder,
+        )
+        .json(&json!({ "name": "Fruits" }));
+    let other_aisle = json_body(request).await["aisle_id"].clone();
+    let weights = json!({ "aisles": [
+        { "id": aisle_id, "sort_weight": 1f32 },
+        { "id": other_aisle, "sort_weight": 1f32 }
+    ] });
+    let request = server
+        .authed(Method::PUT, "sort_weight", &user)
+        .json(&weights);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let request = server
+        .authed(Method::PUT, "sort_weight", &intruder)
+        .json(&weights);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+    let store = json_body(server.authed(Method::GET, &path, &user)).await;
+    assert_eq!(json!(42f32), store["aisles"][0]["sort_weight"]);
+}
+
+#[tokio::test]
+async fn cookie_session_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+
+    let body = json!({ "username": user.username, "password": PASSWORD });
+    let res = server
+        .request(Method::POST, "login")
+        .header(HEADER_SESSION_MODE, SESSION_MODE_COOKIE)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    let cookies: Vec<String> = res
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .map(|v| v.to_str().unwrap().to_owned())
+        .collect();
+    assert!(cookies
+        .iter()
+        .any(|c| c.starts_with(COOKIE_SESSION) && c.contains("HttpOnly")));
+    let session: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(json!(user.user_id), session["user_id"]);
+    assert_eq!(None, session.get("session_token"));
+    let csrf = session["csrf_token"].as_str().unwrap().to_owned();
+
+    // the browser sends back both cookies, only the pair name=value matters
+    let cookie = cookies
+        .iter()
+        .map(|c| c.split(';').next().unwrap())
+        .collect::<Vec<_>>()
+        .join("; ");
+    let request = server
+        .request(Method::GET, "store")
+        .header("cookie", cookie.as_str());
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    let request = server
+        .request(Method::POST, "store")
+        .header("cookie", cookie.as_str())
+        .json(&json!({ "name": "MyStore" }));
+    assert_eq!(StatusCode::FORBIDDEN, status(request).await);
+    let request = server
+        .request(Method::POST, "store")
+        .header("cookie", cookie.as_str())
+        .header(HEADER_CSRF, "forged")
+        .json(&json!({ "name": "MyStore" }));
+    assert_eq!(StatusCode::FORBIDDEN, status(request).await);
+    let request = server
+        .request(Method::POST, "store")
+        .header("cookie", cookie.as_str())
+        .header(HEADER_CSRF, csrf.as_str())
+        .json(&json!({ "name": "MyStore" }));
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    // header sessions are left alone
+    let request = server.authed(Method::GET, "store", &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let request = server.request(Method::GET, "store");
+    assert_eq!(StatusCode::UNAUTHORIZED, status(request).await);
+}
+
+#[tokio::test]
+async fn admin_config_test() {
+    let server = spawn_server();
+    let report = json_body(server.admin(Method::GET, "admin/config")).await;
+    assert_eq!(json!([]), report["config"]["allow_ip"]);
+    assert_eq!(json!([]), report["last_changes"]);
+}
+
+#[tokio::test]
+async fn admin_token_test() {
+    let server = spawn_server();
+    // the server's own address proves nothing behind a reverse proxy
+    let request = server
+        .request(Method::GET, "admin/config")
+        .header("x-forwarded-for", "127.0.0.1");
+    assert_eq!(StatusCode::FORBIDDEN, status(request).await);
+    let request = server
+        .request(Method::GET, "admin/config")
+        .bearer_auth("not-the-token");
+    assert_eq!(StatusCode::FORBIDDEN, status(request).await);
+    let request = server.root_request(Method::GET, "metrics");
+    assert_eq!(StatusCode::FORBIDDEN, status(request).await);
+    let request = server.admin(Method::GET, "admin/config");
+    assert_eq!(StatusCode::OK, status(request).await);
+}
+
+#[tokio::test]
+async fn maintenance_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let health = json_body(server.request(Method::GET, "health")).await;
+    assert_eq!(json!({ "maintenance": false, "read_only": false }), health);
+
+    let request = server
+        .admin(Method::PUT, "admin/maintenance")
+        .json(&json!({ "enabled": true, "retry_after_s": 120 }));
+    let maintenance = json_body(request).await;
+    assert_eq!(json!(true), maintenance["enabled"]);
+    assert_eq!(json!(120), maintenance["retry_after_s"]);
+    assert!(!maintenance["message"].as_str().unwrap().is_empty());
+
+    let res = server
+        .authed(Method::GET, "store", &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    assert_eq!("120", res.headers()["retry-after"]);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(json!("maintenance"), body["error"]);
+    assert_eq!(maintenance["message"], body["message"]);
+    let request = server.request(Method::GET, "challenge");
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(request).await);
+
+    // health checks and the admin API still answer
+    let health = json_body(server.request(Method::GET, "health")).await;
+    assert_eq!(json!({ "maintenance": true, "read_only": false }), health);
+    let current = json_body(server.admin(Method::GET, "admin/maintenance")).await;
+    assert_eq!(maintenance, current);
+
+    let request = server
+        .admin(Method::PUT, "admin/maintenance")
+        .json(&json!({ "enabled": false }));
+    assert_eq!(StatusCode::OK, status(request).await);
+    let request = server.authed(Method::GET, "store", &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+}
+
+#[tokio::test]
+async fn read_only_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let request = server
+        .admin(Method::PUT, "admin/read_only")
+        .json(&json!({ "enabled": true }));
+    let report = json_body(request).await;
+    assert_eq!(json!(true), report["enabled"]);
+    assert_eq!(json!(true), report["switch"]);
+    assert_eq!(json!(false), report["config"]);
+
+    let res = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": "Other" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    assert!(res.headers().contains_key("retry-after"));
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(json!("read_only"), body["error"]);
+    // reads are still served
+    let request = server.authed(Method::GET, &format!("store/{}", store_id), &user);
+    assert_eq!(StatusCode::OK, status(request).await);
+    let health = json_body(server.request(Method::GET, "health")).await;
+    assert_eq!(json!(true), health["read_only"]);
+
+    let request = server
+        .admin(Method::PUT, "admin/read_only")
+        .json(&json!({ "enabled": false }));
+    assert_eq!(json!(false), json_body(request).await["enabled"]);
+    let request = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": "Other" }));
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    // set in the configuration, the switch doesn't lift it
+    let server = spawn_server_with(Config {
+        read_only: true,
+        ..Config::default()
+    });
+    let report = json_body(server.admin(Method::GET, "admin/read_only")).await;
+    assert_eq!(json!(true), report["enabled"]);
+    assert_eq!(json!(true), report["config"]);
+    let request = server
+        .authed(Method::POST, "store", &user)
+        .json(&json!({ "name": "Other" }));
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(request).await);
+}
+
+#[tokio::test]
+async fn admin_schema_test() {
+    let server = spawn_server();
+    let report = json_body(server.admin(Method::GET, "admin/schema")).await;
+    assert_eq!(json!(true), report["compatible"]);
+    assert_eq!(json!(false), report["read_only"]);
+    assert!(report["min_supported"].as_u64() <= report["database"].as_u64());
+    assert!(report["database"].as_u64() <= report["max_supported"].as_u64());
+}
+
+#[tokio::test]
+async fn abuse_ban_test() {
+    let server = spawn_server_with(Config {
+        trusted_proxy: vec!["127.0.0.1".parse().unwrap()],
+        abuse_max_requests: 3,
+        abuse_ban_s: 60,
+        ..Config::default()
+    });
+    let proxied = || {
+        server
+            .request(Method::GET, "health")
+            .header("x-forwarded-for", "203.0.113.7")
+    };
+    for _ in 0..4 {
+        assert_eq!(StatusCode::OK, status(proxied()).await);
+    }
+    // the address made one request too many
+    let res = proxied().send().await.unwrap();
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+    assert_eq!("60", res.headers()["retry-after"]);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(json!("banned"), body["error"]);
+
+    let bans = json_body(server.admin(Method::GET, "admin/bans")).await;
+    assert_eq!(1, bans.as_array().unwrap().len());
+    assert_eq!(json!("ip:203.0.113.7"), bans[0]["subject"]);
+    let revoke = server.admin(Method::DELETE, "admin/bans/ip:203.0.113.7");
+    assert_eq!(StatusCode::OK, status(revoke).await);
+    let revoke = server.admin(Method::DELETE, "admin/bans/ip:203.0.113.7");
+    assert_eq!(StatusCode::NOT_FOUND, status(revoke).await);
+
+    // the proxy itself never gets banned, the user behind it does
+    let user = server.create_user().await;
+    for _ in 0..4 {
+        let request = server.authed(Method::GET, "store", &user);
+        assert_eq!(StatusCode::OK, status(request).await);
+    }
+    let request = server.authed(Method::GET, "store", &user);
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, status(request).await);
+    let request = server.request(Method::GET, "health");
+    assert_eq!(StatusCode::OK, status(request).await);
+    let bans = json_body(server.admin(Method::GET, "admin/bans")).await;
+    assert_eq!(1, bans.as_array().unwrap().len());
+    assert_eq!(json!(format!("user:{}", user.user_id)), bans[0]["subject"]);
+}
+
+// the value of `efficio_events_total` for `event` in the /metrics text
+fn events_total(metrics: &str, event: &str) -> u64 {
+    let prefix = format!("efficio_events_total{{event=\"{}\"}} ", event);
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn metrics_test() {
+    let server = spawn_server();
+    let get_metrics = || async {
+        let res = server
+            .root_request(Method::GET, "metrics")
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        res.text().await.unwrap()
+    };
+    let before = get_metrics().await;
+
+    let user = server.create_user().await;
+    let body = json!({ "username": user.username, "password": PASSWORD });
+    json_body(server.request(Method::POST, "login").json(&body)).await;
+    let store_id = server.create_store(&user, "MyStore").await;
+    let request = server
+        .authed(Method::POST, &format!("store/{}/aisle", store_id), &user)
+        .json(&json!({ "name": "Dairy" }));
+    let aisle_id = json_body(request).await["aisle_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let request = server
+        .authed(Method::POST, &format!("aisle/{}/product", aisle_id), &user)
+        .json(&json!({ "name": "Milk" }));
+    let product_id = json_body(request).await["product_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let request = server
+        .authed(Method::PUT, &format!("product/{}", product_id), &user)
+        .json(&json!({ "is_done": true }));
+    assert_eq!(StatusCode::OK, status(request).await);
+
+    // other tests count along on the same database
+    let after = get_metrics().await;
+    assert!(after.contains("# TYPE efficio_events_total counter"));
+    for event in &["registration", "login", "store_created", "product_checked"] {
+        assert!(events_total(&after, event) > events_total(&before, event));
+    }
+}
+
+#[tokio::test]
+async fn telemetry_test() {
+    // off unless opted in
+    let server = spawn_server();
+    let request = server.admin(Method::GET, "admin/telemetry");
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+
+    let server = spawn_server_with(Config {
+        telemetry: true,
+        ..Config::default()
+    });
+    let report = json_body(server.admin(Method::GET, "admin/telemetry")).await;
+    assert_eq!(10, report["day"].as_str().unwrap().len());
+    assert!(report["version"].is_string());
+    let events = report["events"].as_object().unwrap();
+    assert_eq!(4, events.len());
+    assert!(events["login"].is_u64());
+}
+
+#[tokio::test]
+async fn community_test() {
+    // off unless configured
+    let server = spawn_server();
+    let user = server.create_user().await;
+    let request = server.authed(Method::GET, "community", &user);
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+
+    let server = spawn_server_with(Config {
+        community: true,
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let client = server.client().with_token(&user.auth);
+    let store_id = client.create_store("Market").await.unwrap().store_id;
+    for name in &["Produce", "Dairy"] {
+        client.create_aisle(&store_id, name).await.unwrap();
+    }
+    client.save_template("Weekly", &store_id).await.unwrap();
+    let publish = PublishTemplate::new(
+        "weekly".to_owned(),
+        "Kwik-E-Mart".to_owned(),
+        "Springfield".to_owned(),
+    );
+    let published = client.publish_template(&publish).await.unwrap();
+    assert_eq!(vec!["Produce", "Dairy"], published.aisles);
+    let request = server
+        .authed(Method::POST, "community", &user)
+        .json(&json!({ "name": "Weekly", "supermarket": " " }));
+    assert_eq!(StatusCode::PRECONDITION_FAILED, status(request).await);
+    let request = server
+        .authed(Method::POST, "community", &user)
+        .json(&json!({ "name": "Monthly", "supermarket": "Kwik-E-Mart" }));
+    assert_eq!(StatusCode::NOT_FOUND, status(request).await);
+
+    let other = server.create_user().await;
+    let other_client = server.client().with_token(&other.auth);
+    let found = other_client.community("springfield").await.unwrap();
+    assert_eq!(vec![published.clone()], found.templates);
+    let saved = other_client
+        .save_community_template(&published.template_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![saved],
+        other_client.templates().await.unwrap().templates
+    );
+
+    // enough users reporting it hide it until a moderator restores it
+    for _ in 0..3 {
+        let reporter = server.create_user().await;
+        server
+            .client()
+            .with_token(&reporter.auth)
+            .report_template(&published.template_id)
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        true,
+        client.community("kwik").await.unwrap().templates.is_empty()
+    );
+    let reported = json_body(server.admin(Method::GET, "admin/community")).await;
+    assert_eq!(3, reported[0]["reports"]);
+    assert_eq!(true, reported[0]["hidden"]);
+    let path = format!("admin/community/{}/restore", published.template_id);
+    assert_eq!(
+        StatusCode::OK,
+        status(server.admin(Method::POST, &path)).await
+    );
+    assert_eq!(1, client.community("kwik").await.unwrap().templates.len());
+    let path = format!("admin/community/{}", published.template_id);
+    assert_eq!(
+        StatusCode::OK,
+        status(server.admin(Method::DELETE, &path)).await
+    );
+    assert_eq!(
+        true,
+        client.community("kwik").await.unwrap().templates.is_empty()
+    );
+}
+
+#[tokio::test]
+async fn rate_limit_headers_test() {
+    let server = spawn_server_with(Config {
+        abuse_max_requests: 5,
+        abuse_ban_s: 60,
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    for remaining in &["4", "3"] {
+        let res = server
+            .authed(Method::GET, "store", &user)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("5", res.headers()["x-ratelimit-limit"]);
+        assert_eq!(*remaining, res.headers()["x-ratelimit-remaining"]);
+        let reset: u64 = res.headers()["x-ratelimit-reset"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset <= 60);
+    }
+    // only sessions have a budget of their own
+    let res = server.request(Method::GET, "health").send().await.unwrap();
+    assert!(!res.headers().contains_key("x-ratelimit-limit"));
+}
+
+#[tokio::test]
+async fn default_rate_limit_headers_test() {
+    // as the server starts without abuse options: bans are off
+    let server = spawn_server_with(Config {
+        abuse_max_requests: DEFAULT_ABUSE_MAX_REQUESTS,
+        abuse_max_error_ratio: DEFAULT_ABUSE_MAX_ERROR_RATIO,
+        abuse_min_errors: DEFAULT_ABUSE_MIN_ERRORS,
+        ..Config::default()
+    });
+    let user = server.create_user().await;
+    let res = server
+        .authed(Method::GET, "store", &user)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        DEFAULT_ABUSE_MAX_REQUESTS.to_string(),
+        res.headers()["x-ratelimit-limit"]
+    );
+    assert_eq!(
+        (DEFAULT_ABUSE_MAX_REQUESTS - 1).to_string(),
+        res.headers()["x-ratelimit-remaining"]
+    );
+    assert!(res.headers().contains_key("x-ratelimit-reset"));
+}
+
+#[tokio::test]
+async fn registration_test() {
+    let server = spawn_server_with(Config {
+        registration: Registration::Invite,
+        registration_email_domain: vec!["efficio.app".to_owned()],
+        ..Config::default()
+    });
+    let sign_up = |email: String, invite: Option<&str>| {
+        let username = unique_username();
+        let user = User {
+            email: email.replace("{}", &username).into(),
+            username,
+            password: PASSWORD.into(),
+            invite: invite.map(str::to_owned),
+            challenge: None,
+        };
+        server.request(Method::POST, "user").json(&user).send()
+    };
+    let refused = |res: reqwest::Response| async move {
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        let body: serde_json::Value = res.json().await.unwrap();
+        body["error"].clone()
+    };
+
+    let res = sign_up("{}@elsewhere.org".to_owned(), None).await.unwrap();
+    assert_eq!(json!("email_domain_not_allowed"), refused(res).await);
+    let res = sign_up("{}@efficio.app".to_owned(), None).await.unwrap();
+    assert_eq!(json!("invite_required"), refused(res).await);
+    let res = sign_up("{}@efficio.app".to_owned(), Some("nope"))
+        .await
+        .unwrap();
+    assert_eq!(json!("invite_invalid"), refused(res).await);
+
+    let invite = server
+        .admin(Method::POST, "admin/invites")
+        .json(&json!({ "uses": 1 }));
+    let invite = json_body(invite).await;
+    let code = invite["code"].as_str().unwrap();
+    // a sign up failing validation keeps the invite
+    let res = sign_up("not an email".to_owned(), Some(code))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+    let res = sign_up("{}@efficio.app".to_owned(), Some(code))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    let res = sign_up("{}@efficio.app".to_owned(), Some(code))
+        .await
+        .unwrap();
+    assert_eq!(json!("invite_invalid"), refused(res).await);
+
+    let invite = json_body(server.admin(Method::POST, "admin/invites").json(&json!({}))).await;
+    let revoke = server.admin(
+        Method::DELETE,
+        &format!("admin/invites/{}", invite["code"].as_str().unwrap()),
+    );
+    assert_eq!(StatusCode::OK, status(revoke).await);
+    let res = sign_up("{}@efficio.app".to_owned(), invite["code"].as_str())
+        .await
+        .unwrap();
+    assert_eq!(json!("invite_invalid"), refused(res).await);
+}
+
+#[tokio::test]
+async fn challenge_test() {
+    let server = spawn_server_with(Config {
+        challenge: ChallengeKind::Pow,
+        pow_difficulty: 8,
+        ..Config::default()
+    });
+    let sign_up = |challenge: Option<String>| {
+        let username = unique_username();
+        let user = User {
+            email: format!("{}@efficio.app", username).into(),
+            username,
+            password: PASSWORD.into(),
+            invite: None,
+            challenge,
+        };
+        server.request(Method::POST, "user").json(&user).send()
+    };
+    let refused = |res: reqwest::Response| async move {
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        let body: serde_json::Value = res.json().await.unwrap();
+        body["error"].clone()
+    };
+
+    let res = sign_up(None).await.unwrap();
+    assert_eq!(json!("challenge_required"), refused(res).await);
+    let res = sign_up(Some("nope:0".to_owned())).await.unwrap();
+    assert_eq!(json!("challenge_failed"), refused(res).await);
+
+    let (seed, difficulty) = match server.client().challenge().await.unwrap() {
+        ChallengeInfo::Pow { seed, difficulty } => (seed, difficulty),
+        info => panic!("unexpected challenge {:?}", info),
+    };
+    assert_eq!(8, difficulty);
+    let answer = solve_pow(&seed, difficulty);
+    let res = sign_up(Some(answer.clone())).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    // an answer only passes once
+    let res = sign_up(Some(answer)).await.unwrap();
+    assert_eq!(json!("challenge_failed"), refused(res).await);
+
+    let server = spawn_server();
+    assert_eq!(
+        ChallengeInfo::None,
+        server.client().challenge().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn job_queue_test() {
+    let server = spawn_server();
+    let queue = JobQueue::new(pool(), "api_test")
+        .retry_policy(RetryPolicy {
+            min_idle_ms: 10,
+            max_attempts: 2,
+        })
+        .poll_interval(Duration::from_millis(10))
+        .handler("noop", |_| async { Ok(()) })
+        .handler("broken", |job| async move {
+            assert_eq!("[1,2]", job.payload);
+            Err(ServerError::new(error::INTERNAL_ERROR, "Broken"))
+        });
+    queue.enqueue("noop", &json!({})).unwrap();
+    queue.enqueue("broken", &[1, 2]).unwrap();
+    queue.clone().start(2);
+
+    let mut stats = json!({});
+    for _ in 0..100 {
+        stats = json_body(server.admin(Method::GET, "admin/jobs/api_test")).await;
+        if stats["dead"] == json!(1) {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+    assert_eq!(json!(2), stats["enqueued"]);
+    assert_eq!(json!(1), stats["completed"]);
+    // failed on both attempts
+    assert_eq!(json!(2), stats["failed"]);
+    assert_eq!(json!(0), stats["waiting"]);
+    let dead = json_body(server.admin(Method::GET, "admin/jobs/api_test/dead")).await;
+    assert_eq!(json!("broken"), dead[0]["kind"]);
+    assert_eq!(json!("Broken"), dead[0]["error"]);
+}
+
+#[tokio::test]
+async fn admin_schedule_test() {
+    let server = spawn_server_with(Config {
+        schedule: vec![("janitor".to_owned(), "@daily".parse().unwrap())]
+            .into_iter()
+            .collect(),
+        ..Config::default()
+    });
+    let report = json_body(server.admin(Method::GET, "admin/schedule")).await;
+    assert_eq!(json!("janitor"), report[0]["name"]);
+    assert_eq!(json!("@daily"), report[0]["cron"]);
+    assert_eq!(Some(0), report[0]["next_run_s"].as_u64().map(|t| t % 86400));
+    assert_eq!(json!(false), report[0]["running"]);
+    assert_eq!(json!(0), report[0]["runs"]);
+}
+
+#[tokio::test]
+async fn admin_journals_test() {
+    let server = spawn_server();
+    let user = server.create_user().await;
+    server.create_store(&user, "MyStore").await;
+    let stats = json_body(server.admin(Method::GET, "admin/journals")).await;
+    assert_eq!(true, stats["stores"].as_u64().unwrap() >= 1);
+    for counter in &["entries", "longest", "expired"] {
+        assert_eq!(true, stats[counter].is_u64(), "{}", counter);
+    }
+}
@@ -0,0 +1,689 @@
+// Who may call each route of the API. Every route is listed in `ROUTES` with
+// the access it needs, and `authorization_matrix_test` calls it as every
+// kind of client to check it lets in exactly those it should
+mod common;
+
+use reqwest::{Method, StatusCode};
+
+use common::*;
+use efficio_server::{
+    config::Config, endpoints::HEADER_AUTH, nutrition::NutritionKind, route_table,
+    types::PublishTemplate,
+};
+
+const REDIRECT_URI: &str = "https://assistant.example/link";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    // anyone, a session token changes nothing
+    Public,
+    // the bearer of the admin token, a session token changes nothing
+    Admin,
+    // any logged in user, on their own data
+    Session,
+    // the user owning the store, aisle or product in the path
+    Owner,
+    // the user of the account in the path
+    Account,
+}
+
+impl Access {
+    // What the route answers with no token, a malformed one, one of no
+    // session, and the session of a user who is not the owner; None for a
+    // success. Other users' data is not found to strangers, as if it didn't
+    // exist, but a session naming another account is not theirs to begin
+    // with.
+    fn refusals(self) -> Option<[Option<StatusCode>; 4]> {
+        let unauthorised = Some(StatusCode::UNAUTHORIZED);
+        match self {
+            Access::Public => None,
+            Access::Admin => Some([Some(StatusCode::FORBIDDEN); 4]),
+            Access::Session => Some([unauthorised, unauthorised, unauthorised, None]),
+            Access::Owner => Some([
+                unauthorised,
+                unauthorised,
+                unauthorised,
+                Some(StatusCode::NOT_FOUND),
+            ]),
+            Access::Account => Some([unauthorised; 4]),
+        }
+    }
+}
+
+struct Route {
+    method: Method,
+    // `{user}`, `{store}`, `{other_store}`, `{aisle}` and `{product}` stand for
+    // the ids of the caller's data, or the owner's, `{template_code}` for a
+    // code sharing their store, `{published}` for their template in the
+    // community directory and `{snapshot}` for a snapshot of their store
+    path: &'static str,
+    body: Option<&'static str>,
+    access: Access,
+}
+
+const fn route(
+    method: Method,
+    path: &'static str,
+    body: Option<&'static str>,
+    access: Access,
+) -> Route {
+    Route {
+        method,
+        path,
+        body,
+        access,
+    }
+}
+
+// `POST /nuke` is left out, it would empty the database under the other tests
+const ROUTES: &[Route] = &[
+    route(
+        Method::POST,
+        "user",
+        Some(r#"{"username": "", "email": "", "password": ""}"#),
+        Access::Public,
+    ),
+    route(
+        Method::GET,
+        "user/check?username=someone",
+        None,
+        Access::Public,
+    ),
+    route(
+        Method::POST,
+        "login",
+        Some(r#"{"username": "nobody", "password": "wrong"}"#),
+        Access::Public,
+    ),
+    route(
+        Method::POST,
+        "user/pair/claim",
+        Some(r#"{"code": "AAAAAAAA"}"#),
+        Access::Public,
+    ),
+    route(Method::GET, "challenge", None, Access::Public),
+    route(Method::GET, "schema", None, Access::Public),
+    route(Method::GET, "health", None, Access::Public),
+    route(
+        Method::GET,
+        "store/{store}/export?expires=0&sig=none",
+        None,
+        Access::Public,
+    ),
+    route(
+        Method::GET,
+        "store/{store}/shared?expires=0&sig=none",
+        None,
+        Access::Public,
+    ),
+    route(Method::POST, "user/pair", None, Access::Session),
+    route(Method::GET, "user/default_aisles", None, Access::Session),
+    route(Method::GET, "user/aliases", None, Access::Session),
+    route(
+        Method::PUT,
+        "user/aliases",
+        Some(r#"{"aliases": {}}"#),
+        Access::Session,
+    ),
+    route(Method::GET, "user/anywhere", None, Access::Session),
+    route(
+        Method::DELETE,
+        "user/anywhere/{product}",
+        None,
+        Access::Session,
+    ),
+    route(Method::GET, "user/quota", None, Access::Session),
+    route(Method::GET, "user/staples", None, Access::Session),
+    route(
+        Method::PUT,
+        "user/staples",
+        Some(r#"{"staples": [{"name": "Milk", "aisle": "Dairy"}]}"#),
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "user/staples",
+        Some(r#"{"name": "Bread", "aisle": "Bakery"}"#),
+        Access::Session,
+    ),
+    route(
+        Method::DELETE,
+        "user/staples?name=bread",
+        None,
+        Access::Session,
+    ),
+    route(Method::GET, "templates", None, Access::Session),
+    route(
+        Method::POST,
+        "templates",
+        Some(r#"{"name": "Weekly", "store_id": "{store}"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::DELETE,
+        "templates?name=weekly",
+        None,
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "templates/apply",
+        Some(r#"{"name": "Template", "store_id": "{other_store}"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "templates/redeem",
+        Some(r#"{"code": "{template_code}"}"#),
+        Access::Session,
+    ),
+    route(Method::GET, "community?q=aldi", None, Access::Session),
+    route(
+        Method::POST,
+        "community",
+        Some(r#"{"name": "Template", "supermarket": "Aldi"}"#),
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "community/{published}/report",
+        None,
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "community/{published}/save",
+        None,
+        Access::Session,
+    ),
+    route(
+        Method::PUT,
+        "user/default_aisles",
+        Some(r#"{"aisles": null}"#),
+        Access::Session,
+    ),
+    route(Method::GET, "store", None, Access::Session),
+    route(
+        Method::POST,
+        "store",
+        Some(r#"{"name": "Other"}"#),
+        Access::Session,
+    ),
+    route(
+        Method::GET,
+        "assistant/link?response_type=token&redirect_uri=https%3A%2F%2Fassistant.example%2Flink",
+        None,
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "assistant/add",
+        Some(r#"{"text": "milk"}"#),
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "assistant/list",
+        Some(r#"{}"#),
+        Access::Session,
+    ),
+    route(Method::GET, "store/{store}", None, Access::Owner),
+    route(Method::GET, "store/{store}/print", None, Access::Owner),
+    route(Method::GET, "store/{store}/text", None, Access::Owner),
+    route(Method::GET, "store/{store}/stats", None, Access::Owner),
+    route(Method::GET, "store/{store}/insights", None, Access::Owner),
+    route(Method::GET, "store/{store}/ui_state", None, Access::Owner),
+    route(Method::GET, "store/{store}/crdt", None, Access::Owner),
+    route(
+        Method::POST,
+        "store/{store}/crdt",
+        Some(r#"{"products": [], "removed": []}"#),
+        Access::Owner,
+    ),
+    route(Method::GET, "store/{store}/nutrition", None, Access::Owner),
+    route(
+        Method::POST,
+        "store/{store}/add_staples",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "store/{store}/ui_state",
+        Some(r#"{"collapsed_aisles": ["{aisle}"], "hide_checked": true}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "store/{store}",
+        Some(r#"{"name": "Renamed"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/export_link",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/share_link",
+        Some(r#"{"origin": "https://efficio.app"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/template_code",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/snapshot",
+        Some(r#"{"name": "Monday"}"#),
+        Access::Owner,
+    ),
+    route(Method::GET, "store/{store}/snapshots", None, Access::Owner),
+    route(
+        Method::POST,
+        "store/{store}/snapshots/{snapshot}/restore",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::GET,
+        "store/{store}/snapshots/{snapshot}/diff/live",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/merge_from/{other_store}?dry_run=true",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/merge_from/{other_store}",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/aisle",
+        Some(r#"{"name": "Aisle"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "aisle/{aisle}",
+        Some(r#"{"name": "Renamed"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "aisle/{aisle}/product",
+        Some(r#"{"name": "Milk"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::POST,
+        "store/{store}/product",
+        Some(r#"{"name": "Milk"}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "product/{product}",
+        Some(r#"{"is_done": true}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "sort_weight",
+        Some(r#"{"aisles": [{"id": "{aisle}", "sort_weight": 2.0}]}"#),
+        Access::Owner,
+    ),
+    route(
+        Method::GET,
+        "product/{product}/nutrition",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::GET,
+        "product/{product}/history",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::GET,
+        "nutrition?barcode=3017620422003",
+        None,
+        Access::Session,
+    ),
+    route(
+        Method::POST,
+        "product/{product}/anywhere",
+        None,
+        Access::Owner,
+    ),
+    route(
+        Method::PUT,
+        "products/done",
+        Some(r#"{"product_ids": ["{product}"], "is_done": true}"#),
+        Access::Owner,
+    ),
+    route(Method::DELETE, "product/{product}", None, Access::Owner),
+    route(
+        Method::DELETE,
+        "products",
+        Some(r#"{"product_ids": ["{product}"]}"#),
+        Access::Owner,
+    ),
+    route(Method::DELETE, "aisle/{aisle}", None, Access::Owner),
+    route(
+        Method::DELETE,
+        "store/{store}?dry_run=true",
+        None,
+        Access::Owner,
+    ),
+    route(Method::DELETE, "store/{store}", None, Access::Owner),
+    route(Method::POST, "logout/{user}", None, Access::Account),
+    route(
+        Method::DELETE,
+        "user/{user}?dry_run=true",
+        None,
+        Access::Account,
+    ),
+    route(Method::DELETE, "user/{user}", None, Access::Account),
+    route(Method::GET, "admin/config", None, Access::Admin),
+    route(Method::GET, "admin/maintenance", None, Access::Admin),
+    route(Method::GET, "admin/schema", None, Access::Admin),
+    route(Method::GET, "admin/read_only", None, Access::Admin),
+    route(Method::GET, "admin/telemetry", None, Access::Admin),
+    route(
+        Method::PUT,
+        "admin/read_only",
+        Some(r#"{"enabled": false}"#),
+        Access::Admin,
+    ),
+    route(
+        Method::PUT,
+        "admin/maintenance",
+        Some(r#"{"enabled": false}"#),
+        Access::Admin,
+    ),
+    route(Method::GET, "admin/bans", None, Access::Admin),
+    route(
+        Method::DELETE,
+        "admin/bans/ip:192.0.2.1",
+        None,
+        Access::Admin,
+    ),
+    route(Method::POST, "admin/invites", Some(r#"{}"#), Access::Admin),
+    route(
+        Method::DELETE,
+        "admin/invites/nonexistent",
+        None,
+        Access::Admin,
+    ),
+    route(Method::POST, "admin/email_index", Some("[]"), Access::Admin),
+    route(Method::POST, "admin/store_counts", None, Access::Admin),
+    route(Method::GET, "admin/schedule", None, Access::Admin),
+    route(Method::GET, "admin/journals", None, Access::Admin),
+    route(Method::GET, "admin/jobs/default", None, Access::Admin),
+    route(Method::GET, "admin/jobs/default/dead", None, Access::Admin),
+    route(Method::GET, "admin/community", None, Access::Admin),
+    route(
+        Method::POST,
+        "admin/community/00000000-0000-4000-8000-000000000005/restore",
+        None,
+        Access::Admin,
+    ),
+    route(
+        Method::DELETE,
+        "admin/community/00000000-0000-4000-8000-000000000005",
+        None,
+        Access::Admin,
+    ),
+];
+
+// A user with a store holding an aisle holding a product, an empty store, a
+// template of the first store's aisles, a code sharing it, and the template
+// published to the community directory
+struct Owner {
+    user: TestUser,
+    store: String,
+    other_store: String,
+    aisle: String,
+    product: String,
+    template_code: String,
+    published: String,
+    snapshot: String,
+}
+
+impl Owner {
+    async fn new(server: &TestServer) -> Self {
+        let user = server.create_user().await;
+        let client = server.client().with_token(&user.auth);
+        let store = client.create_store("Store").await.unwrap().store_id;
+        let other_store = client.create_store("Other").await.unwrap().store_id;
+        let aisle = client.create_aisle(&store, "Aisle").await.unwrap().aisle_id;
+        let product = client
+            .create_product(&aisle, "Product")
+            .await
+            .unwrap()
+            .product_id;
+        client.save_template("Template", &store).await.unwrap();
+        let template_code = client.template_code(&store).await.unwrap().code;
+        let published = client
+            .publish_template(&PublishTemplate::new(
+                "Template".to_owned(),
+                "Aldi".to_owned(),
+                String::new(),
+            ))
+            .await
+            .unwrap()
+            .template_id;
+        let snapshot = client
+            .create_snapshot(&store, "Snapshot")
+            .await
+            .unwrap()
+            .snapshot_id;
+        Owner {
+            user,
+            store,
+            other_store,
+            aisle,
+            product,
+            template_code,
+            published,
+            snapshot,
+        }
+    }
+
+    // The same user with ids of a store, aisle and product which don't exist
+    fn with_missing_data(&self) -> Self {
+        Owner {
+            user: self.user.clone(),
+            store: "00000000-0000-4000-8000-000000000001".to_owned(),
+            other_store: "00000000-0000-4000-8000-000000000002".to_owned(),
+            aisle: "00000000-0000-4000-8000-000000000003".to_owned(),
+            product: "00000000-0000-4000-8000-000000000004".to_owned(),
+            template_code: "22222222".to_owned(),
+            published: "00000000-0000-4000-8000-000000000005".to_owned(),
+            snapshot: "00000000-0000-4000-8000-000000000006".to_owned(),
+        }
+    }
+
+    fn fill(&self, template: &str) -> String {
+        template
+            .replace("{user}", &self.user.user_id)
+            .replace("{store}", &self.store)
+            .replace("{other_store}", &self.other_store)
+            .replace("{aisle}", &self.aisle)
+            .replace("{product}", &self.product)
+            .replace("{template_code}", &self.template_code)
+            .replace("{published}", &self.published)
+            .replace("{snapshot}", &self.snapshot)
+    }
+}
+
+async fn call(
+    server: &TestServer,
+    route: &Route,
+    owner: &Owner,
+    token: Option<&str>,
+    admin: bool,
+) -> StatusCode {
+    // a redirection is the answer, not something to follow
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let url = server.url(&owner.fill(route.path));
+    let mut request = client.request(route.method.clone(), &url);
+    if let Some(token) = token {
+        request = request.header(HEADER_AUTH, token);
+    }
+    if admin {
+        request = request.bearer_auth(ADMIN_TOKEN);
+    }
+    if let Some(body) = route.body {
+        request = request
+            .header("content-type", "application/json")
+            .body(owner.fill(body));
+    }
+    status(request).await
+}
+
+#[tokio::test]
+async fn authorization_matrix_test() {
+    let (food_facts, _) = spawn_food_facts();
+    let server = spawn_server_with(Config {
+        assistant_redirect_uri: vec![REDIRECT_URI.to_owned()],
+        nutrition: NutritionKind::OpenFoodFacts,
+        nutrition_url: food_facts,
+        community: true,
+        ..Config::default()
+    });
+    // well formed, in the format of the first sessions
+    let unknown = "0".repeat(64);
+    let other = Owner::new(&server).await;
+    for route in ROUTES {
+        // fresh data for each route, some delete it
+        let owner = Owner::new(&server).await;
+        let what = format!("{} {} ({:?})", route.method, route.path, route.access);
+        let tokens = [
+            None,
+            Some("garbage"),
+            Some(unknown.as_str()),
+            Some(other.user.auth.as_str()),
+        ];
+        let mut statuses = vec![];
+        for token in &tokens {
+            statuses.push(call(&server, route, &owner, *token, false).await);
+        }
+        match route.access.refusals() {
+            Some(refusals) => {
+                for (status, refusal) in statuses.iter().zip(refusals.iter()) {
+                    match refusal {
+                        Some(refusal) => assert_eq!(*refusal, *status, "{}", what),
+                        None => assert_eq!(true, is_allowed(*status), "{}: {}", what, status),
+                    }
+                }
+                // which ids exist can't be told from someone else's
+                if route.access == Access::Owner {
+                    let missing = owner.with_missing_data();
+                    let status =
+                        call(&server, route, &missing, Some(&owner.user.auth), false).await;
+                    assert_eq!(StatusCode::NOT_FOUND, status, "{} with missing ids", what);
+                }
+                if route.access == Access::Admin {
+                    // in, even if what it names doesn't exist
+                    let status = call(&server, route, &owner, None, true).await;
+                    assert_ne!(StatusCode::FORBIDDEN, status, "{} as admin", what);
+                } else {
+                    let status = call(&server, route, &owner, Some(&owner.user.auth), false).await;
+                    assert_eq!(true, is_allowed(status), "{}: {}", what, status);
+                }
+            }
+            // the token is not even looked at
+            None => {
+                for status in &statuses {
+                    assert_eq!(statuses[0], *status, "{}", what);
+                }
+            }
+        }
+    }
+}
+
+// every route of the server is in `ROUTES`
+#[test]
+fn routes_test() {
+    for (method, pattern) in route_table::ROUTES {
+        if (*method, *pattern) == ("POST", "nuke") {
+            continue;
+        }
+        let covered = ROUTES.iter().any(|route| {
+            let path = route.path.split('?').next().unwrap();
+            route.method.as_str() == *method && route_table::matches(pattern, path)
+        });
+        assert_eq!(true, covered, "{} {}", method, pattern);
+    }
+}
+
+// `ROUTES` and the filters agree on the methods of every path: those in the
+// table get past the 405, the others don't
+#[tokio::test]
+async fn route_table_methods_test() {
+    let server = spawn_server_with(Config {
+        community: true,
+        ..Config::default()
+    });
+    let owner = Owner::new(&server).await;
+    let methods = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::PATCH,
+    ];
+    for (_, pattern) in route_table::ROUTES {
+        let path = owner
+            .fill(pattern)
+            .replace("{template}", &owner.published)
+            .replace("{other}", &owner.snapshot)
+            .replace("{subject}", "ip:203.0.113.7")
+            .replace("{code}", &owner.template_code)
+            .replace("{queue}", "default");
+        let allowed = route_table::allowed_methods(&path);
+        for method in &methods {
+            let listed = allowed.contains(&method.as_str());
+            // it would empty the database under the other tests
+            if listed && path == "nuke" {
+                continue;
+            }
+            let mut request = server
+                .request(method.clone(), &path)
+                .header(HEADER_AUTH, &owner.user.auth);
+            if path.starts_with("admin/") {
+                request = request.bearer_auth(ADMIN_TOKEN);
+            }
+            let status = status(request).await;
+            assert_eq!(
+                listed,
+                status != StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}: {}",
+                method,
+                pattern,
+                status
+            );
+        }
+    }
+}
+
+fn is_allowed(status: StatusCode) -> bool {
+    status.is_success() || status == StatusCode::FOUND
+}
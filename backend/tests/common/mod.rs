@@ -0,0 +1,202 @@
+// each test binary only uses some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde_json::Value;
+
+use efficio_client::Client as ApiClient;
+use efficio_server::{
+    breaker::CircuitBreaker,
+    config::{Config, LiveConfig},
+    endpoints::{routes, HEADER_AUTH},
+    storage::{Pools, StorageManager},
+    types::User,
+};
+
+// in-memory storage unless a throwaway Redis database is given, like
+// `EFFICIO_TEST_REDIS=redis://127.0.0.1/14`
+const TEST_REDIS_VAR: &str = "EFFICIO_TEST_REDIS";
+const TEST_DB: i64 = 14;
+pub const PASSWORD: &str = "correct horse battery staple";
+// the admin token of the test servers, unless their config has another one
+pub const ADMIN_TOKEN: &str = "admin-token";
+
+lazy_static! {
+    static ref POOL: routes::Pool = {
+        let manager = match std::env::var(TEST_REDIS_VAR) {
+            Ok(addr) => StorageManager::redis(addr.as_str()).unwrap(),
+            Err(_) => StorageManager::memory(TEST_DB),
+        };
+        let pool = r2d2::Pool::builder().max_size(4).build(manager).unwrap();
+        // flushed once per test binary
+        redis::cmd("FLUSHDB")
+            .query::<()>(&mut *pool.get().unwrap())
+            .expect("error on flush");
+        pool
+    };
+}
+
+static USER_NUM: AtomicUsize = AtomicUsize::new(0);
+
+// the db is shared by all tests of a binary, each user gets a unique name
+pub fn unique_username() -> String {
+    format!("user{}", USER_NUM.fetch_add(1, Ordering::SeqCst))
+}
+
+pub struct TestServer {
+    addr: SocketAddr,
+    client: Client,
+}
+
+#[derive(Clone)]
+pub struct TestUser {
+    pub username: String,
+    pub user_id: String,
+    pub auth: String,
+}
+
+// the pool the test servers use
+pub fn pool() -> routes::Pool {
+    POOL.clone()
+}
+
+// launch the API on a random port for the duration of the test
+pub fn spawn_server() -> TestServer {
+    spawn_server_with(Config::default())
+}
+
+pub fn spawn_server_with(mut config: Config) -> TestServer {
+    if config.admin_token.is_empty() {
+        config.admin_token = ADMIN_TOKEN.to_owned();
+    }
+    let (addr, server) = warp::serve(routes::routes(
+        Pools::new(POOL.clone(), None),
+        Arc::new(LiveConfig::new(config)),
+        CircuitBreaker::new(Duration::from_secs(1)),
+    ))
+    .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    TestServer {
+        addr,
+        client: Client::new(),
+    }
+}
+
+pub const NUTELLA_BARCODE: &str = "3017620422003";
+
+// A stand-in for Open Food Facts knowing only of Nutella, by barcode or name.
+// Returns its URL and how many lookups it got.
+pub fn spawn_food_facts() -> (String, Arc<AtomicUsize>) {
+    use std::collections::HashMap;
+    use warp::Filter;
+
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let nutella = serde_json::json!({
+        "product_name": "Nutella",
+        "nutriments": { "energy-kcal_100g": 539, "sugars_100g": 56.3 }
+    });
+    let by_barcode = {
+        let (nutella, lookups) = (nutella.clone(), lookups.clone());
+        warp::path!("api" / "v0" / "product" / String).map(move |file: String| {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            if file == format!("{}.json", NUTELLA_BARCODE) {
+                warp::reply::json(&serde_json::json!({ "status": 1, "product": nutella }))
+            } else {
+                warp::reply::json(&serde_json::json!({ "status": 0 }))
+            }
+        })
+    };
+    let by_name = {
+        let lookups = lookups.clone();
+        warp::path!("cgi" / "search.pl")
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |query: HashMap<String, String>| {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                let terms = query.get("search_terms").map(|t| t.to_lowercase());
+                let products = if terms.as_deref() == Some("nutella") {
+                    vec![nutella.clone()]
+                } else {
+                    vec![]
+                };
+                warp::reply::json(&serde_json::json!({ "products": products }))
+            })
+    };
+    let (addr, server) =
+        warp::serve(warp::get().and(by_barcode.or(by_name))).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    (format!("http://{}", addr), lookups)
+}
+
+impl TestServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/api/{}", self.addr, path)
+    }
+
+    // a path outside of /api
+    pub fn root_request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, &format!("http://{}/{}", self.addr, path))
+    }
+
+    // typed client of the server, not logged in
+    pub fn client(&self) -> ApiClient {
+        ApiClient::new(&format!("http://{}", self.addr))
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client.request(method, &self.url(path))
+    }
+
+    // a request of the admin API, with the admin token
+    pub fn admin(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request(method, path).bearer_auth(ADMIN_TOKEN)
+    }
+
+    pub fn authed(&self, method: Method, path: &str, user: &TestUser) -> RequestBuilder {
+        self.request(method, path)
+            .header(HEADER_AUTH, user.auth.as_str())
+    }
+
+    pub async fn create_user(&self) -> TestUser {
+        let username = unique_username();
+        let user = User {
+            username: username.clone(),
+            email: format!("{}@efficio.app", username).into(),
+            password: PASSWORD.into(),
+            invite: None,
+            challenge: None,
+        };
+        let token = self.client().create_user(&user).await.unwrap();
+        TestUser {
+            username,
+            user_id: token.user_id.clone(),
+            auth: token.session_token.to_string(),
+        }
+    }
+
+    pub async fn create_store(&self, user: &TestUser, name: &str) -> String {
+        let client = self.client().with_token(&user.auth);
+        client.create_store(name).await.unwrap().store_id.clone()
+    }
+}
+
+pub async fn status(request: RequestBuilder) -> StatusCode {
+    request.send().await.unwrap().status()
+}
+
+// send the request, expect a 200 and return its JSON body
+pub async fn json_body(request: RequestBuilder) -> Value {
+    let res = request.send().await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    res.json().await.unwrap()
+}
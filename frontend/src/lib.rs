@@ -3,6 +3,7 @@
 
 use std::collections::BTreeMap;
 
+use efficio_types::Store;
 use seed::{prelude::*, *};
 use serde::{Deserialize, Serialize};
 
@@ -18,8 +19,6 @@ struct Model {
     page: Page,
 }
 
-struct Store {}
-
 #[derive(Serialize, Deserialize)]
 struct User {}
 